@@ -1,8 +1,17 @@
+use std::{path::Path, sync::Arc};
+
+use openssl::{
+	error::ErrorStack,
+	symm::{decrypt_aead, encrypt_aead, Cipher},
+};
 use tide::StatusCode;
 use tracing::{error, info};
 
 use crate::{error, state::State};
 
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct Credentials {
 	pub username: String,
@@ -14,6 +23,78 @@ pub struct User {
 	pub email: String,
 }
 
+/// Keeps passwords that have to outlive a restart, the held messages of
+/// an account are sent with them. The key never leaves the data directory.
+#[derive(Clone)]
+pub struct Vault {
+	key: Arc<[u8; 32]>,
+}
+
+impl Vault {
+	/// Reads the key at `path`, or makes one when there is none yet.
+	pub fn load(path: &Path) -> std::io::Result<Self> {
+		let mut key = [0; 32];
+		match std::fs::read(path) {
+			Ok(stored) if stored.len() == key.len() => key.copy_from_slice(&stored),
+			Ok(_) => {
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					"the vault key must be 32 bytes",
+				))
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				openssl::rand::rand_bytes(&mut key).map_err(std::io::Error::other)?;
+				if let Some(dir) = path.parent() {
+					std::fs::create_dir_all(dir)?;
+				}
+				std::fs::write(path, key)?;
+			}
+			Err(e) => return Err(e),
+		}
+		Ok(Vault { key: Arc::new(key) })
+	}
+
+	/// The password encrypted for this vault, bound to the username.
+	pub fn seal(&self, credentials: &Credentials) -> Result<String, ErrorStack> {
+		let mut nonce = [0; NONCE_LEN];
+		openssl::rand::rand_bytes(&mut nonce)?;
+		let mut tag = [0; TAG_LEN];
+		let sealed = encrypt_aead(
+			Cipher::aes_256_gcm(),
+			&*self.key,
+			Some(&nonce),
+			credentials.username.as_bytes(),
+			credentials.password.as_bytes(),
+			&mut tag,
+		)?;
+		Ok(base64::encode([&nonce[..], &tag, &sealed].concat()))
+	}
+
+	/// The credentials of `username` from [`Vault::seal`], `None` if they
+	/// were sealed for someone else or with another key.
+	pub fn open(&self, username: &str, sealed: &str) -> Option<Credentials> {
+		let sealed = base64::decode(sealed).ok()?;
+		if sealed.len() < NONCE_LEN + TAG_LEN {
+			return None;
+		}
+		let (nonce, rest) = sealed.split_at(NONCE_LEN);
+		let (tag, sealed) = rest.split_at(TAG_LEN);
+		let password = decrypt_aead(
+			Cipher::aes_256_gcm(),
+			&*self.key,
+			Some(nonce),
+			username.as_bytes(),
+			sealed,
+			tag,
+		)
+		.ok()?;
+		Some(Credentials {
+			username: username.to_owned(),
+			password: String::from_utf8(password).ok()?,
+		})
+	}
+}
+
 pub struct Authentication;
 
 impl Authentication {
@@ -37,7 +118,7 @@ fn unauthorized_response() -> tide::Result {
 		.header("WWW-Authenticate", "Basic")
 		.build();
 
-	return Ok(response);
+	Ok(response)
 }
 
 fn decode_basic_auth(auth_param: &str) -> tide::Result<Credentials> {
@@ -86,25 +167,25 @@ impl tide::Middleware<State> for Authentication {
 		let auth_header = req.header("Authorization");
 		if auth_header.is_none() {
 			info!("no auth header, bailing");
-			return Ok(unauthorized_response()?);
+			return unauthorized_response();
 		}
 
 		let value: Vec<_> = auth_header.unwrap().into_iter().collect();
 		if value.is_empty() {
 			info!("empty auth header, bailing");
-			return Ok(unauthorized_response()?);
+			return unauthorized_response();
 		}
 
 		if value.len() > 1 {
 			error!("multiple auth headers, bailing");
-			return Ok(unauthorized_response()?);
+			return unauthorized_response();
 		}
 
 		let scheme = "Basic ";
 		let value = value[0].as_str();
 		if !value.starts_with(scheme) {
 			error!("received invalid auth value: `{:?}`", value);
-			return Ok(unauthorized_response()?);
+			return unauthorized_response();
 		}
 
 		let auth_param = &value[scheme.len()..];
@@ -117,7 +198,7 @@ impl tide::Middleware<State> for Authentication {
 		let state = req.state();
 		if let Err(e) = state.authenticate(session_id, credentials).await {
 			error!("failed to authenticate: {}", e);
-			return Ok(unauthorized_response()?);
+			return unauthorized_response();
 		}
 
 		req.set_ext(User { email });
@@ -125,3 +206,29 @@ impl tide::Middleware<State> for Authentication {
 		Ok(next.run(req).await)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sealed_credentials_only_open_for_their_user() {
+		let dir = std::env::temp_dir().join(format!("vault-test-{}", std::process::id()));
+		let vault = Vault::load(&dir.join("vault.key")).unwrap();
+		let credentials = Credentials {
+			username: "a@example.com".to_owned(),
+			password: "secret".to_owned(),
+		};
+
+		let sealed = vault.seal(&credentials).unwrap();
+		assert!(!sealed.contains("secret"));
+		assert_eq!(vault.open("a@example.com", &sealed), Some(credentials));
+		assert_eq!(vault.open("b@example.com", &sealed), None);
+		assert_eq!(vault.open("a@example.com", "c2VjcmV0"), None);
+
+		// the key is kept
+		let reloaded = Vault::load(&dir.join("vault.key")).unwrap();
+		assert!(reloaded.open("a@example.com", &sealed).is_some());
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,216 @@
+//! Uploaded blobs, kept on disk until they expire.
+//!
+//! Everything using an upload copies its content, into a mailbox or onto
+//! the ManageSieve server, so nothing keeps referring to it and uploads
+//! simply expire `UPLOAD_TTL` seconds after they were uploaded.
+
+use std::{
+	collections::HashMap,
+	path::PathBuf,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use async_std::fs;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+use crate::jmap::{is_valid_id, Id};
+
+pub const MAX_SIZE_UPLOAD: u64 = 50_000_000;
+pub const MAX_CONCURRENT_UPLOAD: usize = 4;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// What we know about an upload besides its content.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobInfo {
+	pub blob_id:  Id,
+	pub r#type:   String,
+	pub size:     u64,
+	/// RFC 3339.
+	pub uploaded: String,
+}
+
+#[derive(Clone)]
+pub struct BlobStore {
+	dir:       PathBuf,
+	ttl:       chrono::Duration,
+	// uploads in progress by account
+	uploading: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// Counts as an upload in progress until dropped.
+pub struct Upload {
+	account_id: String,
+	uploading:  Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for Upload {
+	fn drop(&mut self) {
+		let mut uploading = self.uploading.lock().unwrap();
+		if let Some(count) = uploading.get_mut(&self.account_id) {
+			*count -= 1;
+			if *count == 0 {
+				uploading.remove(&self.account_id);
+			}
+		}
+	}
+}
+
+/// How long uploads are kept in seconds, from `UPLOAD_TTL`.
+pub fn ttl_from_env() -> u64 {
+	std::env::var("UPLOAD_TTL")
+		.ok()
+		.and_then(|s| s.parse().ok())
+		.unwrap_or(60 * 60)
+}
+
+/// Uploads are content addressed, the same content always gets the same id.
+pub fn upload_blob_id(data: &[u8]) -> Id {
+	format!(
+		"U{}",
+		base64::encode_config(sha2::Sha256::digest(data), base64::URL_SAFE_NO_PAD)
+	)
+}
+
+pub fn is_upload_blob_id(blob_id: &str) -> bool {
+	blob_id.starts_with('U') && is_valid_id(blob_id)
+}
+
+impl BlobStore {
+	pub fn new(dir: impl Into<PathBuf>, ttl: u64) -> Self {
+		BlobStore {
+			dir:       dir.into(),
+			ttl:       chrono::Duration::seconds(ttl as i64),
+			uploading: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	fn account_dir(&self, account_id: &str) -> PathBuf {
+		// like the store, account ids are kept out of the path syntax
+		self.dir
+			.join(base64::encode_config(account_id, base64::URL_SAFE_NO_PAD))
+	}
+
+	fn is_expired(&self, info: &BlobInfo) -> bool {
+		chrono::DateTime::parse_from_rfc3339(&info.uploaded)
+			.map_or(true, |uploaded| uploaded + self.ttl < chrono::Utc::now())
+	}
+
+	/// Starts an upload, `None` if the account has too many in progress.
+	pub fn start_upload(&self, account_id: &str) -> Option<Upload> {
+		let mut uploading = self.uploading.lock().unwrap();
+		let count = uploading.entry(account_id.to_owned()).or_insert(0);
+		if *count >= MAX_CONCURRENT_UPLOAD {
+			return None;
+		}
+		*count += 1;
+
+		Some(Upload {
+			account_id: account_id.to_owned(),
+			uploading:  self.uploading.clone(),
+		})
+	}
+
+	pub async fn put(&self, account_id: &str, r#type: &str, data: &[u8]) -> tide::Result<BlobInfo> {
+		let info = BlobInfo {
+			blob_id:  upload_blob_id(data),
+			r#type:   r#type.to_owned(),
+			size:     data.len() as u64,
+			uploaded: chrono::Utc::now().to_rfc3339(),
+		};
+
+		let dir = self.account_dir(account_id);
+		fs::create_dir_all(&dir).await?;
+		// the content first, an upload only exists once its info does
+		let path = dir.join(&info.blob_id);
+		let tmp = path.with_extension("tmp");
+		fs::write(&tmp, data).await?;
+		fs::rename(&tmp, &path).await?;
+		let tmp = path.with_extension("json.tmp");
+		fs::write(&tmp, serde_json::to_vec(&info)?).await?;
+		fs::rename(&tmp, path.with_extension("json")).await?;
+
+		Ok(info)
+	}
+
+	async fn info(&self, account_id: &str, blob_id: &str) -> tide::Result<Option<BlobInfo>> {
+		if !is_upload_blob_id(blob_id) {
+			return Ok(None);
+		}
+
+		let path = self
+			.account_dir(account_id)
+			.join(blob_id)
+			.with_extension("json");
+		match fs::read(path).await {
+			Ok(data) => {
+				let info: BlobInfo = serde_json::from_slice(&data)?;
+				Ok(Some(info).filter(|i| !self.is_expired(i)))
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// The content of an upload and its info, `None` if there is no such
+	/// upload or it expired.
+	pub async fn get(
+		&self,
+		account_id: &str,
+		blob_id: &str,
+	) -> tide::Result<Option<(BlobInfo, Vec<u8>)>> {
+		let info = match self.info(account_id, blob_id).await? {
+			Some(info) => info,
+			None => return Ok(None),
+		};
+		match fs::read(self.account_dir(account_id).join(blob_id)).await {
+			Ok(data) => Ok(Some((info, data))),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Deletes the expired uploads of all accounts.
+	pub async fn expire(&self) -> tide::Result<()> {
+		let mut accounts = match fs::read_dir(&self.dir).await {
+			Ok(accounts) => accounts,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e.into()),
+		};
+		while let Some(account) = accounts.next().await {
+			let mut entries = fs::read_dir(account?.path()).await?;
+			while let Some(entry) = entries.next().await {
+				let path = entry?.path();
+				if path.extension().is_none_or(|e| e != "json") {
+					continue;
+				}
+
+				// unreadable info counts as expired, the upload is useless anyway
+				let expired = match fs::read(&path).await {
+					Ok(data) => serde_json::from_slice::<BlobInfo>(&data)
+						.map_or(true, |info| self.is_expired(&info)),
+					Err(_) => true,
+				};
+				if expired {
+					fs::remove_file(path.with_extension("")).await.ok();
+					fs::remove_file(&path).await?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Deletes expired uploads, forever.
+pub async fn run(blobs: BlobStore) {
+	loop {
+		if let Err(e) = blobs.expire().await {
+			tracing::error!("expiring uploads failed: {}", e);
+		}
+		async_std::task::sleep(SWEEP_INTERVAL).await;
+	}
+}
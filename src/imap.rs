@@ -1,6 +1,18 @@
+pub mod raw;
+
+use std::{
+	collections::{HashMap, HashSet},
+	ops::RangeInclusive,
+};
+
+use async_imap::types::{NameAttribute, StatusAttribute, UnsolicitedResponse};
 use futures::TryStreamExt;
 
-use crate::auth;
+use crate::{
+	auth,
+	imap::raw::{RawSession, Token},
+	state::ImapSession,
+};
 
 #[tracing::instrument(skip(credentials), fields(email = credentials.username.as_str()))]
 pub async fn create_imap_session(
@@ -29,6 +41,712 @@ pub async fn create_imap_session(
 	Ok(imap_session)
 }
 
+pub async fn create_raw_session(
+	imap_server: &str,
+	credentials: &auth::Credentials,
+) -> async_imap::error::Result<RawSession> {
+	RawSession::connect(imap_server, credentials).await
+}
+
+#[derive(Debug, Clone)]
+pub struct MailboxInfo {
+	pub name:           String,
+	pub delimiter:      Option<String>,
+	pub attributes:     Vec<String>,
+	pub is_subscribed:  bool,
+	pub total:          u32,
+	pub unseen:         u32,
+	pub uid_validity:   u32,
+	pub uid_next:       u32,
+	/// Only with `CONDSTORE` (RFC 7162).
+	pub highest_modseq: Option<u64>,
+}
+
+impl MailboxInfo {
+	pub fn has_attribute(&self, attribute: &str) -> bool {
+		self.attributes
+			.iter()
+			.any(|a| a.eq_ignore_ascii_case(attribute))
+	}
+
+	pub fn is_selectable(&self) -> bool {
+		!self.has_attribute("\\Noselect") && !self.has_attribute("\\NonExistent")
+	}
+
+	/// The name of the parent mailbox according to the hierarchy delimiter.
+	pub fn parent_name(&self) -> Option<&str> {
+		let delimiter = self.delimiter.as_deref()?;
+		self.name.rsplit_once(delimiter).map(|(parent, _)| parent)
+	}
+
+	/// The last segment of the hierarchy, decoded from modified utf-7.
+	pub fn display_name(&self) -> String {
+		let leaf = match self.delimiter.as_deref() {
+			Some(delimiter) => self.name.rsplit(delimiter).next().unwrap_or(&self.name),
+			None => &self.name,
+		};
+		decode_mailbox_name(leaf)
+	}
+}
+
+fn name_attribute_to_string(attribute: &NameAttribute) -> String {
+	match attribute {
+		NameAttribute::NoInferiors => "\\Noinferiors".to_owned(),
+		NameAttribute::NoSelect => "\\Noselect".to_owned(),
+		NameAttribute::Marked => "\\Marked".to_owned(),
+		NameAttribute::Unmarked => "\\Unmarked".to_owned(),
+		NameAttribute::Custom(c) => c.to_string(),
+	}
+}
+
+/// Drops unsolicited responses queued up by previous commands.
+///
+/// async-imap pushes those into a bounded channel, if nobody reads them the
+/// session eventually blocks.
+pub fn drain_unsolicited(session: &mut ImapSession) {
+	while session.unsolicited_responses.try_recv().is_ok() {}
+}
+
+/// Lists all mailboxes with their subscription state and message counts.
+///
+/// With `special_use` the server is explicitly asked for RFC 6154 attributes,
+/// which not every server includes in a plain LIST. `condstore` adds the
+/// highest mod-sequence to the status.
+pub async fn list_mailboxes(
+	session: &mut ImapSession,
+	special_use: bool,
+	condstore: bool,
+) -> async_imap::error::Result<Vec<MailboxInfo>> {
+	let subscribed: HashSet<String> = session
+		.lsub(None, Some("*"))
+		.await?
+		.map_ok(|n| n.name().to_owned())
+		.try_collect()
+		.await?;
+
+	// async-imap puts the pattern into the command verbatim, which lets us
+	// append the return options
+	let pattern = if special_use {
+		"* RETURN (SPECIAL-USE)"
+	} else {
+		"*"
+	};
+	let mut mailboxes: Vec<MailboxInfo> = session
+		.list(None, Some(pattern))
+		.await?
+		.map_ok(|n| MailboxInfo {
+			name:           n.name().to_owned(),
+			delimiter:      n.delimiter().map(|d| d.to_owned()),
+			attributes:     n
+				.attributes()
+				.iter()
+				.map(name_attribute_to_string)
+				.collect(),
+			is_subscribed:  subscribed.contains(n.name()),
+			total:          0,
+			unseen:         0,
+			uid_validity:   0,
+			uid_next:       0,
+			highest_modseq: None,
+		})
+		.try_collect()
+		.await?;
+
+	let items = if condstore {
+		"(MESSAGES UNSEEN UIDVALIDITY UIDNEXT HIGHESTMODSEQ)"
+	} else {
+		"(MESSAGES UNSEEN UIDVALIDITY UIDNEXT)"
+	};
+	for mailbox in mailboxes.iter_mut().filter(|m| m.is_selectable()) {
+		let status = mailbox_status(session, &mailbox.name, items).await?;
+		for attribute in status {
+			match attribute {
+				StatusAttribute::Messages(n) => mailbox.total = n,
+				StatusAttribute::Unseen(n) => mailbox.unseen = n,
+				StatusAttribute::UidValidity(n) => mailbox.uid_validity = n,
+				StatusAttribute::UidNext(n) => mailbox.uid_next = n,
+				StatusAttribute::HighestModSeq(n) => mailbox.highest_modseq = Some(n),
+				_ => {}
+			}
+		}
+	}
+
+	Ok(mailboxes)
+}
+
+/// Runs STATUS for a single mailbox.
+///
+/// async-imap hands STATUS responses to the unsolicited channel instead of
+/// returning them, so we pick the matching one out of there.
+pub async fn mailbox_status(
+	session: &mut ImapSession,
+	name: &str,
+	items: &str,
+) -> async_imap::error::Result<Vec<StatusAttribute>> {
+	drain_unsolicited(session);
+	session.status(name, items).await?;
+
+	let mut result = vec![];
+	while let Ok(response) = session.unsolicited_responses.try_recv() {
+		match response {
+			UnsolicitedResponse::Status {
+				mailbox,
+				attributes,
+			} if mailbox == name => result = attributes,
+			_ => {}
+		}
+	}
+
+	Ok(result)
+}
+
+/// Looks up the RFC 8474 `MAILBOXID` of the given mailboxes.
+///
+/// Only available if the server advertises `OBJECTID`, mailboxes the server
+/// has no id for are left out.
+pub async fn mailbox_object_ids(
+	raw: &mut RawSession,
+	names: &[&str],
+) -> async_imap::error::Result<HashMap<String, String>> {
+	let mut ids = HashMap::new();
+	if !raw.has_capability("OBJECTID") {
+		return Ok(ids);
+	}
+
+	for name in names {
+		let responses = raw
+			.command(&format!("STATUS {} (MAILBOXID)", raw::quote(name)))
+			.await?;
+
+		let id = responses
+			.iter()
+			.filter(|r| r.first().is_some_and(|t| t.is_atom("STATUS")))
+			.filter_map(|r| r.get(2)?.as_list())
+			.find_map(|attributes| raw::find_value(attributes, "MAILBOXID"))
+			.and_then(|id| id.as_list()?.first()?.as_str());
+
+		if let Some(id) = id {
+			ids.insert(name.to_string(), id.to_owned());
+		}
+	}
+
+	Ok(ids)
+}
+
+/// Usage and limit of a resource of a quota root (RFC 9208).
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaResource {
+	pub root:  String,
+	/// The resource name, uppercased, e.g. `STORAGE`.
+	pub name:  String,
+	pub usage: u64,
+	pub limit: u64,
+}
+
+/// Runs `GETQUOTAROOT` for the INBOX and returns the resources of all its
+/// quota roots, nothing if the server doesn't support quotas.
+pub async fn quota(raw: &mut RawSession) -> async_imap::error::Result<Vec<QuotaResource>> {
+	if !raw.has_capability("QUOTA") {
+		return Ok(vec![]);
+	}
+
+	let responses = raw.command("GETQUOTAROOT INBOX").await?;
+
+	Ok(responses
+		.iter()
+		.filter(|r| r.first().is_some_and(|t| t.is_atom("QUOTA")))
+		.filter_map(|r| Some((r.get(1)?.as_str()?, r.get(2)?.as_list()?)))
+		.flat_map(|(root, resources)| {
+			resources.chunks(3).filter_map(move |resource| {
+				let number = |i: usize| resource.get(i)?.as_str()?.parse().ok();
+				Some(QuotaResource {
+					root:  root.to_owned(),
+					name:  resource.first()?.as_str()?.to_ascii_uppercase(),
+					usage: number(1)?,
+					limit: number(2)?,
+				})
+			})
+		})
+		.collect())
+}
+
+/// Opens `mailbox` on the raw connection, read-only unless `read_write` is set.
+pub async fn select(
+	raw: &mut RawSession,
+	mailbox: &str,
+	read_write: bool,
+) -> async_imap::error::Result<Vec<Vec<Token>>> {
+	let command = if read_write { "SELECT" } else { "EXAMINE" };
+	raw.command(&format!("{} {}", command, raw::quote(mailbox)))
+		.await
+}
+
+/// Parses an `INTERNALDATE` like `17-Jul-1996 02:44:25 -0700`.
+pub fn parse_internal_date(date: &str) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+	chrono::DateTime::parse_from_str(date.trim(), "%d-%b-%Y %H:%M:%S %z").ok()
+}
+
+/// Formats uids as a sequence set, merging consecutive ones into ranges.
+pub fn uid_set(uids: &[u32]) -> String {
+	let mut uids = uids.to_vec();
+	uids.sort_unstable();
+	uids.dedup();
+
+	let mut ranges: Vec<(u32, u32)> = vec![];
+	for uid in uids {
+		match ranges.last_mut() {
+			Some((_, end)) if *end + 1 == uid => *end = uid,
+			_ => ranges.push((uid, uid)),
+		}
+	}
+
+	ranges
+		.iter()
+		.map(|(start, end)| {
+			if start == end {
+				start.to_string()
+			} else {
+				format!("{}:{}", start, end)
+			}
+		})
+		.collect::<Vec<_>>()
+		.join(",")
+}
+
+/// Fetches `items` for the given uids of the selected mailbox and returns the
+/// attribute lists by uid.
+pub async fn uid_fetch(
+	raw: &mut RawSession,
+	uids: &[u32],
+	items: &str,
+) -> async_imap::error::Result<Vec<(u32, Vec<Token>)>> {
+	if uids.is_empty() {
+		return Ok(vec![]);
+	}
+
+	fetch_set(raw, &uid_set(uids), items).await
+}
+
+/// [`uid_fetch`] for every message of the selected mailbox.
+pub async fn uid_fetch_all(
+	raw: &mut RawSession,
+	items: &str,
+) -> async_imap::error::Result<Vec<(u32, Vec<Token>)>> {
+	fetch_set(raw, "1:*", items).await
+}
+
+/// [`uid_fetch_all`] limited to messages whose flags changed or that were
+/// added after `modseq` (RFC 7162 `CHANGEDSINCE`).
+///
+/// With `QRESYNC` enabled the uids expunged since then are returned as well,
+/// otherwise they have to be found some other way.
+pub async fn uid_fetch_changed(
+	raw: &mut RawSession,
+	modseq: u64,
+	items: &str,
+) -> async_imap::error::Result<(Vec<(u32, Vec<Token>)>, UidSet)> {
+	let vanished = raw.has_capability("QRESYNC");
+	let modifiers = if vanished {
+		format!("CHANGEDSINCE {} VANISHED", modseq)
+	} else {
+		format!("CHANGEDSINCE {}", modseq)
+	};
+	let responses = raw
+		.command(&format!("UID FETCH 1:* (UID {}) ({})", items, modifiers))
+		.await?;
+
+	let expunged = UidSet(
+		responses
+			.iter()
+			.filter(|r| r.first().is_some_and(|t| t.is_atom("VANISHED")))
+			.filter_map(|r| r.last()?.as_str())
+			.flat_map(|set| parse_uid_set(set).0)
+			.collect(),
+	);
+
+	Ok((fetch_responses(responses), expunged))
+}
+
+/// Uids kept as the ranges they were sent as, `VANISHED` may well name all
+/// possible uids.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UidSet(Vec<RangeInclusive<u32>>);
+
+impl UidSet {
+	pub fn contains(&self, uid: u32) -> bool {
+		self.0.iter().any(|range| range.contains(&uid))
+	}
+}
+
+/// The inverse of [`uid_set`], ignoring anything malformed.
+pub fn parse_uid_set(set: &str) -> UidSet {
+	UidSet(
+		set.split(',')
+			.filter_map(|range| {
+				let (start, end) = range.split_once(':').unwrap_or((range, range));
+				let (start, end): (u32, u32) = (start.parse().ok()?, end.parse().ok()?);
+				Some(start.min(end)..=start.max(end))
+			})
+			.collect(),
+	)
+}
+
+async fn fetch_set(
+	raw: &mut RawSession,
+	set: &str,
+	items: &str,
+) -> async_imap::error::Result<Vec<(u32, Vec<Token>)>> {
+	let responses = raw
+		.command(&format!("UID FETCH {} (UID {})", set, items))
+		.await?;
+
+	Ok(fetch_responses(responses))
+}
+
+/// The attribute lists of `FETCH` responses by uid.
+fn fetch_responses(responses: Vec<Vec<Token>>) -> Vec<(u32, Vec<Token>)> {
+	responses
+		.into_iter()
+		.filter(|r| r.get(1).is_some_and(|t| t.is_atom("FETCH")))
+		.filter_map(|mut r| match r.pop() {
+			Some(Token::List(attributes)) => {
+				let uid = raw::find_value(&attributes, "UID")?.as_number()?;
+				Some((uid, attributes))
+			}
+			_ => None,
+		})
+		.collect()
+}
+
+/// Runs `UID SEARCH` in the selected mailbox.
+pub async fn uid_search(
+	raw: &mut RawSession,
+	criteria: &str,
+) -> async_imap::error::Result<Vec<u32>> {
+	let responses = raw.command(&format!("UID SEARCH {}", criteria)).await?;
+
+	Ok(responses
+		.iter()
+		.filter(|r| r.first().is_some_and(|t| t.is_atom("SEARCH")))
+		.flat_map(|r| r[1..].iter().filter_map(Token::as_number))
+		.collect())
+}
+
+/// Runs `UID SORT` (RFC 5256) in the selected mailbox.
+pub async fn uid_sort(
+	raw: &mut RawSession,
+	sort: &str,
+	criteria: &str,
+) -> async_imap::error::Result<Vec<u32>> {
+	let responses = raw
+		.command(&format!("UID SORT ({}) UTF-8 {}", sort, criteria))
+		.await?;
+
+	Ok(responses
+		.iter()
+		.filter(|r| r.first().is_some_and(|t| t.is_atom("SORT")))
+		.flat_map(|r| r[1..].iter().filter_map(Token::as_number))
+		.collect())
+}
+
+/// Runs `UID THREAD` (RFC 5256) in the selected mailbox and returns the uids
+/// of each thread, the root first.
+pub async fn uid_thread(
+	raw: &mut RawSession,
+	algorithm: &str,
+	criteria: &str,
+) -> async_imap::error::Result<Vec<Vec<u32>>> {
+	fn flatten(tokens: &[Token], uids: &mut Vec<u32>) {
+		for token in tokens {
+			match token {
+				Token::List(children) => flatten(children, uids),
+				token => uids.extend(token.as_number()),
+			}
+		}
+	}
+
+	let responses = raw
+		.command(&format!("UID THREAD {} UTF-8 {}", algorithm, criteria))
+		.await?;
+
+	Ok(responses
+		.iter()
+		.filter(|r| r.first().is_some_and(|t| t.is_atom("THREAD")))
+		.flat_map(|r| r[1..].iter().filter_map(Token::as_list))
+		.map(|thread| {
+			let mut uids = vec![];
+			flatten(thread, &mut uids);
+			uids
+		})
+		.collect())
+}
+
+/// The data of a `BODY[section]` fetch item, ignoring the origin octet of
+/// partial fetches.
+pub fn fetch_section<'a>(attributes: &'a [Token], section: &str) -> Option<&'a [u8]> {
+	fetch_item(attributes, &format!("BODY[{}]", section))
+}
+
+/// Like [`fetch_section`] for `BINARY[section]` (RFC 3516), the content of a
+/// part with its transfer encoding undone by the server.
+pub fn fetch_binary<'a>(attributes: &'a [Token], section: &str) -> Option<&'a [u8]> {
+	fetch_item(attributes, &format!("BINARY[{}]", section))
+}
+
+fn fetch_item<'a>(attributes: &'a [Token], key: &str) -> Option<&'a [u8]> {
+	attributes
+		.chunks(2)
+		.find(|pair| match &pair[0] {
+			Token::Atom(a) => a.split('<').next().unwrap_or(a).eq_ignore_ascii_case(key),
+			_ => false,
+		})
+		.and_then(|pair| pair.get(1)?.as_bytes())
+}
+
+/// Finds the uids of messages with the given RFC 8474 `EMAILID`s in the
+/// selected mailbox.
+pub async fn find_email_ids(
+	raw: &mut RawSession,
+	email_ids: &[&str],
+) -> async_imap::error::Result<Vec<(String, u32)>> {
+	let criteria = match email_ids.split_last() {
+		Some((last, rest)) => rest
+			.iter()
+			.rev()
+			.fold(format!("EMAILID {}", last), |criteria, id| {
+				format!("OR EMAILID {} {}", id, criteria)
+			}),
+		None => return Ok(vec![]),
+	};
+
+	let uids = uid_search(raw, &criteria).await?;
+	if let [uid] = uids[..] {
+		if let [id] = email_ids {
+			return Ok(vec![(id.to_string(), uid)]);
+		}
+	}
+
+	Ok(uid_fetch(raw, &uids, "EMAILID")
+		.await?
+		.into_iter()
+		.filter_map(|(uid, attributes)| {
+			let id = raw::find_value(&attributes, "EMAILID")?
+				.as_list()?
+				.first()?
+				.as_str()?
+				.to_owned();
+			Some((id, uid))
+		})
+		.collect())
+}
+
+/// Adds or removes (`operation` `+FLAGS` or `-FLAGS`) flags of messages in
+/// the selected mailbox.
+pub async fn uid_store(
+	raw: &mut RawSession,
+	uids: &[u32],
+	operation: &str,
+	flags: &[String],
+) -> async_imap::error::Result<()> {
+	if uids.is_empty() || flags.is_empty() {
+		return Ok(());
+	}
+
+	raw.command(&format!(
+		"UID STORE {} {}.SILENT ({})",
+		uid_set(uids),
+		operation,
+		flags.join(" ")
+	))
+	.await
+	.map(|_| ())
+}
+
+/// Copies messages of the selected mailbox to `mailbox`.
+pub async fn uid_copy(
+	raw: &mut RawSession,
+	uids: &[u32],
+	mailbox: &str,
+) -> async_imap::error::Result<()> {
+	raw.command(&format!(
+		"UID COPY {} {}",
+		uid_set(uids),
+		raw::quote(mailbox)
+	))
+	.await
+	.map(|_| ())
+}
+
+/// Moves messages of the selected mailbox to `mailbox`, with RFC 6851 `MOVE`
+/// if possible and `COPY`, `\Deleted` and `UID EXPUNGE` otherwise.
+pub async fn uid_move(
+	raw: &mut RawSession,
+	uids: &[u32],
+	mailbox: &str,
+) -> async_imap::error::Result<()> {
+	if raw.has_capability("MOVE") {
+		return raw
+			.command(&format!(
+				"UID MOVE {} {}",
+				uid_set(uids),
+				raw::quote(mailbox)
+			))
+			.await
+			.map(|_| ());
+	}
+
+	// checked first, the copy couldn't be undone if the delete fails after
+	check_uid_expunge(raw)?;
+	uid_copy(raw, uids, mailbox).await?;
+	uid_delete(raw, uids).await
+}
+
+fn check_uid_expunge(raw: &RawSession) -> async_imap::error::Result<()> {
+	if !raw.has_capability("UIDPLUS") {
+		// a plain EXPUNGE would also remove messages other clients flagged
+		return Err(async_imap::error::Error::No(
+			"the server doesn't support UID EXPUNGE".to_owned(),
+		));
+	}
+	Ok(())
+}
+
+/// Flags messages of the selected mailbox `\Deleted` and expunges exactly
+/// those, which needs `UID EXPUNGE` from RFC 4315.
+pub async fn uid_delete(raw: &mut RawSession, uids: &[u32]) -> async_imap::error::Result<()> {
+	check_uid_expunge(raw)?;
+	uid_store(raw, uids, "+FLAGS", &["\\Deleted".to_owned()]).await?;
+	raw.command(&format!("UID EXPUNGE {}", uid_set(uids)))
+		.await
+		.map(|_| ())
+}
+
+/// Appends a message to `mailbox` and returns its uid if the server tells
+/// (RFC 4315 `APPENDUID`). `date` is an `INTERNALDATE`.
+pub async fn append(
+	raw: &mut RawSession,
+	mailbox: &str,
+	flags: &[String],
+	date: Option<&str>,
+	message: &[u8],
+) -> async_imap::error::Result<Option<u32>> {
+	let mut command = format!("APPEND {} ({})", raw::quote(mailbox), flags.join(" "));
+	if let Some(date) = date {
+		command.push_str(&format!(" \"{}\"", date));
+	}
+	command.push_str(&format!(" {{{}}}\r\n", message.len()));
+
+	let mut command = command.into_bytes();
+	command.extend_from_slice(message);
+	let (_, text) = raw.execute(&command).await?;
+
+	// [APPENDUID <uidvalidity> <uid>]
+	Ok(text
+		.strip_prefix('[')
+		.and_then(|t| t.split(']').next())
+		.and_then(|code| {
+			let mut words = code.split(' ');
+			if !words.next()?.eq_ignore_ascii_case("APPENDUID") {
+				return None;
+			}
+			words.nth(1)?.parse().ok()
+		}))
+}
+
+/// Formats a date as `INTERNALDATE`, see [`parse_internal_date`].
+pub fn format_internal_date<Tz: chrono::TimeZone>(date: &chrono::DateTime<Tz>) -> String
+where
+	Tz::Offset: std::fmt::Display,
+{
+	date.format("%d-%b-%Y %H:%M:%S %z").to_string()
+}
+
+/// Decodes a mailbox name from modified utf-7 (RFC 3501 section 5.1.3).
+pub fn decode_mailbox_name(name: &str) -> String {
+	let mut result = String::with_capacity(name.len());
+	let mut rest = name;
+
+	while let Some(start) = rest.find('&') {
+		result.push_str(&rest[..start]);
+		rest = &rest[start + 1..];
+
+		let end = match rest.find('-') {
+			Some(end) => end,
+			None => {
+				// not valid modified utf-7, keep it as is
+				result.push('&');
+				break;
+			}
+		};
+
+		let encoded = &rest[..end];
+		rest = &rest[end + 1..];
+
+		if encoded.is_empty() {
+			result.push('&');
+			continue;
+		}
+
+		let decoded = base64::decode_config(encoded, base64::IMAP_MUTF7)
+			.ok()
+			.filter(|bytes| bytes.len() % 2 == 0)
+			.and_then(|bytes| {
+				let utf16: Vec<u16> = bytes
+					.chunks(2)
+					.map(|c| u16::from_be_bytes([c[0], c[1]]))
+					.collect();
+				String::from_utf16(&utf16).ok()
+			});
+
+		match decoded {
+			Some(decoded) => result.push_str(&decoded),
+			None => {
+				result.push('&');
+				result.push_str(encoded);
+				result.push('-');
+			}
+		}
+	}
+
+	result.push_str(rest);
+	result
+}
+
+/// Encodes a mailbox name into modified utf-7 (RFC 3501 section 5.1.3).
+pub fn encode_mailbox_name(name: &str) -> String {
+	fn flush(result: &mut String, pending: &mut Vec<u16>) {
+		if pending.is_empty() {
+			return;
+		}
+		let bytes: Vec<u8> = pending.iter().flat_map(|c| c.to_be_bytes()).collect();
+		result.push('&');
+		result.push_str(&base64::encode_config(bytes, base64::IMAP_MUTF7));
+		result.push('-');
+		pending.clear();
+	}
+
+	let mut result = String::with_capacity(name.len());
+	let mut pending = vec![];
+
+	for c in name.chars() {
+		if (' '..='~').contains(&c) {
+			flush(&mut result, &mut pending);
+			if c == '&' {
+				result.push_str("&-");
+			} else {
+				result.push(c);
+			}
+		} else {
+			let mut buf = [0u16; 2];
+			pending.extend_from_slice(c.encode_utf16(&mut buf));
+		}
+	}
+	flush(&mut result, &mut pending);
+
+	result
+}
+
+#[allow(dead_code)]
 pub async fn imap_test(
 	imap_server: &str,
 	login: &str,
@@ -113,3 +831,64 @@ pub async fn imap_test(
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn uid_set_ranges() {
+		assert_eq!(uid_set(&[7, 1, 2, 3, 9, 3]), "1:3,7,9");
+		assert_eq!(uid_set(&[]), "");
+	}
+
+	#[test]
+	fn parse_uid_set_keeps_ranges() {
+		let set = parse_uid_set("1:3,7,10:8,x,5:y");
+		assert_eq!(set, UidSet(vec![1..=3, 7..=7, 8..=10]));
+		assert!([1, 2, 3, 7, 8, 9, 10].iter().all(|uid| set.contains(*uid)));
+		assert!(![0, 4, 5, 6, 11].iter().any(|uid| set.contains(*uid)));
+
+		let everything = parse_uid_set("1:4294967295");
+		assert!(everything.contains(u32::MAX));
+		assert!(!everything.contains(0));
+	}
+
+	#[test]
+	fn decode_modified_utf7() {
+		assert_eq!(
+			decode_mailbox_name("~peter/mail/&U,BTFw-/&ZeVnLIqe-"),
+			"~peter/mail/台北/日本語"
+		);
+		assert_eq!(decode_mailbox_name("Entw&APw-rfe"), "Entwürfe");
+		assert_eq!(decode_mailbox_name("Tom &- Jerry"), "Tom & Jerry");
+		assert_eq!(decode_mailbox_name("&2D3eAA-"), "😀");
+		assert_eq!(decode_mailbox_name("INBOX"), "INBOX");
+	}
+
+	#[test]
+	fn decode_keeps_invalid_modified_utf7() {
+		assert_eq!(decode_mailbox_name("a&b"), "a&b");
+		assert_eq!(decode_mailbox_name("&!!-x"), "&!!-x");
+		// an odd number of octets isn't utf-16
+		assert_eq!(decode_mailbox_name("&AP-"), "&AP-");
+		// a lone surrogate
+		assert_eq!(decode_mailbox_name("&2D0-"), "&2D0-");
+	}
+
+	#[test]
+	fn encode_modified_utf7() {
+		assert_eq!(
+			encode_mailbox_name("~peter/mail/台北/日本語"),
+			"~peter/mail/&U,BTFw-/&ZeVnLIqe-"
+		);
+		assert_eq!(encode_mailbox_name("Tom & Jerry"), "Tom &- Jerry");
+		assert_eq!(encode_mailbox_name("😀 ok"), "&2D3eAA- ok");
+		// control characters aren't printable ascii either
+		assert_eq!(encode_mailbox_name("a\tb"), "a&AAk-b");
+
+		for name in ["Entwürfe", "a&b&-c", "Ünïcödé/😀/&", ""] {
+			assert_eq!(decode_mailbox_name(&encode_mailbox_name(name)), name);
+		}
+	}
+}
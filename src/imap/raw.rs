@@ -0,0 +1,529 @@
+//! A minimal imap connection for extension commands.
+//!
+//! async-imap only understands the responses it has parsers for and treats
+//! everything else as a broken connection, so commands like `STATUS (MAILBOXID)`
+//! go through this connection instead and get their untagged responses back
+//! as generic tokens.
+
+use std::{collections::HashSet, time::Duration};
+
+use async_imap::error::{Error, ParseError, Result};
+use async_native_tls::TlsStream;
+use async_std::{
+	io::{prelude::*, BufReader},
+	net::TcpStream,
+};
+
+use crate::auth;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+	Atom(String),
+	String(Vec<u8>),
+	List(Vec<Token>),
+	Nil,
+}
+
+impl Token {
+	/// Atoms and strings as text.
+	pub fn as_str(&self) -> Option<&str> {
+		match self {
+			Token::Atom(a) => Some(a),
+			Token::String(s) => std::str::from_utf8(s).ok(),
+			_ => None,
+		}
+	}
+
+	pub fn as_bytes(&self) -> Option<&[u8]> {
+		match self {
+			Token::Atom(a) => Some(a.as_bytes()),
+			Token::String(s) => Some(s),
+			_ => None,
+		}
+	}
+
+	pub fn as_number(&self) -> Option<u32> {
+		self.as_str()?.parse().ok()
+	}
+
+	pub fn as_list(&self) -> Option<&[Token]> {
+		match self {
+			Token::List(l) => Some(l),
+			_ => None,
+		}
+	}
+
+	pub fn is_atom(&self, name: &str) -> bool {
+		matches!(self, Token::Atom(a) if a.eq_ignore_ascii_case(name))
+	}
+}
+
+/// Finds the value following `key` in a list of alternating keys and values,
+/// like the attribute list of a `STATUS` or `FETCH` response.
+pub fn find_value<'a>(list: &'a [Token], key: &str) -> Option<&'a Token> {
+	list.chunks(2)
+		.find(|pair| pair[0].is_atom(key))
+		.and_then(|pair| pair.get(1))
+}
+
+/// Quotes `s` for use as an astring, falling back to a literal when needed.
+pub fn quote(s: &str) -> String {
+	if s.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+		format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+	} else {
+		format!("{{{}}}\r\n{}", s.len(), s)
+	}
+}
+
+pub struct RawSession {
+	stream:       BufReader<TlsStream<TcpStream>>,
+	next_tag:     u32,
+	capabilities: HashSet<String>,
+}
+
+impl RawSession {
+	pub async fn connect(imap_server: &str, credentials: &auth::Credentials) -> Result<Self> {
+		let tcp = TcpStream::connect((imap_server, 993)).await?;
+		let tls = async_native_tls::connect(imap_server, tcp).await?;
+
+		let mut session = RawSession {
+			stream:       BufReader::new(tls),
+			next_tag:     1,
+			capabilities: HashSet::new(),
+		};
+
+		// greeting
+		session.read_response().await?;
+
+		session
+			.command(&format!(
+				"LOGIN {} {}",
+				quote(&credentials.username),
+				quote(&credentials.password)
+			))
+			.await?;
+
+		let responses = session.command("CAPABILITY").await?;
+		session.capabilities = responses
+			.iter()
+			.filter(|r| r.first().is_some_and(|t| t.is_atom("CAPABILITY")))
+			.flat_map(|r| r[1..].iter().filter_map(Token::as_str))
+			.map(|c| c.to_ascii_uppercase())
+			.collect();
+
+		// changes since a modseq then include expunged uids (RFC 7162)
+		if session.has_capability("QRESYNC") {
+			session.command("ENABLE QRESYNC").await?;
+		}
+
+		Ok(session)
+	}
+
+	pub fn has_capability(&self, capability: &str) -> bool {
+		self.capabilities.contains(&capability.to_ascii_uppercase())
+	}
+
+	/// Runs `command` and returns all untagged responses it produced.
+	///
+	/// Literals in `command` (as produced by [`quote`]) are sent after the
+	/// server asked for them.
+	pub async fn command(&mut self, command: &str) -> Result<Vec<Vec<Token>>> {
+		self.execute(command.as_bytes())
+			.await
+			.map(|(responses, _)| responses)
+	}
+
+	/// Like [`RawSession::command`] for commands with binary literals, also
+	/// returns the text of the tagged `OK` to get at response codes.
+	pub async fn execute(&mut self, command: &[u8]) -> Result<(Vec<Vec<Token>>, String)> {
+		let tag = format!("X{}", self.next_tag);
+		self.next_tag += 1;
+
+		let mut line = format!("{} ", tag).into_bytes();
+		line.extend_from_slice(command);
+		line.extend_from_slice(b"\r\n");
+		self.send(&tag, &line).await?;
+
+		let mut responses = vec![];
+		loop {
+			let response = self.read_response().await?;
+			if let Some(untagged) = response.strip_prefix(b"* ") {
+				responses.push(parse_response(untagged)?);
+			} else if let Some(done) = strip_tag(&response, &tag) {
+				return check_done(done).map(|text| (responses, text));
+			}
+		}
+	}
+
+	/// Waits in `IDLE` (RFC 2177) until the server reports something or
+	/// `timeout` passed, and returns what it reported.
+	pub async fn idle(&mut self, timeout: Duration) -> Result<Vec<Vec<Token>>> {
+		let tag = format!("X{}", self.next_tag);
+		self.next_tag += 1;
+		self.send(&tag, format!("{} IDLE\r\n", tag).as_bytes())
+			.await?;
+
+		let mut responses = vec![];
+		loop {
+			let response = self.read_response().await?;
+			if response.starts_with(b"+") {
+				break;
+			} else if let Some(untagged) = response.strip_prefix(b"* ") {
+				responses.push(parse_response(untagged)?);
+			} else if let Some(done) = strip_tag(&response, &tag) {
+				return check_done(done).map(|_| responses);
+			}
+		}
+
+		// only wait for a response to start, reading one could be cut off halfway
+		if let Ok(buffered) = async_std::future::timeout(
+			timeout,
+			futures::AsyncBufReadExt::fill_buf(&mut self.stream),
+		)
+		.await
+		{
+			if buffered?.is_empty() {
+				return Err(Error::ConnectionLost);
+			}
+		}
+
+		self.send(&tag, b"DONE\r\n").await?;
+		loop {
+			let response = self.read_response().await?;
+			if let Some(untagged) = response.strip_prefix(b"* ") {
+				responses.push(parse_response(untagged)?);
+			} else if let Some(done) = strip_tag(&response, &tag) {
+				return check_done(done).map(|_| responses);
+			}
+		}
+	}
+
+	async fn send(&mut self, tag: &str, mut data: &[u8]) -> Result<()> {
+		while let Some((end, len)) = next_literal(data) {
+			self.stream.get_mut().write_all(&data[..end]).await?;
+			self.stream.get_mut().flush().await?;
+
+			let response = self.read_response().await?;
+			if !response.starts_with(b"+") {
+				return match strip_tag(&response, tag) {
+					Some(done) => check_done(done).map(|_| ()),
+					None => Err(Error::Bad("expected continuation".to_owned())),
+				};
+			}
+
+			self.stream
+				.get_mut()
+				.write_all(&data[end..end + len])
+				.await?;
+			data = &data[end + len..];
+		}
+
+		self.stream.get_mut().write_all(data).await?;
+		self.stream.get_mut().flush().await?;
+
+		Ok(())
+	}
+
+	/// Reads a single response including all literals it contains.
+	async fn read_response(&mut self) -> Result<Vec<u8>> {
+		let mut response = vec![];
+		loop {
+			let start = response.len();
+			if self.stream.read_until(b'\n', &mut response).await? == 0 {
+				return Err(Error::ConnectionLost);
+			}
+
+			match trailing_literal(&response[start..]) {
+				Some(len) => {
+					let mut literal = vec![0; len];
+					self.stream.read_exact(&mut literal).await?;
+					response.extend(literal);
+				}
+				None => return Ok(response),
+			}
+		}
+	}
+}
+
+fn strip_tag<'a>(response: &'a [u8], tag: &str) -> Option<&'a [u8]> {
+	response
+		.strip_prefix(tag.as_bytes())
+		.and_then(|r| r.strip_prefix(b" "))
+}
+
+fn check_done(done: &[u8]) -> Result<String> {
+	let done = String::from_utf8_lossy(done);
+	let done = done.trim_end();
+	let (status, text) = done.split_once(' ').unwrap_or((done, ""));
+
+	if status.eq_ignore_ascii_case("OK") {
+		Ok(text.to_owned())
+	} else if status.eq_ignore_ascii_case("NO") {
+		Err(Error::No(text.to_owned()))
+	} else {
+		Err(Error::Bad(text.to_owned()))
+	}
+}
+
+/// Position right after the next `{n}\r\n` literal marker and the literal length.
+fn next_literal(data: &[u8]) -> Option<(usize, usize)> {
+	let mut search = 0;
+	while let Some(crlf) = data[search..].windows(2).position(|w| w == b"\r\n") {
+		let line_end = search + crlf;
+		if let Some(len) = trailing_literal(&data[search..line_end + 2]) {
+			// the final crlf terminates the command
+			if line_end + 2 < data.len() {
+				return Some((line_end + 2, len));
+			}
+		}
+		search = line_end + 2;
+	}
+	None
+}
+
+/// The length of the literal announced at the end of `line`, if any.
+pub fn trailing_literal(line: &[u8]) -> Option<usize> {
+	let line = line.strip_suffix(b"\r\n")?.strip_suffix(b"}")?;
+	let open = line.iter().rposition(|&b| b == b'{')?;
+	std::str::from_utf8(&line[open + 1..])
+		.ok()?
+		.trim_end_matches('+')
+		.parse()
+		.ok()
+}
+
+fn parse_response(input: &[u8]) -> Result<Vec<Token>> {
+	let text = String::from_utf8_lossy(input);
+	let first = text.split(' ').next().unwrap_or("");
+
+	// status responses carry human readable text which doesn't follow any grammar
+	if ["OK", "NO", "BAD", "BYE", "PREAUTH"]
+		.iter()
+		.any(|s| first.eq_ignore_ascii_case(s))
+	{
+		return Ok(vec![
+			Token::Atom(first.to_owned()),
+			Token::Atom(text[first.len()..].trim().to_owned()),
+		]);
+	}
+
+	let mut parser = Parser { input, pos: 0 };
+	parser
+		.list(None)
+		.ok_or_else(|| Error::Parse(ParseError::Invalid(input.to_vec())))
+}
+
+/// Parses a line of imap-like syntax, e.g. a ManageSieve response.
+pub fn parse_tokens(input: &[u8]) -> Option<Vec<Token>> {
+	Parser { input, pos: 0 }.list(None)
+}
+
+struct Parser<'a> {
+	input: &'a [u8],
+	pos:   usize,
+}
+
+impl Parser<'_> {
+	fn peek(&self) -> Option<u8> {
+		self.input.get(self.pos).copied()
+	}
+
+	fn list(&mut self, end: Option<u8>) -> Option<Vec<Token>> {
+		let mut tokens = vec![];
+		loop {
+			while self.peek() == Some(b' ') {
+				self.pos += 1;
+			}
+
+			match self.peek() {
+				None | Some(b'\r') | Some(b'\n') => {
+					return if end.is_none() { Some(tokens) } else { None };
+				}
+				Some(c) if Some(c) == end => {
+					self.pos += 1;
+					return Some(tokens);
+				}
+				Some(b'(') => {
+					self.pos += 1;
+					tokens.push(Token::List(self.list(Some(b')'))?));
+				}
+				Some(b'"') => tokens.push(self.quoted()?),
+				Some(b'{') => tokens.push(self.literal()?),
+				// binary literals (RFC 3516) only differ in what they may contain
+				Some(b'~') if self.input.get(self.pos + 1) == Some(&b'{') => {
+					self.pos += 1;
+					tokens.push(self.literal()?);
+				}
+				Some(_) => tokens.push(self.atom()?),
+			}
+		}
+	}
+
+	fn quoted(&mut self) -> Option<Token> {
+		self.pos += 1;
+		let mut s = vec![];
+		loop {
+			let c = self.peek()?;
+			self.pos += 1;
+			match c {
+				b'"' => return Some(Token::String(s)),
+				b'\\' => {
+					s.push(self.peek()?);
+					self.pos += 1;
+				}
+				c => s.push(c),
+			}
+		}
+	}
+
+	fn literal(&mut self) -> Option<Token> {
+		let close = self.pos + self.input[self.pos..].iter().position(|&b| b == b'}')?;
+		let len: usize = std::str::from_utf8(&self.input[self.pos + 1..close])
+			.ok()?
+			.parse()
+			.ok()?;
+		let start = close + 3;
+		let data = self.input.get(start..start + len)?;
+		self.pos = start + len;
+		Some(Token::String(data.to_vec()))
+	}
+
+	fn atom(&mut self) -> Option<Token> {
+		let start = self.pos;
+		let mut depth = 0;
+		while let Some(c) = self.peek() {
+			match c {
+				b'[' => depth += 1,
+				b']' => depth -= 1,
+				b' ' | b'(' | b')' | b'\r' | b'\n' if depth == 0 => break,
+				_ => {}
+			}
+			self.pos += 1;
+		}
+
+		let atom = String::from_utf8_lossy(&self.input[start..self.pos]).into_owned();
+		if atom.is_empty() {
+			None
+		} else if atom.eq_ignore_ascii_case("NIL") {
+			Some(Token::Nil)
+		} else {
+			Some(Token::Atom(atom))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn atom(a: &str) -> Token {
+		Token::Atom(a.to_owned())
+	}
+
+	fn string(s: &str) -> Token {
+		Token::String(s.as_bytes().to_vec())
+	}
+
+	#[test]
+	fn parse_lists_strings_and_atoms() {
+		let tokens =
+			parse_response(br#"STATUS "Sent \"Items\"" (MESSAGES 3 MAILBOXID (F1a-2)) NIL"#)
+				.unwrap();
+		assert_eq!(
+			tokens,
+			vec![
+				atom("STATUS"),
+				string(r#"Sent "Items""#),
+				Token::List(vec![
+					atom("MESSAGES"),
+					atom("3"),
+					atom("MAILBOXID"),
+					Token::List(vec![atom("F1a-2")]),
+				]),
+				Token::Nil,
+			]
+		);
+		assert_eq!(
+			find_value(tokens[2].as_list().unwrap(), "mailboxid")
+				.unwrap()
+				.as_list(),
+			Some(&[atom("F1a-2")][..])
+		);
+		assert_eq!(
+			tokens[2]
+				.as_list()
+				.and_then(|l| find_value(l, "MESSAGES"))
+				.and_then(Token::as_number),
+			Some(3)
+		);
+		assert_eq!(find_value(tokens[2].as_list().unwrap(), "UIDNEXT"), None);
+	}
+
+	#[test]
+	fn parse_literals() {
+		let tokens = parse_response(
+			b"1 FETCH (BODY[HEADER.FIELDS (REFERENCES)] {7}\r\nab\r\ncd BINARY[1] ~{2}\r\n\0x)\r\n",
+		)
+		.unwrap();
+		assert_eq!(
+			tokens,
+			vec![
+				atom("1"),
+				atom("FETCH"),
+				Token::List(vec![
+					atom("BODY[HEADER.FIELDS (REFERENCES)]"),
+					string("ab\r\ncd "),
+					atom("BINARY[1]"),
+					Token::String(b"\0x".to_vec()),
+				]),
+			]
+		);
+		assert_eq!(tokens[2].as_list().unwrap()[3].as_str(), Some("\0x"));
+		assert_eq!(Token::String(vec![0xff]).as_str(), None);
+		assert_eq!(Token::String(vec![0xff]).as_bytes(), Some(&[0xff][..]));
+	}
+
+	#[test]
+	fn parse_rejects_broken_input() {
+		assert!(parse_response(b"(unterminated list").is_err());
+		assert!(parse_response(b"closing) too early").is_err());
+		assert!(parse_response(b"\"unterminated string").is_err());
+		assert!(parse_response(b"{10}\r\nshort").is_err());
+		assert!(parse_response(b"").unwrap().is_empty());
+	}
+
+	#[test]
+	fn status_responses_keep_their_text() {
+		assert_eq!(
+			parse_response(b"OK [READ-WRITE] SELECT done (0.001 secs)").unwrap(),
+			vec![atom("OK"), atom("[READ-WRITE] SELECT done (0.001 secs)"),]
+		);
+		assert!(check_done(b"OK done\r\n").is_ok());
+		assert!(
+			matches!(check_done(b"NO [TRYCREATE] no mailbox"), Err(Error::No(t)) if t == "[TRYCREATE] no mailbox")
+		);
+		assert!(matches!(check_done(b"BAD parse error"), Err(Error::Bad(_))));
+	}
+
+	#[test]
+	fn literals_in_commands() {
+		assert_eq!(trailing_literal(b"A1 LOGIN {5}\r\n"), Some(5));
+		assert_eq!(trailing_literal(b"A1 APPEND INBOX {12+}\r\n"), Some(12));
+		assert_eq!(trailing_literal(b"A1 LOGIN {5}"), None);
+		assert_eq!(trailing_literal(b"A1 SEARCH TEXT x\r\n"), None);
+
+		let command = b"A1 LOGIN {4}\r\nuser {6}\r\nsecret\r\n";
+		assert_eq!(next_literal(command), Some((14, 4)));
+		assert_eq!(next_literal(&command[14 + 4..]), Some((6, 6)));
+		// a literal marker on the last line is nothing to send
+		assert_eq!(next_literal(b"A1 NOOP {4}\r\n"), None);
+	}
+
+	#[test]
+	fn quote_astrings() {
+		assert_eq!(quote("INBOX"), "\"INBOX\"");
+		assert_eq!(quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+		assert_eq!(quote("Entwürfe"), "{9}\r\nEntwürfe");
+		assert_eq!(quote("a\r\nb"), "{4}\r\na\r\nb");
+	}
+}
@@ -1,24 +1,112 @@
-mod mailbox;
+pub mod blob;
+mod changes;
+pub mod email;
+pub mod identity;
+mod index;
+pub mod mailbox;
 pub mod method;
+pub mod push;
+pub mod push_subscription;
+mod query;
+pub mod quota;
 pub mod rfc8620;
+pub mod sieve_script;
+pub mod submission;
+pub mod thread;
+pub mod vacation;
 
-use async_std::{future::Future, stream::StreamExt};
-use futures::TryStreamExt;
+use std::{collections::HashMap, sync::Mutex};
+
+use async_std::{future::Future, sync::MutexGuardArc};
 pub use rfc8620::*;
+use serde::Serialize;
 
 use crate::{
-	jmap::method::{Method, MethodCallResult, MethodResult},
+	auth::User,
+	imap::raw::RawSession,
+	jmap::method::{Method, MethodCallResult, MethodError, MethodResult},
+	sieve,
 	state,
 };
 
+pub const MAX_OBJECTS_IN_GET: usize = 500;
+
 pub struct JmapApi<'a> {
-	session_id: &'a str,
-	state:      &'a state::State,
+	session_id:  &'a str,
+	user:        &'a User,
+	state:       &'a state::State,
+	// creation ids of this request (and the ones passed in by the client)
+	created_ids: Mutex<HashMap<Id, Id>>,
 }
 
 impl JmapApi<'_> {
-	pub fn new<'a>(session_id: &'a str, state: &'a state::State) -> JmapApi<'a> {
-		JmapApi { session_id, state }
+	pub fn new<'a>(session_id: &'a str, user: &'a User, state: &'a state::State) -> JmapApi<'a> {
+		JmapApi {
+			session_id,
+			user,
+			state,
+			created_ids: Mutex::new(HashMap::new()),
+		}
+	}
+
+	async fn with_imap_session<T, F, Fut>(&self, f: F) -> tide::Result<T>
+	where
+		F: Send + FnOnce(MutexGuardArc<state::ImapSession>) -> Fut,
+		Fut: Future<Output = tide::Result<T>> + Send,
+	{
+		self.state.with_imap_session(self.session_id, f).await
+	}
+
+	async fn with_raw_session<T, F, Fut>(&self, f: F) -> tide::Result<T>
+	where
+		F: Send + FnOnce(MutexGuardArc<RawSession>) -> Fut,
+		Fut: Future<Output = tide::Result<T>> + Send,
+	{
+		self.state.with_raw_session(self.session_id, f).await
+	}
+
+	/// A ManageSieve session on the imap server, with the account's credentials.
+	async fn sieve_session(&self) -> Result<sieve::Session, MethodError> {
+		let credentials =
+			self.state
+				.credentials(self.account_id())
+				.ok_or_else(|| MethodError::ServerFail {
+					description: Some("no credentials for the managesieve server".to_owned()),
+				})?;
+		Ok(sieve::connect(state::MAIL_SERVER, &credentials).await?)
+	}
+
+	/// Resolves `#creationId` references, other ids are returned as is.
+	fn resolve_id(&self, id: &str) -> Option<Id> {
+		match id.strip_prefix('#') {
+			Some(creation_id) => self.created_ids.lock().unwrap().get(creation_id).cloned(),
+			None => Some(id.to_owned()),
+		}
+	}
+
+	fn record_created_id(&self, creation_id: &str, id: &str) {
+		self.created_ids
+			.lock()
+			.unwrap()
+			.insert(creation_id.to_owned(), id.to_owned());
+	}
+
+	fn account_id(&self) -> &str {
+		&self.user.email
+	}
+
+	/// The api for `account_id` if the session can reach it, so far that is
+	/// only its own account.
+	fn account_api(&self, account_id: &str) -> Option<&Self> {
+		Some(self).filter(|_| account_id == self.account_id())
+	}
+
+	fn check_account(&self, account_id: &str) -> Result<(), MethodError> {
+		if account_id != self.account_id() {
+			return Err(MethodError::AccountNotFound);
+		}
+
+		Ok(())
 	}
 }
 
@@ -30,63 +118,198 @@ impl JmapApi<'_> {
 			session_state:    "".to_string(),
 		};
 
-		for m in req.method_calls {
-			let result: MethodResult = match m.method {
-				Method::CoreEcho(map) => MethodResult::CoreEcho(map),
+		let echo_created_ids = req.created_ids.is_some();
+		if let Some(created_ids) = req.created_ids {
+			*self.created_ids.lock().unwrap() = created_ids;
+		}
+
+		for method::MethodCall { method, call_id } in req.method_calls {
+			// responses of calls made on behalf of the client, like the
+			// destroy after an `Email/copy`
+			let mut implicit = None;
+			let result = match method {
+				Method::CoreEcho(map) => Ok(MethodResult::CoreEcho(map)),
 				Method::MailboxGet {
 					account_id,
 					ids,
 					properties,
-				} => self.handle_mailbox_get(account_id, ids, properties).await?,
+				} => self.handle_mailbox_get(account_id, ids, properties).await,
+				Method::MailboxSet {
+					request,
+					on_destroy_remove_emails,
+				} => {
+					self.handle_mailbox_set(request, on_destroy_remove_emails)
+						.await
+				}
+				Method::MailboxChanges(request) => self.handle_mailbox_changes(request).await,
+				Method::MailboxQuery {
+					request,
+					sort_as_tree,
+					filter_as_tree,
+				} => {
+					self.handle_mailbox_query(request, sort_as_tree, filter_as_tree)
+						.await
+				}
+				Method::MailboxQueryChanges {
+					request,
+					sort_as_tree,
+					filter_as_tree,
+				} => {
+					self.handle_mailbox_query_changes(request, sort_as_tree, filter_as_tree)
+						.await
+				}
+				Method::EmailGet(request) => self.handle_email_get(request).await,
+				Method::EmailSet(request) => self.handle_email_set(request).await,
+				Method::EmailQuery {
+					request,
+					collapse_threads,
+				} => self.handle_email_query(request, collapse_threads).await,
+				Method::EmailChanges(request) => self.handle_email_changes(request).await,
+				Method::EmailCopy(request) => {
+					self.handle_email_copy(request)
+						.await
+						.map(|(copy, destroy)| {
+							implicit = destroy;
+							copy
+						})
+				}
+				Method::EmailImport(request) => self.handle_email_import(request).await,
+				Method::EmailParse(request) => self.handle_email_parse(request).await,
+				Method::EmailQueryChanges {
+					request,
+					collapse_threads,
+				} => {
+					self.handle_email_query_changes(request, collapse_threads)
+						.await
+				}
+				Method::SearchSnippetGet(request) => self.handle_search_snippet_get(request).await,
+				Method::ThreadGet(request) => self.handle_thread_get(request).await,
+				Method::ThreadChanges(request) => self.handle_thread_changes(request).await,
+				Method::IdentityGet(request) => self.handle_identity_get(request).await,
+				Method::IdentityChanges(request) => self.handle_identity_changes(request).await,
+				Method::IdentitySet(request) => self.handle_identity_set(request).await,
+				Method::EmailSubmissionGet(request) => {
+					self.handle_email_submission_get(request).await
+				}
+				Method::EmailSubmissionChanges(request) => {
+					self.handle_email_submission_changes(request).await
+				}
+				Method::EmailSubmissionQuery(request) => {
+					self.handle_email_submission_query(request).await
+				}
+				Method::EmailSubmissionSet {
+					request,
+					on_success_update_email,
+					on_success_destroy_email,
+				} => self
+					.handle_email_submission_set(
+						request,
+						on_success_update_email,
+						on_success_destroy_email,
+					)
+					.await
+					.map(|(set, email_set)| {
+						implicit = email_set;
+						set
+					}),
+				Method::QuotaGet(request) => self.handle_quota_get(request).await,
+				Method::QuotaChanges(request) => self.handle_quota_changes(request).await,
+				Method::QuotaQuery(request) => self.handle_quota_query(request).await,
+				Method::SieveScriptGet(request) => self.handle_sieve_script_get(request).await,
+				Method::SieveScriptQuery(request) => self.handle_sieve_script_query(request).await,
+				Method::SieveScriptSet {
+					request,
+					on_success_activate_script,
+					on_success_deactivate_script,
+				} => {
+					self.handle_sieve_script_set(
+						request,
+						on_success_activate_script,
+						on_success_deactivate_script,
+					)
+					.await
+				}
+				Method::SieveScriptValidate(request) => {
+					self.handle_sieve_script_validate(request).await
+				}
+				Method::VacationResponseGet(request) => {
+					self.handle_vacation_response_get(request).await
+				}
+				Method::VacationResponseSet(request) => {
+					self.handle_vacation_response_set(request).await
+				}
+				Method::BlobUpload(request) => self.handle_blob_upload(request).await,
+				Method::BlobGet(request) => self.handle_blob_get(request).await,
+				Method::BlobLookup(request) => self.handle_blob_lookup(request).await,
+				Method::PushSubscriptionGet(request) => {
+					self.handle_push_subscription_get(request).await
+				}
+				Method::PushSubscriptionSet(request) => {
+					self.handle_push_subscription_set(request).await
+				}
+				Method::Invalid(e) if e.starts_with("unknown variant") => {
+					Err(MethodError::UnknownMethod)
+				}
+				Method::Invalid(e) => Err(MethodError::invalid_arguments(e)),
 			};
 
+			let result = result.unwrap_or_else(|e| {
+				tracing::error!("method call `{}` failed: {:?}", call_id, e);
+				MethodResult::Error(e)
+			});
+
 			response.method_responses.push(MethodCallResult {
 				method_result: result,
-				call_id:       m.call_id,
+				call_id:       call_id.clone(),
 			});
+			if let Some(result) = implicit {
+				response.method_responses.push(MethodCallResult {
+					method_result: result,
+					call_id,
+				});
+			}
+		}
+
+		if echo_created_ids {
+			response.created_ids = Some(self.created_ids.lock().unwrap().clone());
 		}
 
 		Ok(response)
 	}
+}
 
-	pub async fn handle_mailbox_get(
-		&self,
-		account_id: String,
-		ids: Option<Vec<Id>>,
-		_properties: Option<Vec<String>>,
-	) -> tide::Result<MethodResult> {
-		if ids.is_some() {
-			return Err(tide::Error::from_str(
-				tide::StatusCode::NotImplemented,
-				"selecting ids is not supported yet",
-			));
+/// Serializes `objects` and strips everything not listed in `properties`.
+///
+/// The `id` is always returned, unknown properties are rejected with
+/// `invalidArguments`.
+fn select_properties<T: Serialize>(
+	objects: Vec<T>,
+	properties: &Option<Vec<String>>,
+	known: &[&str],
+) -> Result<Vec<serde_json::Value>, MethodError> {
+	if let Some(properties) = properties {
+		if let Some(unknown) = properties.iter().find(|p| !known.contains(&p.as_str())) {
+			return Err(MethodError::invalid_arguments(format!(
+				"unknown property `{}`",
+				unknown
+			)));
 		}
+	}
+
+	objects
+		.into_iter()
+		.map(|o| {
+			let mut value = serde_json::to_value(o)
+				.map_err(|e| tide::Error::new(tide::StatusCode::InternalServerError, e))?;
 
-		let _list = self
-			.state
-			.with_imap_session(self.session_id, |s| async move {
-				let list: Vec<async_imap::types::Name> =
-					s.list(None, Some("*")).await?.try_collect().await?;
-				Ok(list)
-			})
-			.await?;
-
-		Ok(MethodResult::MailboxGet {
-			account_id,
-			state: "".to_string(),
-			list: vec![],
-			not_found: vec![],
+			if let (Some(properties), Some(obj)) = (properties, value.as_object_mut()) {
+				*obj = std::mem::take(obj)
+					.into_iter()
+					.filter(|(k, _)| k == "id" || properties.contains(k))
+					.collect();
+			}
+
+			Ok(value)
 		})
-	}
+		.collect()
 }
-//
-// impl JmapApi<'_> {
-// 	pub async fn with_imap_session<'a, T, F, Fut>(&self, f: F) -> tide::Result<T>
-// 	where
-// 		F: Send + Sync + FnOnce(&'a mut state::ImapSession) -> Fut + 'a,
-// 		Fut: Future<Output = tide::Result<T>> + Send + 'a,
-// 		T: 'static,
-// 	{
-// 		self.state.with_imap_session(self.session_id, f).await
-// 	}
-// }
@@ -0,0 +1,487 @@
+//! The blob management extension (RFC 9404): uploads inside of method calls,
+//! reading blobs without a download and finding what refers to a blob.
+//!
+//! There is no `Blob/copy` (RFC 8620), a session only has its own account
+//! to copy from.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::Digest;
+
+use crate::{
+	blob,
+	jmap::{
+		email::{parse_blob_id, EmailGetRequest},
+		method::{MethodError, MethodResult},
+		Id,
+		JmapApi,
+		SetError,
+		MAX_OBJECTS_IN_GET,
+	},
+};
+
+pub const MAX_DATA_SOURCES: usize = 64;
+pub const TYPE_NAMES: &[&str] = &["Mailbox", "Thread", "Email"];
+pub const DIGEST_ALGORITHMS: &[&str] = &["sha", "sha-256"];
+
+const PROPERTIES: &[&str] = &[
+	"data",
+	"data:asText",
+	"data:asBase64",
+	"size",
+	"digest:sha",
+	"digest:sha-256",
+];
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobUploadRequest {
+	pub account_id: Id,
+	pub create:     HashMap<Id, Value>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobUploadResponse {
+	pub account_id:  Id,
+	pub created:     Option<HashMap<Id, BlobCreated>>,
+	pub not_created: Option<HashMap<Id, SetError>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobCreated {
+	pub id:     Id,
+	pub r#type: Option<String>,
+	pub size:   u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct UploadObject {
+	data:   Vec<DataSource>,
+	r#type: Option<String>,
+}
+
+/// A piece of the content of an uploaded blob.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum DataSource {
+	Text {
+		#[serde(rename = "data:asText")]
+		text: String,
+	},
+	Base64 {
+		#[serde(rename = "data:asBase64")]
+		base64: String,
+	},
+	#[serde(rename_all = "camelCase")]
+	Blob {
+		blob_id: Id,
+		offset:  Option<u64>,
+		length:  Option<u64>,
+	},
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobGetRequest {
+	pub account_id: Id,
+	pub ids:        Option<Vec<Id>>,
+	pub properties: Option<Vec<String>>,
+	pub offset:     Option<u64>,
+	pub length:     Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobGetResponse {
+	pub account_id: Id,
+	pub list:       Vec<Value>,
+	pub not_found:  Vec<Id>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobLookupRequest {
+	pub account_id: Id,
+	pub type_names: Vec<String>,
+	pub ids:        Vec<Id>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobLookupResponse {
+	pub account_id: Id,
+	pub list:       Vec<BlobLookupInfo>,
+	pub not_found:  Vec<Id>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BlobLookupInfo {
+	pub id:          Id,
+	pub matched_ids: BTreeMap<String, Vec<Id>>,
+}
+
+/// The octets of `data` from `offset` on, at most `length` of them, and
+/// whether the range went past the end.
+fn slice(data: &[u8], offset: Option<u64>, length: Option<u64>) -> (&[u8], bool) {
+	let size = data.len() as u64;
+	let start = offset.unwrap_or(0);
+	let end = length.map_or(size, |l| start.saturating_add(l));
+	let range = &data[start.min(size) as usize..end.min(size) as usize];
+	(range, start > size || end > size)
+}
+
+/// The blob object with the requested `properties` of the `range` of `data`.
+fn blob_object(
+	id: &str,
+	data: &[u8],
+	range: (&[u8], bool),
+	properties: &[String],
+) -> Map<String, Value> {
+	let (range, is_truncated) = range;
+	let text = std::str::from_utf8(range).ok();
+	let base64 = || Value::String(base64::encode(range));
+
+	let mut object = Map::new();
+	object.insert("id".to_owned(), json!(id));
+	for property in properties {
+		match property.as_str() {
+			"data" => match text {
+				Some(text) => {
+					object.insert("data:asText".to_owned(), json!(text));
+				}
+				None => {
+					object.insert("data:asBase64".to_owned(), base64());
+				}
+			},
+			"data:asText" => {
+				object.insert(property.clone(), json!(text));
+			}
+			"data:asBase64" => {
+				object.insert(property.clone(), base64());
+			}
+			"size" => {
+				object.insert(property.clone(), json!(data.len()));
+			}
+			"digest:sha" => {
+				let digest = sha1::Sha1::from(range).digest().bytes();
+				object.insert(property.clone(), json!(base64::encode(digest)));
+			}
+			"digest:sha-256" => {
+				let digest = sha2::Sha256::digest(range);
+				object.insert(property.clone(), json!(base64::encode(digest)));
+			}
+			_ => {}
+		}
+	}
+
+	let wants = |list: &[&str]| properties.iter().any(|p| list.contains(&p.as_str()));
+	if wants(&["data", "data:asText"]) && text.is_none() {
+		object.insert("isEncodingProblem".to_owned(), json!(true));
+	}
+	if wants(&["data", "data:asText", "data:asBase64"]) && is_truncated {
+		object.insert("isTruncated".to_owned(), json!(true));
+	}
+	object
+}
+
+impl JmapApi<'_> {
+	/// The content of an upload, `Err` with the reason if it can't be
+	/// created.
+	async fn upload_content(&self, upload: &UploadObject) -> Result<Vec<u8>, SetError> {
+		if upload.data.len() > MAX_DATA_SOURCES {
+			return Err(SetError::invalid_properties(
+				&["data"],
+				format!("at most {} data sources are allowed", MAX_DATA_SOURCES),
+			));
+		}
+
+		let mut content = vec![];
+		for source in &upload.data {
+			match source {
+				DataSource::Text { text } => content.extend_from_slice(text.as_bytes()),
+				DataSource::Base64 { base64 } => match base64::decode(base64) {
+					Ok(data) => content.extend(data),
+					Err(_) => {
+						return Err(SetError::invalid_properties(
+							&["data"],
+							"data:asBase64 isn't valid base64",
+						))
+					}
+				},
+				DataSource::Blob {
+					blob_id,
+					offset,
+					length,
+				} => {
+					let not_found = || SetError::BlobNotFound {
+						not_found: vec![blob_id.clone()],
+					};
+					let blob_id = self.resolve_id(blob_id).ok_or_else(not_found)?;
+					let data = match self.fetch_blob(&blob_id).await {
+						Ok(Some(data)) => data,
+						Ok(None) => return Err(not_found()),
+						Err(e) => {
+							tracing::error!("fetching blob {} failed: {:?}", blob_id, e);
+							return Err(not_found());
+						}
+					};
+					let (range, is_truncated) = slice(&data, *offset, *length);
+					if is_truncated {
+						return Err(SetError::invalid_properties(
+							&["data"],
+							format!("blob {} is only {} octets long", blob_id, data.len()),
+						));
+					}
+					content.extend_from_slice(range);
+				}
+			}
+
+			if content.len() as u64 > blob::MAX_SIZE_UPLOAD {
+				return Err(SetError::TooLarge {
+					max_size: Some(blob::MAX_SIZE_UPLOAD),
+				});
+			}
+		}
+		Ok(content)
+	}
+
+	pub async fn handle_blob_upload(
+		&self,
+		request: BlobUploadRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let mut created = HashMap::new();
+		let mut not_created = HashMap::new();
+
+		// uploads can be made of other uploads of the same call, whatever
+		// refers to one that isn't there yet waits for it
+		let mut pending: Vec<(Id, Value)> = request.create.into_iter().collect();
+		while !pending.is_empty() {
+			let waiting_for: HashSet<String> =
+				pending.iter().map(|(id, _)| format!("#{}", id)).collect();
+			let (ready, waiting): (Vec<_>, Vec<_>) =
+				pending.into_iter().partition(|(_, upload)| {
+					!upload["data"].as_array().is_some_and(|sources| {
+						sources.iter().any(|s| {
+							s["blobId"]
+								.as_str()
+								.is_some_and(|id| waiting_for.contains(id))
+						})
+					})
+				});
+			// whatever is left refers to itself in a circle
+			let (ready, waiting) = if ready.is_empty() {
+				(waiting, vec![])
+			} else {
+				(ready, waiting)
+			};
+
+			for (creation_id, upload) in ready {
+				let upload: UploadObject = match serde_json::from_value(upload) {
+					Ok(upload) => upload,
+					Err(e) => {
+						not_created.insert(
+							creation_id,
+							SetError::invalid_properties(&["data", "type"], e.to_string()),
+						);
+						continue;
+					}
+				};
+				let content = match self.upload_content(&upload).await {
+					Ok(content) => content,
+					Err(e) => {
+						not_created.insert(creation_id, e);
+						continue;
+					}
+				};
+
+				let r#type = upload
+					.r#type
+					.as_deref()
+					.unwrap_or("application/octet-stream");
+				let info = self
+					.state
+					.blobs
+					.put(self.account_id(), r#type, &content)
+					.await?;
+				self.record_created_id(&creation_id, &info.blob_id);
+				created.insert(
+					creation_id,
+					BlobCreated {
+						id:     info.blob_id,
+						r#type: upload.r#type,
+						size:   info.size,
+					},
+				);
+			}
+			pending = waiting;
+		}
+
+		Ok(MethodResult::BlobUpload(BlobUploadResponse {
+			account_id:  request.account_id,
+			created:     Some(created).filter(|c| !c.is_empty()),
+			not_created: Some(not_created).filter(|n| !n.is_empty()),
+		}))
+	}
+
+	pub async fn handle_blob_get(
+		&self,
+		request: BlobGetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let properties = request
+			.properties
+			.unwrap_or_else(|| vec!["data".to_owned(), "size".to_owned()]);
+		if let Some(p) = properties
+			.iter()
+			.find(|p| p.as_str() != "id" && !PROPERTIES.contains(&p.as_str()))
+		{
+			return Err(MethodError::invalid_arguments(format!(
+				"unknown property {}",
+				p
+			)));
+		}
+		let ids = match request.ids {
+			Some(ids) if ids.len() <= MAX_OBJECTS_IN_GET => ids,
+			// listing every blob of the account isn't supported
+			_ => return Err(MethodError::RequestTooLarge),
+		};
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in ids {
+			let blob_id = match self.resolve_id(&id) {
+				Some(blob_id) => blob_id,
+				None => {
+					not_found.push(id);
+					continue;
+				}
+			};
+			match self.fetch_blob(&blob_id).await? {
+				Some(data) => {
+					let range = slice(&data, request.offset, request.length);
+					list.push(Value::Object(blob_object(
+						&blob_id,
+						&data,
+						range,
+						&properties,
+					)));
+				}
+				None => not_found.push(id),
+			}
+		}
+
+		Ok(MethodResult::BlobGet(BlobGetResponse {
+			account_id: request.account_id,
+			list,
+			not_found,
+		}))
+	}
+
+	pub async fn handle_blob_lookup(
+		&self,
+		request: BlobLookupRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		if !request
+			.type_names
+			.iter()
+			.all(|t| TYPE_NAMES.contains(&t.as_str()))
+		{
+			return Err(MethodError::UnknownDataType);
+		}
+
+		// only emails and their parts are referred to by anything
+		let mut email_ids: HashMap<Id, Id> = HashMap::new();
+		let mut not_found = vec![];
+		let mut others = vec![];
+		for id in &request.ids {
+			let blob_id = match self.resolve_id(id) {
+				Some(blob_id) => blob_id,
+				None => {
+					not_found.push(id.clone());
+					continue;
+				}
+			};
+			match parse_blob_id(&blob_id) {
+				Some((email_id, _)) => {
+					email_ids.insert(id.clone(), email_id);
+				}
+				None => others.push((id.clone(), blob_id)),
+			}
+		}
+
+		let mut emails: HashMap<Id, Value> = HashMap::new();
+		if !email_ids.is_empty() {
+			let unique: HashSet<&Id> = email_ids.values().collect();
+			let request = EmailGetRequest {
+				account_id: self.account_id().to_owned(),
+				ids: Some(unique.into_iter().cloned().collect()),
+				properties: Some(vec!["threadId".to_owned(), "mailboxIds".to_owned()]),
+				..Default::default()
+			};
+			if let MethodResult::EmailGet(response) = self.handle_email_get(request).await? {
+				for email in response.list {
+					if let Some(id) = email["id"].as_str() {
+						emails.insert(id.to_owned(), email);
+					}
+				}
+			}
+		}
+
+		let mut list = vec![];
+		for id in request.ids {
+			let mut matched_ids: BTreeMap<String, Vec<Id>> = request
+				.type_names
+				.iter()
+				.map(|t| (t.clone(), vec![]))
+				.collect();
+
+			if let Some(email_id) = email_ids.get(&id) {
+				let email = match emails.get(email_id) {
+					Some(email) => email,
+					None => {
+						not_found.push(id);
+						continue;
+					}
+				};
+				for (type_name, ids) in matched_ids.iter_mut() {
+					match type_name.as_str() {
+						"Email" => ids.push(email_id.clone()),
+						"Thread" => ids.extend(email["threadId"].as_str().map(str::to_owned)),
+						"Mailbox" => ids.extend(
+							email["mailboxIds"]
+								.as_object()
+								.into_iter()
+								.flat_map(|m| m.keys().cloned()),
+						),
+						_ => {}
+					}
+				}
+			} else if let Some((_, blob_id)) = others.iter().find(|(i, _)| *i == id) {
+				if self.fetch_blob(blob_id).await?.is_none() {
+					not_found.push(id);
+					continue;
+				}
+			} else {
+				continue;
+			}
+
+			list.push(BlobLookupInfo { id, matched_ids });
+		}
+
+		Ok(MethodResult::BlobLookup(BlobLookupResponse {
+			account_id: request.account_id,
+			list,
+			not_found,
+		}))
+	}
+}
@@ -0,0 +1,501 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::jmap::{method::MethodError, ChangesResponse, Id, JmapApi};
+
+// older entries are dropped, clients that far behind have to resync
+const MAX_CHANGES: usize = 2000;
+
+/// 64 bit FNV-1a, stable across builds unlike the std hasher.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+	bytes.into_iter().fold(0xcbf29ce484222325u64, |hash, b| {
+		(hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+	})
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum ChangeKind {
+	Created,
+	Updated,
+	Destroyed,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Change {
+	state:      u64,
+	id:         Id,
+	kind:       ChangeKind,
+	#[serde(default)]
+	properties: Vec<String>,
+}
+
+/// State counter and change log for one object type of an account.
+///
+/// Changes are detected by diffing the objects against a snapshot of
+/// per-property hashes taken the last time they were recorded.
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeLog {
+	state:        u64,
+	oldest_state: u64,
+	snapshot:     HashMap<Id, HashMap<String, u64>>,
+	changes:      Vec<Change>,
+}
+
+#[derive(Debug, Default)]
+pub struct Changes {
+	pub old_state:          String,
+	pub new_state:          String,
+	pub has_more_changes:   bool,
+	pub created:            Vec<Id>,
+	pub updated:            Vec<Id>,
+	pub destroyed:          Vec<Id>,
+	/// All properties changed by the updates, empty if objects were created
+	/// or destroyed.
+	pub updated_properties: BTreeSet<String>,
+}
+
+impl Changes {
+	pub fn into_response(self, account_id: Id) -> ChangesResponse {
+		ChangesResponse {
+			account_id,
+			old_state: self.old_state,
+			new_state: self.new_state,
+			has_more_changes: self.has_more_changes,
+			created: self.created,
+			updated: self.updated,
+			destroyed: self.destroyed,
+		}
+	}
+}
+
+/// Objects by id as a change log records them.
+pub fn to_objects<'a, T: Serialize + 'a>(
+	objects: impl IntoIterator<Item = (&'a Id, &'a T)>,
+) -> Result<Vec<(Id, Value)>, MethodError> {
+	Ok(objects
+		.into_iter()
+		.map(|(id, object)| Ok((id.clone(), serde_json::to_value(object)?)))
+		.collect::<serde_json::Result<Vec<_>>>()
+		.map_err(tide::Error::from)?)
+}
+
+fn property_hashes(object: &serde_json::Value) -> HashMap<String, u64> {
+	object
+		.as_object()
+		.into_iter()
+		.flatten()
+		.map(|(k, v)| (k.clone(), fnv1a(v.to_string().into_bytes())))
+		.collect()
+}
+
+impl ChangeLog {
+	pub fn state(&self) -> String {
+		self.state.to_string()
+	}
+
+	/// Records the current version of all objects and returns the new state.
+	pub fn record(&mut self, objects: &[(Id, serde_json::Value)]) -> String {
+		let mut changes = vec![];
+		let mut snapshot = HashMap::new();
+		let next_state = self.state + 1;
+
+		for (id, object) in objects {
+			let hashes = property_hashes(object);
+			match self.snapshot.get(id) {
+				None => changes.push(Change {
+					state:      next_state,
+					id:         id.clone(),
+					kind:       ChangeKind::Created,
+					properties: vec![],
+				}),
+				Some(old) if *old != hashes => {
+					let mut properties: Vec<String> = hashes
+						.iter()
+						.filter(|(k, v)| old.get(*k) != Some(v))
+						.map(|(k, _)| k.clone())
+						.chain(old.keys().filter(|k| !hashes.contains_key(*k)).cloned())
+						.collect();
+					properties.sort();
+					changes.push(Change {
+						state: next_state,
+						id: id.clone(),
+						kind: ChangeKind::Updated,
+						properties,
+					});
+				}
+				Some(_) => {}
+			}
+			snapshot.insert(id.clone(), hashes);
+		}
+
+		for id in self.snapshot.keys() {
+			if !snapshot.contains_key(id) {
+				changes.push(Change {
+					state:      next_state,
+					id:         id.clone(),
+					kind:       ChangeKind::Destroyed,
+					properties: vec![],
+				});
+			}
+		}
+
+		self.snapshot = snapshot;
+		if !changes.is_empty() {
+			self.push(changes);
+		}
+
+		self.state()
+	}
+
+	/// Forgets all changes, clients with an older state have to resync.
+	pub fn invalidate(&mut self) {
+		self.changes.clear();
+		self.oldest_state = self.state;
+	}
+
+	fn push(&mut self, changes: Vec<Change>) {
+		self.state += 1;
+		self.changes.extend(changes);
+
+		if self.changes.len() > MAX_CHANGES {
+			let cut = self.changes.len() - MAX_CHANGES;
+			// never cut a state in half
+			let cut_state = self.changes[cut].state;
+			self.changes.retain(|c| c.state >= cut_state);
+			self.oldest_state = cut_state - 1;
+		}
+	}
+
+	/// Everything that changed after `since_state`, at most `max_changes` ids.
+	pub fn changes(
+		&self,
+		since_state: &str,
+		max_changes: Option<u64>,
+	) -> Result<Changes, MethodError> {
+		let since: u64 = since_state
+			.parse()
+			.map_err(|_| MethodError::CannotCalculateChanges)?;
+		if since < self.oldest_state || since > self.state {
+			return Err(MethodError::CannotCalculateChanges);
+		}
+
+		// whole states only, otherwise the client would skip changes
+		let mut new_state = since;
+		let mut ids = BTreeSet::new();
+		let mut has_more_changes = false;
+		for change in self.changes.iter().filter(|c| c.state > since) {
+			if change.state != new_state {
+				let state_ids: BTreeSet<&Id> = self
+					.changes
+					.iter()
+					.filter(|c| c.state == change.state)
+					.map(|c| &c.id)
+					.collect();
+				let total = ids.union(&state_ids).count() as u64;
+				if max_changes.is_some_and(|max| total > max) && new_state != since {
+					has_more_changes = true;
+					break;
+				}
+				ids.extend(state_ids);
+				new_state = change.state;
+			}
+		}
+
+		let mut first: HashMap<&Id, ChangeKind> = HashMap::new();
+		let mut last: HashMap<&Id, ChangeKind> = HashMap::new();
+		let mut updated_properties = BTreeSet::new();
+		for change in self
+			.changes
+			.iter()
+			.filter(|c| c.state > since && c.state <= new_state)
+		{
+			first.entry(&change.id).or_insert(change.kind);
+			last.insert(&change.id, change.kind);
+			updated_properties.extend(change.properties.iter().cloned());
+		}
+
+		let mut result = Changes {
+			old_state: since.to_string(),
+			new_state: new_state.to_string(),
+			has_more_changes,
+			..Default::default()
+		};
+		for (id, first_kind) in first {
+			match (first_kind, last[id]) {
+				// never seen by the client
+				(ChangeKind::Created, ChangeKind::Destroyed) => {}
+				(ChangeKind::Created, _) => result.created.push(id.clone()),
+				(_, ChangeKind::Destroyed) => result.destroyed.push(id.clone()),
+				(_, _) => result.updated.push(id.clone()),
+			}
+		}
+		result.created.sort();
+		result.updated.sort();
+		result.destroyed.sort();
+
+		if result.created.is_empty() && result.destroyed.is_empty() {
+			result.updated_properties = updated_properties;
+		}
+
+		Ok(result)
+	}
+}
+
+impl AsRef<ChangeLog> for ChangeLog {
+	fn as_ref(&self) -> &ChangeLog {
+		self
+	}
+}
+
+impl JmapApi<'_> {
+	/// Records `objects` in the change log stored as `name` and returns the
+	/// state.
+	pub async fn record_state(
+		&self,
+		name: &str,
+		objects: &[(Id, Value)],
+	) -> Result<String, MethodError> {
+		Ok(self
+			.state
+			.store
+			.update(self.account_id(), name, |log: &mut ChangeLog| {
+				log.record(objects)
+			})
+			.await?)
+	}
+
+	/// Everything that changed after `since_state` in the change log stored
+	/// as `name`, brought up to date with the current `objects` first.
+	pub async fn record_changes(
+		&self,
+		name: &str,
+		objects: &[(Id, Value)],
+		since_state: &str,
+		max_changes: Option<u64>,
+	) -> Result<Changes, MethodError> {
+		self.state
+			.store
+			.update(self.account_id(), name, |log: &mut ChangeLog| {
+				log.record(objects);
+				log.changes(since_state, max_changes)
+			})
+			.await?
+	}
+
+	/// Like [`JmapApi::record_changes`] for logs kept with more, that are
+	/// brought up to date on their own.
+	pub async fn read_changes<L>(
+		&self,
+		name: &str,
+		since_state: &str,
+		max_changes: Option<u64>,
+	) -> Result<Changes, MethodError>
+	where
+		L: AsRef<ChangeLog> + DeserializeOwned + Default,
+	{
+		let stored: L = self.state.store.get(self.account_id(), name).await?;
+		stored.as_ref().changes(since_state, max_changes)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	fn objects(objects: &[(&str, serde_json::Value)]) -> Vec<(Id, serde_json::Value)> {
+		objects
+			.iter()
+			.map(|(id, object)| (id.to_string(), object.clone()))
+			.collect()
+	}
+
+	fn ids(ids: &[&str]) -> Vec<Id> {
+		ids.iter().map(|id| id.to_string()).collect()
+	}
+
+	#[test]
+	fn fnv1a_is_stable() {
+		assert_eq!(fnv1a(vec![]), 0xcbf29ce484222325);
+		assert_eq!(fnv1a(b"a".iter().copied()), 0xaf63dc4c8601ec8c);
+		assert_eq!(fnv1a(b"foobar".iter().copied()), 0x85944171f73967e8);
+	}
+
+	#[test]
+	fn record_counts_states_only_for_changes() {
+		let mut log = ChangeLog::default();
+		assert_eq!(log.state(), "0");
+		let a = json!({ "name": "a", "total": 1 });
+		assert_eq!(log.record(&objects(&[("A", a.clone())])), "1");
+		assert_eq!(log.record(&objects(&[("A", a)])), "1");
+
+		let changes = log.changes("0", None).unwrap();
+		assert_eq!(
+			(changes.old_state.as_str(), changes.new_state.as_str()),
+			("0", "1")
+		);
+		assert_eq!(changes.created, ids(&["A"]));
+		assert!(changes.updated.is_empty() && changes.destroyed.is_empty());
+		assert!(changes.updated_properties.is_empty());
+		assert!(!changes.has_more_changes);
+	}
+
+	#[test]
+	fn changes_combine_states() {
+		let mut log = ChangeLog::default();
+		log.record(&objects(&[
+			("A", json!({ "name": "a", "total": 1 })),
+			("B", json!({ "name": "b" })),
+		]));
+		log.record(&objects(&[
+			("A", json!({ "name": "a", "total": 2 })),
+			("B", json!({ "name": "b" })),
+			("C", json!({ "name": "c" })),
+		]));
+		log.record(&objects(&[
+			("A", json!({ "name": "a", "total": 3, "role": null })),
+			("B", json!({ "name": "b" })),
+		]));
+		assert_eq!(log.state(), "3");
+
+		// C came and went since state 1
+		let changes = log.changes("1", None).unwrap();
+		assert_eq!(changes.new_state, "3");
+		assert!(changes.created.is_empty() && changes.destroyed.is_empty());
+		assert_eq!(changes.updated, ids(&["A"]));
+		let properties: Vec<&str> = changes
+			.updated_properties
+			.iter()
+			.map(String::as_str)
+			.collect();
+		assert_eq!(properties, vec!["role", "total"]);
+
+		let changes = log.changes("2", None).unwrap();
+		assert_eq!(changes.updated, ids(&["A"]));
+		assert_eq!(changes.destroyed, ids(&["C"]));
+		// properties only make sense without creates and destroys
+		assert!(changes.updated_properties.is_empty());
+
+		let changes = log.changes("3", None).unwrap();
+		assert_eq!(changes.new_state, "3");
+		assert!(changes.created.is_empty() && changes.updated.is_empty());
+	}
+
+	#[test]
+	fn max_changes_pages_by_whole_states() {
+		let mut log = ChangeLog::default();
+		log.record(&objects(&[
+			("A", json!({ "n": 1 })),
+			("B", json!({ "n": 1 })),
+		]));
+		log.record(&objects(&[
+			("A", json!({ "n": 1 })),
+			("B", json!({ "n": 1 })),
+			("C", json!({ "n": 1 })),
+		]));
+		log.record(&objects(&[
+			("A", json!({ "n": 2 })),
+			("B", json!({ "n": 1 })),
+			("C", json!({ "n": 1 })),
+		]));
+
+		// the first state is returned even if it is too large on its own
+		let changes = log.changes("0", Some(1)).unwrap();
+		assert_eq!(changes.new_state, "1");
+		assert_eq!(changes.created, ids(&["A", "B"]));
+		assert!(changes.has_more_changes);
+
+		let changes = log.changes("1", Some(2)).unwrap();
+		assert_eq!(changes.new_state, "3");
+		assert_eq!(changes.created, ids(&["C"]));
+		assert_eq!(changes.updated, ids(&["A"]));
+		assert!(!changes.has_more_changes);
+	}
+
+	#[test]
+	fn unknown_states_cannot_be_calculated() {
+		let mut log = ChangeLog::default();
+		log.record(&objects(&[("A", json!({ "n": 1 }))]));
+		for state in &["x", "-1", "2"] {
+			assert!(matches!(
+				log.changes(state, None),
+				Err(MethodError::CannotCalculateChanges)
+			));
+		}
+
+		log.invalidate();
+		assert!(matches!(
+			log.changes("0", None),
+			Err(MethodError::CannotCalculateChanges)
+		));
+		assert!(log.changes("1", None).is_ok());
+	}
+
+	#[test]
+	fn old_changes_are_dropped() {
+		let mut log = ChangeLog::default();
+		let ids: Vec<String> = (0..3).map(|i| format!("M{}", i)).collect();
+		for round in 0..MAX_CHANGES {
+			let objects: Vec<(Id, serde_json::Value)> = ids
+				.iter()
+				.map(|id| (id.clone(), json!({ "n": round })))
+				.collect();
+			log.record(&objects);
+		}
+		// states are kept whole
+		assert!(log.changes.len() < MAX_CHANGES + ids.len());
+		assert!(matches!(
+			log.changes("0", None),
+			Err(MethodError::CannotCalculateChanges)
+		));
+		let recent = (MAX_CHANGES - 10).to_string();
+		assert_eq!(log.changes(&recent, None).unwrap().updated, ids);
+	}
+
+	#[async_std::test]
+	async fn changes_are_answered_from_the_recorded_log() {
+		let dir = std::env::temp_dir().join(format!("changes-test-{}", std::process::id()));
+		let state = crate::state::State::with_data_dir(&dir);
+		let user = crate::auth::User {
+			email: "user@example.com".to_owned(),
+		};
+		let jmap_api = JmapApi::new("session", &user, &state);
+
+		let before = to_objects([(&"A".to_owned(), &json!({ "name": "a" }))]).unwrap();
+		assert_eq!(
+			jmap_api
+				.record_state("test-changes", &before)
+				.await
+				.unwrap(),
+			"1"
+		);
+
+		let after = objects(&[("A", json!({ "name": "b" })), ("B", json!({ "name": "b" }))]);
+		let changes = jmap_api
+			.record_changes("test-changes", &after, "1", None)
+			.await
+			.unwrap()
+			.into_response("user@example.com".to_owned());
+		assert_eq!(
+			(changes.old_state.as_str(), changes.new_state.as_str()),
+			("1", "2")
+		);
+		assert_eq!(
+			(changes.created, changes.updated),
+			(ids(&["B"]), ids(&["A"]))
+		);
+
+		let read = jmap_api
+			.read_changes::<ChangeLog>("test-changes", "0", None)
+			.await
+			.unwrap();
+		assert_eq!(read.created, ids(&["A", "B"]));
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,1815 @@
+pub mod changes;
+pub mod import;
+pub mod parse;
+pub mod search;
+pub mod set;
+pub mod snippet;
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+	blob::is_upload_blob_id,
+	imap,
+	imap::raw::{self, Token},
+	jmap::{
+		is_valid_id,
+		method::{MethodError, MethodResult},
+		sieve_script::parse_script_blob_id,
+		thread::Threads,
+		GetResponse,
+		Id,
+		JmapApi,
+		MAX_OBJECTS_IN_GET,
+	},
+	mime,
+};
+
+pub const PROPERTIES: &[&str] = &[
+	"id",
+	"blobId",
+	"threadId",
+	"mailboxIds",
+	"keywords",
+	"size",
+	"receivedAt",
+	"headers",
+	"messageId",
+	"inReplyTo",
+	"references",
+	"sender",
+	"from",
+	"to",
+	"cc",
+	"bcc",
+	"replyTo",
+	"subject",
+	"sentAt",
+	"bodyStructure",
+	"bodyValues",
+	"textBody",
+	"htmlBody",
+	"attachments",
+	"hasAttachment",
+	"preview",
+];
+
+const DEFAULT_PROPERTIES: &[&str] = &[
+	"id",
+	"blobId",
+	"threadId",
+	"mailboxIds",
+	"keywords",
+	"size",
+	"receivedAt",
+	"messageId",
+	"inReplyTo",
+	"references",
+	"sender",
+	"from",
+	"to",
+	"cc",
+	"bcc",
+	"replyTo",
+	"subject",
+	"sentAt",
+	"hasAttachment",
+	"preview",
+	"bodyValues",
+	"textBody",
+	"htmlBody",
+	"attachments",
+];
+
+pub const BODY_PROPERTIES: &[&str] = &[
+	"partId",
+	"blobId",
+	"size",
+	"headers",
+	"name",
+	"type",
+	"charset",
+	"disposition",
+	"cid",
+	"language",
+	"location",
+	"subParts",
+];
+
+const DEFAULT_BODY_PROPERTIES: &[&str] = &[
+	"partId",
+	"blobId",
+	"size",
+	"name",
+	"type",
+	"charset",
+	"disposition",
+	"cid",
+	"language",
+	"location",
+];
+
+/// Properties the imap `ENVELOPE` can answer without fetching the header.
+///
+/// `sender` and `replyTo` are missing on purpose, the envelope falls back to
+/// `From` for those.
+const ENVELOPE_PROPERTIES: &[&str] = &[
+	"sentAt",
+	"subject",
+	"from",
+	"to",
+	"cc",
+	"bcc",
+	"inReplyTo",
+	"messageId",
+];
+
+const BODY_STRUCTURE_PROPERTIES: &[&str] = &[
+	"bodyStructure",
+	"bodyValues",
+	"textBody",
+	"htmlBody",
+	"attachments",
+	"hasAttachment",
+	"preview",
+];
+
+const MAX_PREVIEW_CHARS: usize = 256;
+// enough encoded text to fill a preview in all but pathological cases
+const PREVIEW_FETCH_BYTES: usize = 4096;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailGetRequest {
+	pub account_id: Id,
+	pub ids:        Option<Vec<Id>>,
+	pub properties: Option<Vec<String>>,
+	#[serde(flatten)]
+	pub body:       BodyArguments,
+}
+
+/// Arguments shared by `Email/get` and `Email/parse`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BodyArguments {
+	pub body_properties:        Option<Vec<String>>,
+	#[serde(default)]
+	pub fetch_text_body_values: bool,
+	#[serde(default, rename = "fetchHTMLBodyValues")]
+	pub fetch_html_body_values: bool,
+	#[serde(default)]
+	pub fetch_all_body_values:  bool,
+	#[serde(default)]
+	pub max_body_value_bytes:   u64,
+}
+
+/// The parts of a get request that decide how bodies are returned.
+pub struct BodyOptions {
+	pub body_properties:        Vec<String>,
+	pub fetch_text_body_values: bool,
+	pub fetch_html_body_values: bool,
+	pub max_body_value_bytes:   u64,
+}
+
+impl BodyOptions {
+	pub fn from_request(request: &BodyArguments) -> Result<Self, MethodError> {
+		let body_properties = match &request.body_properties {
+			Some(properties) => {
+				if let Some(unknown) = properties.iter().find(|p| {
+					!BODY_PROPERTIES.contains(&p.as_str()) && HeaderProperty::parse(p).is_none()
+				}) {
+					return Err(MethodError::invalid_arguments(format!(
+						"unknown body property `{}`",
+						unknown
+					)));
+				}
+				properties.clone()
+			}
+			None => DEFAULT_BODY_PROPERTIES
+				.iter()
+				.map(|p| p.to_string())
+				.collect(),
+		};
+
+		Ok(BodyOptions {
+			body_properties,
+			fetch_text_body_values: request.fetch_text_body_values || request.fetch_all_body_values,
+			fetch_html_body_values: request.fetch_html_body_values || request.fetch_all_body_values,
+			max_body_value_bytes: request.max_body_value_bytes,
+		})
+	}
+
+	/// Whether the body properties can only be answered from the full message.
+	fn needs_part_headers(&self) -> bool {
+		self.body_properties
+			.iter()
+			.any(|p| p == "headers" || p.starts_with("header:"))
+	}
+}
+
+pub fn flag_to_keyword(flag: &str) -> Option<String> {
+	match flag.to_ascii_lowercase().as_str() {
+		"\\seen" => Some("$seen".to_owned()),
+		"\\answered" => Some("$answered".to_owned()),
+		"\\flagged" => Some("$flagged".to_owned()),
+		"\\draft" => Some("$draft".to_owned()),
+		// \Deleted messages are gone as far as jmap is concerned
+		f if f.starts_with('\\') => None,
+		f => Some(f.to_owned()),
+	}
+}
+
+/// RFC 8621 section 4.1.1, keywords are imap flag atoms.
+pub fn is_valid_keyword(keyword: &str) -> bool {
+	(1..=255).contains(&keyword.len())
+		&& keyword
+			.bytes()
+			.all(|b| (0x21..=0x7e).contains(&b) && !b"(){]%*\"\\".contains(&b))
+}
+
+/// The imap flag for a keyword, `None` for invalid keywords.
+pub fn keyword_to_flag(keyword: &str) -> Option<String> {
+	if !is_valid_keyword(keyword) {
+		return None;
+	}
+	Some(match keyword.to_ascii_lowercase().as_str() {
+		"$seen" => "\\Seen".to_owned(),
+		"$answered" => "\\Answered".to_owned(),
+		"$flagged" => "\\Flagged".to_owned(),
+		"$draft" => "\\Draft".to_owned(),
+		k => k.to_owned(),
+	})
+}
+
+pub fn email_blob_id(email_id: &str) -> Id {
+	format!("B{}", email_id)
+}
+
+/// Blob id of a body part, `container` is the blob id of the message the
+/// part is in. Dots aren't allowed in ids so they're replaced in the part id.
+pub fn part_blob_id(container: &str, part_id: &str) -> Id {
+	format!("P{}_{}", part_id.replace('.', "-"), container)
+}
+
+/// The email id behind a blob id and the part ids leading to the blob, the
+/// outermost first. Parts of attached messages are parts of parts, see
+/// [`email_blob_id`] and [`part_blob_id`].
+pub fn parse_blob_id(blob_id: &str) -> Option<(Id, Vec<String>)> {
+	if let Some(email_id) = blob_id.strip_prefix('B') {
+		return Some((email_id.to_owned(), vec![]));
+	}
+	let (part_id, container) = blob_id.strip_prefix('P')?.split_once('_')?;
+	let (email_id, mut part_ids) = parse_blob_id(container)?;
+	part_ids.push(part_id.replace('-', "."));
+	Some((email_id, part_ids))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HeaderForm {
+	Raw,
+	Text,
+	Addresses,
+	GroupedAddresses,
+	MessageIds,
+	Date,
+	Urls,
+}
+
+impl HeaderForm {
+	fn value(self, raw: &str) -> Value {
+		match self {
+			HeaderForm::Raw => Value::String(raw.to_owned()),
+			HeaderForm::Text => {
+				Value::String(mime::decode_words(&mime::unfold(raw)).trim().to_owned())
+			}
+			HeaderForm::Addresses => json!(mime::parse_addresses(raw)),
+			HeaderForm::GroupedAddresses => json!(mime::parse_grouped_addresses(raw)),
+			HeaderForm::MessageIds => json!(mime::parse_message_ids(raw)),
+			HeaderForm::Date => json!(mime::parse_date(raw).map(|d| d.to_rfc3339())),
+			HeaderForm::Urls => json!(mime::parse_urls(raw)),
+		}
+	}
+
+	/// Writes a header value given in this form, `None` if it doesn't fit.
+	fn encode(self, value: &Value) -> Option<String> {
+		let strings =
+			|value: &Value| -> Option<Vec<String>> { serde_json::from_value(value.clone()).ok() };
+		Some(match self {
+			HeaderForm::Raw => value.as_str()?.to_owned(),
+			HeaderForm::Text => mime::encode_words(value.as_str()?),
+			HeaderForm::Addresses => {
+				mime::format_addresses(&serde_json::from_value::<Vec<_>>(value.clone()).ok()?)
+			}
+			HeaderForm::GroupedAddresses => mime::format_grouped_addresses(
+				&serde_json::from_value::<Vec<_>>(value.clone()).ok()?,
+			),
+			HeaderForm::MessageIds => strings(value)?
+				.iter()
+				.map(|id| format!("<{}>", id))
+				.collect::<Vec<_>>()
+				.join(" "),
+			HeaderForm::Date => chrono::DateTime::parse_from_rfc3339(value.as_str()?)
+				.ok()?
+				.to_rfc2822(),
+			HeaderForm::Urls => strings(value)?
+				.iter()
+				.map(|url| format!("<{}>", url))
+				.collect::<Vec<_>>()
+				.join(", "),
+		})
+	}
+}
+
+const ADDRESS_HEADERS: &[&str] = &[
+	"From",
+	"Sender",
+	"Reply-To",
+	"To",
+	"Cc",
+	"Bcc",
+	"Resent-From",
+	"Resent-Sender",
+	"Resent-Reply-To",
+	"Resent-To",
+	"Resent-Cc",
+	"Resent-Bcc",
+];
+const MESSAGE_ID_HEADERS: &[&str] = &[
+	"Message-ID",
+	"In-Reply-To",
+	"References",
+	"Resent-Message-ID",
+];
+const DATE_HEADERS: &[&str] = &["Date", "Resent-Date"];
+const URL_HEADERS: &[&str] = &[
+	"List-Help",
+	"List-Unsubscribe",
+	"List-Subscribe",
+	"List-Post",
+	"List-Owner",
+	"List-Archive",
+];
+const TEXT_HEADERS: &[&str] = &["Subject", "Comments", "Keywords", "List-Id"];
+
+/// A `header:{name}[:as{form}][:all]` property.
+struct HeaderProperty {
+	name: String,
+	form: HeaderForm,
+	all:  bool,
+}
+
+impl HeaderProperty {
+	fn parse(property: &str) -> Option<Self> {
+		let mut segments = property.strip_prefix("header:")?.split(':');
+		let name = segments.next().filter(|n| !n.is_empty())?;
+
+		let mut form = HeaderForm::Raw;
+		let mut all = false;
+		let mut next = segments.next();
+		if let Some(segment) = next.and_then(|s| s.strip_prefix("as")) {
+			form = match segment {
+				"Raw" => HeaderForm::Raw,
+				"Text" => HeaderForm::Text,
+				"Addresses" => HeaderForm::Addresses,
+				"GroupedAddresses" => HeaderForm::GroupedAddresses,
+				"MessageIds" => HeaderForm::MessageIds,
+				"Date" => HeaderForm::Date,
+				"URLs" => HeaderForm::Urls,
+				_ => return None,
+			};
+			next = segments.next();
+		}
+		if next == Some("all") {
+			all = true;
+			next = segments.next();
+		}
+		if next.is_some() {
+			return None;
+		}
+
+		// known headers may only be fetched in the forms that make sense for them
+		let is = |list: &[&str]| list.iter().any(|h| h.eq_ignore_ascii_case(name));
+		let known = is(ADDRESS_HEADERS)
+			|| is(MESSAGE_ID_HEADERS)
+			|| is(DATE_HEADERS)
+			|| is(URL_HEADERS)
+			|| is(TEXT_HEADERS);
+		let allowed = match form {
+			HeaderForm::Raw => true,
+			HeaderForm::Text => is(TEXT_HEADERS),
+			HeaderForm::Addresses | HeaderForm::GroupedAddresses => is(ADDRESS_HEADERS),
+			HeaderForm::MessageIds => is(MESSAGE_ID_HEADERS),
+			HeaderForm::Date => is(DATE_HEADERS),
+			HeaderForm::Urls => is(URL_HEADERS),
+		};
+		if known && !allowed {
+			return None;
+		}
+
+		Some(HeaderProperty {
+			name: name.to_owned(),
+			form,
+			all,
+		})
+	}
+
+	/// The header behind one of the convenience properties like `from`.
+	fn convenience(property: &str) -> Option<Self> {
+		let (name, form) = match property {
+			"messageId" => ("Message-ID", HeaderForm::MessageIds),
+			"inReplyTo" => ("In-Reply-To", HeaderForm::MessageIds),
+			"references" => ("References", HeaderForm::MessageIds),
+			"sender" => ("Sender", HeaderForm::Addresses),
+			"from" => ("From", HeaderForm::Addresses),
+			"to" => ("To", HeaderForm::Addresses),
+			"cc" => ("Cc", HeaderForm::Addresses),
+			"bcc" => ("Bcc", HeaderForm::Addresses),
+			"replyTo" => ("Reply-To", HeaderForm::Addresses),
+			"subject" => ("Subject", HeaderForm::Text),
+			"sentAt" => ("Date", HeaderForm::Date),
+			_ => return None,
+		};
+		Some(HeaderProperty {
+			name: name.to_owned(),
+			form,
+			all: false,
+		})
+	}
+
+	fn value(&self, headers: &[mime::Header]) -> Value {
+		let mut values = headers
+			.iter()
+			.filter(|h| h.name.eq_ignore_ascii_case(&self.name))
+			.map(|h| self.form.value(&h.value));
+
+		if self.all {
+			Value::Array(values.collect())
+		} else {
+			values.next_back().unwrap_or(Value::Null)
+		}
+	}
+}
+
+fn headers_value(headers: &[mime::Header]) -> Value {
+	headers
+		.iter()
+		.map(|h| json!({ "name": h.name, "value": h.value }))
+		.collect()
+}
+
+/// The decoded size of `encoded` octets of base64 in `lines` lines, which
+/// are taken to be the usual 76 characters long if unknown. The padding
+/// can't be told without the content, that's at most 2 octets too many.
+fn base64_decoded_size(encoded: u64, lines: Option<u64>) -> u64 {
+	let lines = lines.unwrap_or_else(|| encoded.div_ceil(78));
+	encoded.saturating_sub(2 * lines) / 4 * 3
+}
+
+/// An `EmailBodyPart`, built from a `BODYSTRUCTURE` or a parsed message.
+#[derive(Debug, Clone, Default)]
+pub struct BodyPart {
+	pub part_id:     Option<String>,
+	pub size:        u64,
+	/// Only known when the part was parsed from the message itself.
+	pub headers:     Vec<mime::Header>,
+	pub name:        Option<String>,
+	pub r#type:      String,
+	pub charset:     Option<String>,
+	pub disposition: Option<String>,
+	pub cid:         Option<String>,
+	pub language:    Option<Vec<String>>,
+	pub location:    Option<String>,
+	pub encoding:    Option<String>,
+	pub sub_parts:   Vec<BodyPart>,
+}
+
+/// Section of the `index`th child, imap numbers parts starting at one.
+fn child_section(section: &str, index: usize) -> String {
+	if section.is_empty() {
+		(index + 1).to_string()
+	} else {
+		format!("{}.{}", section, index + 1)
+	}
+}
+
+fn token_parameters(token: Option<&Token>) -> HashMap<String, String> {
+	let params = token
+		.and_then(Token::as_list)
+		.unwrap_or(&[])
+		.chunks(2)
+		.filter_map(|pair| {
+			Some((
+				pair.first()?.as_str()?.to_owned(),
+				pair.get(1)?.as_str()?.to_owned(),
+			))
+		})
+		.collect();
+	mime::decode_parameters(params)
+}
+
+fn content_id(value: &str) -> String {
+	value
+		.trim()
+		.trim_start_matches('<')
+		.trim_end_matches('>')
+		.to_owned()
+}
+
+impl BodyPart {
+	pub fn is_multipart(&self) -> bool {
+		self.r#type.starts_with("multipart/")
+	}
+
+	pub fn find(&self, part_id: &str) -> Option<&BodyPart> {
+		if self.part_id.as_deref() == Some(part_id) {
+			return Some(self);
+		}
+		self.sub_parts.iter().find_map(|p| p.find(part_id))
+	}
+
+	/// Converts a `BODYSTRUCTURE`. `section` is the imap section of the
+	/// message the structure belongs to, empty for the top level message.
+	pub fn from_bodystructure(tokens: &[Token], section: &str) -> BodyPart {
+		BodyPart::from_bodystructure_part(tokens, section, true)
+	}
+
+	fn from_bodystructure_part(tokens: &[Token], section: &str, root: bool) -> BodyPart {
+		let string = |i: usize| tokens.get(i).and_then(Token::as_str);
+
+		if tokens.first().is_some_and(|t| t.as_list().is_some()) {
+			let children: Vec<&[Token]> = tokens.iter().map_while(Token::as_list).collect();
+			let subtype = string(children.len())
+				.unwrap_or("mixed")
+				.to_ascii_lowercase();
+			// extension data: parameters, disposition, language, location
+			let extension = tokens.get(children.len() + 2..).unwrap_or(&[]);
+
+			let mut part = BodyPart {
+				r#type: format!("multipart/{}", subtype),
+				sub_parts: children
+					.iter()
+					.enumerate()
+					.map(|(i, c)| {
+						BodyPart::from_bodystructure_part(c, &child_section(section, i), false)
+					})
+					.collect(),
+				..Default::default()
+			};
+			part.apply_extension(extension);
+			return part;
+		}
+
+		let r#type = format!(
+			"{}/{}",
+			string(0).unwrap_or("text"),
+			string(1).unwrap_or("plain")
+		)
+		.to_ascii_lowercase();
+		let params = token_parameters(tokens.get(2));
+		let encoding = string(5).map(|e| e.to_ascii_lowercase());
+		let encoded_size = string(6).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+		let size = match encoding.as_deref() {
+			Some("base64") => {
+				// only text parts tell their number of lines
+				let lines = if r#type.starts_with("text/") {
+					string(7).and_then(|s| s.parse().ok())
+				} else {
+					None
+				};
+				base64_decoded_size(encoded_size, lines)
+			}
+			_ => encoded_size,
+		};
+
+		// the extension data starts after the type specific fields, skip the md5
+		let extension_start = if r#type.starts_with("text/") {
+			8
+		} else if r#type == "message/rfc822" || r#type == "message/global" {
+			10
+		} else {
+			7
+		};
+		let extension = tokens.get(extension_start + 1..).unwrap_or(&[]);
+
+		let mut part = BodyPart {
+			part_id: Some(if root {
+				child_section(section, 0)
+			} else {
+				section.to_owned()
+			}),
+			size,
+			name: params.get("name").cloned(),
+			charset: params
+				.get("charset")
+				.cloned()
+				.or_else(|| r#type.starts_with("text/").then(|| "us-ascii".to_owned())),
+			cid: string(3).map(content_id),
+			encoding,
+			r#type,
+			..Default::default()
+		};
+		part.apply_extension(extension);
+		part
+	}
+
+	/// Disposition, language and location from the extension data.
+	fn apply_extension(&mut self, extension: &[Token]) {
+		if let Some(disposition) = extension.first().and_then(Token::as_list) {
+			self.disposition = disposition
+				.first()
+				.and_then(Token::as_str)
+				.map(|d| d.to_ascii_lowercase());
+			let params = token_parameters(disposition.get(1));
+			if let Some(filename) = params.get("filename") {
+				self.name = Some(filename.clone());
+			}
+		}
+
+		self.language = match extension.get(1) {
+			Some(Token::List(languages)) => Some(
+				languages
+					.iter()
+					.filter_map(Token::as_str)
+					.map(str::to_owned)
+					.collect(),
+			),
+			Some(token) => token.as_str().map(|l| vec![l.to_owned()]),
+			None => None,
+		};
+
+		self.location = extension.get(2).and_then(Token::as_str).map(str::to_owned);
+	}
+
+	/// Converts a parsed message, see [`BodyPart::from_bodystructure`].
+	pub fn from_mime(data: &[u8], part: &mime::Part, section: &str) -> BodyPart {
+		BodyPart::from_mime_part(data, part, section, true)
+	}
+
+	fn from_mime_part(data: &[u8], part: &mime::Part, section: &str, root: bool) -> BodyPart {
+		let header = |name| mime::find_header(&part.headers, name);
+		let encoding = header("Content-Transfer-Encoding").map(|e| e.trim().to_ascii_lowercase());
+		let disposition = header("Content-Disposition").map(mime::parse_parameters);
+		let is_multipart = part.content_type.starts_with("multipart/");
+
+		BodyPart {
+			part_id: match (is_multipart, root) {
+				(true, _) => None,
+				(false, true) => Some(child_section(section, 0)),
+				(false, false) => Some(section.to_owned()),
+			},
+			size: if is_multipart {
+				0
+			} else {
+				mime::decode_transfer(encoding.as_deref(), &data[part.body.clone()]).len() as u64
+			},
+			headers: part.headers.clone(),
+			name: disposition
+				.as_ref()
+				.and_then(|(_, params)| params.get("filename"))
+				.or_else(|| part.params.get("name"))
+				.cloned(),
+			charset: part.params.get("charset").cloned().or_else(|| {
+				part.content_type
+					.starts_with("text/")
+					.then(|| "us-ascii".to_owned())
+			}),
+			disposition: disposition.map(|(d, _)| d.to_ascii_lowercase()),
+			cid: header("Content-ID").map(content_id),
+			language: header("Content-Language").map(|l| {
+				mime::unfold(l)
+					.split(',')
+					.map(|l| l.trim().to_owned())
+					.filter(|l| !l.is_empty())
+					.collect()
+			}),
+			location: header("Content-Location").map(|l| mime::unfold(l).trim().to_owned()),
+			encoding,
+			r#type: part.content_type.clone(),
+			sub_parts: part
+				.sub_parts
+				.iter()
+				.enumerate()
+				.map(|(i, p)| BodyPart::from_mime_part(data, p, &child_section(section, i), false))
+				.collect(),
+		}
+	}
+
+	fn to_value(&self, properties: &[String], container: Option<&str>) -> Value {
+		let mut object = Map::new();
+		for property in properties {
+			let value = match property.as_str() {
+				"partId" => json!(self.part_id),
+				"blobId" => json!(self
+					.part_id
+					.as_deref()
+					.zip(container)
+					.map(|(part, container)| part_blob_id(container, part))),
+				"size" => json!(self.size),
+				"headers" => headers_value(&self.headers),
+				"name" => json!(self.name),
+				"type" => json!(self.r#type),
+				"charset" => json!(self.charset),
+				"disposition" => json!(self.disposition),
+				"cid" => json!(self.cid),
+				"language" => json!(self.language),
+				"location" => json!(self.location),
+				"subParts" if self.is_multipart() => self
+					.sub_parts
+					.iter()
+					.map(|p| p.to_value(properties, container))
+					.collect(),
+				"subParts" => Value::Null,
+				p => match HeaderProperty::parse(p) {
+					Some(header) => header.value(&self.headers),
+					None => continue,
+				},
+			};
+			object.insert(property.clone(), value);
+		}
+		Value::Object(object)
+	}
+}
+
+fn is_inline_media_type(r#type: &str) -> bool {
+	r#type.starts_with("image/") || r#type.starts_with("audio/") || r#type.starts_with("video/")
+}
+
+#[derive(Default)]
+pub struct BodyLists<'a> {
+	pub text:        Vec<&'a BodyPart>,
+	pub html:        Vec<&'a BodyPart>,
+	pub attachments: Vec<&'a BodyPart>,
+}
+
+impl<'a> BodyLists<'a> {
+	pub fn new(root: &'a BodyPart) -> Self {
+		let mut lists = BodyLists::default();
+		lists.parse_structure(std::slice::from_ref(root), "mixed", false, true, true);
+		lists
+	}
+
+	/// The algorithm from RFC 8621 section 4.1.4, `text_enabled` and
+	/// `html_enabled` stand in for the lists being set to null.
+	fn parse_structure(
+		&mut self,
+		parts: &'a [BodyPart],
+		multipart_type: &str,
+		in_alternative: bool,
+		mut text_enabled: bool,
+		mut html_enabled: bool,
+	) {
+		let text_length = self.text.len();
+		let html_length = self.html.len();
+
+		for (i, part) in parts.iter().enumerate() {
+			let r#type = part.r#type.as_str();
+			let is_inline = part.disposition.as_deref() != Some("attachment")
+				&& (r#type == "text/plain"
+					|| r#type == "text/html"
+					|| is_inline_media_type(r#type))
+				&& (i == 0
+					|| (multipart_type != "related"
+						&& (is_inline_media_type(r#type) || part.name.is_none())));
+
+			if part.is_multipart() {
+				let subtype = r#type.trim_start_matches("multipart/");
+				self.parse_structure(
+					&part.sub_parts,
+					subtype,
+					in_alternative || subtype == "alternative",
+					text_enabled,
+					html_enabled,
+				);
+			} else if is_inline {
+				if multipart_type == "alternative" {
+					match r#type {
+						"text/plain" if text_enabled => self.text.push(part),
+						"text/html" if html_enabled => self.html.push(part),
+						"text/plain" | "text/html" => {}
+						_ => self.attachments.push(part),
+					}
+					continue;
+				} else if in_alternative {
+					if r#type == "text/plain" {
+						html_enabled = false;
+					}
+					if r#type == "text/html" {
+						text_enabled = false;
+					}
+				}
+				if text_enabled {
+					self.text.push(part);
+				}
+				if html_enabled {
+					self.html.push(part);
+				}
+				if (!text_enabled || !html_enabled) && is_inline_media_type(r#type) {
+					self.attachments.push(part);
+				}
+			} else {
+				self.attachments.push(part);
+			}
+		}
+
+		if multipart_type == "alternative" && text_enabled && html_enabled {
+			if text_length == self.text.len() && html_length != self.html.len() {
+				let added = self.html[html_length..].to_vec();
+				self.text.extend(added);
+			}
+			if html_length == self.html.len() && text_length != self.text.len() {
+				let added = self.text[text_length..].to_vec();
+				self.html.extend(added);
+			}
+		}
+	}
+
+	/// The part the preview is generated from.
+	pub fn preview_part(&self) -> Option<&'a BodyPart> {
+		self.text
+			.iter()
+			.chain(self.html.iter())
+			.find(|p| p.r#type == "text/plain" || p.r#type == "text/html")
+			.copied()
+	}
+
+	/// The text parts whose values were asked for.
+	fn value_parts(&self, options: &BodyOptions) -> Vec<&'a BodyPart> {
+		let mut parts: Vec<&BodyPart> = vec![];
+		if options.fetch_text_body_values {
+			parts.extend(self.text.iter().copied());
+		}
+		if options.fetch_html_body_values {
+			parts.extend(self.html.iter().copied());
+		}
+		parts.retain(|p| p.r#type.starts_with("text/"));
+		parts.dedup_by(|a, b| a.part_id == b.part_id);
+		parts
+	}
+}
+
+fn decode_entities(text: &str) -> String {
+	let mut out = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(start) = rest.find('&') {
+		out.push_str(&rest[..start]);
+		rest = &rest[start..];
+
+		let entity = rest[1..]
+			.find(';')
+			.filter(|&end| end <= 10)
+			.map(|end| &rest[1..end + 1]);
+		let decoded = entity.and_then(|entity| match entity {
+			"amp" => Some('&'),
+			"lt" => Some('<'),
+			"gt" => Some('>'),
+			"quot" => Some('"'),
+			"apos" => Some('\''),
+			"nbsp" => Some(' '),
+			e => e
+				.strip_prefix("#x")
+				.or_else(|| e.strip_prefix("#X"))
+				.and_then(|hex| u32::from_str_radix(hex, 16).ok())
+				.or_else(|| e.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+				.and_then(char::from_u32),
+		});
+
+		match (entity, decoded) {
+			(Some(entity), Some(c)) => {
+				out.push(c);
+				rest = &rest[entity.len() + 2..];
+			}
+			_ => {
+				out.push('&');
+				rest = &rest[1..];
+			}
+		}
+	}
+	out.push_str(rest);
+	out
+}
+
+/// Rough plain text version of an html body, good enough for previews and
+/// search snippets.
+pub fn html_to_text(html: &str) -> String {
+	const BLOCK_TAGS: &[&str] = &[
+		"br",
+		"p",
+		"div",
+		"tr",
+		"li",
+		"h1",
+		"h2",
+		"h3",
+		"h4",
+		"h5",
+		"h6",
+		"blockquote",
+		"table",
+	];
+
+	let mut out = String::with_capacity(html.len());
+	let mut rest = html;
+	while let Some(start) = rest.find('<') {
+		out.push_str(&decode_entities(&rest[..start]));
+
+		let end = rest[start..]
+			.find('>')
+			.map_or(rest.len(), |end| start + end + 1);
+		let tag = rest[start + 1..end].to_ascii_lowercase();
+		let closing = tag.starts_with('/');
+		let name: String = tag
+			.trim_start_matches('/')
+			.chars()
+			.take_while(char::is_ascii_alphanumeric)
+			.collect();
+		rest = &rest[end..];
+
+		if !closing && ["style", "script", "head", "title"].contains(&name.as_str()) {
+			// ascii lowercasing keeps the byte offsets intact
+			rest = match rest.to_ascii_lowercase().find(&format!("</{}", name)) {
+				Some(close) => {
+					let after = &rest[close..];
+					&after[after.find('>').map_or(after.len(), |e| e + 1)..]
+				}
+				None => "",
+			};
+		} else if BLOCK_TAGS.contains(&name.as_str()) {
+			out.push('\n');
+		}
+	}
+	out.push_str(&decode_entities(rest));
+	out
+}
+
+pub fn preview(text: &str) -> String {
+	text.split_whitespace()
+		.flat_map(|word| std::iter::once(' ').chain(word.chars()))
+		.skip(1)
+		.take(MAX_PREVIEW_CHARS)
+		.collect()
+}
+
+/// Decodes the content of a text part into a string.
+fn decode_text(part: &BodyPart, data: &[u8]) -> (String, bool) {
+	let decoded = mime::decode_transfer(part.encoding.as_deref(), data);
+	mime::decode_charset(part.charset.as_deref().unwrap_or("us-ascii"), &decoded)
+}
+
+fn envelope_addresses(token: Option<&Token>) -> Value {
+	let addresses = match token.and_then(Token::as_list) {
+		Some(addresses) => addresses,
+		None => return Value::Null,
+	};
+
+	addresses
+		.iter()
+		.filter_map(Token::as_list)
+		// group start and end markers have no host
+		.filter_map(|address| {
+			let host = address.get(3)?.as_str()?;
+			let mailbox = address.get(2)?.as_str()?;
+			Some(mime::EmailAddress {
+				name:  address
+					.first()
+					.and_then(Token::as_str)
+					.map(mime::decode_words)
+					.filter(|n| !n.is_empty()),
+				email: format!("{}@{}", mailbox, host),
+			})
+		})
+		.map(|a| json!(a))
+		.collect()
+}
+
+fn envelope_value(property: &str, envelope: &[Token]) -> Value {
+	let string = |i: usize| envelope.get(i).and_then(Token::as_str);
+	match property {
+		"sentAt" => json!(string(0).and_then(mime::parse_date).map(|d| d.to_rfc3339())),
+		"subject" => json!(string(1).map(|s| mime::decode_words(s).trim().to_owned())),
+		"from" => envelope_addresses(envelope.get(2)),
+		"to" => envelope_addresses(envelope.get(5)),
+		"cc" => envelope_addresses(envelope.get(6)),
+		"bcc" => envelope_addresses(envelope.get(7)),
+		"inReplyTo" => json!(string(8).and_then(mime::parse_message_ids)),
+		"messageId" => json!(string(9).and_then(mime::parse_message_ids)),
+		_ => Value::Null,
+	}
+}
+
+/// Everything known about one message, fetched over imap or parsed from a
+/// blob. Whatever wasn't needed for the requested properties is left empty.
+#[derive(Default)]
+pub struct EmailData {
+	pub id:          Option<Id>,
+	pub blob_id:     Option<Id>,
+	/// Blob id the part blob ids are relative to.
+	pub container:   Option<Id>,
+	pub thread_id:   Option<Id>,
+	pub mailbox_ids: Option<Vec<Id>>,
+	pub keywords:    Option<Vec<String>>,
+	pub size:        u64,
+	pub received_at: Option<String>,
+	pub headers:     Option<Vec<mime::Header>>,
+	pub envelope:    Option<Vec<Token>>,
+	pub body:        Option<BodyPart>,
+	/// Decoded text parts by part id, with whether decoding went wrong.
+	pub texts:       HashMap<String, (String, bool)>,
+}
+
+impl EmailData {
+	/// The whole text the preview is taken from, html converted to text.
+	pub fn preview_text(&self, lists: &BodyLists) -> Option<String> {
+		let part = lists.preview_part()?;
+		let (text, _) = self.texts.get(part.part_id.as_deref()?)?;
+		Some(if part.r#type == "text/html" {
+			html_to_text(text)
+		} else {
+			text.clone()
+		})
+	}
+
+	/// Takes the headers, body structure and texts from the whole message.
+	pub fn set_message(&mut self, data: &[u8]) {
+		let message = mime::parse_message(data);
+		let body = BodyPart::from_mime(data, &message, "");
+		self.texts = text_parts(&body)
+			.into_iter()
+			.filter_map(|part| {
+				let range = find_mime_part(&message, &body, part)?;
+				Some((part.part_id.clone()?, decode_text(part, &data[range])))
+			})
+			.collect();
+		self.headers = Some(message.headers);
+		self.body = Some(body);
+	}
+
+	/// Builds the object with the given properties, `id` is always included.
+	pub fn to_object(&self, properties: &[String], options: &BodyOptions) -> Value {
+		let lists = self.body.as_ref().map(BodyLists::new).unwrap_or_default();
+		let container = self.container.as_deref();
+		let parts_value = |parts: &[&BodyPart]| -> Value {
+			parts
+				.iter()
+				.map(|p| p.to_value(&options.body_properties, container))
+				.collect()
+		};
+
+		let mut object = Map::new();
+		object.insert("id".to_owned(), json!(self.id));
+
+		for property in properties {
+			let value = match property.as_str() {
+				"id" => continue,
+				"blobId" => json!(self.blob_id),
+				"threadId" => json!(self.thread_id),
+				"mailboxIds" => json!(self.mailbox_ids.as_ref().map(|ids| {
+					ids.iter()
+						.map(|id| (id.clone(), true))
+						.collect::<BTreeMap<_, _>>()
+				})),
+				"keywords" => json!(self.keywords.as_ref().map(|keywords| {
+					keywords
+						.iter()
+						.map(|k| (k.clone(), true))
+						.collect::<BTreeMap<_, _>>()
+				})),
+				"size" => json!(self.size),
+				"receivedAt" => json!(self.received_at),
+				"headers" => headers_value(self.headers.as_deref().unwrap_or(&[])),
+				"bodyStructure" => match &self.body {
+					Some(body) => body.to_value(&options.body_properties, container),
+					None => Value::Null,
+				},
+				"textBody" => parts_value(&lists.text),
+				"htmlBody" => parts_value(&lists.html),
+				"attachments" => parts_value(&lists.attachments),
+				"hasAttachment" => json!(!lists.attachments.is_empty()),
+				"preview" => json!(preview(self.preview_text(&lists).as_deref().unwrap_or(""))),
+				"bodyValues" => {
+					let mut values = Map::new();
+					for part in lists.value_parts(options) {
+						let part_id = match &part.part_id {
+							Some(id) => id,
+							None => continue,
+						};
+						if let Some((text, is_encoding_problem)) = self.texts.get(part_id) {
+							let (value, is_truncated) =
+								truncate(text, options.max_body_value_bytes);
+							values.insert(
+								part_id.clone(),
+								json!({
+									"value": value,
+									"isEncodingProblem": is_encoding_problem,
+									"isTruncated": is_truncated,
+								}),
+							);
+						}
+					}
+					Value::Object(values)
+				}
+				p => {
+					let header =
+						HeaderProperty::convenience(p).or_else(|| HeaderProperty::parse(p));
+					match (&self.headers, &self.envelope, header) {
+						(Some(headers), _, Some(header)) => header.value(headers),
+						(None, Some(envelope), _) if ENVELOPE_PROPERTIES.contains(&p) => {
+							envelope_value(p, envelope)
+						}
+						(_, _, Some(_)) => Value::Null,
+						(_, _, None) => continue,
+					}
+				}
+			};
+			object.insert(property.clone(), value);
+		}
+
+		Value::Object(object)
+	}
+}
+
+/// Cuts `text` to at most `max_bytes` bytes of utf-8, zero means no limit.
+fn truncate(text: &str, max_bytes: u64) -> (&str, bool) {
+	let max = max_bytes as usize;
+	if max == 0 || text.len() <= max {
+		return (text, false);
+	}
+	let mut end = max;
+	while !text.is_char_boundary(end) {
+		end -= 1;
+	}
+	(&text[..end], true)
+}
+
+fn validate_properties(
+	properties: &Option<Vec<String>>,
+	default: &[&str],
+) -> Result<Vec<String>, MethodError> {
+	match properties {
+		Some(properties) => {
+			if let Some(unknown) = properties
+				.iter()
+				.find(|p| !PROPERTIES.contains(&p.as_str()) && HeaderProperty::parse(p).is_none())
+			{
+				return Err(MethodError::invalid_arguments(format!(
+					"unknown property `{}`",
+					unknown
+				)));
+			}
+			Ok(properties.clone())
+		}
+		None => Ok(default.iter().map(|p| p.to_string()).collect()),
+	}
+}
+
+/// Where a message lives on the imap server.
+#[derive(Debug, Clone)]
+pub struct Location {
+	pub mailbox_id: Id,
+	pub mailbox:    String,
+	pub uid:        u32,
+}
+
+/// Email id for servers without `OBJECTID`.
+///
+/// Without a server side id the location is all we have, so the id changes
+/// when the message is moved to another mailbox.
+pub fn location_email_id(mailbox_id: &str, uid: u32) -> Id {
+	format!("{}-{}", mailbox_id, uid)
+}
+
+/// Where the content of an email or body part blob is on the imap server,
+/// to fetch it piece by piece.
+#[derive(Debug, Clone)]
+pub struct BlobLocation {
+	pub mailbox:  String,
+	pub uid:      u32,
+	/// The imap section, empty for the whole message.
+	pub section:  String,
+	pub encoding: Option<String>,
+	/// The size after decoding, `None` when that's only known once decoded.
+	pub size:     Option<u64>,
+	/// Whether the server undoes the encoding (RFC 3516 `BINARY`).
+	pub binary:   bool,
+}
+
+impl BlobLocation {
+	/// Whether the content needs decoding after fetching it.
+	pub fn is_encoded(&self) -> bool {
+		!self.binary
+			&& matches!(
+				self.encoding.as_deref(),
+				Some("base64" | "quoted-printable")
+			)
+	}
+
+	/// The fetch item for `length` octets from `offset` on, counted in the
+	/// content as fetched.
+	pub fn fetch_item(&self, offset: u64, length: u64) -> String {
+		let item = if self.binary {
+			"BINARY.PEEK"
+		} else {
+			"BODY.PEEK"
+		};
+		format!("{}[{}]<{}.{}>", item, self.section, offset, length)
+	}
+
+	/// The content from the response to [`BlobLocation::fetch_item`].
+	pub fn content<'a>(&self, attributes: &'a [Token]) -> Option<&'a [u8]> {
+		if self.binary {
+			imap::fetch_binary(attributes, &self.section)
+		} else {
+			imap::fetch_section(attributes, &self.section)
+		}
+	}
+}
+
+/// The `BODYSTRUCTURE` of the message attached as `part_id` of the message
+/// `structure` belongs to.
+fn attached_structure<'a>(mut structure: &'a [Token], part_id: &str) -> Option<&'a [Token]> {
+	for index in part_id.split('.') {
+		let index: usize = index.parse().ok()?;
+		if structure.first()?.as_list().is_some() {
+			structure = structure.get(index.checked_sub(1)?)?.as_list()?;
+		} else if index != 1 {
+			// the only part of a single part message is part 1
+			return None;
+		}
+	}
+	// the structure of the attached message follows its envelope
+	structure.get(8)?.as_list()
+}
+
+impl JmapApi<'_> {
+	pub async fn has_object_ids(&self) -> Result<bool, MethodError> {
+		Ok(self
+			.with_raw_session(|s| async move { Ok(s.has_capability("OBJECTID")) })
+			.await?)
+	}
+
+	/// Finds all mailboxes and uids of the given emails, unknown ids are left
+	/// out.
+	pub async fn locate_emails(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		ids: &[Id],
+	) -> Result<HashMap<Id, Vec<Location>>, MethodError> {
+		let mut locations: HashMap<Id, Vec<Location>> = HashMap::new();
+
+		if !self.has_object_ids().await? {
+			// see `location_email_id`
+			for id in ids {
+				let location = id.rsplit_once('-').and_then(|(mailbox_id, uid)| {
+					let (_, info) = mailboxes.iter().find(|(m, _)| m == mailbox_id)?;
+					Some(Location {
+						mailbox_id: mailbox_id.to_owned(),
+						mailbox:    info.name.clone(),
+						uid:        uid.parse().ok()?,
+					})
+				});
+				if let Some(location) = location {
+					locations.entry(id.clone()).or_default().push(location);
+				}
+			}
+			return Ok(locations);
+		}
+
+		// anything else could inject search keys
+		let wanted: Vec<String> = ids.iter().filter(|id| is_valid_id(id)).cloned().collect();
+		if wanted.is_empty() {
+			return Ok(locations);
+		}
+
+		// the index knows where messages are unless their mailbox changed since,
+		// only those are searched
+		let index = self.stored_email_index().await?;
+		let (indexed, stale) =
+			index.locate(mailboxes, &wanted.iter().map(String::as_str).collect());
+		let name = |mailbox_id: &Id| {
+			mailboxes
+				.iter()
+				.find(|(id, _)| id == mailbox_id)
+				.map(|(_, info)| info.name.clone())
+				.unwrap_or_default()
+		};
+		let mut found: Vec<(Id, Location)> = indexed
+			.into_iter()
+			.map(|(email_id, mailbox_id, uid)| {
+				let mailbox = name(&mailbox_id);
+				(
+					email_id,
+					Location {
+						mailbox_id,
+						mailbox,
+						uid,
+					},
+				)
+			})
+			.collect();
+
+		let stale: Vec<(Id, String)> = stale.into_iter().map(|id| (id.clone(), name(id))).collect();
+		if !stale.is_empty() {
+			found.extend(
+				self.with_raw_session(|mut s| async move {
+					let wanted: Vec<&str> = wanted.iter().map(|id| id.as_str()).collect();
+					let mut found = vec![];
+					for (mailbox_id, name) in stale {
+						imap::select(&mut s, &name, false).await?;
+						for (email_id, uid) in imap::find_email_ids(&mut s, &wanted).await? {
+							found.push((
+								email_id,
+								Location {
+									mailbox_id: mailbox_id.clone(),
+									mailbox: name.clone(),
+									uid,
+								},
+							));
+						}
+					}
+					Ok(found)
+				})
+				.await?,
+			);
+		}
+
+		for (email_id, location) in found {
+			locations.entry(email_id).or_default().push(location);
+		}
+		Ok(locations)
+	}
+
+	/// Fetches `items` for each `(mailbox, uid, items)`, selecting every
+	/// mailbox only once.
+	pub async fn fetch_messages(
+		&self,
+		messages: Vec<(String, u32, String)>,
+	) -> Result<HashMap<(String, u32), Vec<Token>>, MethodError> {
+		Ok(self
+			.with_raw_session(|mut s| async move {
+				let mut by_mailbox: BTreeMap<String, BTreeMap<String, Vec<u32>>> = BTreeMap::new();
+				for (mailbox, uid, items) in messages {
+					by_mailbox
+						.entry(mailbox)
+						.or_default()
+						.entry(items)
+						.or_default()
+						.push(uid);
+				}
+
+				let mut fetched = HashMap::new();
+				for (mailbox, by_items) in by_mailbox {
+					imap::select(&mut s, &mailbox, false).await?;
+					for (items, uids) in by_items {
+						for (uid, attributes) in imap::uid_fetch(&mut s, &uids, &items).await? {
+							fetched.insert((mailbox.clone(), uid), attributes);
+						}
+					}
+				}
+				Ok(fetched)
+			})
+			.await?)
+	}
+
+	/// The content of an uploaded, email, body part or sieve script blob,
+	/// `None` if there is no such blob.
+	pub async fn fetch_blob(&self, blob_id: &str) -> Result<Option<Vec<u8>>, MethodError> {
+		if is_upload_blob_id(blob_id) {
+			let upload = self.state.blobs.get(self.account_id(), blob_id).await?;
+			return Ok(upload.map(|(_, data)| data));
+		}
+		if let Some((script_id, hash)) = parse_script_blob_id(blob_id) {
+			return self.fetch_script_blob(&script_id, hash).await;
+		}
+
+		let (email_id, part_ids) = match parse_blob_id(blob_id) {
+			Some(ids) => ids,
+			None => return Ok(None),
+		};
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		let locations = self
+			.locate_emails(&mailboxes, std::slice::from_ref(&email_id))
+			.await?;
+		let location = match locations.get(&email_id).and_then(|l| l.first()) {
+			Some(location) => location,
+			None => return Ok(None),
+		};
+		let key = (location.mailbox.clone(), location.uid);
+		let fetched = self
+			.fetch_messages(vec![(key.0.clone(), key.1, "BODY.PEEK[]".to_owned())])
+			.await?;
+		let data = match fetched
+			.get(&key)
+			.and_then(|attributes| imap::fetch_section(attributes, ""))
+		{
+			Some(data) => data,
+			None => return Ok(None),
+		};
+
+		let mut data = data.to_vec();
+		for part_id in part_ids {
+			let message = mime::parse_message(&data);
+			let body = BodyPart::from_mime(&data, &message, "");
+			let part = body.find(&part_id).and_then(|part| {
+				let range = find_mime_part(&message, &body, part)?;
+				Some(mime::decode_transfer(
+					part.encoding.as_deref(),
+					&data[range],
+				))
+			});
+			data = match part {
+				Some(part) => part,
+				None => return Ok(None),
+			};
+		}
+		Ok(Some(data))
+	}
+
+	/// Finds an email or body part blob on the imap server, `None` if there
+	/// is no such blob.
+	pub async fn locate_blob(&self, blob_id: &str) -> Result<Option<BlobLocation>, MethodError> {
+		let (email_id, part_ids) = match parse_blob_id(blob_id) {
+			Some(ids) => ids,
+			None => return Ok(None),
+		};
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		let locations = self
+			.locate_emails(&mailboxes, std::slice::from_ref(&email_id))
+			.await?;
+		let location = match locations.get(&email_id).and_then(|l| l.first()) {
+			Some(location) => location,
+			None => return Ok(None),
+		};
+		let key = (location.mailbox.clone(), location.uid);
+		let fetched = self
+			.fetch_messages(vec![(
+				key.0.clone(),
+				key.1,
+				"RFC822.SIZE BODYSTRUCTURE".to_owned(),
+			)])
+			.await?;
+		let attributes = match fetched.get(&key) {
+			Some(attributes) => attributes,
+			None => return Ok(None),
+		};
+
+		let mut blob = BlobLocation {
+			mailbox:  key.0.clone(),
+			uid:      key.1,
+			section:  String::new(),
+			encoding: None,
+			size:     raw::find_value(attributes, "RFC822.SIZE")
+				.and_then(Token::as_str)
+				.and_then(|s| s.parse().ok()),
+			binary:   false,
+		};
+		if part_ids.is_empty() {
+			return Ok(Some(blob));
+		}
+
+		let mut structure =
+			match raw::find_value(attributes, "BODYSTRUCTURE").and_then(Token::as_list) {
+				Some(structure) => structure,
+				None => return Ok(None),
+			};
+		let mut part: Option<BodyPart> = None;
+		for (i, part_id) in part_ids.iter().enumerate() {
+			// part ids of attached messages start over in the attached message
+			if let Some(message) = &part {
+				if message.r#type != "message/rfc822" && message.r#type != "message/global" {
+					return Ok(None);
+				}
+				structure = match attached_structure(structure, &part_ids[i - 1]) {
+					Some(structure) => structure,
+					None => return Ok(None),
+				};
+			}
+			let section = if blob.section.is_empty() {
+				part_id.clone()
+			} else {
+				format!("{}.{}", blob.section, part_id)
+			};
+			part = BodyPart::from_bodystructure(structure, &blob.section)
+				.find(&section)
+				.cloned();
+			if part.is_none() {
+				return Ok(None);
+			}
+			blob.section = section;
+		}
+		let part = match part {
+			Some(part) => part,
+			None => return Ok(None),
+		};
+
+		blob.encoding = part.encoding;
+		blob.size = Some(part.size);
+		if blob.is_encoded() {
+			blob.binary = self
+				.with_raw_session(|s| async move { Ok(s.has_capability("BINARY")) })
+				.await?;
+			// the size in the structure is only an estimate
+			blob.size = None;
+			if blob.binary {
+				let item = format!("BINARY.SIZE[{}]", blob.section);
+				let fetched = self
+					.fetch_messages(vec![(key.0.clone(), key.1, item.clone())])
+					.await?;
+				blob.size = fetched
+					.get(&key)
+					.and_then(|attributes| raw::find_value(attributes, &item))
+					.and_then(Token::as_str)
+					.and_then(|s| s.parse().ok());
+			}
+		}
+
+		Ok(Some(blob))
+	}
+
+	pub async fn handle_email_get(
+		&self,
+		request: EmailGetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let properties = validate_properties(&request.properties, DEFAULT_PROPERTIES)?;
+		let options = BodyOptions::from_request(&request.body)?;
+		let ids = match &request.ids {
+			Some(ids) if ids.len() <= MAX_OBJECTS_IN_GET => ids.clone(),
+			// listing every email of the account isn't supported
+			_ => return Err(MethodError::RequestTooLarge),
+		};
+
+		let wants = |list: &[&str]| properties.iter().any(|p| list.contains(&p.as_str()));
+		let needs_structure = wants(BODY_STRUCTURE_PROPERTIES);
+		let needs_full = needs_structure && options.needs_part_headers();
+		let needs_headers = !needs_full
+			&& properties.iter().any(|p| {
+				p == "headers"
+					|| p.starts_with("header:")
+					|| (HeaderProperty::convenience(p).is_some()
+						&& !ENVELOPE_PROPERTIES.contains(&p.as_str()))
+			});
+		let needs_envelope = !needs_full && !needs_headers && wants(ENVELOPE_PROPERTIES);
+		let object_ids = self.has_object_ids().await?;
+
+		let mut items = vec![];
+		if wants(&["keywords"]) {
+			items.push("FLAGS");
+		}
+		if wants(&["receivedAt"]) {
+			items.push("INTERNALDATE");
+		}
+		if wants(&["size"]) {
+			items.push("RFC822.SIZE");
+		}
+		if wants(&["threadId"]) && object_ids {
+			items.push("THREADID");
+		}
+		if needs_envelope {
+			items.push("ENVELOPE");
+		}
+		if needs_headers {
+			items.push("BODY.PEEK[HEADER]");
+		}
+		if needs_full {
+			items.push("BODY.PEEK[]");
+		} else if needs_structure {
+			items.push("BODYSTRUCTURE");
+		}
+		let items = items.join(" ");
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		let locations = self.locate_emails(&mailboxes, &ids).await?;
+		let index = self.email_index(&mailboxes).await?;
+		let state = self.email_state(&index).await?;
+		let threads = if wants(&["threadId"]) && !object_ids {
+			Some(Threads::new(&index))
+		} else {
+			None
+		};
+
+		let fetched = self
+			.fetch_messages(
+				locations
+					.values()
+					.filter_map(|l| l.first())
+					.map(|l| (l.mailbox.clone(), l.uid, items.clone()))
+					.collect(),
+			)
+			.await?;
+
+		let mut emails = vec![];
+		let mut not_found = vec![];
+		for id in ids {
+			let (locations, attributes) = match locations.get(&id).and_then(|l| {
+				let first = l.first()?;
+				Some((l, fetched.get(&(first.mailbox.clone(), first.uid))?))
+			}) {
+				Some(found) => found,
+				None => {
+					not_found.push(id);
+					continue;
+				}
+			};
+
+			let mut email = EmailData {
+				blob_id: Some(email_blob_id(&id)),
+				container: Some(email_blob_id(&id)),
+				thread_id: raw::find_value(attributes, "THREADID")
+					.and_then(Token::as_list)
+					.and_then(|l| l.first()?.as_str())
+					.map(str::to_owned)
+					.or_else(|| threads.as_ref()?.by_email.get(&id).cloned())
+					// a message the server doesn't thread is its own thread
+					.or_else(|| Some(format!("T{}", id))),
+				mailbox_ids: Some(locations.iter().map(|l| l.mailbox_id.clone()).collect()),
+				keywords: raw::find_value(attributes, "FLAGS")
+					.and_then(Token::as_list)
+					.map(|flags| {
+						flags
+							.iter()
+							.filter_map(Token::as_str)
+							.filter_map(flag_to_keyword)
+							.collect()
+					}),
+				size: raw::find_value(attributes, "RFC822.SIZE")
+					.and_then(Token::as_number)
+					.unwrap_or(0)
+					.into(),
+				received_at: raw::find_value(attributes, "INTERNALDATE")
+					.and_then(Token::as_str)
+					.and_then(imap::parse_internal_date)
+					.map(|d| {
+						d.with_timezone(&chrono::Utc)
+							.format("%Y-%m-%dT%H:%M:%SZ")
+							.to_string()
+					}),
+				envelope: raw::find_value(attributes, "ENVELOPE")
+					.and_then(Token::as_list)
+					.map(<[Token]>::to_vec),
+				id: Some(id),
+				..Default::default()
+			};
+
+			if let Some(header) = imap::fetch_section(attributes, "HEADER") {
+				email.headers = Some(mime::parse_headers(header).0);
+			}
+			if let Some(data) = imap::fetch_section(attributes, "") {
+				email.set_message(data);
+			} else if let Some(structure) =
+				raw::find_value(attributes, "BODYSTRUCTURE").and_then(Token::as_list)
+			{
+				email.body = Some(BodyPart::from_bodystructure(structure, ""));
+			}
+
+			emails.push((locations[0].clone(), email));
+		}
+
+		if needs_structure && !needs_full {
+			self.fetch_texts(&mut emails, &properties, &options).await?;
+		}
+
+		Ok(MethodResult::EmailGet(GetResponse {
+			account_id: request.account_id,
+			state,
+			list: emails
+				.iter()
+				.map(|(_, email)| email.to_object(&properties, &options))
+				.collect(),
+			not_found,
+		}))
+	}
+
+	/// Fetches the text parts needed for body values and previews.
+	async fn fetch_texts(
+		&self,
+		emails: &mut [(Location, EmailData)],
+		properties: &[String],
+		options: &BodyOptions,
+	) -> Result<(), MethodError> {
+		let wants_values = properties.iter().any(|p| p == "bodyValues");
+		let wants_preview = properties.iter().any(|p| p == "preview");
+
+		let mut requests = vec![];
+		let mut wanted = vec![];
+		for (location, email) in emails.iter() {
+			let body = match &email.body {
+				Some(body) => body,
+				None => continue,
+			};
+			let lists = BodyLists::new(body);
+
+			let mut parts: Vec<(&BodyPart, bool)> = vec![];
+			if wants_values {
+				parts.extend(lists.value_parts(options).into_iter().map(|p| (p, true)));
+			}
+			if wants_preview {
+				if let Some(part) = lists.preview_part() {
+					if !parts.iter().any(|(p, _)| p.part_id == part.part_id) {
+						parts.push((part, false));
+					}
+				}
+			}
+			if parts.is_empty() {
+				continue;
+			}
+
+			let items = parts
+				.iter()
+				.filter_map(|(part, full)| {
+					let section = part.part_id.as_deref()?;
+					Some(if *full {
+						format!("BODY.PEEK[{}]", section)
+					} else {
+						format!("BODY.PEEK[{}]<0.{}>", section, PREVIEW_FETCH_BYTES)
+					})
+				})
+				.collect::<Vec<_>>()
+				.join(" ");
+			requests.push((location.mailbox.clone(), location.uid, items));
+			wanted.push(
+				parts
+					.into_iter()
+					.map(|(p, _)| p.clone())
+					.collect::<Vec<_>>(),
+			);
+		}
+
+		let fetched = self.fetch_messages(requests.clone()).await?;
+
+		for ((mailbox, uid, _), parts) in requests.into_iter().zip(wanted) {
+			let attributes = match fetched.get(&(mailbox.clone(), uid)) {
+				Some(attributes) => attributes,
+				None => continue,
+			};
+			let email = match emails
+				.iter_mut()
+				.find(|(l, _)| l.mailbox == mailbox && l.uid == uid)
+			{
+				Some((_, email)) => email,
+				None => continue,
+			};
+			for part in parts {
+				let part_id = match &part.part_id {
+					Some(id) => id,
+					None => continue,
+				};
+				if let Some(data) = imap::fetch_section(attributes, part_id) {
+					email
+						.texts
+						.insert(part_id.clone(), decode_text(&part, data));
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// All leaf parts with a text type.
+fn text_parts(body: &BodyPart) -> Vec<&BodyPart> {
+	if body.is_multipart() {
+		body.sub_parts.iter().flat_map(text_parts).collect()
+	} else if body.r#type.starts_with("text/") {
+		vec![body]
+	} else {
+		vec![]
+	}
+}
+
+/// The body range of `target` in the parsed message `part` was converted to.
+fn find_mime_part(
+	part: &mime::Part,
+	body: &BodyPart,
+	target: &BodyPart,
+) -> Option<std::ops::Range<usize>> {
+	if std::ptr::eq(body, target) {
+		return Some(part.body.clone());
+	}
+	part.sub_parts
+		.iter()
+		.zip(&body.sub_parts)
+		.find_map(|(p, b)| find_mime_part(p, b, target))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn base64_sizes_leave_out_line_breaks() {
+		// 114 octets are 152 characters, two lines of 76
+		assert_eq!(base64_decoded_size(156, Some(2)), 114);
+		assert_eq!(base64_decoded_size(156, None), 114);
+		let encoded = base64::encode(vec![0; 1000]);
+		let lines = encoded.len().div_ceil(76) as u64;
+		assert_eq!(
+			base64_decoded_size(encoded.len() as u64 + 2 * lines, None),
+			1002
+		);
+		assert_eq!(base64_decoded_size(0, None), 0);
+	}
+}
@@ -0,0 +1,165 @@
+//! `Email/changes` and `Email/queryChanges` on top of the [`EmailIndex`].
+//!
+//! The index follows the server with `CONDSTORE`/`QRESYNC` where available,
+//! the email state is then derived by diffing the mailboxes and keywords of
+//! every email against the previous snapshot.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::jmap::{
+	changes::ChangeLog,
+	email::search::EmailFilterCondition,
+	index::EmailIndex,
+	method::{MethodError, MethodResult},
+	query,
+	ChangesRequest,
+	Id,
+	JmapApi,
+	QueryChangesRequest,
+	QueryChangesResponse,
+};
+
+const EMAIL_CHANGES_STORE_NAME: &str = "email-changes";
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct EmailChangeLog {
+	/// [`EmailIndex::generation`] the log was last recorded with.
+	generation: u64,
+	/// [`EmailIndex::revision`] the log was last recorded with.
+	#[serde(default)]
+	revision:   u64,
+	log:        ChangeLog,
+}
+
+impl AsRef<ChangeLog> for EmailChangeLog {
+	fn as_ref(&self) -> &ChangeLog {
+		&self.log
+	}
+}
+
+impl JmapApi<'_> {
+	/// Records the mailboxes and keywords of every email and returns the state.
+	pub async fn email_state(&self, index: &EmailIndex) -> Result<String, MethodError> {
+		let mut emails: BTreeMap<&Id, (BTreeSet<&Id>, BTreeSet<&String>)> = BTreeMap::new();
+		for (mailbox_id, message) in index.messages() {
+			let (mailbox_ids, keywords) = emails.entry(&message.email_id).or_default();
+			mailbox_ids.insert(mailbox_id);
+			keywords.extend(&message.keywords);
+		}
+		let objects: Vec<(Id, serde_json::Value)> = emails
+			.into_iter()
+			.map(|(id, (mailbox_ids, keywords))| {
+				(
+					id.clone(),
+					json!({ "mailboxIds": mailbox_ids, "keywords": keywords }),
+				)
+			})
+			.collect();
+
+		let (generation, revision) = (index.generation, index.revision);
+		Ok(self
+			.state
+			.store
+			.update(
+				self.account_id(),
+				EMAIL_CHANGES_STORE_NAME,
+				|stored: &mut EmailChangeLog| {
+					// a request that started earlier, the log is already past it
+					if revision < stored.revision {
+						return stored.log.state();
+					}
+					stored.revision = revision;
+					let state = stored.log.record(&objects);
+					// uids were reassigned, nothing can be said about older states
+					if stored.generation != generation {
+						stored.log.invalidate();
+						stored.generation = generation;
+					}
+					state
+				},
+			)
+			.await?)
+	}
+
+	/// The current email state, after bringing the index up to date.
+	pub async fn current_email_state(&self) -> Result<String, MethodError> {
+		let mailboxes = self.fetch_mailboxes().await?;
+		let index = self.email_index(&mailboxes).await?;
+		self.email_state(&index).await
+	}
+
+	pub async fn handle_email_changes(
+		&self,
+		request: ChangesRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		// bring the log up to date before reading from it
+		self.current_email_state().await?;
+		let changes = self
+			.read_changes::<EmailChangeLog>(
+				EMAIL_CHANGES_STORE_NAME,
+				&request.since_state,
+				request.max_changes,
+			)
+			.await?;
+
+		Ok(MethodResult::EmailChanges(
+			changes.into_response(request.account_id),
+		))
+	}
+
+	pub async fn handle_email_query_changes(
+		&self,
+		request: QueryChangesRequest<EmailFilterCondition>,
+		collapse_threads: bool,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		// which email stands for a thread can change without the email itself
+		// changing
+		if collapse_threads {
+			return Err(MethodError::CannotCalculateChanges);
+		}
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		let index = self.email_index(&mailboxes).await?;
+		let new_query_state = self.email_state(&index).await?;
+
+		let changes = self
+			.read_changes::<EmailChangeLog>(
+				EMAIL_CHANGES_STORE_NAME,
+				&request.since_query_state,
+				None,
+			)
+			.await?;
+		let changed: BTreeSet<Id> = changes
+			.created
+			.into_iter()
+			.chain(changes.updated)
+			.chain(changes.destroyed)
+			.collect();
+
+		let current = self
+			.query_email_ids(&mailboxes, &request.filter, &request.sort)
+			.await?;
+		let (removed, added) = query::query_changes(&current, &changed, request.max_changes)?;
+
+		Ok(MethodResult::EmailQueryChanges(QueryChangesResponse {
+			account_id: request.account_id,
+			old_query_state: request.since_query_state,
+			new_query_state,
+			total: if request.calculate_total {
+				Some(current.len() as u64)
+			} else {
+				None
+			},
+			removed,
+			added,
+		}))
+	}
+}
@@ -0,0 +1,344 @@
+//! `Email/import` and `Email/copy`, both append whole messages to mailboxes.
+//!
+//! Copies go through `APPEND` as well since imap `COPY` can neither change
+//! the flags nor the internal date of the copy, nor reach another account.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+	imap,
+	imap::raw::{self, Token},
+	jmap::{
+		email::{
+			set::{new_email_flags, new_email_received_at},
+			Location,
+		},
+		method::{MethodError, MethodResult},
+		CopyRequest,
+		CopyResponse,
+		Id,
+		JmapApi,
+		SetError,
+		SetRequest,
+	},
+	mime,
+};
+
+const IMPORT_PROPERTIES: &[&str] = &["blobId", "mailboxIds", "keywords", "receivedAt"];
+const COPY_PROPERTIES: &[&str] = &["id", "mailboxIds", "keywords", "receivedAt"];
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailImportRequest {
+	pub account_id:  Id,
+	pub if_in_state: Option<String>,
+	pub emails:      HashMap<Id, Map<String, Value>>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailImportResponse {
+	pub account_id:  Id,
+	pub old_state:   Option<String>,
+	pub new_state:   String,
+	pub created:     HashMap<Id, Value>,
+	pub not_created: HashMap<Id, SetError>,
+}
+
+fn check_properties(object: &Map<String, Value>, allowed: &[&str]) -> Result<(), SetError> {
+	if let Some(key) = object.keys().find(|k| !allowed.contains(&k.as_str())) {
+		return Err(SetError::invalid_properties(
+			&[key],
+			"property can't be set",
+		));
+	}
+	if !object.contains_key("mailboxIds") {
+		return Err(SetError::invalid_properties(
+			&["mailboxIds"],
+			"an email must be in at least one mailbox",
+		));
+	}
+	Ok(())
+}
+
+/// The first `Message-ID` of a message, without angle brackets.
+fn message_id(message: &[u8]) -> Option<String> {
+	let (headers, _) = mime::parse_headers(message);
+	mime::parse_message_ids(mime::find_header(&headers, "Message-ID")?)?
+		.into_iter()
+		.next()
+}
+
+impl JmapApi<'_> {
+	pub async fn handle_email_import(
+		&self,
+		request: EmailImportRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let old_state = self.current_email_state().await?;
+		if request
+			.if_in_state
+			.as_ref()
+			.is_some_and(|s| *s != old_state)
+		{
+			return Err(MethodError::StateMismatch);
+		}
+
+		let mut response = EmailImportResponse {
+			account_id: request.account_id,
+			old_state: Some(old_state),
+			..Default::default()
+		};
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		for (creation_id, object) in request.emails {
+			match self.import_email(&mailboxes, &object).await? {
+				Ok(created) => {
+					if let Some(id) = created["id"].as_str() {
+						self.record_created_id(&creation_id, id);
+					}
+					response.created.insert(creation_id, created);
+				}
+				Err(e) => {
+					response.not_created.insert(creation_id, e);
+				}
+			}
+		}
+
+		response.new_state = self.current_email_state().await?;
+
+		Ok(MethodResult::EmailImport(response))
+	}
+
+	async fn import_email(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		object: &Map<String, Value>,
+	) -> Result<Result<Value, SetError>, MethodError> {
+		if let Err(e) = check_properties(object, IMPORT_PROPERTIES) {
+			return Ok(Err(e));
+		}
+		let blob_id = match object.get("blobId").and_then(Value::as_str) {
+			Some(blob_id) => blob_id,
+			None => {
+				return Ok(Err(SetError::invalid_properties(
+					&["blobId"],
+					"must be a blob id",
+				)))
+			}
+		};
+		let mailbox_ids = match self.new_email_mailboxes(mailboxes, object) {
+			Ok(ids) => ids,
+			Err(e) => return Ok(Err(e)),
+		};
+		let (flags, received_at) =
+			match new_email_flags(object).and_then(|f| Ok((f, new_email_received_at(object)?))) {
+				Ok(parsed) => parsed,
+				Err(e) => return Ok(Err(e)),
+			};
+
+		let message = match self.fetch_blob(blob_id).await? {
+			Some(message) => message,
+			None => {
+				return Ok(Err(SetError::BlobNotFound {
+					not_found: vec![blob_id.to_owned()],
+				}))
+			}
+		};
+		if mime::parse_headers(&message).0.is_empty() {
+			return Ok(Err(SetError::InvalidEmail {
+				description: Some("the blob has no header fields".to_owned()),
+			}));
+		}
+
+		let message_id = message_id(&message);
+		self.append_email(
+			mailboxes,
+			&mailbox_ids,
+			flags.unwrap_or_default(),
+			received_at,
+			message,
+			message_id.as_deref(),
+		)
+		.await
+	}
+
+	/// Copies emails from another account of the session into this one.
+	/// Also returns the response of the implicit `Email/set` for
+	/// `onSuccessDestroyOriginal`.
+	pub async fn handle_email_copy(
+		&self,
+		request: CopyRequest,
+	) -> Result<(MethodResult, Option<MethodResult>), MethodError> {
+		self.check_account(&request.account_id)?;
+		// within an account that's an `Email/set` of `mailboxIds`
+		if request.from_account_id == request.account_id {
+			return Err(MethodError::invalid_arguments(
+				"fromAccountId must be another account",
+			));
+		}
+		let source = self
+			.account_api(&request.from_account_id)
+			.ok_or(MethodError::FromAccountNotFound)?;
+
+		if let Some(state) = &request.if_from_in_state {
+			if *state != source.current_email_state().await? {
+				return Err(MethodError::StateMismatch);
+			}
+		}
+		let old_state = self.current_email_state().await?;
+		if request
+			.if_in_state
+			.as_ref()
+			.is_some_and(|s| *s != old_state)
+		{
+			return Err(MethodError::StateMismatch);
+		}
+
+		let mut response = CopyResponse {
+			from_account_id: request.from_account_id.clone(),
+			account_id: request.account_id.clone(),
+			old_state: Some(old_state),
+			..Default::default()
+		};
+
+		let originals: Vec<Id> = request
+			.create
+			.values()
+			.filter_map(|o| self.resolve_id(o.get("id")?.as_str()?))
+			.collect();
+		let source_mailboxes = source.fetch_mailboxes().await?;
+		let locations = source.locate_emails(&source_mailboxes, &originals).await?;
+		let mailboxes = self.fetch_mailboxes().await?;
+
+		let mut copied = vec![];
+		for (creation_id, object) in request.create {
+			match self
+				.copy_email(source, &mailboxes, &locations, &object)
+				.await?
+			{
+				Ok((original, created)) => {
+					if let Some(id) = created["id"].as_str() {
+						self.record_created_id(&creation_id, id);
+					}
+					response.created.insert(creation_id, created);
+					copied.push(original);
+				}
+				Err(e) => {
+					response.not_created.insert(creation_id, e);
+				}
+			}
+		}
+
+		response.new_state = self.current_email_state().await?;
+
+		let destroyed = if request.on_success_destroy_original {
+			let destroy = SetRequest {
+				account_id:  request.from_account_id,
+				if_in_state: request.destroy_from_if_in_state,
+				create:      None,
+				update:      None,
+				destroy:     Some(copied),
+			};
+			Some(
+				source
+					.handle_email_set(destroy)
+					.await
+					.unwrap_or_else(MethodResult::Error),
+			)
+		} else {
+			None
+		};
+
+		Ok((MethodResult::EmailCopy(response), destroyed))
+	}
+
+	/// Appends a copy of an email of `source`, returns the id of the
+	/// original and the new email.
+	async fn copy_email(
+		&self,
+		source: &Self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		locations: &HashMap<Id, Vec<Location>>,
+		object: &Map<String, Value>,
+	) -> Result<Result<(Id, Value), SetError>, MethodError> {
+		if let Err(e) = check_properties(object, COPY_PROPERTIES) {
+			return Ok(Err(e));
+		}
+		let id = match object
+			.get("id")
+			.and_then(Value::as_str)
+			.and_then(|id| self.resolve_id(id))
+		{
+			Some(id) => id,
+			None => {
+				return Ok(Err(SetError::invalid_properties(
+					&["id"],
+					"must be an email id",
+				)))
+			}
+		};
+		let location = match locations.get(&id).and_then(|l| l.first()) {
+			Some(location) => location.clone(),
+			None => return Ok(Err(SetError::NotFound)),
+		};
+		let mailbox_ids = match self.new_email_mailboxes(mailboxes, object) {
+			Ok(ids) => ids,
+			Err(e) => return Ok(Err(e)),
+		};
+		let (flags, received_at) =
+			match new_email_flags(object).and_then(|f| Ok((f, new_email_received_at(object)?))) {
+				Ok(parsed) => parsed,
+				Err(e) => return Ok(Err(e)),
+			};
+
+		let key = (location.mailbox, location.uid);
+		let fetched = source
+			.fetch_messages(vec![(
+				key.0.clone(),
+				key.1,
+				"FLAGS INTERNALDATE BODY.PEEK[]".to_owned(),
+			)])
+			.await?;
+		let (attributes, message) = match fetched
+			.get(&key)
+			.and_then(|a| Some((a, imap::fetch_section(a, "")?)))
+		{
+			Some(found) => found,
+			None => return Ok(Err(SetError::NotFound)),
+		};
+
+		// the copy keeps what isn't set explicitly
+		let flags = flags.unwrap_or_else(|| {
+			raw::find_value(attributes, "FLAGS")
+				.and_then(Token::as_list)
+				.unwrap_or(&[])
+				.iter()
+				.filter_map(Token::as_str)
+				.filter(|f| !f.eq_ignore_ascii_case("\\Recent"))
+				.map(str::to_owned)
+				.collect()
+		});
+		let received_at = received_at.or_else(|| {
+			raw::find_value(attributes, "INTERNALDATE")
+				.and_then(Token::as_str)
+				.map(str::to_owned)
+		});
+
+		let created = self
+			.append_email(
+				mailboxes,
+				&mailbox_ids,
+				flags,
+				received_at,
+				message.to_vec(),
+				message_id(message).as_deref(),
+			)
+			.await?;
+		Ok(created.map(|created| (id, created)))
+	}
+}
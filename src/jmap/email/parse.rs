@@ -0,0 +1,103 @@
+//! `Email/parse`, the same conversion as `Email/get` on a message blob.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+	jmap::{
+		email::{validate_properties, BodyArguments, BodyOptions, EmailData},
+		method::{MethodError, MethodResult},
+		Id,
+		JmapApi,
+		MAX_OBJECTS_IN_GET,
+	},
+	mime,
+};
+
+const DEFAULT_PARSE_PROPERTIES: &[&str] = &[
+	"messageId",
+	"inReplyTo",
+	"references",
+	"sender",
+	"from",
+	"to",
+	"cc",
+	"bcc",
+	"replyTo",
+	"subject",
+	"sentAt",
+	"hasAttachment",
+	"preview",
+	"bodyValues",
+	"textBody",
+	"htmlBody",
+	"attachments",
+];
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailParseRequest {
+	pub account_id: Id,
+	pub blob_ids:   Vec<Id>,
+	pub properties: Option<Vec<String>>,
+	#[serde(flatten)]
+	pub body:       BodyArguments,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailParseResponse {
+	pub account_id:   Id,
+	pub parsed:       HashMap<Id, Value>,
+	pub not_parsable: Vec<Id>,
+	pub not_found:    Vec<Id>,
+}
+
+impl JmapApi<'_> {
+	pub async fn handle_email_parse(
+		&self,
+		request: EmailParseRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let properties = validate_properties(&request.properties, DEFAULT_PARSE_PROPERTIES)?;
+		let options = BodyOptions::from_request(&request.body)?;
+		if request.blob_ids.len() > MAX_OBJECTS_IN_GET {
+			return Err(MethodError::RequestTooLarge);
+		}
+
+		let mut response = EmailParseResponse {
+			account_id: request.account_id,
+			..Default::default()
+		};
+		for blob_id in request.blob_ids {
+			let data = match self.fetch_blob(&blob_id).await? {
+				Some(data) => data,
+				None => {
+					response.not_found.push(blob_id);
+					continue;
+				}
+			};
+			if mime::parse_headers(&data).0.is_empty() {
+				response.not_parsable.push(blob_id);
+				continue;
+			}
+
+			// parsed emails aren't in any mailbox, so they have no id either
+			let mut email = EmailData {
+				blob_id: Some(blob_id.clone()),
+				container: Some(blob_id.clone()),
+				size: data.len() as u64,
+				..Default::default()
+			};
+			email.set_message(&data);
+			response
+				.parsed
+				.insert(blob_id, email.to_object(&properties, &options));
+		}
+
+		Ok(MethodResult::EmailParse(response))
+	}
+}
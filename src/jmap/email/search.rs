@@ -0,0 +1,985 @@
+//! Translation of `Email/query` into imap `SEARCH` and `SORT`.
+
+use std::{cmp::Ordering, collections::HashSet};
+
+use chrono::{DateTime, Timelike, Utc};
+use serde::Deserialize;
+
+use crate::{
+	imap,
+	imap::raw::{self, Token},
+	jmap::{
+		email::{is_valid_keyword, location_email_id, Location},
+		method::{MethodError, MethodResult},
+		query,
+		thread::Threads,
+		Comparator,
+		Filter,
+		Id,
+		JmapApi,
+		Operator,
+		QueryRequest,
+		QueryResponse,
+	},
+	mime,
+};
+
+/// Sort properties advertised in the session, all of them work across
+/// mailboxes and without `SORT` support on the server.
+pub const SORT_OPTIONS: &[&str] = &[
+	"receivedAt",
+	"from",
+	"to",
+	"subject",
+	"size",
+	"header.x-spam-score",
+];
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct EmailFilterCondition {
+	in_mailbox: Option<Id>,
+	in_mailbox_other_than: Option<Vec<Id>>,
+	before: Option<String>,
+	after: Option<String>,
+	min_size: Option<u64>,
+	max_size: Option<u64>,
+	all_in_thread_have_keyword: Option<String>,
+	some_in_thread_have_keyword: Option<String>,
+	none_in_thread_have_keyword: Option<String>,
+	has_keyword: Option<String>,
+	not_keyword: Option<String>,
+	has_attachment: Option<bool>,
+	text: Option<String>,
+	from: Option<String>,
+	to: Option<String>,
+	cc: Option<String>,
+	bcc: Option<String>,
+	subject: Option<String>,
+	body: Option<String>,
+	header: Option<Vec<String>>,
+}
+
+/// A search key with constant results folded away, so mailboxes that can't
+/// match aren't searched at all.
+#[derive(Debug, Clone, PartialEq)]
+enum SearchKey {
+	All,
+	Nothing,
+	Key(String),
+}
+
+impl SearchKey {
+	fn and(self, other: SearchKey) -> SearchKey {
+		match (self, other) {
+			(SearchKey::Nothing, _) | (_, SearchKey::Nothing) => SearchKey::Nothing,
+			(SearchKey::All, k) | (k, SearchKey::All) => k,
+			(SearchKey::Key(a), SearchKey::Key(b)) => SearchKey::Key(format!("({} {})", a, b)),
+		}
+	}
+
+	fn or(self, other: SearchKey) -> SearchKey {
+		match (self, other) {
+			(SearchKey::All, _) | (_, SearchKey::All) => SearchKey::All,
+			(SearchKey::Nothing, k) | (k, SearchKey::Nothing) => k,
+			(SearchKey::Key(a), SearchKey::Key(b)) => SearchKey::Key(format!("OR {} {}", a, b)),
+		}
+	}
+
+	fn not(self) -> SearchKey {
+		match self {
+			SearchKey::All => SearchKey::Nothing,
+			SearchKey::Nothing => SearchKey::All,
+			SearchKey::Key(k) => SearchKey::Key(format!("NOT {}", k)),
+		}
+	}
+
+	fn criteria(self) -> String {
+		match self {
+			SearchKey::Key(k) => k,
+			_ => "ALL".to_owned(),
+		}
+	}
+}
+
+/// What the server can do that affects the translation.
+struct SearchContext {
+	/// RFC 5032 `OLDER`/`YOUNGER` with second precision.
+	within: bool,
+	now:    DateTime<Utc>,
+}
+
+fn unsupported_filter(description: impl Into<String>) -> MethodError {
+	MethodError::UnsupportedFilter {
+		description: Some(description.into()),
+	}
+}
+
+fn keyword_key(keyword: &str, has: bool) -> Result<SearchKey, MethodError> {
+	// anything else would break the command
+	if !is_valid_keyword(keyword) {
+		return Err(MethodError::invalid_arguments(format!(
+			"invalid keyword `{}`",
+			keyword
+		)));
+	}
+
+	let (set, unset) = match keyword.to_ascii_lowercase().as_str() {
+		"$seen" => ("SEEN", "UNSEEN"),
+		"$flagged" => ("FLAGGED", "UNFLAGGED"),
+		"$answered" => ("ANSWERED", "UNANSWERED"),
+		"$draft" => ("DRAFT", "UNDRAFT"),
+		_ => {
+			return Ok(SearchKey::Key(format!(
+				"{} {}",
+				if has { "KEYWORD" } else { "UNKEYWORD" },
+				keyword
+			)))
+		}
+	};
+	Ok(SearchKey::Key(if has { set } else { unset }.to_owned()))
+}
+
+/// `receivedAt` before (or at or after) `date`.
+fn date_key(date: &str, before: bool, context: &SearchContext) -> Result<SearchKey, MethodError> {
+	let date = DateTime::parse_from_rfc3339(date)
+		.map_err(|_| MethodError::invalid_arguments(format!("invalid date `{}`", date)))?
+		.with_timezone(&Utc);
+
+	if context.within {
+		let age = (context.now - date).num_seconds();
+		return Ok(match (before, age) {
+			(true, age) if age <= 0 => SearchKey::All,
+			(false, age) if age <= 0 => SearchKey::Nothing,
+			(true, age) => SearchKey::Key(format!("OLDER {}", age)),
+			(false, age) => SearchKey::Key(format!("YOUNGER {}", age)),
+		});
+	}
+
+	// plain SEARCH only knows days
+	if date.num_seconds_from_midnight() != 0 {
+		return Err(unsupported_filter(
+			"the server can only compare dates by day, times must be midnight utc",
+		));
+	}
+	let day = date.format("%d-%b-%Y");
+	Ok(SearchKey::Key(if before {
+		format!("BEFORE {}", day)
+	} else {
+		format!("SINCE {}", day)
+	}))
+}
+
+fn text_key(key: &str, value: &str) -> SearchKey {
+	SearchKey::Key(format!("{} {}", key, raw::quote(value)))
+}
+
+fn translate_condition(
+	condition: &EmailFilterCondition,
+	mailbox_id: &str,
+	context: &SearchContext,
+) -> Result<SearchKey, MethodError> {
+	if condition.all_in_thread_have_keyword.is_some()
+		|| condition.some_in_thread_have_keyword.is_some()
+		|| condition.none_in_thread_have_keyword.is_some()
+	{
+		return Err(unsupported_filter(
+			"thread keyword filters are not supported",
+		));
+	}
+	if condition.has_attachment.is_some() {
+		return Err(unsupported_filter("imap can't search for attachments"));
+	}
+
+	let mut key = SearchKey::All;
+
+	if let Some(id) = &condition.in_mailbox {
+		if id != mailbox_id {
+			key = SearchKey::Nothing;
+		}
+	}
+	if let Some(ids) = &condition.in_mailbox_other_than {
+		if ids.iter().any(|id| id == mailbox_id) {
+			key = SearchKey::Nothing;
+		}
+	}
+	if let Some(date) = &condition.before {
+		key = key.and(date_key(date, true, context)?);
+	}
+	if let Some(date) = &condition.after {
+		key = key.and(date_key(date, false, context)?);
+	}
+	if let Some(size) = condition.min_size {
+		if size > 0 {
+			key = key.and(SearchKey::Key(format!("LARGER {}", size - 1)));
+		}
+	}
+	if let Some(size) = condition.max_size {
+		key = key.and(match size {
+			0 => SearchKey::Nothing,
+			size => SearchKey::Key(format!("SMALLER {}", size)),
+		});
+	}
+	if let Some(keyword) = &condition.has_keyword {
+		key = key.and(keyword_key(keyword, true)?);
+	}
+	if let Some(keyword) = &condition.not_keyword {
+		key = key.and(keyword_key(keyword, false)?);
+	}
+	if let Some(text) = &condition.text {
+		// the fields jmap defines for text, imap TEXT would also match any other header
+		key = key.and(
+			["FROM", "TO", "CC", "BCC", "SUBJECT", "BODY"]
+				.iter()
+				.map(|k| text_key(k, text))
+				.fold(SearchKey::Nothing, SearchKey::or),
+		);
+	}
+	for (search_key, value) in &[
+		("FROM", &condition.from),
+		("TO", &condition.to),
+		("CC", &condition.cc),
+		("BCC", &condition.bcc),
+		("SUBJECT", &condition.subject),
+		("BODY", &condition.body),
+	] {
+		if let Some(value) = value {
+			key = key.and(text_key(search_key, value));
+		}
+	}
+	if let Some(header) = &condition.header {
+		let (name, value) = match &header[..] {
+			[name] => (name, ""),
+			[name, value] => (name, value.as_str()),
+			_ => {
+				return Err(unsupported_filter(
+					"header filters take a name and an optional value",
+				))
+			}
+		};
+		key = key.and(SearchKey::Key(format!(
+			"HEADER {} {}",
+			raw::quote(name),
+			raw::quote(value)
+		)));
+	}
+
+	Ok(key)
+}
+
+fn translate(
+	filter: &Filter<EmailFilterCondition>,
+	mailbox_id: &str,
+	context: &SearchContext,
+) -> Result<SearchKey, MethodError> {
+	match filter {
+		Filter::Condition(condition) => translate_condition(condition, mailbox_id, context),
+		Filter::Operator {
+			operator,
+			conditions,
+		} => {
+			let keys = conditions
+				.iter()
+				.map(|c| translate(c, mailbox_id, context))
+				.collect::<Result<Vec<_>, _>>()?;
+			Ok(match operator {
+				Operator::And => keys.into_iter().fold(SearchKey::All, SearchKey::and),
+				Operator::Or => keys.into_iter().fold(SearchKey::Nothing, SearchKey::or),
+				Operator::Not => keys
+					.into_iter()
+					.fold(SearchKey::Nothing, SearchKey::or)
+					.not(),
+			})
+		}
+	}
+}
+
+/// What to highlight in search snippets, whitespace normalized and lowercase.
+#[derive(Debug, Default)]
+pub struct HighlightTerms {
+	pub subject: Vec<String>,
+	pub body:    Vec<String>,
+}
+
+/// The text conditions of `filter`, except for negated ones which can't have
+/// matched anything.
+pub fn highlight_terms(filter: &Filter<EmailFilterCondition>) -> HighlightTerms {
+	fn normalize(term: &str) -> String {
+		term.split_whitespace()
+			.collect::<Vec<_>>()
+			.join(" ")
+			.to_lowercase()
+	}
+
+	fn collect(filter: &Filter<EmailFilterCondition>, terms: &mut HighlightTerms) {
+		match filter {
+			Filter::Condition(condition) => {
+				terms
+					.subject
+					.extend(condition.text.as_deref().map(normalize));
+				terms
+					.subject
+					.extend(condition.subject.as_deref().map(normalize));
+				terms.body.extend(condition.text.as_deref().map(normalize));
+				terms.body.extend(condition.body.as_deref().map(normalize));
+			}
+			Filter::Operator {
+				operator: Operator::Not,
+				..
+			} => {}
+			Filter::Operator { conditions, .. } => {
+				for condition in conditions {
+					collect(condition, terms);
+				}
+			}
+		}
+	}
+
+	let mut terms = HighlightTerms::default();
+	collect(filter, &mut terms);
+	terms.subject.retain(|t| !t.is_empty());
+	terms.body.retain(|t| !t.is_empty());
+	terms
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortKey {
+	ReceivedAt,
+	From,
+	To,
+	Subject,
+	Size,
+	SpamScore,
+}
+
+impl SortKey {
+	fn parse(comparator: &Comparator) -> Result<(SortKey, bool), MethodError> {
+		let key = match comparator.property.as_str() {
+			"receivedAt" => SortKey::ReceivedAt,
+			"from" => SortKey::From,
+			"to" => SortKey::To,
+			"subject" => SortKey::Subject,
+			"size" => SortKey::Size,
+			"header.x-spam-score" => SortKey::SpamScore,
+			property => {
+				return Err(MethodError::UnsupportedSort {
+					description: Some(format!("can't sort emails by {}", property)),
+				})
+			}
+		};
+		if let Some(collation) = comparator.collation.as_deref() {
+			if !["i;unicode-casemap", "i;ascii-casemap"].contains(&collation) {
+				return Err(MethodError::UnsupportedSort {
+					description: Some(format!("unsupported collation {}", collation)),
+				});
+			}
+		}
+		Ok((key, comparator.is_ascending))
+	}
+
+	/// The RFC 5256 sort key, if there is one.
+	fn imap(self) -> Option<&'static str> {
+		match self {
+			SortKey::ReceivedAt => Some("ARRIVAL"),
+			SortKey::From => Some("FROM"),
+			SortKey::To => Some("TO"),
+			SortKey::Subject => Some("SUBJECT"),
+			SortKey::Size => Some("SIZE"),
+			SortKey::SpamScore => None,
+		}
+	}
+
+	fn fetch_item(self) -> &'static str {
+		match self {
+			SortKey::ReceivedAt => "INTERNALDATE",
+			SortKey::From | SortKey::To | SortKey::Subject => "ENVELOPE",
+			SortKey::Size => "RFC822.SIZE",
+			SortKey::SpamScore => "BODY.PEEK[HEADER.FIELDS (X-SPAM-SCORE)]",
+		}
+	}
+
+	/// The value to sort by, computed the way RFC 5256 does for `SORT`.
+	fn value(self, attributes: &[Token]) -> SortValue {
+		let envelope = raw::find_value(attributes, "ENVELOPE").and_then(Token::as_list);
+		let first_mailbox = |index: usize| {
+			envelope
+				.and_then(|e| e.get(index)?.as_list())
+				.and_then(|addresses| addresses.iter().filter_map(Token::as_list).next())
+				.and_then(|address| address.get(2)?.as_str())
+				.map(str::to_lowercase)
+				.unwrap_or_default()
+		};
+
+		match self {
+			SortKey::ReceivedAt => SortValue::Number(
+				raw::find_value(attributes, "INTERNALDATE")
+					.and_then(Token::as_str)
+					.and_then(imap::parse_internal_date)
+					.map_or(0, |d| d.timestamp()),
+			),
+			SortKey::Size => SortValue::Number(
+				raw::find_value(attributes, "RFC822.SIZE")
+					.and_then(Token::as_number)
+					.map_or(0, i64::from),
+			),
+			SortKey::From => SortValue::Text(first_mailbox(2)),
+			SortKey::To => SortValue::Text(first_mailbox(5)),
+			SortKey::Subject => SortValue::Text(
+				envelope
+					.and_then(|e| e.get(1)?.as_str())
+					.map(mime::base_subject)
+					.unwrap_or_default(),
+			),
+			SortKey::SpamScore => SortValue::Score(
+				imap::fetch_section(attributes, "HEADER.FIELDS (X-SPAM-SCORE)").and_then(|data| {
+					let (headers, _) = mime::parse_headers(data);
+					mime::find_header(&headers, "X-Spam-Score")?
+						.trim()
+						.parse()
+						.ok()
+				}),
+			),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+enum SortValue {
+	Number(i64),
+	Text(String),
+	/// Messages without a score sort before all others.
+	Score(Option<f64>),
+}
+
+impl SortValue {
+	fn compare(&self, other: &SortValue) -> Ordering {
+		match (self, other) {
+			(SortValue::Number(a), SortValue::Number(b)) => a.cmp(b),
+			(SortValue::Text(a), SortValue::Text(b)) => a.cmp(b),
+			(SortValue::Score(a), SortValue::Score(b)) => {
+				a.partial_cmp(b).unwrap_or(Ordering::Equal)
+			}
+			_ => Ordering::Equal,
+		}
+	}
+}
+
+/// One message of the query result.
+#[derive(Debug, Clone)]
+struct Entry {
+	location:  Location,
+	email_id:  Option<Id>,
+	thread_id: Option<Id>,
+	values:    Vec<SortValue>,
+}
+
+impl PartialEq for Entry {
+	fn eq(&self, other: &Entry) -> bool {
+		self.location.mailbox == other.location.mailbox && self.location.uid == other.location.uid
+	}
+}
+
+/// An `EMAILID` or `THREADID` (RFC 8474) fetch item.
+fn object_id(attributes: &[Token], item: &str) -> Option<Id> {
+	raw::find_value(attributes, item)?
+		.as_list()?
+		.first()?
+		.as_str()
+		.map(str::to_owned)
+}
+
+/// Adds the charset to criteria that contain non-ascii strings.
+fn search_criteria(criteria: &str) -> String {
+	if criteria.is_ascii() {
+		criteria.to_owned()
+	} else {
+		format!("CHARSET UTF-8 {}", criteria)
+	}
+}
+
+impl JmapApi<'_> {
+	pub async fn handle_email_query(
+		&self,
+		request: QueryRequest<EmailFilterCondition>,
+		collapse_threads: bool,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		let index = self.email_index(&mailboxes).await?;
+		let query_state = self.email_state(&index).await?;
+
+		let (mut entries, object_ids) = self
+			.search_emails(&mailboxes, &request.filter, &request.sort, collapse_threads)
+			.await?;
+		let total = entries.len() as u64;
+
+		let anchor = match &request.anchor {
+			Some(anchor) => {
+				let locations = self
+					.locate_emails(&mailboxes, std::slice::from_ref(anchor))
+					.await?;
+				let entry = locations
+					.get(anchor)
+					.into_iter()
+					.flatten()
+					.find_map(|l| {
+						entries
+							.iter()
+							.find(|e| e.location.mailbox == l.mailbox && e.location.uid == l.uid)
+					})
+					.cloned()
+					.ok_or(MethodError::AnchorNotFound)?;
+				Some(entry)
+			}
+			None => None,
+		};
+		let page = query::page(
+			std::mem::take(&mut entries),
+			request.position,
+			anchor.as_ref(),
+			request.anchor_offset,
+			request.limit,
+		)?;
+
+		let ids = self.email_ids(page.ids, object_ids).await?;
+
+		Ok(MethodResult::EmailQuery(QueryResponse {
+			account_id: request.account_id,
+			query_state,
+			can_calculate_changes: !collapse_threads,
+			position: page.position,
+			ids,
+			total: if request.calculate_total {
+				Some(total)
+			} else {
+				None
+			},
+			limit: None,
+		}))
+	}
+
+	/// All emails matching `filter` in query order and whether their ids
+	/// are server side `EMAILID`s.
+	async fn search_emails(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		filter: &Option<Filter<EmailFilterCondition>>,
+		sort: &[Comparator],
+		collapse_threads: bool,
+	) -> Result<(Vec<Entry>, bool), MethodError> {
+		let mut sort = sort
+			.iter()
+			.map(SortKey::parse)
+			.collect::<Result<Vec<_>, _>>()?;
+		if sort.is_empty() {
+			sort.push((SortKey::ReceivedAt, false));
+		}
+
+		let (object_ids, can_sort, within) = self
+			.with_raw_session(|s| async move {
+				Ok((
+					s.has_capability("OBJECTID"),
+					s.has_capability("SORT"),
+					s.has_capability("WITHIN"),
+				))
+			})
+			.await?;
+		let context = SearchContext {
+			within,
+			now: Utc::now(),
+		};
+
+		// without server side thread ids the threads are worked out locally
+		let threads = if collapse_threads && !object_ids {
+			Some(self.threads(mailboxes).await?.1)
+		} else {
+			None
+		};
+		let mut searches = vec![];
+		for (id, info) in mailboxes.iter().filter(|(_, i)| i.is_selectable()) {
+			let key = match filter {
+				Some(filter) => translate(filter, id, &context)?,
+				None => SearchKey::All,
+			};
+			if key != SearchKey::Nothing {
+				searches.push((id.clone(), info.name.clone(), key.criteria()));
+			}
+		}
+
+		let server_sort = sort
+			.iter()
+			.map(|(key, ascending)| {
+				key.imap().map(|k| {
+					if *ascending {
+						k.to_owned()
+					} else {
+						format!("REVERSE {}", k)
+					}
+				})
+			})
+			.collect::<Option<Vec<_>>>()
+			.filter(|_| can_sort && searches.len() == 1 && !collapse_threads)
+			.map(|keys| keys.join(" "));
+
+		let entries = match server_sort {
+			Some(server_sort) => {
+				let (mailbox_id, mailbox, criteria) = searches.remove(0);
+				self.with_raw_session(|mut s| async move {
+					imap::select(&mut s, &mailbox, false).await?;
+					let uids = imap::uid_sort(&mut s, &server_sort, &criteria).await?;
+					Ok(uids
+						.into_iter()
+						.map(|uid| Entry {
+							location:  Location {
+								mailbox_id: mailbox_id.clone(),
+								mailbox: mailbox.clone(),
+								uid,
+							},
+							email_id:  None,
+							thread_id: None,
+							values:    vec![],
+						})
+						.collect::<Vec<_>>())
+				})
+				.await?
+			}
+			None => {
+				self.search_and_sort(
+					searches,
+					&sort,
+					object_ids,
+					collapse_threads,
+					threads.as_ref(),
+				)
+				.await?
+			}
+		};
+
+		Ok((entries, object_ids))
+	}
+
+	/// The ids of all emails matching `filter` in query order.
+	pub async fn query_email_ids(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		filter: &Option<Filter<EmailFilterCondition>>,
+		sort: &[Comparator],
+	) -> Result<Vec<Id>, MethodError> {
+		let (entries, object_ids) = self.search_emails(mailboxes, filter, sort, false).await?;
+		self.email_ids(entries, object_ids).await
+	}
+
+	/// Searches every mailbox and sorts the combined result locally.
+	async fn search_and_sort(
+		&self,
+		searches: Vec<(Id, String, String)>,
+		sort: &[(SortKey, bool)],
+		object_ids: bool,
+		collapse_threads: bool,
+		threads: Option<&Threads>,
+	) -> Result<Vec<Entry>, MethodError> {
+		let mut items: Vec<&str> = sort.iter().map(|(key, _)| key.fetch_item()).collect();
+		if object_ids {
+			items.push("EMAILID");
+		}
+		if collapse_threads && object_ids {
+			items.push("THREADID");
+		}
+		items.sort_unstable();
+		items.dedup();
+		let items = items.join(" ");
+		let keys: Vec<SortKey> = sort.iter().map(|(key, _)| *key).collect();
+
+		let mut entries = self
+			.with_raw_session(|mut s| async move {
+				let mut entries = vec![];
+				for (mailbox_id, mailbox, criteria) in searches {
+					imap::select(&mut s, &mailbox, false).await?;
+					let uids = imap::uid_search(&mut s, &search_criteria(&criteria)).await?;
+					for (uid, attributes) in imap::uid_fetch(&mut s, &uids, &items).await? {
+						entries.push(Entry {
+							location:  Location {
+								mailbox_id: mailbox_id.clone(),
+								mailbox: mailbox.clone(),
+								uid,
+							},
+							email_id:  object_id(&attributes, "EMAILID"),
+							thread_id: object_id(&attributes, "THREADID"),
+							values:    keys.iter().map(|key| key.value(&attributes)).collect(),
+						});
+					}
+				}
+				Ok(entries)
+			})
+			.await?;
+
+		entries.sort_by(|a, b| {
+			a.values
+				.iter()
+				.zip(&b.values)
+				.zip(sort)
+				.map(|((a, b), (_, ascending))| {
+					let ordering = a.compare(b);
+					if *ascending {
+						ordering
+					} else {
+						ordering.reverse()
+					}
+				})
+				.find(|o| *o != Ordering::Equal)
+				.unwrap_or(Ordering::Equal)
+		});
+
+		// with server side ids a message in several mailboxes is still one email
+		if object_ids {
+			let mut seen = HashSet::new();
+			entries.retain(|e| e.email_id.as_ref().is_none_or(|id| seen.insert(id.clone())));
+		}
+		if let Some(threads) = threads {
+			for entry in &mut entries {
+				let id = location_email_id(&entry.location.mailbox_id, entry.location.uid);
+				entry.thread_id = threads.by_email.get(&id).cloned();
+			}
+		}
+		if collapse_threads {
+			let mut seen = HashSet::new();
+			entries.retain(|e| {
+				e.thread_id
+					.as_ref()
+					.is_none_or(|id| seen.insert(id.clone()))
+			});
+		}
+
+		Ok(entries)
+	}
+
+	/// Email ids of the entries, fetching `EMAILID`s where they aren't known yet.
+	async fn email_ids(
+		&self,
+		entries: Vec<Entry>,
+		object_ids: bool,
+	) -> Result<Vec<Id>, MethodError> {
+		if !object_ids {
+			return Ok(entries
+				.iter()
+				.map(|e| location_email_id(&e.location.mailbox_id, e.location.uid))
+				.collect());
+		}
+
+		let missing = entries
+			.iter()
+			.filter(|e| e.email_id.is_none())
+			.map(|e| {
+				(
+					e.location.mailbox.clone(),
+					e.location.uid,
+					"EMAILID".to_owned(),
+				)
+			})
+			.collect();
+		let fetched = self.fetch_messages(missing).await?;
+
+		// leaving an entry out would shift every position after it
+		entries
+			.into_iter()
+			.map(
+				|Entry {
+				     location, email_id, ..
+				 }| {
+					email_id
+						.or_else(|| {
+							object_id(
+								fetched.get(&(location.mailbox.clone(), location.uid))?,
+								"EMAILID",
+							)
+						})
+						.ok_or_else(|| MethodError::ServerFail {
+							description: Some(format!(
+								"no EMAILID for uid {} in {}, it may have been expunged",
+								location.uid, location.mailbox
+							)),
+						})
+				},
+			)
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+	use serde_json::json;
+
+	use super::*;
+
+	fn context(within: bool) -> SearchContext {
+		SearchContext {
+			within,
+			now: Utc.ymd(2021, 3, 10).and_hms(12, 0, 0),
+		}
+	}
+
+	fn criteria(filter: serde_json::Value, mailbox_id: &str) -> Result<SearchKey, MethodError> {
+		let filter: Filter<EmailFilterCondition> = serde_json::from_value(filter).unwrap();
+		translate(&filter, mailbox_id, &context(false))
+	}
+
+	fn key(key: &str) -> SearchKey {
+		SearchKey::Key(key.to_owned())
+	}
+
+	#[test]
+	fn constant_keys_are_folded() {
+		assert_eq!(SearchKey::All.and(key("SEEN")), key("SEEN"));
+		assert_eq!(SearchKey::Nothing.and(key("SEEN")), SearchKey::Nothing);
+		assert_eq!(SearchKey::All.or(key("SEEN")), SearchKey::All);
+		assert_eq!(SearchKey::Nothing.or(key("SEEN")), key("SEEN"));
+		assert_eq!(key("SEEN").and(key("DRAFT")), key("(SEEN DRAFT)"));
+		assert_eq!(key("SEEN").or(key("DRAFT")), key("OR SEEN DRAFT"));
+		assert_eq!(SearchKey::All.not(), SearchKey::Nothing);
+		assert_eq!(key("SEEN").not(), key("NOT SEEN"));
+		assert_eq!(SearchKey::Nothing.criteria(), "ALL");
+	}
+
+	#[test]
+	fn keywords_use_system_flags() {
+		assert_eq!(keyword_key("$Seen", true).unwrap(), key("SEEN"));
+		assert_eq!(keyword_key("$flagged", false).unwrap(), key("UNFLAGGED"));
+		assert_eq!(keyword_key("$junk", true).unwrap(), key("KEYWORD $junk"));
+		assert_eq!(keyword_key("work", false).unwrap(), key("UNKEYWORD work"));
+		assert!(matches!(
+			keyword_key("a b", true),
+			Err(MethodError::InvalidArguments { .. })
+		));
+		assert!(matches!(
+			keyword_key("a)", true),
+			Err(MethodError::InvalidArguments { .. })
+		));
+	}
+
+	#[test]
+	fn dates_are_days_without_within() {
+		let context = context(false);
+		assert_eq!(
+			date_key("2021-03-01T00:00:00Z", true, &context).unwrap(),
+			key("BEFORE 01-Mar-2021")
+		);
+		assert_eq!(
+			date_key("2021-03-01T01:00:00+01:00", false, &context).unwrap(),
+			key("SINCE 01-Mar-2021")
+		);
+		assert!(matches!(
+			date_key("2021-03-01T12:00:00Z", true, &context),
+			Err(MethodError::UnsupportedFilter { .. })
+		));
+		assert!(matches!(
+			date_key("yesterday", true, &context),
+			Err(MethodError::InvalidArguments { .. })
+		));
+	}
+
+	#[test]
+	fn dates_are_ages_with_within() {
+		let context = context(true);
+		assert_eq!(
+			date_key("2021-03-10T11:00:00Z", true, &context).unwrap(),
+			key("OLDER 3600")
+		);
+		assert_eq!(
+			date_key("2021-03-10T11:59:30Z", false, &context).unwrap(),
+			key("YOUNGER 30")
+		);
+		// the future
+		assert_eq!(
+			date_key("2021-03-11T00:00:00Z", true, &context).unwrap(),
+			SearchKey::All
+		);
+		assert_eq!(
+			date_key("2021-03-11T00:00:00Z", false, &context).unwrap(),
+			SearchKey::Nothing
+		);
+	}
+
+	#[test]
+	fn conditions_are_combined() {
+		assert_eq!(
+			criteria(
+				json!({ "minSize": 100, "maxSize": 200, "hasKeyword": "$seen" }),
+				"M1"
+			)
+			.unwrap(),
+			key("((LARGER 99 SMALLER 200) SEEN)")
+		);
+		assert_eq!(
+			criteria(json!({ "minSize": 0, "maxSize": 0 }), "M1").unwrap(),
+			SearchKey::Nothing
+		);
+		assert_eq!(
+			criteria(json!({ "from": "a\"b", "subject": "hi" }), "M1").unwrap(),
+			key(r#"(FROM "a\"b" SUBJECT "hi")"#)
+		);
+		assert_eq!(
+			criteria(json!({ "header": ["X-Spam"] }), "M1").unwrap(),
+			key(r#"HEADER "X-Spam" """#)
+		);
+		assert_eq!(
+			criteria(json!({ "text": "x" }), "M1").unwrap(),
+			key(r#"OR OR OR OR OR FROM "x" TO "x" CC "x" BCC "x" SUBJECT "x" BODY "x""#)
+		);
+	}
+
+	#[test]
+	fn mailbox_conditions_skip_mailboxes() {
+		let filter = json!({ "inMailbox": "M1", "hasKeyword": "$seen" });
+		assert_eq!(criteria(filter.clone(), "M1").unwrap(), key("SEEN"));
+		assert_eq!(criteria(filter, "M2").unwrap(), SearchKey::Nothing);
+
+		let filter = json!({ "inMailboxOtherThan": ["M1"] });
+		assert_eq!(criteria(filter.clone(), "M1").unwrap(), SearchKey::Nothing);
+		assert_eq!(criteria(filter, "M2").unwrap(), SearchKey::All);
+
+		let filter = json!({
+			"operator": "OR",
+			"conditions": [{ "inMailbox": "M1" }, { "hasKeyword": "$draft" }],
+		});
+		assert_eq!(criteria(filter.clone(), "M1").unwrap(), SearchKey::All);
+		assert_eq!(criteria(filter, "M2").unwrap(), key("DRAFT"));
+
+		let filter = json!({
+			"operator": "NOT",
+			"conditions": [{ "inMailbox": "M1" }, { "hasKeyword": "$draft" }],
+		});
+		assert_eq!(criteria(filter.clone(), "M1").unwrap(), SearchKey::Nothing);
+		assert_eq!(criteria(filter, "M2").unwrap(), key("NOT DRAFT"));
+	}
+
+	#[test]
+	fn unsupported_conditions_are_rejected() {
+		for filter in [
+			json!({ "hasAttachment": true }),
+			json!({ "someInThreadHaveKeyword": "$seen" }),
+			json!({ "header": [] }),
+			json!({ "header": ["a", "b", "c"] }),
+		] {
+			assert!(matches!(
+				criteria(filter, "M1"),
+				Err(MethodError::UnsupportedFilter { .. })
+			));
+		}
+	}
+
+	#[test]
+	fn non_ascii_criteria_have_a_charset() {
+		assert_eq!(search_criteria("SUBJECT \"hi\""), "SUBJECT \"hi\"");
+		let criteria = match text_key("SUBJECT", "grüße") {
+			SearchKey::Key(k) => k,
+			_ => unreachable!(),
+		};
+		assert_eq!(criteria, "SUBJECT {7}\r\ngrüße");
+		assert_eq!(
+			search_criteria(&criteria),
+			"CHARSET UTF-8 SUBJECT {7}\r\ngrüße"
+		);
+	}
+}
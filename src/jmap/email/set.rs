@@ -0,0 +1,1134 @@
+//! `Email/set`: keywords and mailboxes map to flags and moves, new emails are
+//! composed and appended.
+
+use std::collections::{BTreeSet, HashMap};
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+	imap,
+	imap::raw::{self, Token},
+	jmap::{
+		email::{
+			email_blob_id,
+			flag_to_keyword,
+			keyword_to_flag,
+			location_email_id,
+			HeaderForm,
+			HeaderProperty,
+			Location,
+		},
+		mailbox::imap_set_result,
+		method::{MethodError, MethodResult},
+		Id,
+		JmapApi,
+		SetError,
+		SetRequest,
+		SetResponse,
+	},
+	mime::{self, Entity, EntityBody, Header},
+};
+
+const CREATE_PROPERTIES: &[&str] = &[
+	"mailboxIds",
+	"keywords",
+	"receivedAt",
+	"headers",
+	"messageId",
+	"inReplyTo",
+	"references",
+	"sender",
+	"from",
+	"to",
+	"cc",
+	"bcc",
+	"replyTo",
+	"subject",
+	"sentAt",
+	"bodyStructure",
+	"bodyValues",
+	"textBody",
+	"htmlBody",
+	"attachments",
+];
+
+/// Headers that are generated from the body part properties.
+const CONTENT_HEADERS: &[&str] = &["Content-Type", "Content-Transfer-Encoding"];
+
+/// A new value for `keywords` or `mailboxIds`, either replacing the whole set
+/// or patching single members.
+#[derive(Debug, PartialEq)]
+enum SetChange {
+	Replace(BTreeSet<String>),
+	Patch {
+		add:    BTreeSet<String>,
+		remove: BTreeSet<String>,
+	},
+}
+
+impl SetChange {
+	fn apply(&self, current: &BTreeSet<String>) -> BTreeSet<String> {
+		match self {
+			SetChange::Replace(set) => set.clone(),
+			SetChange::Patch { add, remove } => current
+				.iter()
+				.filter(|m| !remove.contains(*m))
+				.chain(add)
+				.cloned()
+				.collect(),
+		}
+	}
+}
+
+/// Collects the `property` and `property/member` entries of a patch, with
+/// `member` mapping members to their normalized form.
+fn parse_set_change(
+	patch: &Map<String, Value>,
+	property: &str,
+	member: impl Fn(&str) -> Option<String>,
+) -> Result<Option<SetChange>, SetError> {
+	let invalid = |description: &str| SetError::invalid_properties(&[property], description);
+	let prefix = format!("{}/", property);
+
+	let mut change = match patch.get(property) {
+		Some(Value::Object(set)) => {
+			let mut members = BTreeSet::new();
+			for (key, value) in set {
+				if value != &Value::Bool(true) {
+					return Err(invalid("values must be true"));
+				}
+				members.insert(member(key).ok_or_else(|| invalid("invalid member"))?);
+			}
+			Some(SetChange::Replace(members))
+		}
+		Some(_) => return Err(invalid("must be an object")),
+		None => None,
+	};
+
+	for (key, value) in patch {
+		let key = match key.strip_prefix(&prefix) {
+			Some(key) => key,
+			None => continue,
+		};
+		let (add, remove) = match &mut change {
+			None => {
+				change = Some(SetChange::Patch {
+					add:    BTreeSet::new(),
+					remove: BTreeSet::new(),
+				});
+				match &mut change {
+					Some(SetChange::Patch { add, remove }) => (add, remove),
+					_ => unreachable!(),
+				}
+			}
+			Some(SetChange::Patch { add, remove }) => (add, remove),
+			Some(SetChange::Replace(_)) => {
+				return Err(invalid("can't be replaced and patched at once"))
+			}
+		};
+
+		let key = member(key).ok_or_else(|| invalid("invalid member"))?;
+		match value {
+			Value::Bool(true) => add.insert(key),
+			Value::Null => remove.insert(key),
+			_ => return Err(invalid("patched values must be true or null")),
+		};
+	}
+
+	Ok(change)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct BodyValue {
+	value:               String,
+	#[serde(default)]
+	is_encoding_problem: bool,
+	#[serde(default)]
+	is_truncated:        bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct HeaderValue {
+	name:  String,
+	value: String,
+}
+
+/// An `EmailBodyPart` of a new email.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct NewBodyPart {
+	part_id:     Option<String>,
+	blob_id:     Option<Id>,
+	#[serde(rename = "type")]
+	r#type:      Option<String>,
+	charset:     Option<String>,
+	disposition: Option<String>,
+	cid:         Option<String>,
+	language:    Option<Vec<String>>,
+	location:    Option<String>,
+	name:        Option<String>,
+	#[serde(default)]
+	headers:     Vec<HeaderValue>,
+	sub_parts:   Option<Vec<NewBodyPart>>,
+	/// Ignored, the size follows from the content.
+	#[allow(dead_code)]
+	size:        Option<u64>,
+	/// `header:` properties.
+	#[serde(flatten)]
+	extra:       Map<String, Value>,
+}
+
+impl NewBodyPart {
+	fn blob_ids<'a>(&'a self, ids: &mut Vec<&'a Id>) {
+		ids.extend(&self.blob_id);
+		for part in self.sub_parts.iter().flatten() {
+			part.blob_ids(ids);
+		}
+	}
+
+	fn to_entity(
+		&self,
+		values: &HashMap<String, BodyValue>,
+		blobs: &HashMap<Id, Vec<u8>>,
+		property: &str,
+	) -> Result<Entity, SetError> {
+		let invalid = |description: &str| SetError::invalid_properties(&[property], description);
+
+		let mut headers = header_values(&self.headers, &self.extra, property)?;
+		if headers.iter().any(|h| {
+			CONTENT_HEADERS
+				.iter()
+				.any(|c| c.eq_ignore_ascii_case(&h.name))
+		}) {
+			return Err(invalid("content headers are set by the server"));
+		}
+
+		let r#type = self.r#type.as_deref().map(str::to_ascii_lowercase);
+		let (content_type, body) = match (&self.sub_parts, &self.part_id, &self.blob_id) {
+			(Some(sub_parts), None, None) => {
+				let r#type = r#type.unwrap_or_else(|| "multipart/mixed".to_owned());
+				if !r#type.starts_with("multipart/") {
+					return Err(invalid("parts with subParts must be multipart"));
+				}
+				let parts = sub_parts
+					.iter()
+					.map(|p| p.to_entity(values, blobs, property))
+					.collect::<Result<_, _>>()?;
+				(r#type, EntityBody::Multipart(parts))
+			}
+			(None, Some(part_id), None) => {
+				let r#type = r#type.unwrap_or_else(|| "text/plain".to_owned());
+				if !r#type.starts_with("text/") {
+					return Err(invalid("body values can only be used for text parts"));
+				}
+				// the value is always written as utf-8
+				if self
+					.charset
+					.as_deref()
+					.is_some_and(|c| !c.eq_ignore_ascii_case("utf-8"))
+				{
+					return Err(invalid("text parts must not have a charset"));
+				}
+				let value = values
+					.get(part_id)
+					.ok_or_else(|| invalid("partId not found in bodyValues"))?;
+				(
+					format!("{}; charset=utf-8", r#type),
+					EntityBody::Data(value.value.as_bytes().to_vec()),
+				)
+			}
+			(None, None, Some(blob_id)) => {
+				let r#type = r#type.unwrap_or_else(|| "application/octet-stream".to_owned());
+				if r#type.starts_with("multipart/") {
+					return Err(invalid("multipart parts need subParts"));
+				}
+				let mut content_type = r#type;
+				if let Some(charset) = &self.charset {
+					content_type.push_str(&mime::encode_parameter("charset", charset));
+				}
+				let data = blobs.get(blob_id).cloned().unwrap_or_default();
+				(content_type, EntityBody::Data(data))
+			}
+			_ => {
+				return Err(invalid(
+					"parts need exactly one of partId, blobId and subParts",
+				))
+			}
+		};
+		if !is_valid_type(&content_type) {
+			return Err(invalid("invalid type"));
+		}
+
+		let mut content_type = content_type;
+		if let Some(name) = &self.name {
+			content_type.push_str(&mime::encode_parameter("name", name));
+		}
+		let disposition = self
+			.disposition
+			.clone()
+			.or_else(|| self.name.as_ref().map(|_| "attachment".to_owned()));
+		if let Some(disposition) = disposition {
+			let mut value = disposition;
+			if let Some(name) = &self.name {
+				value.push_str(&mime::encode_parameter("filename", name));
+			}
+			headers.push(header("Content-Disposition", value));
+		}
+		if let Some(cid) = &self.cid {
+			headers.push(header("Content-ID", format!("<{}>", cid)));
+		}
+		if let Some(language) = &self.language {
+			headers.push(header("Content-Language", language.join(", ")));
+		}
+		if let Some(location) = &self.location {
+			headers.push(header("Content-Location", location.clone()));
+		}
+
+		Ok(Entity {
+			content_type,
+			headers,
+			body,
+		})
+	}
+}
+
+fn is_valid_type(content_type: &str) -> bool {
+	let r#type = content_type.split(';').next().unwrap_or("");
+	matches!(r#type.split_once('/'), Some((t, s)) if !t.is_empty() && !s.is_empty())
+		&& r#type
+			.bytes()
+			.all(|b| b.is_ascii_alphanumeric() || b"/-+.!#$&^_".contains(&b))
+}
+
+fn header(name: &str, value: String) -> Header {
+	Header {
+		name: name.to_owned(),
+		value,
+	}
+}
+
+/// Headers from a `headers` list and `header:` properties.
+fn header_values(
+	list: &[HeaderValue],
+	properties: &Map<String, Value>,
+	property: &str,
+) -> Result<Vec<Header>, SetError> {
+	let mut headers: Vec<Header> = list
+		.iter()
+		.map(|h| header(&h.name, h.value.clone()))
+		.collect();
+
+	for (key, value) in properties {
+		let header_property = HeaderProperty::parse(key)
+			.ok_or_else(|| SetError::invalid_properties(&[property, key], "unknown property"))?;
+		let values = match (header_property.all, value) {
+			(_, Value::Null) => vec![],
+			(true, Value::Array(values)) => values.iter().collect(),
+			(true, _) => {
+				return Err(SetError::invalid_properties(
+					&[key],
+					"values of :all headers must be arrays",
+				))
+			}
+			(false, value) => vec![value],
+		};
+		for value in values {
+			let encoded = header_property.form.encode(value).ok_or_else(|| {
+				SetError::invalid_properties(&[key], "value doesn't match the header form")
+			})?;
+			headers.push(header(&header_property.name, encoded));
+		}
+	}
+
+	if let Some(h) = headers.iter().find(|h| {
+		h.name.is_empty()
+			|| !h
+				.name
+				.bytes()
+				.all(|b| (0x21..0x7f).contains(&b) && b != b':')
+			// folding is fine, anything else would start a new header
+			|| h.value
+				.replace("\r\n ", " ")
+				.replace("\r\n\t", " ")
+				.contains(['\r', '\n'])
+	}) {
+		return Err(SetError::invalid_properties(
+			&[property],
+			format!("invalid header `{}`", h.name),
+		));
+	}
+
+	Ok(headers)
+}
+
+/// Everything an email is created from, checked but not yet composed.
+struct NewEmail {
+	flags:       Vec<String>,
+	received_at: Option<String>,
+	/// Without angle brackets.
+	message_id:  String,
+	/// The whole message with all headers.
+	message:     Entity,
+}
+
+/// The flags for the `keywords` of a new email, if given.
+pub fn new_email_flags(object: &Map<String, Value>) -> Result<Option<Vec<String>>, SetError> {
+	match parse_set_change(object, "keywords", |k| Some(k.to_ascii_lowercase()))? {
+		Some(SetChange::Replace(keywords)) => keywords
+			.iter()
+			.map(|k| keyword_to_flag(k))
+			.collect::<Option<Vec<_>>>()
+			.map(Some)
+			.ok_or_else(|| SetError::invalid_properties(&["keywords"], "invalid keyword")),
+		Some(SetChange::Patch { .. }) => Err(SetError::invalid_properties(
+			&["keywords"],
+			"new emails have no keywords to patch",
+		)),
+		None => Ok(None),
+	}
+}
+
+/// The `receivedAt` of a new email as an imap date, if given.
+pub fn new_email_received_at(object: &Map<String, Value>) -> Result<Option<String>, SetError> {
+	object
+		.get("receivedAt")
+		.map(|value| {
+			value
+				.as_str()
+				.and_then(|d| chrono::DateTime::parse_from_rfc3339(d).ok())
+				.map(|d| imap::format_internal_date(&d))
+				.ok_or_else(|| SetError::invalid_properties(&["receivedAt"], "must be a UTCDate"))
+		})
+		.transpose()
+}
+
+fn parse_new_email(
+	object: &Map<String, Value>,
+	blobs: &HashMap<Id, Vec<u8>>,
+) -> Result<NewEmail, SetError> {
+	if let Some(key) = object
+		.keys()
+		.find(|k| !CREATE_PROPERTIES.contains(&k.as_str()) && !k.starts_with("header:"))
+	{
+		return Err(SetError::invalid_properties(
+			&[key],
+			"property can't be set",
+		));
+	}
+
+	// keywords default to none (RFC 8621 section 4.1.1)
+	let flags = new_email_flags(object)?.unwrap_or_default();
+	let received_at = new_email_received_at(object)?;
+
+	let list: Vec<HeaderValue> = match object.get("headers") {
+		Some(value) => serde_json::from_value(value.clone())
+			.map_err(|e| SetError::invalid_properties(&["headers"], e.to_string()))?,
+		None => vec![],
+	};
+	let mut header_properties: Map<String, Value> = object
+		.iter()
+		.filter(|(k, _)| k.starts_with("header:"))
+		.map(|(k, v)| (k.clone(), v.clone()))
+		.collect();
+	for (key, value) in object {
+		if let Some(property) = HeaderProperty::convenience(key) {
+			header_properties.insert(
+				format!("header:{}:as{}", property.name, form_name(property.form)),
+				value.clone(),
+			);
+		}
+	}
+	let mut headers = header_values(&list, &header_properties, "headers")?;
+	if headers
+		.iter()
+		.any(|h| h.name.to_ascii_lowercase().starts_with("content-"))
+	{
+		return Err(SetError::invalid_properties(
+			&["headers"],
+			"content headers belong to body parts",
+		));
+	}
+
+	let has = |name: &str| headers.iter().any(|h| h.name.eq_ignore_ascii_case(name));
+	if !has("Date") {
+		headers.push(header("Date", chrono::Local::now().to_rfc2822()));
+	}
+	let message_id = match headers
+		.iter()
+		.find(|h| h.name.eq_ignore_ascii_case("Message-ID"))
+	{
+		Some(h) => mime::parse_message_ids(&h.value)
+			.and_then(|ids| ids.into_iter().next())
+			.ok_or_else(|| SetError::invalid_properties(&["messageId"], "invalid message id"))?,
+		None => {
+			let domain = headers
+				.iter()
+				.filter(|h| h.name.eq_ignore_ascii_case("From"))
+				.flat_map(|h| mime::parse_addresses(&h.value))
+				.find_map(|a| Some(a.email.rsplit_once('@')?.1.to_owned()))
+				.unwrap_or_else(|| "localhost".to_owned());
+			let id = mime::generate_message_id(&domain);
+			headers.push(header("Message-ID", format!("<{}>", id)));
+			id
+		}
+	};
+	headers.push(header("MIME-Version", "1.0".to_owned()));
+
+	let values: HashMap<String, BodyValue> = match object.get("bodyValues") {
+		Some(value) => serde_json::from_value(value.clone())
+			.map_err(|e| SetError::invalid_properties(&["bodyValues"], e.to_string()))?,
+		None => HashMap::new(),
+	};
+	if values
+		.values()
+		.any(|v| v.is_encoding_problem || v.is_truncated)
+	{
+		return Err(SetError::invalid_properties(
+			&["bodyValues"],
+			"values can't be truncated or have encoding problems",
+		));
+	}
+
+	let mut message = body_entity(object, &values, blobs)?;
+	message.headers.splice(0..0, headers);
+
+	Ok(NewEmail {
+		flags,
+		received_at,
+		message_id,
+		message,
+	})
+}
+
+fn form_name(form: HeaderForm) -> &'static str {
+	match form {
+		HeaderForm::Raw => "Raw",
+		HeaderForm::Text => "Text",
+		HeaderForm::Addresses => "Addresses",
+		HeaderForm::GroupedAddresses => "GroupedAddresses",
+		HeaderForm::MessageIds => "MessageIds",
+		HeaderForm::Date => "Date",
+		HeaderForm::Urls => "URLs",
+	}
+}
+
+fn parse_parts(object: &Map<String, Value>, property: &str) -> Result<Vec<NewBodyPart>, SetError> {
+	match object.get(property) {
+		Some(value) => serde_json::from_value(value.clone())
+			.map_err(|e| SetError::invalid_properties(&[property], e.to_string())),
+		None => Ok(vec![]),
+	}
+}
+
+/// Blob ids used anywhere in the body of a new email.
+fn body_blob_ids(object: &Map<String, Value>) -> Result<Vec<Id>, SetError> {
+	let mut parts = vec![];
+	if let Some(structure) = object.get("bodyStructure") {
+		parts.push(
+			serde_json::from_value::<NewBodyPart>(structure.clone())
+				.map_err(|e| SetError::invalid_properties(&["bodyStructure"], e.to_string()))?,
+		);
+	}
+	for property in &["textBody", "htmlBody", "attachments"] {
+		parts.extend(parse_parts(object, property)?);
+	}
+
+	let mut ids = vec![];
+	for part in &parts {
+		part.blob_ids(&mut ids);
+	}
+	let mut ids: Vec<Id> = ids.into_iter().cloned().collect();
+	ids.sort();
+	ids.dedup();
+	Ok(ids)
+}
+
+/// The body from either `bodyStructure` or the text, html and attachment
+/// lists (RFC 8621 section 4.6).
+fn body_entity(
+	object: &Map<String, Value>,
+	values: &HashMap<String, BodyValue>,
+	blobs: &HashMap<Id, Vec<u8>>,
+) -> Result<Entity, SetError> {
+	if let Some(structure) = object.get("bodyStructure") {
+		if let Some(key) = ["textBody", "htmlBody", "attachments"]
+			.iter()
+			.find(|k| object.contains_key(**k))
+		{
+			return Err(SetError::invalid_properties(
+				&["bodyStructure", key],
+				"bodyStructure can't be combined with body lists",
+			));
+		}
+		let part: NewBodyPart = serde_json::from_value(structure.clone())
+			.map_err(|e| SetError::invalid_properties(&["bodyStructure"], e.to_string()))?;
+		return part.to_entity(values, blobs, "bodyStructure");
+	}
+
+	let single = |property: &str, r#type: &str| -> Result<Option<Entity>, SetError> {
+		let mut parts = parse_parts(object, property)?;
+		if parts.len() > 1 {
+			return Err(SetError::invalid_properties(
+				&[property],
+				"only a single part is supported",
+			));
+		}
+		match parts.pop() {
+			Some(mut part) => {
+				let given = part.r#type.get_or_insert_with(|| r#type.to_owned());
+				if !given.eq_ignore_ascii_case(r#type) {
+					return Err(SetError::invalid_properties(
+						&[property],
+						format!("the part must be {}", r#type),
+					));
+				}
+				part.to_entity(values, blobs, property).map(Some)
+			}
+			None => Ok(None),
+		}
+	};
+	let text = single("textBody", "text/plain")?;
+	let html = single("htmlBody", "text/html")?;
+	let attachments = parse_parts(object, "attachments")?
+		.iter()
+		.map(|p| p.to_entity(values, blobs, "attachments"))
+		.collect::<Result<Vec<_>, _>>()?;
+
+	let multipart = |r#type: &str, parts: Vec<Entity>| Entity {
+		content_type: r#type.to_owned(),
+		headers:      vec![],
+		body:         EntityBody::Multipart(parts),
+	};
+	let body = match (text, html) {
+		(Some(text), Some(html)) => Some(multipart("multipart/alternative", vec![text, html])),
+		(Some(part), None) | (None, Some(part)) => Some(part),
+		(None, None) => None,
+	};
+
+	Ok(match (body, attachments.is_empty()) {
+		(Some(body), true) => body,
+		(Some(body), false) => multipart(
+			"multipart/mixed",
+			std::iter::once(body).chain(attachments).collect(),
+		),
+		(None, false) => multipart("multipart/mixed", attachments),
+		(None, true) => Entity {
+			content_type: "text/plain; charset=utf-8".to_owned(),
+			headers:      vec![],
+			body:         EntityBody::Data(vec![]),
+		},
+	})
+}
+
+impl JmapApi<'_> {
+	pub async fn handle_email_set(&self, request: SetRequest) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let old_state = self.current_email_state().await?;
+		if request
+			.if_in_state
+			.as_ref()
+			.is_some_and(|s| *s != old_state)
+		{
+			return Err(MethodError::StateMismatch);
+		}
+
+		let mut response = SetResponse {
+			account_id: request.account_id.clone(),
+			old_state: Some(old_state),
+			..Default::default()
+		};
+
+		let mailboxes = self.fetch_mailboxes().await?;
+
+		for (creation_id, object) in request.create.unwrap_or_default() {
+			match self.create_email(&mailboxes, &object).await? {
+				Ok(created) => {
+					if let Some(id) = created["id"].as_str() {
+						self.record_created_id(&creation_id, id);
+					}
+					response.created.insert(creation_id, created);
+				}
+				Err(e) => {
+					response.not_created.insert(creation_id, e);
+				}
+			}
+		}
+
+		let update = request.update.unwrap_or_default();
+		let destroy = request.destroy.unwrap_or_default();
+		let ids: Vec<Id> = update
+			.keys()
+			.chain(&destroy)
+			.filter_map(|id| self.resolve_id(id))
+			.collect();
+		let locations = self.locate_emails(&mailboxes, &ids).await?;
+
+		for (id, patch) in update {
+			let locations = match self.resolve_id(&id).and_then(|i| locations.get(&i)) {
+				Some(locations) if !locations.is_empty() => locations,
+				_ => {
+					response.not_updated.insert(id, SetError::NotFound);
+					continue;
+				}
+			};
+			match self.update_email(&mailboxes, locations, &patch).await? {
+				Ok(()) => {
+					response.updated.insert(id, None);
+				}
+				Err(e) => {
+					response.not_updated.insert(id, e);
+				}
+			}
+		}
+
+		for id in destroy {
+			let locations = match self.resolve_id(&id).and_then(|i| locations.get(&i)) {
+				Some(locations) if !locations.is_empty() => locations.clone(),
+				_ => {
+					response.not_destroyed.insert(id, SetError::NotFound);
+					continue;
+				}
+			};
+			let destroyed = self
+				.with_raw_session(|mut s| async move {
+					for location in locations {
+						let result = async {
+							imap::select(&mut s, &location.mailbox, true).await?;
+							imap::uid_delete(&mut s, &[location.uid]).await
+						}
+						.await;
+						if result.is_err() {
+							return Ok(result);
+						}
+					}
+					Ok(Ok(()))
+				})
+				.await?;
+			match imap_set_result(destroyed)? {
+				Ok(()) => response.destroyed.push(id),
+				Err(e) => {
+					response.not_destroyed.insert(id, e);
+				}
+			}
+		}
+
+		response.new_state = self.current_email_state().await?;
+
+		Ok(MethodResult::EmailSet(response))
+	}
+
+	/// Checks and composes a new email and appends it to its mailboxes.
+	async fn create_email(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		object: &Map<String, Value>,
+	) -> Result<Result<Value, SetError>, MethodError> {
+		let mut blobs = HashMap::new();
+		let mut not_found = vec![];
+		let blob_ids = match body_blob_ids(object) {
+			Ok(ids) => ids,
+			Err(e) => return Ok(Err(e)),
+		};
+		for blob_id in blob_ids {
+			match self.fetch_blob(&blob_id).await? {
+				Some(data) => {
+					blobs.insert(blob_id, data);
+				}
+				None => not_found.push(blob_id),
+			}
+		}
+		if !not_found.is_empty() {
+			return Ok(Err(SetError::BlobNotFound { not_found }));
+		}
+
+		let email = match parse_new_email(object, &blobs) {
+			Ok(email) => email,
+			Err(e) => return Ok(Err(e)),
+		};
+		let mailbox_ids = match self.new_email_mailboxes(mailboxes, object) {
+			Ok(ids) => ids,
+			Err(e) => return Ok(Err(e)),
+		};
+
+		self.append_email(
+			mailboxes,
+			&mailbox_ids,
+			email.flags,
+			email.received_at,
+			email.message.to_bytes(),
+			Some(&email.message_id),
+		)
+		.await
+	}
+
+	/// Appends `message` to the mailboxes and returns the new email with its
+	/// server set properties.
+	///
+	/// `message_id` (without angle brackets) is needed to find the message
+	/// again on servers without `UIDPLUS`.
+	pub async fn append_email(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		mailbox_ids: &[Id],
+		flags: Vec<String>,
+		received_at: Option<String>,
+		message: Vec<u8>,
+		message_id: Option<&str>,
+	) -> Result<Result<Value, SetError>, MethodError> {
+		let targets: Vec<(Id, String)> = mailbox_ids
+			.iter()
+			.filter_map(|id| {
+				let (_, info) = mailboxes.iter().find(|(m, _)| m == id)?;
+				Some((id.clone(), info.name.clone()))
+			})
+			.collect();
+		if targets.is_empty() {
+			return Ok(Err(SetError::invalid_properties(
+				&["mailboxIds"],
+				"an email must be in at least one mailbox",
+			)));
+		}
+
+		let size = message.len();
+		let search =
+			message_id.map(|id| format!("HEADER Message-ID {}", raw::quote(&format!("<{}>", id))));
+		let object_ids = self.has_object_ids().await?;
+
+		let appended = self
+			.with_raw_session(|mut s| async move {
+				let result = async {
+					let (_, first) = &targets[0];
+					let uid = imap::append(&mut s, first, &flags, received_at.as_deref(), &message)
+						.await?;
+					imap::select(&mut s, first, false).await?;
+					// without UIDPLUS the new message has to be found again
+					let uid = match (uid, search) {
+						(Some(uid), _) => Some(uid),
+						(None, Some(search)) => imap::uid_search(&mut s, &search).await?.pop(),
+						(None, None) => None,
+					};
+					let uid = match uid {
+						Some(uid) => uid,
+						None => return Ok(None),
+					};
+					for (_, mailbox) in &targets[1..] {
+						imap::uid_copy(&mut s, &[uid], mailbox).await?;
+					}
+					let ids = if object_ids {
+						imap::uid_fetch(&mut s, &[uid], "EMAILID THREADID")
+							.await?
+							.pop()
+							.map(|(_, attributes)| attributes)
+					} else {
+						None
+					};
+					Ok(Some((uid, ids)))
+				}
+				.await;
+				Ok(result.map(|r| r.map(|(uid, ids)| (targets[0].0.clone(), uid, ids))))
+			})
+			.await?;
+
+		let (mailbox_id, uid, attributes) = match imap_set_result(appended)? {
+			Ok(Some(appended)) => appended,
+			Ok(None) => {
+				return Ok(Err(SetError::forbidden(
+					"the server didn't tell the uid of the new message",
+				)))
+			}
+			Err(e) => return Ok(Err(e)),
+		};
+
+		let object_id = |item: &str| {
+			raw::find_value(attributes.as_deref()?, item)?
+				.as_list()?
+				.first()?
+				.as_str()
+				.map(str::to_owned)
+		};
+		let id = object_id("EMAILID").unwrap_or_else(|| location_email_id(&mailbox_id, uid));
+		let thread_id = match object_id("THREADID") {
+			Some(thread_id) => Some(thread_id),
+			None => {
+				let mailboxes = self.fetch_mailboxes().await?;
+				let (_, threads) = self.threads(&mailboxes).await?;
+				threads.by_email.get(&id).cloned()
+			}
+		}
+		.unwrap_or_else(|| format!("T{}", id));
+
+		Ok(Ok(json!({
+			"id": id,
+			"blobId": email_blob_id(&id),
+			"threadId": thread_id,
+			"size": size,
+		})))
+	}
+
+	/// The mailboxes of a new email, drafts go to the drafts mailbox if none
+	/// are given.
+	pub fn new_email_mailboxes(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		object: &Map<String, Value>,
+	) -> Result<Vec<Id>, SetError> {
+		let ids = match parse_set_change(object, "mailboxIds", |id| {
+			self.resolve_id(id)
+				.filter(|id| mailboxes.iter().any(|(m, _)| m == id))
+		})? {
+			Some(change) => change.apply(&BTreeSet::new()),
+			None => self
+				.build_mailboxes(mailboxes)
+				.into_iter()
+				.find(|m| m.role.as_deref() == Some("drafts"))
+				.map(|m| m.id)
+				.into_iter()
+				.collect(),
+		};
+
+		if ids.is_empty() {
+			return Err(SetError::invalid_properties(
+				&["mailboxIds"],
+				"an email must be in at least one mailbox",
+			));
+		}
+		Ok(ids.into_iter().collect())
+	}
+
+	async fn update_email(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		locations: &[Location],
+		patch: &Map<String, Value>,
+	) -> Result<Result<(), SetError>, MethodError> {
+		if let Some(key) = patch.keys().find(|k| {
+			!["keywords", "mailboxIds"].contains(&k.as_str())
+				&& !k.starts_with("keywords/")
+				&& !k.starts_with("mailboxIds/")
+		}) {
+			return Ok(Err(SetError::invalid_properties(
+				&[key],
+				"property can't be changed",
+			)));
+		}
+
+		let keywords = match parse_set_change(patch, "keywords", |k| {
+			keyword_to_flag(k).map(|_| k.to_ascii_lowercase())
+		}) {
+			Ok(k) => k,
+			Err(e) => return Ok(Err(e)),
+		};
+		let mailbox_ids = match parse_set_change(patch, "mailboxIds", |id| {
+			self.resolve_id(id)
+				.filter(|id| mailboxes.iter().any(|(m, _)| m == id))
+		}) {
+			Ok(m) => m,
+			Err(e) => return Ok(Err(e)),
+		};
+
+		let (add_flags, remove_flags) = match keywords {
+			Some(change) => {
+				let current = match &change {
+					SetChange::Replace(_) => self.current_keywords(&locations[0]).await?,
+					SetChange::Patch { .. } => BTreeSet::new(),
+				};
+				let (add, remove) = match change {
+					SetChange::Replace(new) => (
+						new.difference(&current).cloned().collect(),
+						current.difference(&new).cloned().collect(),
+					),
+					SetChange::Patch { add, remove } => (add, remove),
+				};
+				let flags = |keywords: BTreeSet<String>| -> Vec<String> {
+					keywords.iter().filter_map(|k| keyword_to_flag(k)).collect()
+				};
+				(flags(add), flags(remove))
+			}
+			None => (vec![], vec![]),
+		};
+
+		let current: BTreeSet<Id> = locations.iter().map(|l| l.mailbox_id.clone()).collect();
+		let (copies, moves, deletes) = match mailbox_ids {
+			Some(change) => {
+				let new = change.apply(&current);
+				if new.is_empty() {
+					return Ok(Err(SetError::invalid_properties(
+						&["mailboxIds"],
+						"an email must be in at least one mailbox",
+					)));
+				}
+				let mailbox_name = |id: &Id| {
+					mailboxes
+						.iter()
+						.find(|(m, _)| m == id)
+						.map(|(_, info)| info.name.clone())
+						.unwrap_or_default()
+				};
+				let mut added: Vec<String> = new.difference(&current).map(mailbox_name).collect();
+				let mut removed: Vec<Location> = locations
+					.iter()
+					.filter(|l| !new.contains(&l.mailbox_id))
+					.cloned()
+					.collect();
+
+				// moving where possible keeps the number of copies down
+				let mut moves = vec![];
+				while let (Some(_), Some(_)) = (added.last(), removed.last()) {
+					moves.push((removed.pop().unwrap(), added.pop().unwrap()));
+				}
+				(added, moves, removed)
+			}
+			None => (vec![], vec![], vec![]),
+		};
+
+		let locations = locations.to_vec();
+		let updated = self
+			.with_raw_session(|mut s| async move {
+				let result = async {
+					if !add_flags.is_empty() || !remove_flags.is_empty() {
+						for location in &locations {
+							imap::select(&mut s, &location.mailbox, true).await?;
+							imap::uid_store(&mut s, &[location.uid], "+FLAGS", &add_flags).await?;
+							imap::uid_store(&mut s, &[location.uid], "-FLAGS", &remove_flags)
+								.await?;
+						}
+					}
+					// copies come from a location that stays
+					if !copies.is_empty() {
+						let source = &locations[0];
+						imap::select(&mut s, &source.mailbox, false).await?;
+						for target in &copies {
+							imap::uid_copy(&mut s, &[source.uid], target).await?;
+						}
+					}
+					for (location, target) in &moves {
+						imap::select(&mut s, &location.mailbox, true).await?;
+						imap::uid_move(&mut s, &[location.uid], target).await?;
+					}
+					for location in &deletes {
+						imap::select(&mut s, &location.mailbox, true).await?;
+						imap::uid_delete(&mut s, &[location.uid]).await?;
+					}
+					Ok(())
+				}
+				.await;
+				Ok(result)
+			})
+			.await?;
+
+		imap_set_result(updated)
+	}
+
+	async fn current_keywords(&self, location: &Location) -> Result<BTreeSet<String>, MethodError> {
+		let key = (location.mailbox.clone(), location.uid);
+		let fetched = self
+			.fetch_messages(vec![(key.0.clone(), key.1, "FLAGS".to_owned())])
+			.await?;
+
+		Ok(fetched
+			.get(&key)
+			.and_then(|attributes| raw::find_value(attributes, "FLAGS"))
+			.and_then(Token::as_list)
+			.unwrap_or(&[])
+			.iter()
+			.filter_map(Token::as_str)
+			.filter_map(flag_to_keyword)
+			.collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn values(list: Value, properties: Value) -> Result<Vec<(String, String)>, SetError> {
+		let list: Vec<HeaderValue> = serde_json::from_value(list).unwrap();
+		let properties = match properties {
+			Value::Object(properties) => properties,
+			_ => unreachable!(),
+		};
+		Ok(header_values(&list, &properties, "headers")?
+			.into_iter()
+			.map(|h| (h.name, h.value))
+			.collect())
+	}
+
+	fn is_invalid(result: Result<Vec<(String, String)>, SetError>) -> bool {
+		matches!(result, Err(SetError::InvalidProperties { .. }))
+	}
+
+	#[test]
+	fn headers_come_from_the_list_and_properties() {
+		let headers = values(
+			json!([{ "name": "X-Mailer", "value": " test" }]),
+			json!({
+				"header:Subject:asText": "grüße",
+				"header:X-Tag:all": [" a", " b"],
+				"header:X-Gone": null,
+			}),
+		)
+		.unwrap();
+		assert_eq!(
+			headers,
+			vec![
+				("X-Mailer".to_owned(), " test".to_owned()),
+				("Subject".to_owned(), "=?UTF-8?B?Z3LDvMOfZQ==?=".to_owned()),
+				("X-Tag".to_owned(), " a".to_owned()),
+				("X-Tag".to_owned(), " b".to_owned()),
+			]
+		);
+	}
+
+	#[test]
+	fn folded_values_are_kept() {
+		let headers = values(
+			json!([{ "name": "X-Long", "value": " a\r\n b\r\n\tc" }]),
+			json!({}),
+		)
+		.unwrap();
+		assert_eq!(headers[0].1, " a\r\n b\r\n\tc");
+	}
+
+	#[test]
+	fn line_breaks_are_rejected() {
+		for value in [" a\r\nBcc: x@example.com", " a\nb", " a\rb", " a\r\n"] {
+			assert!(is_invalid(values(
+				json!([{ "name": "X-Note", "value": value }]),
+				json!({}),
+			)));
+			assert!(is_invalid(values(
+				json!([]),
+				json!({ "header:X-Note": value }),
+			)));
+		}
+		// text is encoded instead
+		assert!(values(json!([]), json!({ "header:X-Note:asText": "a\r\nb" })).is_ok());
+	}
+
+	#[test]
+	fn invalid_names_are_rejected() {
+		for name in ["", "X Note", "X:Note", "X-Nöte", "X-Note\r\n"] {
+			assert!(is_invalid(values(
+				json!([{ "name": name, "value": " a" }]),
+				json!({}),
+			)));
+		}
+	}
+
+	#[test]
+	fn property_values_must_fit() {
+		assert!(is_invalid(values(
+			json!([]),
+			json!({ "header:X-Tag:all": " a" })
+		)));
+		assert!(is_invalid(values(json!([]), json!({ "header:X-Tag": 1 }))));
+		assert!(is_invalid(values(
+			json!([]),
+			json!({ "header:X-Tag:asSomething": "a" })
+		)));
+		assert!(is_invalid(values(json!([]), json!({ "subject": "a" }))));
+	}
+}
@@ -0,0 +1,225 @@
+//! `SearchSnippet/get`, highlighting the text conditions of an `Email/query`
+//! filter in the subject and preview text.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	imap,
+	jmap::{
+		email::{
+			search::{highlight_terms, EmailFilterCondition, HighlightTerms},
+			BodyLists,
+			EmailData,
+			MAX_PREVIEW_CHARS,
+		},
+		method::{MethodError, MethodResult},
+		Filter,
+		Id,
+		JmapApi,
+		MAX_OBJECTS_IN_GET,
+	},
+	mime,
+};
+
+// how much text before the first match is kept in the preview
+const PREVIEW_CONTEXT_CHARS: usize = 60;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchSnippetGetRequest {
+	pub account_id: Id,
+	pub filter:     Option<Filter<EmailFilterCondition>>,
+	pub email_ids:  Vec<Id>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchSnippet {
+	pub email_id: Id,
+	pub subject:  Option<String>,
+	pub preview:  Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchSnippetGetResponse {
+	pub account_id: Id,
+	pub list:       Vec<SearchSnippet>,
+	pub not_found:  Vec<Id>,
+}
+
+/// Lowercases char by char so positions stay the same as in the original.
+fn lowercase(text: &[char]) -> Vec<char> {
+	text.iter()
+		.map(|c| c.to_lowercase().next().unwrap_or(*c))
+		.collect()
+}
+
+/// Where the first term occurs, and the length of the match.
+fn find_match(text: &[char], terms: &[Vec<char>], from: usize) -> Option<(usize, usize)> {
+	(from..text.len()).find_map(|i| {
+		terms
+			.iter()
+			.filter(|t| !t.is_empty() && text[i..].starts_with(t))
+			.map(|t| (i, t.len()))
+			.max_by_key(|(_, len)| *len)
+	})
+}
+
+/// Escapes `text` for html and wraps all occurrences of `terms` in `<mark>`,
+/// `None` if there are none.
+fn highlight(text: &[char], terms: &[Vec<char>]) -> Option<String> {
+	let lower = lowercase(text);
+	find_match(&lower, terms, 0)?;
+
+	let mut out = String::with_capacity(text.len() + 16);
+	let mut position = 0;
+	while position < text.len() {
+		let (start, len) = find_match(&lower, terms, position).unwrap_or((text.len(), 0));
+		escape_into(&text[position..start], &mut out);
+		if len > 0 {
+			out.push_str("<mark>");
+			escape_into(&text[start..start + len], &mut out);
+			out.push_str("</mark>");
+		}
+		position = start + len;
+	}
+	Some(out)
+}
+
+fn escape_into(text: &[char], out: &mut String) {
+	for c in text {
+		match c {
+			'&' => out.push_str("&amp;"),
+			'<' => out.push_str("&lt;"),
+			'>' => out.push_str("&gt;"),
+			'"' => out.push_str("&quot;"),
+			'\'' => out.push_str("&#39;"),
+			c => out.push(*c),
+		}
+	}
+}
+
+/// A preview sized piece of `text` around the first match.
+fn highlight_preview(text: &str, terms: &[Vec<char>]) -> Option<String> {
+	let text: Vec<char> = text
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ")
+		.chars()
+		.collect();
+	let (first, _) = find_match(&lowercase(&text), terms, 0)?;
+
+	let mut start = first.saturating_sub(PREVIEW_CONTEXT_CHARS);
+	// don't start in the middle of a word
+	if start > 0 {
+		if let Some(space) = text[start..first].iter().position(|c| *c == ' ') {
+			start += space + 1;
+		}
+	}
+	let end = (start + MAX_PREVIEW_CHARS).min(text.len());
+	highlight(&text[start..end], terms)
+}
+
+fn term_chars(terms: &[String]) -> Vec<Vec<char>> {
+	terms
+		.iter()
+		.map(|t| lowercase(&t.chars().collect::<Vec<_>>()))
+		.collect()
+}
+
+impl JmapApi<'_> {
+	pub async fn handle_search_snippet_get(
+		&self,
+		request: SearchSnippetGetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		if request.email_ids.len() > MAX_OBJECTS_IN_GET {
+			return Err(MethodError::RequestTooLarge);
+		}
+
+		let terms = request
+			.filter
+			.as_ref()
+			.map(highlight_terms)
+			.unwrap_or_default();
+		let HighlightTerms { subject, body } = &terms;
+		let (subject_terms, body_terms) = (term_chars(subject), term_chars(body));
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		let locations = self.locate_emails(&mailboxes, &request.email_ids).await?;
+
+		// the preview needs the whole message, the subject only the header
+		let (item, section) = if !body_terms.is_empty() {
+			("BODY.PEEK[]", "")
+		} else {
+			("BODY.PEEK[HEADER]", "HEADER")
+		};
+		let fetched = if subject_terms.is_empty() && body_terms.is_empty() {
+			Default::default()
+		} else {
+			self.fetch_messages(
+				locations
+					.values()
+					.filter_map(|l| l.first())
+					.map(|l| (l.mailbox.clone(), l.uid, item.to_owned()))
+					.collect(),
+			)
+			.await?
+		};
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in request.email_ids {
+			let location = match locations.get(&id).and_then(|l| l.first()) {
+				Some(location) => location,
+				None => {
+					not_found.push(id);
+					continue;
+				}
+			};
+
+			let mut email = EmailData::default();
+			if let Some(data) = fetched
+				.get(&(location.mailbox.clone(), location.uid))
+				.and_then(|attributes| imap::fetch_section(attributes, section))
+			{
+				if section.is_empty() {
+					email.set_message(data);
+				} else {
+					email.headers = Some(mime::parse_headers(data).0);
+				}
+			}
+
+			let subject = email
+				.headers
+				.as_deref()
+				.and_then(|headers| mime::find_header(headers, "Subject"))
+				.and_then(|subject| {
+					let subject: Vec<char> = mime::decode_words(&mime::unfold(subject))
+						.trim()
+						.chars()
+						.collect();
+					highlight(&subject, &subject_terms)
+				});
+			let preview = email
+				.body
+				.as_ref()
+				.and_then(|body| email.preview_text(&BodyLists::new(body)))
+				.and_then(|text| highlight_preview(&text, &body_terms));
+
+			list.push(SearchSnippet {
+				email_id: id,
+				subject,
+				preview,
+			});
+		}
+
+		Ok(MethodResult::SearchSnippetGet(SearchSnippetGetResponse {
+			account_id: request.account_id,
+			list,
+			not_found,
+		}))
+	}
+}
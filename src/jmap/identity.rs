@@ -0,0 +1,347 @@
+//! Sending identities, kept in the store since imap has no notion of them.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+	jmap::{
+		changes,
+		method::{MethodError, MethodResult},
+		ChangesRequest,
+		GetRequest,
+		GetResponse,
+		Id,
+		JmapApi,
+		SetError,
+		SetRequest,
+		SetResponse,
+		MAX_OBJECTS_IN_GET,
+	},
+	mime::EmailAddress,
+};
+
+const IDENTITY_STORE_NAME: &str = "identities";
+const IDENTITY_CHANGES_STORE_NAME: &str = "identity-changes";
+
+/// The identity every account starts with, sending as the login address.
+const DEFAULT_IDENTITY_ID: &str = "default";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Identity {
+	pub id:             Id,
+	pub name:           String,
+	pub email:          String,
+	pub reply_to:       Option<Vec<EmailAddress>>,
+	pub bcc:            Option<Vec<EmailAddress>>,
+	pub text_signature: String,
+	pub html_signature: String,
+	pub may_delete:     bool,
+}
+
+impl Identity {
+	const PROPERTIES: &'static [&'static str] = &[
+		"id",
+		"name",
+		"email",
+		"replyTo",
+		"bcc",
+		"textSignature",
+		"htmlSignature",
+		"mayDelete",
+	];
+
+	fn new(id: Id, email: String, may_delete: bool) -> Self {
+		Identity {
+			id,
+			name: String::new(),
+			email,
+			reply_to: None,
+			bcc: None,
+			text_signature: String::new(),
+			html_signature: String::new(),
+			may_delete,
+		}
+	}
+
+	/// Sets the properties in `object`, server set and immutable ones may
+	/// only be given with their current value.
+	fn apply(&mut self, object: &Map<String, Value>, immutable: &[&str]) -> Result<(), SetError> {
+		let current = serde_json::to_value(&*self).unwrap_or_default();
+		for (key, value) in object {
+			let invalid = |description: &str| SetError::invalid_properties(&[key], description);
+			let string = || {
+				value
+					.as_str()
+					.map(str::to_owned)
+					.ok_or_else(|| invalid("must be a string"))
+			};
+			let addresses = || {
+				serde_json::from_value::<Option<Vec<EmailAddress>>>(value.clone())
+					.map_err(|e| invalid(&e.to_string()))
+			};
+
+			match key.as_str() {
+				k if ["id", "mayDelete"].contains(&k) || immutable.contains(&k) => {
+					if current.get(k) != Some(value) {
+						return Err(invalid("property can't be changed"));
+					}
+				}
+				"name" => self.name = string()?,
+				"email" => self.email = string()?,
+				"replyTo" => self.reply_to = addresses()?,
+				"bcc" => self.bcc = addresses()?,
+				"textSignature" => self.text_signature = string()?,
+				"htmlSignature" => self.html_signature = string()?,
+				_ => return Err(invalid("unknown property")),
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Identities {
+	next_id:    u64,
+	identities: BTreeMap<Id, Identity>,
+}
+
+impl Identities {
+	fn seed(&mut self, email: &str) {
+		if !self.identities.contains_key(DEFAULT_IDENTITY_ID) {
+			self.identities.insert(
+				DEFAULT_IDENTITY_ID.to_owned(),
+				Identity::new(DEFAULT_IDENTITY_ID.to_owned(), email.to_owned(), false),
+			);
+		}
+	}
+}
+
+/// Addresses and domains identities may send as, from `ALLOWED_SENDERS`,
+/// e.g. `alias@example.com,example.org`. The login address is always allowed.
+pub fn allowed_senders_from_env() -> Vec<String> {
+	std::env::var("ALLOWED_SENDERS")
+		.unwrap_or_default()
+		.split(',')
+		.map(|s| s.trim().trim_start_matches('@').to_lowercase())
+		.filter(|s| !s.is_empty())
+		.collect()
+}
+
+pub fn is_allowed_sender(allowed: &[String], login: &str, email: &str) -> bool {
+	let email = email.trim().to_lowercase();
+	if email == login.to_lowercase() {
+		return true;
+	}
+	let domain = email.rsplit_once('@').map(|(_, domain)| domain);
+	allowed.iter().any(|a| {
+		if a.contains('@') {
+			*a == email
+		} else {
+			domain == Some(a.as_str())
+		}
+	})
+}
+
+impl JmapApi<'_> {
+	/// All identities of the account, the default one is created on first use.
+	pub async fn identities(&self) -> Result<Vec<Identity>, MethodError> {
+		let login = self.account_id().to_owned();
+		Ok(self
+			.state
+			.store
+			.update(
+				self.account_id(),
+				IDENTITY_STORE_NAME,
+				|identities: &mut Identities| {
+					identities.seed(&login);
+					identities.identities.values().cloned().collect()
+				},
+			)
+			.await?)
+	}
+
+	async fn identity_state(&self, identities: &[Identity]) -> Result<String, MethodError> {
+		let objects = changes::to_objects(identities.iter().map(|i| (&i.id, i)))?;
+		self.record_state(IDENTITY_CHANGES_STORE_NAME, &objects)
+			.await
+	}
+
+	pub async fn handle_identity_get(
+		&self,
+		request: GetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let identities = self.identities().await?;
+		let state = self.identity_state(&identities).await?;
+
+		let ids: Vec<Id> = match request.ids {
+			Some(ids) => ids,
+			None => identities.iter().map(|i| i.id.clone()).collect(),
+		};
+		if ids.len() > MAX_OBJECTS_IN_GET {
+			return Err(MethodError::RequestTooLarge);
+		}
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in ids {
+			match identities.iter().find(|i| i.id == id) {
+				Some(identity) => list.push(identity),
+				None => not_found.push(id),
+			}
+		}
+
+		Ok(MethodResult::IdentityGet(GetResponse {
+			account_id: request.account_id,
+			state,
+			list: super::select_properties(list, &request.properties, Identity::PROPERTIES)?,
+			not_found,
+		}))
+	}
+
+	pub async fn handle_identity_changes(
+		&self,
+		request: ChangesRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let identities = self.identities().await?;
+		let objects = changes::to_objects(identities.iter().map(|i| (&i.id, i)))?;
+		let changes = self
+			.record_changes(
+				IDENTITY_CHANGES_STORE_NAME,
+				&objects,
+				&request.since_state,
+				request.max_changes,
+			)
+			.await?;
+
+		Ok(MethodResult::IdentityChanges(
+			changes.into_response(request.account_id),
+		))
+	}
+
+	pub async fn handle_identity_set(
+		&self,
+		request: SetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let old_state = self.identity_state(&self.identities().await?).await?;
+		if request
+			.if_in_state
+			.as_ref()
+			.is_some_and(|s| *s != old_state)
+		{
+			return Err(MethodError::StateMismatch);
+		}
+
+		let login = self.account_id().to_owned();
+		let allowed = self.state.allowed_senders.clone();
+		let check_sender = |identity: &Identity| {
+			if !identity.email.contains('@') {
+				return Err(SetError::invalid_properties(
+					&["email"],
+					"must be an email address",
+				));
+			}
+			if !is_allowed_sender(&allowed, &login, &identity.email) {
+				return Err(SetError::ForbiddenFrom {
+					description: Some(format!("can't send as {}", identity.email)),
+				});
+			}
+			Ok(())
+		};
+
+		let mut response = self
+			.state
+			.store
+			.update(
+				self.account_id(),
+				IDENTITY_STORE_NAME,
+				|identities: &mut Identities| {
+					let mut response = SetResponse {
+						account_id: request.account_id.clone(),
+						old_state: Some(old_state),
+						..Default::default()
+					};
+
+					for (creation_id, object) in request.create.unwrap_or_default() {
+						let id = format!("I{}", identities.next_id);
+						let mut identity = Identity::new(id.clone(), String::new(), true);
+						match identity
+							.apply(&object, &[])
+							.and_then(|()| check_sender(&identity))
+						{
+							Ok(()) => {
+								identities.next_id += 1;
+								self.record_created_id(&creation_id, &id);
+								response.created.insert(
+									creation_id,
+									serde_json::json!({ "id": id, "mayDelete": true }),
+								);
+								identities.identities.insert(id, identity);
+							}
+							Err(e) => {
+								response.not_created.insert(creation_id, e);
+							}
+						}
+					}
+
+					for (id, patch) in request.update.unwrap_or_default() {
+						let identity = match self
+							.resolve_id(&id)
+							.and_then(|i| identities.identities.get_mut(&i))
+						{
+							Some(identity) => identity,
+							None => {
+								response.not_updated.insert(id, SetError::NotFound);
+								continue;
+							}
+						};
+						let mut updated = identity.clone();
+						match updated.apply(&patch, &["email"]) {
+							Ok(()) => {
+								*identity = updated;
+								response.updated.insert(id, None);
+							}
+							Err(e) => {
+								response.not_updated.insert(id, e);
+							}
+						}
+					}
+
+					for id in request.destroy.unwrap_or_default() {
+						let resolved = self.resolve_id(&id).unwrap_or_default();
+						match identities.identities.get(&resolved) {
+							Some(identity) if identity.may_delete => {
+								identities.identities.remove(&resolved);
+								response.destroyed.push(id);
+							}
+							Some(_) => {
+								response.not_destroyed.insert(
+									id,
+									SetError::forbidden("this identity can't be deleted"),
+								);
+							}
+							None => {
+								response.not_destroyed.insert(id, SetError::NotFound);
+							}
+						}
+					}
+
+					response
+				},
+			)
+			.await?;
+
+		response.new_state = self.identity_state(&self.identities().await?).await?;
+
+		Ok(MethodResult::IdentitySet(response))
+	}
+}
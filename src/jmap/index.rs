@@ -0,0 +1,483 @@
+//! A snapshot of every message in the account, for threading and for
+//! counting threads per mailbox.
+//!
+//! Mailboxes whose status changed since they were indexed are updated with
+//! the messages changed since their last `HIGHESTMODSEQ` (RFC 7162) or, if the
+//! server doesn't support that, fetched again as a whole. Unchanged ones are
+//! taken from the store, without `CONDSTORE` after comparing their flags.
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	imap,
+	imap::raw::{self, Token},
+	jmap::{
+		email::{flag_to_keyword, location_email_id},
+		method::MethodError,
+		Id,
+		JmapApi,
+	},
+	mime,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexedMessage {
+	pub uid:         u32,
+	pub email_id:    Id,
+	/// RFC 8474 `THREADID`.
+	#[serde(default)]
+	pub thread_id:   Option<Id>,
+	pub keywords:    Vec<String>,
+	pub received_at: i64,
+	/// The rest is only kept when threads are built locally.
+	#[serde(default)]
+	pub message_id:  Option<String>,
+	/// `In-Reply-To` and `References`.
+	#[serde(default)]
+	pub references:  Vec<String>,
+	/// See [`mime::base_subject`].
+	#[serde(default)]
+	pub subject:     String,
+	/// Whether the subject had a reply or forward marker.
+	#[serde(default)]
+	pub is_reply:    bool,
+}
+
+impl IndexedMessage {
+	pub fn is_seen(&self) -> bool {
+		self.keywords.iter().any(|k| k == "$seen")
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexedMailbox {
+	pub name:       String,
+	uid_validity:   u32,
+	uid_next:       u32,
+	total:          u32,
+	unseen:         u32,
+	highest_modseq: Option<u64>,
+	pub messages:   Vec<IndexedMessage>,
+	/// Uids grouped by `THREAD=REFERENCES`, if the server does threading.
+	#[serde(default)]
+	pub threads:    Vec<Vec<u32>>,
+}
+
+impl IndexedMailbox {
+	/// Whether the status didn't change, without `CONDSTORE` flags may have
+	/// all the same.
+	fn is_current(&self, info: &imap::MailboxInfo) -> bool {
+		self.name == info.name
+			&& self.uid_validity == info.uid_validity
+			&& self.uid_next == info.uid_next
+			&& self.total == info.total
+			&& self.unseen == info.unseen
+			&& self.highest_modseq == info.highest_modseq
+	}
+
+	/// Takes the keywords from a `UID FETCH 1:* (FLAGS)`, `false` if the
+	/// messages aren't the indexed ones anymore.
+	fn update_flags(&mut self, fetched: &[(u32, Vec<Token>)]) -> bool {
+		if fetched.len() != self.messages.len() {
+			return false;
+		}
+		let flags: BTreeMap<u32, &[Token]> = fetched
+			.iter()
+			.map(|(uid, attributes)| (*uid, &attributes[..]))
+			.collect();
+		if !self.messages.iter().all(|m| flags.contains_key(&m.uid)) {
+			return false;
+		}
+		for message in &mut self.messages {
+			message.keywords = keywords(flags[&message.uid]);
+		}
+		true
+	}
+}
+
+/// Indexed mailboxes by mailbox id.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailIndex {
+	pub mailboxes:  BTreeMap<Id, IndexedMailbox>,
+	/// Counts the `UIDVALIDITY` changes seen, uids from before are meaningless.
+	#[serde(default)]
+	pub generation: u64,
+	/// Counts the times the index was stored, older snapshots have a lower one.
+	#[serde(default)]
+	pub revision:   u64,
+}
+
+impl EmailIndex {
+	const STORE_NAME: &'static str = "email-index";
+
+	/// Finds `ids` in the mailboxes that didn't change since they were
+	/// indexed, as `(email id, mailbox id, uid)`. The mailboxes the index
+	/// can't vouch for are returned as well, they have to be searched.
+	pub fn locate<'a>(
+		&self,
+		mailboxes: &'a [(Id, imap::MailboxInfo)],
+		ids: &HashSet<&str>,
+	) -> (Vec<(Id, Id, u32)>, Vec<&'a Id>) {
+		let mut found = vec![];
+		let mut stale = vec![];
+		for (mailbox_id, info) in mailboxes.iter().filter(|(_, i)| i.is_selectable()) {
+			match self.mailboxes.get(mailbox_id) {
+				Some(mailbox) if mailbox.is_current(info) => found.extend(
+					mailbox
+						.messages
+						.iter()
+						.filter(|m| ids.contains(m.email_id.as_str()))
+						.map(|m| (m.email_id.clone(), mailbox_id.clone(), m.uid)),
+				),
+				_ => stale.push(mailbox_id),
+			}
+		}
+		(found, stale)
+	}
+
+	/// All messages with the id of their mailbox.
+	pub fn messages(&self) -> impl Iterator<Item = (&Id, &IndexedMessage)> {
+		self.mailboxes
+			.iter()
+			.flat_map(|(id, mailbox)| mailbox.messages.iter().map(move |m| (id, m)))
+	}
+}
+
+fn envelope_string(envelope: &[Token], index: usize) -> Option<String> {
+	envelope
+		.get(index)?
+		.as_bytes()
+		.map(|b| String::from_utf8_lossy(b).into_owned())
+}
+
+fn keywords(attributes: &[Token]) -> Vec<String> {
+	raw::find_value(attributes, "FLAGS")
+		.and_then(Token::as_list)
+		.unwrap_or(&[])
+		.iter()
+		.filter_map(Token::as_str)
+		.filter_map(flag_to_keyword)
+		.collect()
+}
+
+fn indexed_message(
+	mailbox_id: &str,
+	uid: u32,
+	attributes: &[Token],
+	object_ids: bool,
+) -> IndexedMessage {
+	let object_id = |item: &str| {
+		raw::find_value(attributes, item)?
+			.as_list()?
+			.first()?
+			.as_str()
+			.map(str::to_owned)
+	};
+
+	let mut message = IndexedMessage {
+		uid,
+		email_id: object_id("EMAILID")
+			.filter(|_| object_ids)
+			.unwrap_or_else(|| location_email_id(mailbox_id, uid)),
+		thread_id: object_id("THREADID"),
+		keywords: keywords(attributes),
+		received_at: raw::find_value(attributes, "INTERNALDATE")
+			.and_then(Token::as_str)
+			.and_then(imap::parse_internal_date)
+			.map_or(0, |d| d.timestamp()),
+		message_id: None,
+		references: vec![],
+		subject: String::new(),
+		is_reply: false,
+	};
+
+	if let Some(envelope) = raw::find_value(attributes, "ENVELOPE").and_then(Token::as_list) {
+		let subject = envelope_string(envelope, 1).unwrap_or_default();
+		let normalized = mime::decode_words(&mime::unfold(&subject))
+			.split_whitespace()
+			.collect::<Vec<_>>()
+			.join(" ")
+			.to_lowercase();
+		message.subject = mime::base_subject(&subject);
+		message.is_reply = message.subject != normalized;
+		message.message_id = envelope_string(envelope, 9)
+			.and_then(|id| mime::parse_message_ids(&id)?.into_iter().next());
+		message.references = envelope_string(envelope, 8)
+			.and_then(|ids| mime::parse_message_ids(&ids))
+			.unwrap_or_default();
+	}
+	if let Some(header) = imap::fetch_section(attributes, "HEADER.FIELDS (REFERENCES)") {
+		let (headers, _) = mime::parse_headers(header);
+		if let Some(ids) =
+			mime::find_header(&headers, "References").and_then(mime::parse_message_ids)
+		{
+			message.references.extend(ids);
+		}
+	}
+
+	message
+}
+
+impl JmapApi<'_> {
+	/// The index as it was last stored, without bringing it up to date.
+	pub async fn stored_email_index(&self) -> Result<EmailIndex, MethodError> {
+		Ok(self
+			.state
+			.store
+			.get(self.account_id(), EmailIndex::STORE_NAME)
+			.await?)
+	}
+
+	/// Brings the index up to date with `mailboxes` as listed by
+	/// [`JmapApi::fetch_mailboxes`].
+	pub async fn email_index(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+	) -> Result<EmailIndex, MethodError> {
+		// concurrent requests would store their snapshots in any order otherwise
+		self.state
+			.store
+			.update_async(self.account_id(), EmailIndex::STORE_NAME, |stored| {
+				self.refresh_email_index(mailboxes, stored)
+			})
+			.await
+	}
+
+	/// The `stored` index brought up to date, and what to store if anything
+	/// changed.
+	async fn refresh_email_index(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+		stored: EmailIndex,
+	) -> Result<(Option<EmailIndex>, EmailIndex), MethodError> {
+		let mut index = EmailIndex {
+			generation: stored.generation,
+			revision: stored.revision,
+			..Default::default()
+		};
+		let mut outdated = vec![];
+		// without `CONDSTORE` nothing tells whether flags changed
+		let mut unverified = vec![];
+		for (id, info) in mailboxes.iter().filter(|(_, i)| i.is_selectable()) {
+			match stored.mailboxes.get(id) {
+				Some(mailbox)
+					if mailbox.is_current(info)
+						&& info.highest_modseq.is_none()
+						&& info.total > 0 =>
+				{
+					unverified.push((id.clone(), info.clone(), mailbox.clone()));
+				}
+				Some(mailbox) if mailbox.is_current(info) => {
+					index.mailboxes.insert(id.clone(), mailbox.clone());
+				}
+				Some(mailbox) if mailbox.uid_validity != info.uid_validity => {
+					index.generation = stored.generation + 1;
+					outdated.push((id.clone(), info.clone(), None));
+				}
+				mailbox => outdated.push((id.clone(), info.clone(), mailbox.cloned())),
+			}
+		}
+		if outdated.is_empty()
+			&& unverified.is_empty()
+			&& index.mailboxes.len() == stored.mailboxes.len()
+		{
+			return Ok((None, index));
+		}
+
+		let refreshed = self
+			.with_raw_session(|mut s| async move {
+				let object_ids = s.has_capability("OBJECTID");
+				let server_threads = !object_ids && s.has_capability("THREAD=REFERENCES");
+				let mut items = vec!["FLAGS", "INTERNALDATE"];
+				if object_ids {
+					items.push("EMAILID THREADID");
+				} else if !server_threads {
+					items.push("ENVELOPE BODY.PEEK[HEADER.FIELDS (REFERENCES)]");
+				}
+				let items = items.join(" ");
+
+				let mut refreshed = vec![];
+				let mut outdated = outdated;
+				for (id, info, mut mailbox) in unverified {
+					imap::select(&mut s, &info.name, false).await?;
+					let flags = imap::uid_fetch_all(&mut s, "FLAGS").await?;
+					if mailbox.update_flags(&flags) {
+						refreshed.push((id, mailbox));
+					} else {
+						outdated.push((id, info, None));
+					}
+				}
+				for (id, info, previous) in outdated {
+					let mut mailbox = IndexedMailbox {
+						name: info.name.clone(),
+						uid_validity: info.uid_validity,
+						uid_next: info.uid_next,
+						total: info.total,
+						unseen: info.unseen,
+						highest_modseq: info.highest_modseq,
+						..Default::default()
+					};
+					if info.total > 0 {
+						imap::select(&mut s, &info.name, false).await?;
+						let modseq = previous.as_ref().and_then(|p| p.highest_modseq);
+						mailbox.messages = match (previous, modseq) {
+							(Some(previous), Some(modseq)) if info.highest_modseq.is_some() => {
+								let (changed, expunged) =
+									imap::uid_fetch_changed(&mut s, modseq, &items).await?;
+								let mut messages: BTreeMap<u32, IndexedMessage> =
+									previous.messages.into_iter().map(|m| (m.uid, m)).collect();
+								messages.retain(|uid, _| !expunged.contains(*uid));
+								for (uid, attributes) in changed {
+									messages.insert(
+										uid,
+										indexed_message(&id, uid, &attributes, object_ids),
+									);
+								}
+								// without `QRESYNC` expunges only show in the count
+								if messages.len() != info.total as usize {
+									let uids: HashSet<u32> = imap::uid_search(&mut s, "ALL")
+										.await?
+										.into_iter()
+										.collect();
+									messages.retain(|uid, _| uids.contains(uid));
+								}
+								messages.into_values().collect()
+							}
+							_ => imap::uid_fetch_all(&mut s, &items)
+								.await?
+								.into_iter()
+								.map(|(uid, attributes)| {
+									indexed_message(&id, uid, &attributes, object_ids)
+								})
+								.collect(),
+						};
+						if server_threads {
+							mailbox.threads = imap::uid_thread(&mut s, "REFERENCES", "ALL").await?;
+						}
+					}
+					refreshed.push((id, mailbox));
+				}
+				Ok(refreshed)
+			})
+			.await?;
+		index.mailboxes.extend(refreshed);
+		index.revision += 1;
+
+		Ok((Some(index.clone()), index))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn info(name: &str, uid_next: u32) -> imap::MailboxInfo {
+		imap::MailboxInfo {
+			name: name.to_owned(),
+			delimiter: Some("/".to_owned()),
+			attributes: vec![],
+			is_subscribed: true,
+			total: 2,
+			unseen: 0,
+			uid_validity: 1,
+			uid_next,
+			highest_modseq: None,
+		}
+	}
+
+	fn message(uid: u32, email_id: &str) -> IndexedMessage {
+		IndexedMessage {
+			uid,
+			email_id: email_id.to_owned(),
+			thread_id: None,
+			keywords: vec![],
+			received_at: 0,
+			message_id: None,
+			references: vec![],
+			subject: String::new(),
+			is_reply: false,
+		}
+	}
+
+	fn indexed(info: &imap::MailboxInfo, messages: Vec<IndexedMessage>) -> IndexedMailbox {
+		IndexedMailbox {
+			name: info.name.clone(),
+			uid_validity: info.uid_validity,
+			uid_next: info.uid_next,
+			total: info.total,
+			unseen: info.unseen,
+			highest_modseq: info.highest_modseq,
+			messages,
+			threads: vec![],
+		}
+	}
+
+	#[test]
+	fn locate_trusts_only_current_mailboxes() {
+		let inbox = info("INBOX", 3);
+		let archive = info("Archive", 3);
+		let mut index = EmailIndex::default();
+		index.mailboxes.insert(
+			"M1".to_owned(),
+			indexed(&inbox, vec![message(1, "Ea"), message(2, "Eb")]),
+		);
+		index
+			.mailboxes
+			.insert("M2".to_owned(), indexed(&archive, vec![message(1, "Ec")]));
+
+		// the archive got a new message since it was indexed
+		let mut noselect = info("Folder", 1);
+		noselect.attributes.push("\\Noselect".to_owned());
+		let mailboxes = vec![
+			("M1".to_owned(), inbox),
+			("M2".to_owned(), info("Archive", 4)),
+			("M3".to_owned(), info("Junk", 1)),
+			("M4".to_owned(), noselect),
+		];
+
+		let ids = ["Eb", "Ec", "Ez"].iter().copied().collect();
+		let (found, stale) = index.locate(&mailboxes, &ids);
+		assert_eq!(found, vec![("Eb".to_owned(), "M1".to_owned(), 2)]);
+		assert_eq!(stale, vec!["M2", "M3"]);
+	}
+
+	fn flags(uid: u32, flags: &[&str]) -> (u32, Vec<Token>) {
+		let flags = flags.iter().map(|f| Token::Atom(f.to_string())).collect();
+		(
+			uid,
+			vec![Token::Atom("FLAGS".to_owned()), Token::List(flags)],
+		)
+	}
+
+	#[test]
+	fn flags_are_compared_without_condstore() {
+		let inbox = info("INBOX", 3);
+		let mut seen = message(1, "Ea");
+		seen.keywords = vec!["$seen".to_owned()];
+		let mut mailbox = indexed(&inbox, vec![seen, message(2, "Eb")]);
+		assert!(mailbox.is_current(&inbox));
+
+		// only \Flagged changed, the status is the same
+		assert!(mailbox.update_flags(&[
+			flags(1, &["\\Seen", "\\Flagged"]),
+			flags(2, &["$Forwarded"]),
+		]));
+		let keywords: Vec<_> = mailbox.messages.iter().map(|m| &m.keywords[..]).collect();
+		assert_eq!(
+			keywords,
+			[
+				&["$seen".to_owned(), "$flagged".to_owned()][..],
+				&["$forwarded".to_owned()][..],
+			]
+		);
+
+		// other messages have to be fetched again
+		assert!(!mailbox.update_flags(&[flags(1, &[]), flags(3, &[])]));
+		assert!(!mailbox.update_flags(&[flags(1, &[])]));
+	}
+}
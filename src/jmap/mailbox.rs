@@ -1,21 +1,47 @@
+use std::{
+	cmp::Ordering,
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+};
+
 use serde::{Deserialize, Serialize};
 
-use crate::jmap::Id;
+use crate::{
+	imap,
+	imap::raw,
+	jmap::{
+		changes::{self, fnv1a},
+		double_option,
+		method::{MethodError, MethodResult},
+		query,
+		ChangesRequest,
+		Comparator,
+		Filter,
+		Id,
+		JmapApi,
+		QueryChangesRequest,
+		QueryChangesResponse,
+		QueryRequest,
+		QueryResponse,
+		SetError,
+		SetRequest,
+		SetResponse,
+	},
+};
 
 #[derive(Deserialize, Serialize, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Mailbox {
-	id:             Id,
-	name:           String,
-	parent_id:      Option<Id>,
-	role:           Option<String>,
-	sort_order:     u64,
-	total_emails:   u64,
-	unread_emails:  u64,
-	total_threads:  u64,
-	unread_threads: u64,
-	my_rights:      MailboxRights,
-	is_subscribed:  bool,
+	pub id:             Id,
+	pub name:           String,
+	pub parent_id:      Option<Id>,
+	pub role:           Option<String>,
+	pub sort_order:     u64,
+	pub total_emails:   u64,
+	pub unread_emails:  u64,
+	pub total_threads:  u64,
+	pub unread_threads: u64,
+	pub my_rights:      MailboxRights,
+	pub is_subscribed:  bool,
 	// "hidden": 0,
 	// "purgeOlderThanDays": 31,
 	// "identityRef": null,
@@ -35,13 +61,1341 @@ pub struct Mailbox {
 #[derive(Deserialize, Serialize, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct MailboxRights {
-	may_read_items:   bool,
-	may_add_items:    bool,
-	may_remove_items: bool,
-	may_set_seen:     bool,
-	may_set_keywords: bool,
-	may_create_child: bool,
-	may_rename:       bool,
-	may_delete:       bool,
-	may_submit:       bool,
+	pub may_read_items:   bool,
+	pub may_add_items:    bool,
+	pub may_remove_items: bool,
+	pub may_set_seen:     bool,
+	pub may_set_keywords: bool,
+	pub may_create_child: bool,
+	pub may_rename:       bool,
+	pub may_delete:       bool,
+	pub may_submit:       bool,
+}
+
+impl Mailbox {
+	pub const PROPERTIES: &'static [&'static str] = &[
+		"id",
+		"name",
+		"parentId",
+		"role",
+		"sortOrder",
+		"totalEmails",
+		"unreadEmails",
+		"totalThreads",
+		"unreadThreads",
+		"myRights",
+		"isSubscribed",
+	];
+}
+
+/// Mailbox ids for servers without `OBJECTID`, persisted per account.
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MailboxIdMap {
+	mailboxes: Vec<MailboxIdEntry>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MailboxIdEntry {
+	id:           Id,
+	name:         String,
+	uid_validity: u32,
+}
+
+impl MailboxIdMap {
+	pub const STORE_NAME: &'static str = "mailbox-ids";
+
+	/// Assigns ids to `mailboxes` (name and uidvalidity), keeping the ids of
+	/// known mailboxes and of mailboxes that were renamed since the last call.
+	pub fn resolve(&mut self, mailboxes: &[(&str, u32)]) -> Vec<Id> {
+		let mut ids: Vec<Option<Id>> = mailboxes
+			.iter()
+			.map(|(name, uid_validity)| {
+				self.mailboxes
+					.iter()
+					.find(|e| e.name == *name && e.uid_validity == *uid_validity)
+					.map(|e| e.id.clone())
+			})
+			.collect();
+
+		// a mailbox that vanished and a new one with the same uidvalidity showed
+		// up, that's a rename as long as there is only one candidate
+		let vanished: Vec<&MailboxIdEntry> = self
+			.mailboxes
+			.iter()
+			.filter(|e| !mailboxes.iter().any(|(name, _)| *name == e.name))
+			.collect();
+		for (i, (_, uid_validity)) in mailboxes.iter().enumerate() {
+			if ids[i].is_some() || *uid_validity == 0 {
+				continue;
+			}
+
+			let candidates: Vec<&&MailboxIdEntry> = vanished
+				.iter()
+				.filter(|e| e.uid_validity == *uid_validity)
+				.collect();
+			let unclaimed = mailboxes
+				.iter()
+				.enumerate()
+				.filter(|(j, (_, v))| ids[*j].is_none() && v == uid_validity)
+				.count() == 1;
+			if let ([entry], true) = (candidates.as_slice(), unclaimed) {
+				ids[i] = Some(entry.id.clone());
+			}
+		}
+
+		self.mailboxes = mailboxes
+			.iter()
+			.zip(ids)
+			.map(|((name, uid_validity), id)| MailboxIdEntry {
+				id:           id.unwrap_or_else(|| generate_mailbox_id(name, *uid_validity)),
+				name:         name.to_string(),
+				uid_validity: *uid_validity,
+			})
+			.collect();
+
+		self.mailboxes.iter().map(|e| e.id.clone()).collect()
+	}
+
+	/// Follows a rename done through the proxy, including the inferiors that
+	/// were renamed along with the mailbox.
+	pub fn rename(&mut self, from: &str, to: &str, delimiter: Option<&str>) {
+		for entry in self.mailboxes.iter_mut() {
+			if entry.name == from {
+				entry.name = to.to_owned();
+			} else if let Some(rest) = delimiter
+				.and_then(|d| entry.name.strip_prefix(from)?.strip_prefix(d))
+				.map(|rest| rest.to_owned())
+			{
+				entry.name = format!("{}{}{}", to, delimiter.unwrap_or_default(), rest);
+			}
+		}
+	}
+}
+
+/// Deterministic id for a mailbox we haven't seen before.
+fn generate_mailbox_id(name: &str, uid_validity: u32) -> Id {
+	let hash = fnv1a(
+		name.bytes()
+			.chain(uid_validity.to_be_bytes().iter().copied()),
+	);
+	format!("M{:016x}", hash)
+}
+
+const MAILBOX_CHANGES_STORE_NAME: &str = "mailbox-changes";
+
+const SPECIAL_USE_ROLES: &[(&str, &str)] = &[
+	("\\All", "all"),
+	("\\Archive", "archive"),
+	("\\Drafts", "drafts"),
+	("\\Flagged", "flagged"),
+	("\\Important", "important"),
+	("\\Junk", "junk"),
+	("\\Sent", "sent"),
+	("\\Trash", "trash"),
+];
+
+const DEFAULT_ROLE_NAMES: &[(&str, &[&str])] = &[
+	("archive", &["Archive", "Archives", "Archiv"]),
+	(
+		"drafts",
+		&["Drafts", "Entwürfe", "Brouillons", "Borradores", "Bozze"],
+	),
+	(
+		"junk",
+		&["Junk", "Spam", "Junk E-mail", "Junk-E-Mail", "Indésirables"],
+	),
+	(
+		"sent",
+		&[
+			"Sent",
+			"Sent Items",
+			"Sent Messages",
+			"Gesendet",
+			"Gesendete Objekte",
+			"Gesendete Elemente",
+			"Envoyés",
+			"Enviados",
+			"Posta inviata",
+		],
+	),
+	(
+		"trash",
+		&[
+			"Trash",
+			"Deleted Items",
+			"Deleted Messages",
+			"Papierkorb",
+			"Gelöschte Objekte",
+			"Gelöschte Elemente",
+			"Corbeille",
+			"Papelera",
+			"Cestino",
+		],
+	),
+];
+
+/// Maps lowercased folder names to roles, used for servers without SPECIAL-USE.
+///
+/// Extended by `MAILBOX_ROLE_NAMES`, e.g. `trash=Bin,Mülleimer;sent=Postausgang`.
+pub fn role_names_from_env() -> HashMap<String, String> {
+	let mut names = HashMap::new();
+	for (role, role_names) in DEFAULT_ROLE_NAMES {
+		for name in role_names.iter() {
+			names.insert(name.to_lowercase(), role.to_string());
+		}
+	}
+
+	if let Ok(config) = std::env::var("MAILBOX_ROLE_NAMES") {
+		for entry in config.split(';') {
+			let (role, role_names) = match entry.split_once('=') {
+				Some(e) => e,
+				None => {
+					tracing::warn!("ignoring invalid MAILBOX_ROLE_NAMES entry `{}`", entry);
+					continue;
+				}
+			};
+			for name in role_names.split(',') {
+				names.insert(name.trim().to_lowercase(), role.trim().to_owned());
+			}
+		}
+	}
+
+	names
+}
+
+fn is_inbox(info: &imap::MailboxInfo) -> bool {
+	info.name.eq_ignore_ascii_case("INBOX")
+}
+
+fn special_use_role(info: &imap::MailboxInfo) -> Option<&'static str> {
+	SPECIAL_USE_ROLES
+		.iter()
+		.find(|(attribute, _)| info.has_attribute(attribute))
+		.map(|(_, role)| *role)
+}
+
+/// Picks a role for every mailbox, each role is only given out once.
+///
+/// SPECIAL-USE attributes take precedence over the name based fallback, which
+/// only looks at top level mailboxes and direct children of the inbox.
+fn assign_roles(
+	infos: &[(Id, imap::MailboxInfo)],
+	role_names: &HashMap<String, String>,
+) -> Vec<Option<String>> {
+	let mut roles: Vec<Option<String>> = infos
+		.iter()
+		.map(|(_, info)| {
+			if is_inbox(info) {
+				Some("inbox".to_owned())
+			} else {
+				special_use_role(info).map(|r| r.to_owned())
+			}
+		})
+		.collect();
+
+	let mut seen = HashSet::new();
+	for role in roles.iter_mut() {
+		if role.as_ref().is_some_and(|r| !seen.insert(r.clone())) {
+			*role = None;
+		}
+	}
+
+	for (i, (_, info)) in infos.iter().enumerate() {
+		let top_level = info
+			.parent_name()
+			.is_none_or(|p| p.eq_ignore_ascii_case("INBOX"));
+		if roles[i].is_some() || !top_level {
+			continue;
+		}
+
+		if let Some(role) = role_names.get(&info.display_name().to_lowercase()) {
+			if seen.insert(role.clone()) {
+				roles[i] = Some(role.clone());
+			}
+		}
+	}
+
+	roles
+}
+
+fn mailbox_from_info(
+	id: &Id,
+	info: &imap::MailboxInfo,
+	role: Option<String>,
+	ids: &HashMap<&str, &Id>,
+) -> Mailbox {
+	let selectable = info.is_selectable();
+	let inbox = is_inbox(info);
+
+	Mailbox {
+		id: id.clone(),
+		name: info.display_name(),
+		parent_id: info
+			.parent_name()
+			.and_then(|parent| ids.get(parent))
+			.map(|id| id.to_string()),
+		role,
+		sort_order: 0,
+		total_emails: info.total.into(),
+		unread_emails: info.unseen.into(),
+		total_threads: 0,
+		unread_threads: 0,
+		my_rights: MailboxRights {
+			may_read_items:   selectable,
+			may_add_items:    selectable,
+			may_remove_items: selectable,
+			may_set_seen:     selectable,
+			may_set_keywords: selectable,
+			may_create_child: !info.has_attribute("\\Noinferiors"),
+			may_rename:       !inbox,
+			may_delete:       !inbox,
+			may_submit:       selectable,
+		},
+		is_subscribed: info.is_subscribed,
+	}
+}
+
+/// The mailboxes as the change log records them, thread counts are left out
+/// since they are only counted when asked for.
+fn mailbox_objects(mailboxes: &[Mailbox]) -> Result<Vec<(Id, serde_json::Value)>, MethodError> {
+	let mut objects = changes::to_objects(mailboxes.iter().map(|m| (&m.id, m)))?;
+	for (_, object) in &mut objects {
+		if let Some(object) = object.as_object_mut() {
+			for property in THREAD_COUNT_PROPERTIES {
+				object.remove(*property);
+			}
+		}
+	}
+	Ok(objects)
+}
+
+impl JmapApi<'_> {
+	/// Lists all imap mailboxes together with their jmap ids.
+	pub async fn fetch_mailboxes(&self) -> Result<Vec<(Id, imap::MailboxInfo)>, MethodError> {
+		let (special_use, condstore) = self
+			.with_raw_session(|s| async move {
+				Ok((
+					s.has_capability("SPECIAL-USE"),
+					s.has_capability("CONDSTORE"),
+				))
+			})
+			.await?;
+		let infos = self
+			.with_imap_session(|mut s| async move {
+				Ok(imap::list_mailboxes(&mut s, special_use, condstore).await?)
+			})
+			.await?;
+
+		let names: Vec<String> = infos
+			.iter()
+			.filter(|i| i.is_selectable())
+			.map(|i| i.name.clone())
+			.collect();
+		let object_ids = self
+			.with_raw_session(|mut s| async move {
+				let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+				Ok(imap::mailbox_object_ids(&mut s, &names).await?)
+			})
+			.await?;
+
+		let without_object_id: Vec<(&str, u32)> = infos
+			.iter()
+			.filter(|i| !object_ids.contains_key(&i.name))
+			.map(|i| (i.name.as_str(), i.uid_validity))
+			.collect();
+		let mut generated = self
+			.state
+			.store
+			.update(
+				self.account_id(),
+				MailboxIdMap::STORE_NAME,
+				|map: &mut MailboxIdMap| map.resolve(&without_object_id),
+			)
+			.await?
+			.into_iter();
+
+		Ok(infos
+			.into_iter()
+			.map(|info| {
+				let id = match object_ids.get(&info.name) {
+					Some(id) => id.clone(),
+					None => generated.next().unwrap_or_default(),
+				};
+				(id, info)
+			})
+			.collect())
+	}
+
+	pub fn build_mailboxes(&self, infos: &[(Id, imap::MailboxInfo)]) -> Vec<Mailbox> {
+		let ids_by_name: HashMap<&str, &Id> =
+			infos.iter().map(|(id, i)| (i.name.as_str(), id)).collect();
+		let roles = assign_roles(infos, &self.state.role_names);
+
+		infos
+			.iter()
+			.zip(roles)
+			.map(|((id, info), role)| mailbox_from_info(id, info, role, &ids_by_name))
+			.collect()
+	}
+
+	/// [`JmapApi::build_mailboxes`] with thread counts, which need every
+	/// message indexed.
+	pub async fn build_mailboxes_with_threads(
+		&self,
+		infos: &[(Id, imap::MailboxInfo)],
+	) -> Result<Vec<Mailbox>, MethodError> {
+		let (index, threads) = self.threads(infos).await?;
+		let counts = threads.counts(&index);
+
+		let mut mailboxes = self.build_mailboxes(infos);
+		for mailbox in &mut mailboxes {
+			if let Some((total, unread)) = counts.get(&mailbox.id) {
+				mailbox.total_threads = *total;
+				mailbox.unread_threads = *unread;
+			}
+		}
+		Ok(mailboxes)
+	}
+
+	/// Records the current mailboxes in the change log and returns the state.
+	async fn mailbox_state(&self, mailboxes: &[Mailbox]) -> Result<String, MethodError> {
+		self.record_state(MAILBOX_CHANGES_STORE_NAME, &mailbox_objects(mailboxes)?)
+			.await
+	}
+
+	pub async fn handle_mailbox_get(
+		&self,
+		account_id: String,
+		ids: Option<Vec<Id>>,
+		properties: Option<Vec<String>>,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&account_id)?;
+
+		let infos = self.fetch_mailboxes().await?;
+		// counting threads needs every message indexed
+		let count_threads = ids.as_ref().is_none_or(|ids| !ids.is_empty())
+			&& properties.as_ref().is_none_or(|properties| {
+				properties
+					.iter()
+					.any(|p| THREAD_COUNT_PROPERTIES.contains(&p.as_str()))
+			});
+		let mailboxes = if count_threads {
+			self.build_mailboxes_with_threads(&infos).await?
+		} else {
+			self.build_mailboxes(&infos)
+		};
+		let state = self.mailbox_state(&mailboxes).await?;
+
+		let (list, not_found) = match ids {
+			Some(ids) => {
+				let mut list = vec![];
+				let mut not_found = vec![];
+				for id in ids {
+					match mailboxes.iter().find(|m| m.id == id) {
+						Some(m) => list.push(m),
+						None => not_found.push(id),
+					}
+				}
+				(list, not_found)
+			}
+			None => (mailboxes.iter().collect(), vec![]),
+		};
+
+		Ok(MethodResult::MailboxGet {
+			account_id,
+			state,
+			list: super::select_properties(list, &properties, Mailbox::PROPERTIES)?,
+			not_found,
+		})
+	}
+}
+
+const MAX_SIZE_MAILBOX_NAME: usize = 490;
+
+/// Turns a NO from the server into a per-object error, everything else fails
+/// the whole method call.
+pub fn imap_set_result<T>(
+	result: async_imap::error::Result<T>,
+) -> Result<Result<T, SetError>, MethodError> {
+	match result {
+		Ok(t) => Ok(Ok(t)),
+		Err(async_imap::error::Error::No(text)) => Ok(Err(SetError::forbidden(text))),
+		Err(e) => Err(e.into()),
+	}
+}
+
+fn validate_name(name: &serde_json::Value, delimiter: Option<&str>) -> Result<String, SetError> {
+	let name = match name.as_str() {
+		Some(name) if !name.is_empty() => name,
+		_ => {
+			return Err(SetError::invalid_properties(
+				&["name"],
+				"name must be a non-empty string",
+			))
+		}
+	};
+
+	if name.len() > MAX_SIZE_MAILBOX_NAME {
+		return Err(SetError::invalid_properties(&["name"], "name is too long"));
+	}
+	if name.chars().any(char::is_control) {
+		return Err(SetError::invalid_properties(
+			&["name"],
+			"name must not contain control characters",
+		));
+	}
+	if delimiter.is_some_and(|d| name.contains(d)) {
+		return Err(SetError::invalid_properties(
+			&["name"],
+			"name must not contain the hierarchy delimiter",
+		));
+	}
+
+	Ok(name.to_owned())
+}
+
+fn special_use_attribute(role: &str) -> Option<&'static str> {
+	SPECIAL_USE_ROLES
+		.iter()
+		.find(|(_, r)| *r == role)
+		.map(|(attribute, _)| *attribute)
+}
+
+/// The properties of a mailbox to create.
+type Object = serde_json::Map<String, serde_json::Value>;
+
+/// Orders creates so that parents referred to by `#creationId` are created
+/// before their children. Creates whose parents form a cycle are returned
+/// apart, they can't be created.
+fn order_creates(create: HashMap<Id, Object>) -> (Vec<(Id, Object)>, Vec<Id>) {
+	let parent = |object: &Object| {
+		object
+			.get("parentId")
+			.and_then(serde_json::Value::as_str)
+			.and_then(|p| p.strip_prefix('#'))
+			.map(str::to_owned)
+	};
+
+	let mut pending: BTreeMap<Id, _> = create.into_iter().collect();
+	let mut ordered = Vec::with_capacity(pending.len());
+	loop {
+		let ready: Vec<Id> = pending
+			.iter()
+			.filter(|(_, object)| parent(object).is_none_or(|p| !pending.contains_key(&p)))
+			.map(|(creation_id, _)| creation_id.clone())
+			.collect();
+		if ready.is_empty() {
+			break;
+		}
+		for creation_id in ready {
+			let object = pending.remove(&creation_id).unwrap_or_default();
+			ordered.push((creation_id, object));
+		}
+	}
+	(ordered, pending.into_keys().collect())
+}
+
+impl JmapApi<'_> {
+	pub async fn handle_mailbox_set(
+		&self,
+		request: SetRequest,
+		on_destroy_remove_emails: bool,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let mut response = SetResponse {
+			account_id: request.account_id.clone(),
+			..Default::default()
+		};
+
+		let mut infos = self.fetch_mailboxes().await?;
+
+		let old_state = self.mailbox_state(&self.build_mailboxes(&infos)).await?;
+		if request
+			.if_in_state
+			.as_ref()
+			.is_some_and(|s| *s != old_state)
+		{
+			return Err(MethodError::StateMismatch);
+		}
+		response.old_state = Some(old_state);
+
+		let (create, cycles) = order_creates(request.create.unwrap_or_default());
+		for creation_id in cycles {
+			response.not_created.insert(
+				creation_id,
+				SetError::invalid_properties(&["parentId"], "parent mailboxes form a cycle"),
+			);
+		}
+		for (creation_id, object) in create {
+			match self.create_mailbox(&infos, &object).await? {
+				Ok(name) => {
+					infos = self.fetch_mailboxes().await?;
+					let created = self
+						.build_mailboxes(&infos)
+						.into_iter()
+						.zip(infos.iter())
+						.find(|(_, (_, info))| info.name == name)
+						.map(|(mailbox, _)| mailbox);
+
+					match created {
+						Some(mailbox) => {
+							self.record_created_id(&creation_id, &mailbox.id);
+							response.created.insert(
+								creation_id,
+								serde_json::to_value(mailbox).map_err(tide::Error::from)?,
+							);
+						}
+						None => {
+							response.not_created.insert(
+								creation_id,
+								SetError::forbidden("mailbox vanished after creating it"),
+							);
+						}
+					}
+				}
+				Err(e) => {
+					response.not_created.insert(creation_id, e);
+				}
+			}
+		}
+
+		for (id, patch) in request.update.unwrap_or_default() {
+			match self.update_mailbox(&infos, &id, &patch).await? {
+				Ok(()) => {
+					infos = self.fetch_mailboxes().await?;
+					response.updated.insert(id, None);
+				}
+				Err(e) => {
+					response.not_updated.insert(id, e);
+				}
+			}
+		}
+
+		for id in request.destroy.unwrap_or_default() {
+			match self
+				.destroy_mailbox(&infos, &id, on_destroy_remove_emails)
+				.await?
+			{
+				Ok(()) => {
+					infos = self.fetch_mailboxes().await?;
+					response.destroyed.push(id);
+				}
+				Err(e) => {
+					response.not_destroyed.insert(id, e);
+				}
+			}
+		}
+
+		response.new_state = self.mailbox_state(&self.build_mailboxes(&infos)).await?;
+
+		Ok(MethodResult::MailboxSet(response))
+	}
+
+	fn default_delimiter(infos: &[(Id, imap::MailboxInfo)]) -> Option<String> {
+		infos.iter().find_map(|(_, info)| info.delimiter.clone())
+	}
+
+	/// Resolves a `parentId` value to the imap mailbox it refers to.
+	fn resolve_parent<'i>(
+		&self,
+		infos: &'i [(Id, imap::MailboxInfo)],
+		parent_id: &serde_json::Value,
+	) -> Result<Option<&'i imap::MailboxInfo>, SetError> {
+		let parent_id = match parent_id {
+			serde_json::Value::Null => return Ok(None),
+			serde_json::Value::String(id) => id,
+			_ => {
+				return Err(SetError::invalid_properties(
+					&["parentId"],
+					"parentId must be a string or null",
+				))
+			}
+		};
+
+		let parent = self
+			.resolve_id(parent_id)
+			.and_then(|id| infos.iter().find(|(i, _)| *i == id))
+			.map(|(_, info)| info);
+
+		match parent {
+			Some(parent) if parent.has_attribute("\\Noinferiors") => Err(
+				SetError::invalid_properties(&["parentId"], "parent mailbox can't have children"),
+			),
+			Some(parent) => Ok(Some(parent)),
+			None => Err(SetError::invalid_properties(
+				&["parentId"],
+				"parent mailbox not found",
+			)),
+		}
+	}
+
+	/// Builds the full imap name for `name` below `parent`.
+	fn imap_name(
+		infos: &[(Id, imap::MailboxInfo)],
+		parent: Option<&imap::MailboxInfo>,
+		name: &str,
+	) -> Result<String, SetError> {
+		let name = imap::encode_mailbox_name(name);
+		let parent = match parent {
+			Some(parent) => parent,
+			None => return Ok(name),
+		};
+
+		match parent
+			.delimiter
+			.clone()
+			.or_else(|| Self::default_delimiter(infos))
+		{
+			Some(delimiter) => Ok(format!("{}{}{}", parent.name, delimiter, name)),
+			None => Err(SetError::invalid_properties(
+				&["parentId"],
+				"the server doesn't support nested mailboxes",
+			)),
+		}
+	}
+
+	async fn create_mailbox(
+		&self,
+		infos: &[(Id, imap::MailboxInfo)],
+		object: &serde_json::Map<String, serde_json::Value>,
+	) -> Result<Result<String, SetError>, MethodError> {
+		if let Some(key) = object.keys().find(|k| {
+			!["name", "parentId", "role", "sortOrder", "isSubscribed"].contains(&k.as_str())
+		}) {
+			return Ok(Err(SetError::invalid_properties(
+				&[key],
+				"property can't be set",
+			)));
+		}
+
+		let parent = match self.resolve_parent(
+			infos,
+			object.get("parentId").unwrap_or(&serde_json::Value::Null),
+		) {
+			Ok(p) => p,
+			Err(e) => return Ok(Err(e)),
+		};
+		let delimiter = parent
+			.and_then(|p| p.delimiter.clone())
+			.or_else(|| Self::default_delimiter(infos));
+		let name = match validate_name(
+			object.get("name").unwrap_or(&serde_json::Value::Null),
+			delimiter.as_deref(),
+		) {
+			Ok(n) => n,
+			Err(e) => return Ok(Err(e)),
+		};
+		let full_name = match Self::imap_name(infos, parent, &name) {
+			Ok(n) => n,
+			Err(e) => return Ok(Err(e)),
+		};
+
+		if let Some((existing_id, _)) = infos.iter().find(|(_, i)| i.name == full_name) {
+			return Ok(Err(SetError::AlreadyExists {
+				existing_id: existing_id.clone(),
+			}));
+		}
+
+		if object
+			.get("sortOrder")
+			.is_some_and(|o| o.as_u64() != Some(0))
+		{
+			return Ok(Err(SetError::invalid_properties(
+				&["sortOrder"],
+				"custom sort orders are not supported",
+			)));
+		}
+
+		let subscribe = match object.get("isSubscribed") {
+			None => true,
+			Some(serde_json::Value::Bool(b)) => *b,
+			Some(_) => {
+				return Ok(Err(SetError::invalid_properties(
+					&["isSubscribed"],
+					"isSubscribed must be a boolean",
+				)))
+			}
+		};
+
+		let created = match object.get("role") {
+			None | Some(serde_json::Value::Null) => {
+				let full_name = full_name.clone();
+				self.with_imap_session(|mut s| async move { Ok(s.create(full_name).await) })
+					.await?
+			}
+			Some(role) => {
+				let attribute = match role.as_str().and_then(special_use_attribute) {
+					Some(a) => a,
+					None => {
+						return Ok(Err(SetError::invalid_properties(&["role"], "unknown role")))
+					}
+				};
+
+				let roles = assign_roles(infos, &self.state.role_names);
+				if roles
+					.iter()
+					.flatten()
+					.any(|r| Some(r.as_str()) == role.as_str())
+				{
+					return Ok(Err(SetError::invalid_properties(
+						&["role"],
+						"another mailbox already has this role",
+					)));
+				}
+
+				let command = format!("CREATE {} (USE ({}))", raw::quote(&full_name), attribute);
+				let created = self
+					.with_raw_session(|mut s| async move {
+						if !s.has_capability("CREATE-SPECIAL-USE") {
+							return Ok(Err(async_imap::error::Error::No(
+								"the server doesn't support setting roles".to_owned(),
+							)));
+						}
+						Ok(s.command(&command).await.map(|_| ()))
+					})
+					.await?;
+				created.map_err(|e| match e {
+					async_imap::error::Error::No(text) => {
+						async_imap::error::Error::No(format!("role: {}", text))
+					}
+					e => e,
+				})
+			}
+		};
+		if let Err(e) = imap_set_result(created)? {
+			return Ok(Err(e));
+		}
+
+		if subscribe {
+			let full_name = full_name.clone();
+			let subscribed = self
+				.with_imap_session(|mut s| async move { Ok(s.subscribe(full_name).await) })
+				.await?;
+			if let Err(e) = imap_set_result(subscribed)? {
+				return Ok(Err(e));
+			}
+		}
+
+		Ok(Ok(full_name))
+	}
+
+	async fn update_mailbox(
+		&self,
+		infos: &[(Id, imap::MailboxInfo)],
+		id: &str,
+		patch: &serde_json::Map<String, serde_json::Value>,
+	) -> Result<Result<(), SetError>, MethodError> {
+		let index = match infos.iter().position(|(i, _)| i == id) {
+			Some(i) => i,
+			None => return Ok(Err(SetError::NotFound)),
+		};
+		let info = &infos[index].1;
+		let mailbox = self.build_mailboxes(infos).swap_remove(index);
+
+		if let Some(key) = patch.keys().find(|k| {
+			!["name", "parentId", "role", "sortOrder", "isSubscribed"].contains(&k.as_str())
+		}) {
+			return Ok(Err(SetError::invalid_properties(
+				&[key],
+				"property can't be changed",
+			)));
+		}
+
+		if let Some(role) = patch.get("role") {
+			if role.as_str() != mailbox.role.as_deref() {
+				return Ok(Err(SetError::invalid_properties(
+					&["role"],
+					"changing the role of an existing mailbox is not supported",
+				)));
+			}
+		}
+
+		if patch
+			.get("sortOrder")
+			.is_some_and(|o| o.as_u64() != Some(0))
+		{
+			return Ok(Err(SetError::invalid_properties(
+				&["sortOrder"],
+				"custom sort orders are not supported",
+			)));
+		}
+
+		let mut name = info.name.clone();
+		if patch.contains_key("name") || patch.contains_key("parentId") {
+			let parent = match patch.get("parentId") {
+				Some(parent_id) => match self.resolve_parent(infos, parent_id) {
+					Ok(p) => p,
+					Err(e) => return Ok(Err(e)),
+				},
+				None => info
+					.parent_name()
+					.and_then(|p| infos.iter().find(|(_, i)| i.name == p))
+					.map(|(_, i)| i),
+			};
+
+			let delimiter = info
+				.delimiter
+				.clone()
+				.or_else(|| Self::default_delimiter(infos));
+			let display_name = match patch.get("name") {
+				Some(n) => match validate_name(n, delimiter.as_deref()) {
+					Ok(n) => n,
+					Err(e) => return Ok(Err(e)),
+				},
+				None => info.display_name(),
+			};
+
+			name = match Self::imap_name(infos, parent, &display_name) {
+				Ok(n) => n,
+				Err(e) => return Ok(Err(e)),
+			};
+
+			if let (Some(parent), Some(delimiter)) = (parent, delimiter.as_deref()) {
+				let prefix = format!("{}{}", info.name, delimiter);
+				if parent.name == info.name || parent.name.starts_with(&prefix) {
+					return Ok(Err(SetError::invalid_properties(
+						&["parentId"],
+						"a mailbox can't be moved below itself",
+					)));
+				}
+			}
+		}
+
+		if name != info.name {
+			if is_inbox(info) {
+				return Ok(Err(SetError::forbidden("the inbox can't be renamed")));
+			}
+			if let Some((existing_id, _)) = infos.iter().find(|(_, i)| i.name == name) {
+				return Ok(Err(SetError::AlreadyExists {
+					existing_id: existing_id.clone(),
+				}));
+			}
+
+			let (from, to) = (info.name.clone(), name.clone());
+			let renamed = self
+				.with_imap_session(|mut s| async move { Ok(s.rename(from, to).await) })
+				.await?;
+			if let Err(e) = imap_set_result(renamed)? {
+				return Ok(Err(e));
+			}
+
+			let (from, to, delimiter) = (info.name.clone(), name.clone(), info.delimiter.clone());
+			self.state
+				.store
+				.update(
+					self.account_id(),
+					MailboxIdMap::STORE_NAME,
+					|map: &mut MailboxIdMap| map.rename(&from, &to, delimiter.as_deref()),
+				)
+				.await?;
+
+			// not every server moves the subscription along
+			if info.is_subscribed {
+				let (from, to) = (info.name.clone(), name.clone());
+				self.with_imap_session(|mut s| async move {
+					s.unsubscribe(from).await.ok();
+					Ok(s.subscribe(to).await.ok())
+				})
+				.await?;
+			}
+		}
+
+		match patch.get("isSubscribed") {
+			None => {}
+			Some(serde_json::Value::Bool(subscribe)) => {
+				let (subscribe, name) = (*subscribe, name.clone());
+				let result = self
+					.with_imap_session(|mut s| async move {
+						Ok(if subscribe {
+							s.subscribe(name).await
+						} else {
+							s.unsubscribe(name).await
+						})
+					})
+					.await?;
+				if let Err(e) = imap_set_result(result)? {
+					return Ok(Err(e));
+				}
+			}
+			Some(_) => {
+				return Ok(Err(SetError::invalid_properties(
+					&["isSubscribed"],
+					"isSubscribed must be a boolean",
+				)))
+			}
+		}
+
+		Ok(Ok(()))
+	}
+
+	async fn destroy_mailbox(
+		&self,
+		infos: &[(Id, imap::MailboxInfo)],
+		id: &str,
+		remove_emails: bool,
+	) -> Result<Result<(), SetError>, MethodError> {
+		let info = match infos.iter().find(|(i, _)| i == id) {
+			Some((_, info)) => info,
+			None => return Ok(Err(SetError::NotFound)),
+		};
+
+		if is_inbox(info) {
+			return Ok(Err(SetError::forbidden("the inbox can't be destroyed")));
+		}
+		if infos
+			.iter()
+			.any(|(_, i)| i.parent_name() == Some(info.name.as_str()))
+		{
+			return Ok(Err(SetError::MailboxHasChild));
+		}
+		if info.total > 0 && !remove_emails {
+			return Ok(Err(SetError::MailboxHasEmail));
+		}
+
+		let name = info.name.clone();
+		let deleted = self
+			.with_imap_session(|mut s| async move {
+				let deleted = s.delete(&name).await;
+				if deleted.is_ok() {
+					// some servers keep subscriptions of deleted mailboxes around
+					s.unsubscribe(&name).await.ok();
+				}
+				Ok(deleted)
+			})
+			.await?;
+
+		imap_set_result(deleted)
+	}
+}
+
+const COUNT_PROPERTIES: &[&str] = &["totalEmails", "unreadEmails"];
+
+const THREAD_COUNT_PROPERTIES: &[&str] = &["totalThreads", "unreadThreads"];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MailboxFilterCondition {
+	#[serde(default, deserialize_with = "double_option")]
+	parent_id:     Option<Option<Id>>,
+	name:          Option<String>,
+	#[serde(default, deserialize_with = "double_option")]
+	role:          Option<Option<String>>,
+	has_any_role:  Option<bool>,
+	is_subscribed: Option<bool>,
+}
+
+impl MailboxFilterCondition {
+	fn matches(&self, mailbox: &Mailbox) -> bool {
+		self.parent_id
+			.as_ref()
+			.is_none_or(|p| *p == mailbox.parent_id)
+			&& self
+				.name
+				.as_ref()
+				.is_none_or(|n| mailbox.name.to_lowercase().contains(&n.to_lowercase()))
+			&& self.role.as_ref().is_none_or(|r| *r == mailbox.role)
+			&& self
+				.has_any_role
+				.is_none_or(|h| h == mailbox.role.is_some())
+			&& self
+				.is_subscribed
+				.is_none_or(|s| s == mailbox.is_subscribed)
+	}
+}
+
+fn compare_mailboxes(sort: &[Comparator], a: &Mailbox, b: &Mailbox) -> Ordering {
+	sort.iter()
+		.map(|c| {
+			let ordering = match c.property.as_str() {
+				"sortOrder" => a.sort_order.cmp(&b.sort_order),
+				_ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+			};
+			if c.is_ascending {
+				ordering
+			} else {
+				ordering.reverse()
+			}
+		})
+		.find(|o| *o != Ordering::Equal)
+		.unwrap_or_else(|| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)))
+}
+
+/// Runs a Mailbox/query over all mailboxes and returns the full result.
+fn query_mailboxes(
+	mailboxes: &[Mailbox],
+	filter: &Option<Filter<MailboxFilterCondition>>,
+	sort: &[Comparator],
+	sort_as_tree: bool,
+	filter_as_tree: bool,
+) -> Result<Vec<Id>, MethodError> {
+	for c in sort {
+		if c.property != "sortOrder" && c.property != "name" {
+			return Err(MethodError::UnsupportedSort {
+				description: Some(format!("can't sort mailboxes by {}", c.property)),
+			});
+		}
+		// names are always compared case insensitively
+		if let Some(collation) = c.collation.as_deref() {
+			if !["i;unicode-casemap", "i;ascii-casemap"].contains(&collation) {
+				return Err(MethodError::UnsupportedSort {
+					description: Some(format!("unsupported collation {}", collation)),
+				});
+			}
+		}
+	}
+
+	let by_id: HashMap<&Id, &Mailbox> = mailboxes.iter().map(|m| (&m.id, m)).collect();
+	let matches = |m: &Mailbox| {
+		filter
+			.as_ref()
+			.is_none_or(|f| f.matches(&|c: &MailboxFilterCondition| c.matches(m)))
+	};
+	let included = |m: &Mailbox| {
+		if !matches(m) {
+			return false;
+		}
+		if !filter_as_tree {
+			return true;
+		}
+		// every ancestor has to match as well
+		let mut parent = m.parent_id.as_ref().and_then(|p| by_id.get(p));
+		while let Some(p) = parent {
+			if !matches(p) {
+				return false;
+			}
+			parent = p.parent_id.as_ref().and_then(|p| by_id.get(p));
+		}
+		true
+	};
+
+	let mut sorted: Vec<&Mailbox> = mailboxes.iter().collect();
+	sorted.sort_by(|a, b| compare_mailboxes(sort, a, b));
+
+	if sort_as_tree {
+		let mut tree = Vec::with_capacity(sorted.len());
+		let mut stack: Vec<&Mailbox> = sorted
+			.iter()
+			.rev()
+			.filter(|m| m.parent_id.as_ref().is_none_or(|p| !by_id.contains_key(p)))
+			.copied()
+			.collect();
+		while let Some(m) = stack.pop() {
+			tree.push(m);
+			stack.extend(
+				sorted
+					.iter()
+					.rev()
+					.filter(|c| c.parent_id.as_ref() == Some(&m.id)),
+			);
+		}
+		sorted = tree;
+	}
+
+	Ok(sorted
+		.into_iter()
+		.filter(|m| included(m))
+		.map(|m| m.id.clone())
+		.collect())
+}
+
+impl JmapApi<'_> {
+	pub async fn handle_mailbox_changes(
+		&self,
+		request: ChangesRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let infos = self.fetch_mailboxes().await?;
+		let objects = mailbox_objects(&self.build_mailboxes(&infos))?;
+		let mut changes = self
+			.record_changes(
+				MAILBOX_CHANGES_STORE_NAME,
+				&objects,
+				&request.since_state,
+				request.max_changes,
+			)
+			.await?;
+
+		// only the counts changed, the client can skip refetching everything else
+		let updated_properties = if !changes.updated_properties.is_empty()
+			&& changes
+				.updated_properties
+				.iter()
+				.all(|p| COUNT_PROPERTIES.contains(&p.as_str()))
+		{
+			// thread counts aren't in the log, they change with the email counts
+			let thread_counts = THREAD_COUNT_PROPERTIES.iter().map(|p| p.to_string());
+			Some(
+				std::mem::take(&mut changes.updated_properties)
+					.into_iter()
+					.chain(thread_counts)
+					.collect(),
+			)
+		} else {
+			None
+		};
+
+		Ok(MethodResult::MailboxChanges {
+			changes: changes.into_response(request.account_id),
+			updated_properties,
+		})
+	}
+
+	pub async fn handle_mailbox_query(
+		&self,
+		request: QueryRequest<MailboxFilterCondition>,
+		sort_as_tree: bool,
+		filter_as_tree: bool,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let infos = self.fetch_mailboxes().await?;
+		let mailboxes = self.build_mailboxes(&infos);
+		let query_state = self.mailbox_state(&mailboxes).await?;
+
+		let ids = query_mailboxes(
+			&mailboxes,
+			&request.filter,
+			&request.sort,
+			sort_as_tree,
+			filter_as_tree,
+		)?;
+		let total = ids.len() as u64;
+		let page = query::page(
+			ids,
+			request.position,
+			request.anchor.as_ref(),
+			request.anchor_offset,
+			request.limit,
+		)?;
+
+		Ok(MethodResult::MailboxQuery(QueryResponse {
+			account_id: request.account_id,
+			query_state,
+			can_calculate_changes: true,
+			position: page.position,
+			ids: page.ids,
+			total: if request.calculate_total {
+				Some(total)
+			} else {
+				None
+			},
+			limit: None,
+		}))
+	}
+
+	pub async fn handle_mailbox_query_changes(
+		&self,
+		request: QueryChangesRequest<MailboxFilterCondition>,
+		sort_as_tree: bool,
+		filter_as_tree: bool,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let infos = self.fetch_mailboxes().await?;
+		let mailboxes = self.build_mailboxes(&infos);
+		let changes = self
+			.record_changes(
+				MAILBOX_CHANGES_STORE_NAME,
+				&mailbox_objects(&mailboxes)?,
+				&request.since_query_state,
+				None,
+			)
+			.await?;
+		// all changes at once, so that is the current state
+		let new_query_state = changes.new_state.clone();
+
+		let mut changed: BTreeSet<Id> = changes
+			.created
+			.into_iter()
+			.chain(changes.updated)
+			.chain(changes.destroyed)
+			.collect();
+		if sort_as_tree || filter_as_tree {
+			// moving or renaming a mailbox moves its whole subtree
+			let mut added = true;
+			while added {
+				added = false;
+				for m in &mailboxes {
+					if !changed.contains(&m.id)
+						&& m.parent_id.as_ref().is_some_and(|p| changed.contains(p))
+					{
+						changed.insert(m.id.clone());
+						added = true;
+					}
+				}
+			}
+		}
+
+		let current = query_mailboxes(
+			&mailboxes,
+			&request.filter,
+			&request.sort,
+			sort_as_tree,
+			filter_as_tree,
+		)?;
+		let (removed, added) = query::query_changes(&current, &changed, request.max_changes)?;
+
+		Ok(MethodResult::MailboxQueryChanges(QueryChangesResponse {
+			account_id: request.account_id,
+			old_query_state: request.since_query_state,
+			new_query_state,
+			total: if request.calculate_total {
+				Some(current.len() as u64)
+			} else {
+				None
+			},
+			removed,
+			added,
+		}))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	fn create(
+		objects: &[(&str, serde_json::Value)],
+	) -> HashMap<Id, serde_json::Map<String, serde_json::Value>> {
+		objects
+			.iter()
+			.map(|(id, object)| (id.to_string(), object.as_object().unwrap().clone()))
+			.collect()
+	}
+
+	#[test]
+	fn parents_are_created_first() {
+		let (ordered, cycles) = order_creates(create(&[
+			("a", json!({ "name": "a", "parentId": "#b" })),
+			("b", json!({ "name": "b", "parentId": "#c" })),
+			("c", json!({ "name": "c", "parentId": "M1" })),
+			("d", json!({ "name": "d", "parentId": null })),
+			// created by an earlier method call
+			("e", json!({ "name": "e", "parentId": "#earlier" })),
+		]));
+		let order: Vec<&str> = ordered.iter().map(|(id, _)| id.as_str()).collect();
+		assert_eq!(order, vec!["c", "d", "e", "b", "a"]);
+		assert!(cycles.is_empty());
+		assert_eq!(ordered[4].1["name"], "a");
+	}
+
+	#[test]
+	fn cycles_are_not_created() {
+		let (ordered, cycles) = order_creates(create(&[
+			("a", json!({ "name": "a", "parentId": "#b" })),
+			("b", json!({ "name": "b", "parentId": "#a" })),
+			("c", json!({ "name": "c", "parentId": "#c" })),
+			("d", json!({ "name": "d", "parentId": "#a" })),
+			("e", json!({ "name": "e" })),
+		]));
+		let order: Vec<&str> = ordered.iter().map(|(id, _)| id.as_str()).collect();
+		assert_eq!(order, vec!["e"]);
+		assert_eq!(cycles, vec!["a", "b", "c", "d"]);
+	}
 }
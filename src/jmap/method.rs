@@ -9,35 +9,363 @@ use serde::{
 	Serializer,
 };
 
-use crate::jmap::{mailbox::Mailbox, Id};
+use crate::jmap::{
+	blob::{
+		BlobGetRequest,
+		BlobGetResponse,
+		BlobLookupRequest,
+		BlobLookupResponse,
+		BlobUploadRequest,
+		BlobUploadResponse,
+	},
+	email::{
+		import::{EmailImportRequest, EmailImportResponse},
+		parse::{EmailParseRequest, EmailParseResponse},
+		search::EmailFilterCondition,
+		snippet::{SearchSnippetGetRequest, SearchSnippetGetResponse},
+		EmailGetRequest,
+	},
+	mailbox::MailboxFilterCondition,
+	push_subscription::{
+		PushSubscriptionGetRequest,
+		PushSubscriptionGetResponse,
+		PushSubscriptionSetRequest,
+		PushSubscriptionSetResponse,
+	},
+	quota::QuotaFilterCondition,
+	sieve_script::{
+		SieveScriptFilterCondition,
+		SieveScriptValidateRequest,
+		SieveScriptValidateResponse,
+	},
+	submission::EmailSubmissionFilterCondition,
+	ChangesRequest,
+	ChangesResponse,
+	CopyRequest,
+	CopyResponse,
+	GetRequest,
+	GetResponse,
+	Id,
+	QueryChangesRequest,
+	QueryChangesResponse,
+	QueryRequest,
+	QueryResponse,
+	SetRequest,
+	SetResponse,
+};
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "t", content = "c")]
 #[serde(rename_all = "camelCase")]
+#[allow(clippy::large_enum_variant)]
 pub enum Method {
 	#[serde(rename = "Core/echo")]
 	CoreEcho(serde_json::Map<String, serde_json::Value>),
-	#[serde(rename = "Mailbox/get")]
+	#[serde(rename = "Mailbox/get", rename_all = "camelCase")]
 	MailboxGet {
 		account_id: String,
 		ids:        Option<Vec<Id>>,
 		properties: Option<Vec<String>>,
 	},
+	#[serde(rename = "Mailbox/set", rename_all = "camelCase")]
+	MailboxSet {
+		#[serde(flatten)]
+		request:                  SetRequest,
+		#[serde(default)]
+		on_destroy_remove_emails: bool,
+	},
+	#[serde(rename = "Mailbox/changes")]
+	MailboxChanges(ChangesRequest),
+	#[serde(rename = "Mailbox/query", rename_all = "camelCase")]
+	MailboxQuery {
+		#[serde(flatten)]
+		request:        QueryRequest<MailboxFilterCondition>,
+		#[serde(default)]
+		sort_as_tree:   bool,
+		#[serde(default)]
+		filter_as_tree: bool,
+	},
+	#[serde(rename = "Mailbox/queryChanges", rename_all = "camelCase")]
+	MailboxQueryChanges {
+		#[serde(flatten)]
+		request:        QueryChangesRequest<MailboxFilterCondition>,
+		#[serde(default)]
+		sort_as_tree:   bool,
+		#[serde(default)]
+		filter_as_tree: bool,
+	},
+	#[serde(rename = "Email/get")]
+	EmailGet(EmailGetRequest),
+	#[serde(rename = "Email/set")]
+	EmailSet(SetRequest),
+	#[serde(rename = "Email/query", rename_all = "camelCase")]
+	EmailQuery {
+		#[serde(flatten)]
+		request:          QueryRequest<EmailFilterCondition>,
+		#[serde(default)]
+		collapse_threads: bool,
+	},
+	#[serde(rename = "Email/changes")]
+	EmailChanges(ChangesRequest),
+	#[serde(rename = "Email/copy")]
+	EmailCopy(CopyRequest),
+	#[serde(rename = "Email/import")]
+	EmailImport(EmailImportRequest),
+	#[serde(rename = "Email/parse")]
+	EmailParse(EmailParseRequest),
+	#[serde(rename = "Email/queryChanges", rename_all = "camelCase")]
+	EmailQueryChanges {
+		#[serde(flatten)]
+		request:          QueryChangesRequest<EmailFilterCondition>,
+		#[serde(default)]
+		collapse_threads: bool,
+	},
+	#[serde(rename = "SearchSnippet/get")]
+	SearchSnippetGet(SearchSnippetGetRequest),
+	#[serde(rename = "Thread/get")]
+	ThreadGet(GetRequest),
+	#[serde(rename = "Thread/changes")]
+	ThreadChanges(ChangesRequest),
+	#[serde(rename = "Identity/get")]
+	IdentityGet(GetRequest),
+	#[serde(rename = "Identity/changes")]
+	IdentityChanges(ChangesRequest),
+	#[serde(rename = "Identity/set")]
+	IdentitySet(SetRequest),
+	#[serde(rename = "EmailSubmission/get")]
+	EmailSubmissionGet(GetRequest),
+	#[serde(rename = "EmailSubmission/changes")]
+	EmailSubmissionChanges(ChangesRequest),
+	#[serde(rename = "EmailSubmission/query")]
+	EmailSubmissionQuery(QueryRequest<EmailSubmissionFilterCondition>),
+	#[serde(rename = "EmailSubmission/set", rename_all = "camelCase")]
+	EmailSubmissionSet {
+		#[serde(flatten)]
+		request:                  SetRequest,
+		on_success_update_email:  Option<HashMap<Id, serde_json::Map<String, serde_json::Value>>>,
+		on_success_destroy_email: Option<Vec<Id>>,
+	},
+	#[serde(rename = "Quota/get")]
+	QuotaGet(GetRequest),
+	#[serde(rename = "Quota/changes")]
+	QuotaChanges(ChangesRequest),
+	#[serde(rename = "Quota/query")]
+	QuotaQuery(QueryRequest<QuotaFilterCondition>),
+	#[serde(rename = "SieveScript/get")]
+	SieveScriptGet(GetRequest),
+	#[serde(rename = "SieveScript/query")]
+	SieveScriptQuery(QueryRequest<SieveScriptFilterCondition>),
+	#[serde(rename = "SieveScript/set", rename_all = "camelCase")]
+	SieveScriptSet {
+		#[serde(flatten)]
+		request: SetRequest,
+		on_success_activate_script: Option<Id>,
+		#[serde(default)]
+		on_success_deactivate_script: bool,
+	},
+	#[serde(rename = "SieveScript/validate")]
+	SieveScriptValidate(SieveScriptValidateRequest),
+	#[serde(rename = "VacationResponse/get")]
+	VacationResponseGet(GetRequest),
+	#[serde(rename = "VacationResponse/set")]
+	VacationResponseSet(SetRequest),
+	#[serde(rename = "Blob/upload")]
+	BlobUpload(BlobUploadRequest),
+	#[serde(rename = "Blob/get")]
+	BlobGet(BlobGetRequest),
+	#[serde(rename = "Blob/lookup")]
+	BlobLookup(BlobLookupRequest),
+	#[serde(rename = "PushSubscription/get")]
+	PushSubscriptionGet(PushSubscriptionGetRequest),
+	#[serde(rename = "PushSubscription/set")]
+	PushSubscriptionSet(PushSubscriptionSetRequest),
+	/// Arguments that failed to deserialize, or a method we don't know.
+	#[serde(skip)]
+	Invalid(String),
 }
 
 #[derive(Serialize, Debug)]
 #[serde(tag = "t", content = "c")]
 #[serde(rename_all = "camelCase")]
+#[allow(clippy::large_enum_variant)]
 pub enum MethodResult {
 	#[serde(rename = "Core/echo")]
 	CoreEcho(serde_json::Map<String, serde_json::Value>),
-	#[serde(rename = "Mailbox/get")]
+	#[serde(rename = "Mailbox/get", rename_all = "camelCase")]
 	MailboxGet {
 		account_id: String,
 		state:      String,
-		list:       Vec<Mailbox>,
+		list:       Vec<serde_json::Value>,
 		not_found:  Vec<Id>,
 	},
+	#[serde(rename = "Mailbox/set")]
+	MailboxSet(SetResponse),
+	#[serde(rename = "Mailbox/changes", rename_all = "camelCase")]
+	MailboxChanges {
+		#[serde(flatten)]
+		changes:            ChangesResponse,
+		updated_properties: Option<Vec<String>>,
+	},
+	#[serde(rename = "Mailbox/query")]
+	MailboxQuery(QueryResponse),
+	#[serde(rename = "Mailbox/queryChanges")]
+	MailboxQueryChanges(QueryChangesResponse),
+	#[serde(rename = "Email/get")]
+	EmailGet(GetResponse),
+	#[serde(rename = "Email/set")]
+	EmailSet(SetResponse),
+	#[serde(rename = "Email/query")]
+	EmailQuery(QueryResponse),
+	#[serde(rename = "Email/changes")]
+	EmailChanges(ChangesResponse),
+	#[serde(rename = "Email/copy")]
+	EmailCopy(CopyResponse),
+	#[serde(rename = "Email/import")]
+	EmailImport(EmailImportResponse),
+	#[serde(rename = "Email/parse")]
+	EmailParse(EmailParseResponse),
+	#[serde(rename = "Email/queryChanges")]
+	EmailQueryChanges(QueryChangesResponse),
+	#[serde(rename = "SearchSnippet/get")]
+	SearchSnippetGet(SearchSnippetGetResponse),
+	#[serde(rename = "Thread/get")]
+	ThreadGet(GetResponse),
+	#[serde(rename = "Thread/changes")]
+	ThreadChanges(ChangesResponse),
+	#[serde(rename = "Identity/get")]
+	IdentityGet(GetResponse),
+	#[serde(rename = "Identity/changes")]
+	IdentityChanges(ChangesResponse),
+	#[serde(rename = "Identity/set")]
+	IdentitySet(SetResponse),
+	#[serde(rename = "EmailSubmission/get")]
+	EmailSubmissionGet(GetResponse),
+	#[serde(rename = "EmailSubmission/changes")]
+	EmailSubmissionChanges(ChangesResponse),
+	#[serde(rename = "EmailSubmission/query")]
+	EmailSubmissionQuery(QueryResponse),
+	#[serde(rename = "EmailSubmission/set")]
+	EmailSubmissionSet(SetResponse),
+	#[serde(rename = "Quota/get")]
+	QuotaGet(GetResponse),
+	#[serde(rename = "Quota/changes")]
+	QuotaChanges(ChangesResponse),
+	#[serde(rename = "Quota/query")]
+	QuotaQuery(QueryResponse),
+	#[serde(rename = "SieveScript/get")]
+	SieveScriptGet(GetResponse),
+	#[serde(rename = "SieveScript/query")]
+	SieveScriptQuery(QueryResponse),
+	#[serde(rename = "SieveScript/set")]
+	SieveScriptSet(SetResponse),
+	#[serde(rename = "SieveScript/validate")]
+	SieveScriptValidate(SieveScriptValidateResponse),
+	#[serde(rename = "VacationResponse/get")]
+	VacationResponseGet(GetResponse),
+	#[serde(rename = "VacationResponse/set")]
+	VacationResponseSet(SetResponse),
+	#[serde(rename = "Blob/upload")]
+	BlobUpload(BlobUploadResponse),
+	#[serde(rename = "Blob/get")]
+	BlobGet(BlobGetResponse),
+	#[serde(rename = "Blob/lookup")]
+	BlobLookup(BlobLookupResponse),
+	#[serde(rename = "PushSubscription/get")]
+	PushSubscriptionGet(PushSubscriptionGetResponse),
+	#[serde(rename = "PushSubscription/set")]
+	PushSubscriptionSet(PushSubscriptionSetResponse),
+	#[serde(rename = "error")]
+	Error(MethodError),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum MethodError {
+	UnknownMethod,
+	AccountNotFound,
+	FromAccountNotFound,
+	AnchorNotFound,
+	CannotCalculateChanges,
+	/// RFC 9404, a type name `Blob/lookup` doesn't know.
+	UnknownDataType,
+	RequestTooLarge,
+	StateMismatch,
+	TooManyChanges,
+	Forbidden {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	UnsupportedFilter {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	UnsupportedSort {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	InvalidArguments {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	ServerFail {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+}
+
+impl MethodError {
+	pub fn invalid_arguments(description: impl Into<String>) -> Self {
+		MethodError::InvalidArguments {
+			description: Some(description.into()),
+		}
+	}
+}
+
+// outside of method calls, like for downloads, all that's left is failing the request
+impl From<MethodError> for tide::Error {
+	fn from(e: MethodError) -> Self {
+		tide::Error::from_str(tide::StatusCode::InternalServerError, format!("{:?}", e))
+	}
+}
+
+impl From<tide::Error> for MethodError {
+	fn from(e: tide::Error) -> Self {
+		MethodError::ServerFail {
+			description: Some(e.to_string()),
+		}
+	}
+}
+
+impl From<crate::smtp::Error> for MethodError {
+	fn from(e: crate::smtp::Error) -> Self {
+		MethodError::ServerFail {
+			description: Some(e.to_string()),
+		}
+	}
+}
+
+impl From<crate::sieve::Error> for MethodError {
+	fn from(e: crate::sieve::Error) -> Self {
+		MethodError::ServerFail {
+			description: Some(e.to_string()),
+		}
+	}
+}
+
+impl From<crate::webpush::Error> for MethodError {
+	fn from(e: crate::webpush::Error) -> Self {
+		MethodError::ServerFail {
+			description: Some(e.to_string()),
+		}
+	}
+}
+
+impl From<async_imap::error::Error> for MethodError {
+	fn from(e: async_imap::error::Error) -> Self {
+		MethodError::ServerFail {
+			description: Some(e.to_string()),
+		}
+	}
 }
 
 #[derive(Debug)]
@@ -55,6 +383,7 @@ pub struct MethodCall {
 #[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Request {
+	#[allow(dead_code)]
 	pub using:        Vec<String>,
 	pub method_calls: Vec<MethodCall>,
 	pub created_ids:  Option<HashMap<Id, Id>>,
@@ -77,7 +406,7 @@ impl Serialize for MethodCallResult {
 		let mut s = serializer.serialize_tuple(3)?;
 
 		let method =
-			serde_json::to_value(&self.method_result).map_err(|e| serde::ser::Error::custom(e))?;
+			serde_json::to_value(&self.method_result).map_err(serde::ser::Error::custom)?;
 		let obj = method.as_object().unwrap();
 
 		s.serialize_element(&obj["t"])?;
@@ -126,11 +455,12 @@ impl<'de> Deserialize<'de> for MethodCall {
 
 		let v = deserializer.deserialize_tuple(3, MethodCallVisitor)?;
 
+		// bad arguments only fail this method call, not the whole request
 		let m = Method::deserialize(serde_json::json!({
 			"t": v.method_name,
 			"c": v.method_args,
 		}))
-		.map_err(|e| serde::de::Error::custom(e))?;
+		.unwrap_or_else(|e| Method::Invalid(e.to_string()));
 
 		Ok(MethodCall {
 			method:  m,
@@ -0,0 +1,290 @@
+//! Telling clients about changes (RFC 8620 section 7).
+//!
+//! Changes are noticed on an imap connection of their own, with `NOTIFY`
+//! (RFC 5465) for all mailboxes or else `IDLE` on the INBOX. Everything else,
+//! like changes that don't go through imap at all, is found by looking at the
+//! states again every [`POLL_INTERVAL`]. Mail states are only computed again
+//! when the server reported something or the mailbox statuses moved.
+
+use std::{
+	collections::{BTreeMap, HashMap},
+	time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+	auth,
+	imap::{self, raw::RawSession},
+	jmap::{
+		changes::fnv1a,
+		email::EmailGetRequest,
+		method::{MethodError, MethodResult},
+		GetRequest,
+		Id,
+		JmapApi,
+	},
+	state,
+};
+
+pub const TYPE_NAMES: &[&str] = &[
+	"Mailbox",
+	"Email",
+	"EmailDelivery",
+	"Thread",
+	"Identity",
+	"EmailSubmission",
+	"VacationResponse",
+	"SieveScript",
+	"Quota",
+];
+
+/// Types whose state can only change along with the mailboxes.
+const MAIL_TYPE_NAMES: &[&str] = &["Mailbox", "Email", "EmailDelivery", "Thread", "Quota"];
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StateChange {
+	#[serde(rename = "@type")]
+	pub r#type:  String,
+	pub changed: HashMap<Id, BTreeMap<String, String>>,
+}
+
+impl StateChange {
+	pub fn new(account_id: &str, changed: BTreeMap<String, String>) -> Self {
+		StateChange {
+			r#type:  "StateChange".to_owned(),
+			changed: std::iter::once((account_id.to_owned(), changed)).collect(),
+		}
+	}
+}
+
+/// The known type names of a comma separated list, `*` for all of them.
+pub fn parse_types(types: &str) -> Vec<String> {
+	if types.trim() == "*" {
+		return TYPE_NAMES.iter().map(|t| t.to_string()).collect();
+	}
+	types
+		.split(',')
+		.map(str::trim)
+		.filter(|t| TYPE_NAMES.contains(t))
+		.map(str::to_owned)
+		.collect()
+}
+
+/// The states in `current` that differ from the ones in `previous`.
+pub fn changed_states(
+	previous: &BTreeMap<String, String>,
+	current: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+	current
+		.iter()
+		.filter(|(type_name, state)| previous.get(*type_name) != Some(*state))
+		.map(|(type_name, state)| (type_name.clone(), state.clone()))
+		.collect()
+}
+
+/// The states of the types a client wants, to find out which of them changed.
+pub struct StateTracker {
+	types:     Vec<String>,
+	states:    BTreeMap<String, String>,
+	/// A hash of the mailbox statuses the mail states were computed with.
+	mailboxes: Option<u64>,
+	last_poll: Instant,
+}
+
+impl StateTracker {
+	pub async fn new(jmap_api: &JmapApi<'_>, types: Vec<String>) -> Self {
+		let mut tracker = StateTracker {
+			types:     vec![],
+			states:    BTreeMap::new(),
+			mailboxes: None,
+			last_poll: Instant::now(),
+		};
+		tracker.set_types(types);
+		tracker.mailboxes = jmap_api.mailbox_statuses().await.ok();
+		tracker.states = jmap_api.type_states(&tracker.types).await;
+		tracker
+	}
+
+	/// Types that weren't wanted before are reported once they change.
+	pub fn set_types(&mut self, types: Vec<String>) {
+		self.states.retain(|type_name, _| types.contains(type_name));
+		self.types = types;
+	}
+
+	/// The states that changed, `reported` when the imap server told about
+	/// a change. Only what could have changed is looked at.
+	pub async fn changes(
+		&mut self,
+		jmap_api: &JmapApi<'_>,
+		reported: bool,
+	) -> BTreeMap<String, String> {
+		let polled = self.last_poll.elapsed() >= POLL_INTERVAL;
+		if !reported && !polled {
+			return BTreeMap::new();
+		}
+		if polled {
+			self.last_poll = Instant::now();
+		}
+
+		let mailboxes = jmap_api.mailbox_statuses().await.ok();
+		let mail_changed = reported || mailboxes.is_none() || mailboxes != self.mailboxes;
+		self.mailboxes = mailboxes;
+
+		let due: Vec<String> = self
+			.types
+			.iter()
+			.filter(|t| {
+				!self.states.contains_key(*t)
+					|| if MAIL_TYPE_NAMES.contains(&t.as_str()) {
+						mail_changed
+					} else {
+						polled
+					}
+			})
+			.cloned()
+			.collect();
+		let current = jmap_api.type_states(&due).await;
+
+		let changed: BTreeMap<String, String> = changed_states(&self.states, &current)
+			.into_iter()
+			// there is nothing to compare a first state with
+			.filter(|(type_name, _)| self.states.contains_key(type_name))
+			.collect();
+		self.states.extend(current);
+		changed
+	}
+}
+
+/// A connection of its own to the imap server to wait for changes on.
+pub struct Watcher {
+	session: RawSession,
+}
+
+impl Watcher {
+	pub async fn connect(credentials: &auth::Credentials) -> async_imap::error::Result<Self> {
+		let mut session = imap::create_raw_session(state::MAIL_SERVER, credentials).await?;
+
+		let notify = session.has_capability("NOTIFY")
+			&& session
+				.command(
+					"NOTIFY SET (personal (MessageNew MessageExpunge FlagChange MailboxName \
+					 SubscriptionChange))",
+				)
+				.await
+				.is_ok();
+		if !notify {
+			imap::select(&mut session, "INBOX", false).await?;
+		}
+
+		Ok(Watcher { session })
+	}
+
+	/// Waits until the server reports something, at most `timeout`. Returns
+	/// whether it did.
+	pub async fn wait(&mut self, timeout: Duration) -> async_imap::error::Result<bool> {
+		if self.session.has_capability("IDLE") {
+			return Ok(!self.session.idle(timeout).await?.is_empty());
+		}
+
+		async_std::task::sleep(timeout).await;
+		Ok(!self.session.command("NOOP").await?.is_empty())
+	}
+}
+
+impl JmapApi<'_> {
+	/// The current state of each of `types`, those that can't be found out
+	/// right now are left out.
+	pub async fn type_states(&self, types: &[String]) -> BTreeMap<String, String> {
+		let mut states = BTreeMap::new();
+		for type_name in types {
+			match self.type_state(type_name).await {
+				Ok(Some(state)) => {
+					states.insert(type_name.clone(), state);
+				}
+				Ok(None) => {}
+				Err(e) => tracing::warn!("getting the {} state failed: {:?}", type_name, e),
+			}
+		}
+		states
+	}
+
+	async fn type_state(&self, type_name: &str) -> Result<Option<String>, MethodError> {
+		let request = || GetRequest {
+			account_id: self.account_id().to_owned(),
+			ids: Some(vec![]),
+			..Default::default()
+		};
+		let result = match type_name {
+			"Mailbox" => {
+				self.handle_mailbox_get(self.account_id().to_owned(), Some(vec![]), None)
+					.await?
+			}
+			"Email" => {
+				self.handle_email_get(EmailGetRequest {
+					account_id: self.account_id().to_owned(),
+					ids: Some(vec![]),
+					..Default::default()
+				})
+				.await?
+			}
+			"EmailDelivery" => return Ok(Some(self.email_delivery_state().await?)),
+			"Thread" => self.handle_thread_get(request()).await?,
+			"Identity" => self.handle_identity_get(request()).await?,
+			"EmailSubmission" => self.handle_email_submission_get(request()).await?,
+			"VacationResponse" => self.handle_vacation_response_get(request()).await?,
+			"SieveScript" => self.handle_sieve_script_get(request()).await?,
+			"Quota" => self.handle_quota_get(request()).await?,
+			_ => return Ok(None),
+		};
+
+		Ok(match result {
+			MethodResult::MailboxGet { state, .. } => Some(state),
+			MethodResult::EmailGet(response)
+			| MethodResult::ThreadGet(response)
+			| MethodResult::IdentityGet(response)
+			| MethodResult::EmailSubmissionGet(response)
+			| MethodResult::VacationResponseGet(response)
+			| MethodResult::SieveScriptGet(response)
+			| MethodResult::QuotaGet(response) => Some(response.state),
+			_ => None,
+		})
+	}
+
+	/// A hash of what `STATUS` tells about every mailbox.
+	async fn mailbox_statuses(&self) -> Result<u64, MethodError> {
+		let mut mailboxes = self.fetch_mailboxes().await?;
+		mailboxes.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+		let mut bytes = vec![];
+		for (id, info) in &mailboxes {
+			bytes.extend_from_slice(id.as_bytes());
+			for n in [info.uid_validity, info.uid_next, info.total, info.unseen] {
+				bytes.extend_from_slice(&n.to_be_bytes());
+			}
+			bytes.extend_from_slice(&info.highest_modseq.unwrap_or(0).to_be_bytes());
+		}
+		Ok(fnv1a(bytes))
+	}
+
+	/// Changes when new messages arrive, taken from the next uid of the
+	/// mailboxes mail is delivered to.
+	async fn email_delivery_state(&self) -> Result<String, MethodError> {
+		let mut mailboxes = self.fetch_mailboxes().await?;
+		mailboxes.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+		let mut bytes = vec![];
+		for (id, info) in &mailboxes {
+			// messages the user put there weren't delivered
+			if info.has_attribute("\\Drafts") || info.has_attribute("\\Sent") {
+				continue;
+			}
+			bytes.extend_from_slice(id.as_bytes());
+			bytes.extend_from_slice(&info.uid_validity.to_be_bytes());
+			bytes.extend_from_slice(&info.uid_next.to_be_bytes());
+		}
+		Ok(format!("{:x}", fnv1a(bytes)))
+	}
+}
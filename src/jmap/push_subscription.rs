@@ -0,0 +1,797 @@
+//! Push subscriptions (RFC 8620 section 7.2), state changes delivered with
+//! Web Push to devices that can't keep an event source open.
+//!
+//! Subscriptions belong to the login, which here is the same as the account.
+//! A background task watches accounts with verified subscriptions like the
+//! event source does. It needs the account's credentials for that, so after a
+//! restart pushes resume once the account logged in again.
+
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	net::IpAddr,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use async_std::net::ToSocketAddrs;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tide::http::Url;
+
+use crate::{
+	auth::{self, User},
+	jmap::{
+		method::{MethodError, MethodResult},
+		push::{self, StateChange, StateTracker, Watcher},
+		Id,
+		JmapApi,
+		SetError,
+		MAX_OBJECTS_IN_GET,
+	},
+	state::State,
+	webpush::{self, Keys},
+};
+
+const PUSH_SUBSCRIPTION_STORE_NAME: &str = "push-subscriptions";
+
+/// How far in the future in seconds subscriptions may expire, also when
+/// they expire if the client doesn't say.
+const MAX_EXPIRES: i64 = 7 * 24 * 60 * 60;
+
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscription {
+	pub id:                Id,
+	pub device_client_id:  String,
+	pub url:               String,
+	pub keys:              Option<Keys>,
+	pub verification_code: Option<String>,
+	pub expires:           String,
+	pub types:             Option<Vec<String>>,
+	/// The code pushed to the url, the subscription is verified once the
+	/// client sets it as its `verificationCode`.
+	pub expected_code:     String,
+}
+
+impl PushSubscription {
+	// `url` and `keys` are private to the device and never returned
+	const PROPERTIES: &'static [&'static str] = &[
+		"id",
+		"deviceClientId",
+		"verificationCode",
+		"expires",
+		"types",
+	];
+
+	fn is_verified(&self) -> bool {
+		self.verification_code.as_deref() == Some(&self.expected_code)
+	}
+
+	fn is_expired(&self) -> bool {
+		chrono::DateTime::parse_from_rfc3339(&self.expires)
+			.map_or(true, |expires| expires < chrono::Utc::now())
+	}
+
+	/// Sets the properties in `object`, server set and immutable ones may
+	/// only be given with their current value.
+	fn apply(&mut self, object: &Map<String, Value>, immutable: &[&str]) -> Result<(), SetError> {
+		let current = serde_json::to_value(&*self).unwrap_or_default();
+		for (key, value) in object {
+			let invalid = |description: &str| SetError::invalid_properties(&[key], description);
+			let string = || {
+				value
+					.as_str()
+					.map(str::to_owned)
+					.ok_or_else(|| invalid("must be a string"))
+			};
+
+			match key.as_str() {
+				k if k == "id" || immutable.contains(&k) => {
+					if current.get(k) != Some(value) {
+						return Err(invalid("property can't be changed"));
+					}
+				}
+				"deviceClientId" => self.device_client_id = string()?,
+				// checked with `is_push_url` before, resolving can't happen here
+				"url" => self.url = string()?,
+				"keys" => {
+					let keys: Option<Keys> = parse(key, value)?;
+					if keys.as_ref().is_some_and(|k| !k.is_valid()) {
+						return Err(invalid(
+							"must be a P-256 public key and a secret of 16 octets",
+						));
+					}
+					self.keys = keys;
+				}
+				"verificationCode" => self.verification_code = parse(key, value)?,
+				"expires" => self.expires = expires(parse(key, value)?).map_err(&invalid)?,
+				"types" => self.types = parse(key, value)?,
+				_ => return Err(invalid("unknown property")),
+			}
+		}
+		Ok(())
+	}
+}
+
+fn parse<T: DeserializeOwned>(key: &str, value: &Value) -> Result<T, SetError> {
+	serde_json::from_value(value.clone())
+		.map_err(|e| SetError::invalid_properties(&[key], e.to_string()))
+}
+
+/// The expiry for the one asked for, the latest one allowed if it's too far
+/// away or missing.
+fn expires(requested: Option<String>) -> Result<String, &'static str> {
+	let now = chrono::Utc::now();
+	let latest = now + chrono::Duration::seconds(MAX_EXPIRES);
+	let expires = match requested {
+		Some(requested) => chrono::DateTime::parse_from_rfc3339(&requested)
+			.map_err(|_| "must be a date")?
+			.with_timezone(&chrono::Utc),
+		None => latest,
+	};
+	if expires < now {
+		return Err("must be in the future");
+	}
+	Ok(expires.min(latest).format("%Y-%m-%dT%H:%M:%SZ").to_string())
+}
+
+/// Pushes only go to https urls (RFC 8620 section 7.2) of public hosts,
+/// anything else could make us post to internal services.
+async fn is_push_url(url: &str) -> bool {
+	let url = match Url::parse(url) {
+		Ok(url) if url.scheme() == "https" => url,
+		_ => return false,
+	};
+	let host = match url.host_str() {
+		Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+		None => return false,
+	};
+	match (host, url.port_or_known_default().unwrap_or(443))
+		.to_socket_addrs()
+		.await
+	{
+		Ok(addresses) => {
+			let addresses: Vec<_> = addresses.collect();
+			!addresses.is_empty() && addresses.iter().all(|a| is_public(a.ip()))
+		}
+		Err(_) => false,
+	}
+}
+
+fn is_public(ip: IpAddr) -> bool {
+	match ip {
+		IpAddr::V4(ip) => {
+			!(ip.is_loopback()
+				|| ip.is_private()
+				|| ip.is_link_local()
+				|| ip.is_unspecified()
+				|| ip.is_broadcast()
+				|| ip.is_multicast())
+		}
+		IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+			Some(ip) => is_public(IpAddr::V4(ip)),
+			None => {
+				!(ip.is_loopback()
+					|| ip.is_unique_local()
+					|| ip.is_unicast_link_local()
+					|| ip.is_unspecified()
+					|| ip.is_multicast())
+			}
+		},
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct PushSubscriptions {
+	next_id:       u64,
+	subscriptions: BTreeMap<Id, PushSubscription>,
+}
+
+/// Like a standard /get, but without an account or state.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionGetRequest {
+	pub ids:        Option<Vec<Id>>,
+	pub properties: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionGetResponse {
+	pub list:      Vec<Value>,
+	pub not_found: Vec<Id>,
+}
+
+/// Like a standard /set, but without an account or states.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionSetRequest {
+	pub create:  Option<HashMap<Id, Map<String, Value>>>,
+	pub update:  Option<HashMap<Id, Map<String, Value>>>,
+	pub destroy: Option<Vec<Id>>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PushSubscriptionSetResponse {
+	pub created:       HashMap<Id, Value>,
+	pub updated:       HashMap<Id, Option<Value>>,
+	pub destroyed:     Vec<Id>,
+	pub not_created:   HashMap<Id, SetError>,
+	pub not_updated:   HashMap<Id, SetError>,
+	pub not_destroyed: HashMap<Id, SetError>,
+}
+
+/// The unexpired subscriptions of an account, expired ones are dropped.
+async fn subscriptions(state: &State, account_id: &str) -> tide::Result<Vec<PushSubscription>> {
+	state
+		.store
+		.update(
+			account_id,
+			PUSH_SUBSCRIPTION_STORE_NAME,
+			|subscriptions: &mut PushSubscriptions| {
+				subscriptions.subscriptions.retain(|_, s| !s.is_expired());
+				subscriptions.subscriptions.values().cloned().collect()
+			},
+		)
+		.await
+}
+
+async fn send_verification(vapid: webpush::Vapid, subscription: PushSubscription) {
+	let verification = json!({
+		"@type": "PushVerification",
+		"pushSubscriptionId": subscription.id,
+		"verificationCode": subscription.expected_code,
+	});
+	let sent = webpush::send(
+		&vapid,
+		&subscription.url,
+		subscription.keys.as_ref(),
+		verification.to_string().as_bytes(),
+	)
+	.await;
+	if let Err(e) = sent {
+		tracing::warn!(
+			"verifying push subscription {} failed: {}",
+			subscription.id,
+			e
+		);
+	}
+}
+
+impl JmapApi<'_> {
+	pub async fn handle_push_subscription_get(
+		&self,
+		request: PushSubscriptionGetRequest,
+	) -> Result<MethodResult, MethodError> {
+		if let Some(private) = request
+			.properties
+			.iter()
+			.flatten()
+			.find(|p| *p == "url" || *p == "keys")
+		{
+			return Err(MethodError::Forbidden {
+				description: Some(format!("`{}` is never returned", private)),
+			});
+		}
+
+		let subscriptions = subscriptions(self.state, self.account_id()).await?;
+
+		let ids: Vec<Id> = match request.ids {
+			Some(ids) => ids,
+			None => subscriptions.iter().map(|s| s.id.clone()).collect(),
+		};
+		if ids.len() > MAX_OBJECTS_IN_GET {
+			return Err(MethodError::RequestTooLarge);
+		}
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in ids {
+			match subscriptions.iter().find(|s| s.id == id) {
+				Some(subscription) => list.push(subscription),
+				None => not_found.push(id),
+			}
+		}
+
+		// always a list, everything else is private
+		let properties = request.properties.or_else(|| {
+			Some(
+				PushSubscription::PROPERTIES
+					.iter()
+					.map(|p| p.to_string())
+					.collect(),
+			)
+		});
+		Ok(MethodResult::PushSubscriptionGet(
+			PushSubscriptionGetResponse {
+				list: super::select_properties(list, &properties, PushSubscription::PROPERTIES)?,
+				not_found,
+			},
+		))
+	}
+
+	pub async fn handle_push_subscription_set(
+		&self,
+		request: PushSubscriptionSetRequest,
+	) -> Result<MethodResult, MethodError> {
+		let PushSubscriptionSetRequest {
+			create,
+			update,
+			destroy,
+		} = request;
+		let create = create.unwrap_or_default();
+		let codes = create
+			.keys()
+			.map(|_| webpush::random_token())
+			.collect::<Result<Vec<_>, _>>()?;
+		// resolving hosts has to wait, not while the store is locked
+		let mut rejected = HashMap::new();
+		for (creation_id, object) in &create {
+			if let Some(url) = object.get("url").and_then(Value::as_str) {
+				if !is_push_url(url).await {
+					rejected.insert(
+						creation_id.clone(),
+						SetError::invalid_properties(&["url"], "must be a public https url"),
+					);
+				}
+			}
+		}
+
+		let (response, created) =
+			self.state
+				.store
+				.update(
+					self.account_id(),
+					PUSH_SUBSCRIPTION_STORE_NAME,
+					|subscriptions: &mut PushSubscriptions| {
+						subscriptions.subscriptions.retain(|_, s| !s.is_expired());
+						let mut response = PushSubscriptionSetResponse::default();
+						let mut created = vec![];
+
+						for ((creation_id, object), code) in create.into_iter().zip(codes) {
+							if let Some(e) = rejected.remove(&creation_id) {
+								response.not_created.insert(creation_id, e);
+								continue;
+							}
+							let id = format!("P{}", subscriptions.next_id);
+							let mut subscription = PushSubscription {
+								id:                id.clone(),
+								device_client_id:  String::new(),
+								url:               String::new(),
+								keys:              None,
+								verification_code: None,
+								expires:           String::new(),
+								types:             None,
+								expected_code:     code,
+							};
+							let required = ["deviceClientId", "url"];
+							let applied = subscription
+								.apply(&object, &["verificationCode"])
+								.and_then(|()| {
+									if required.iter().all(|p| object.contains_key(*p)) {
+										Ok(())
+									} else {
+										Err(SetError::invalid_properties(&required, "are required"))
+									}
+								});
+							if let Err(e) = applied {
+								response.not_created.insert(creation_id, e);
+								continue;
+							}
+							if !object.contains_key("expires") {
+								subscription.expires = expires(None).unwrap_or_default();
+							}
+
+							subscriptions.next_id += 1;
+							self.record_created_id(&creation_id, &id);
+							response.created.insert(
+								creation_id,
+								json!({ "id": id, "expires": subscription.expires }),
+							);
+							created.push(subscription.clone());
+							subscriptions.subscriptions.insert(id, subscription);
+						}
+
+						for (id, patch) in update.unwrap_or_default() {
+							let subscription = match self
+								.resolve_id(&id)
+								.and_then(|i| subscriptions.subscriptions.get_mut(&i))
+							{
+								Some(subscription) => subscription,
+								None => {
+									response.not_updated.insert(id, SetError::NotFound);
+									continue;
+								}
+							};
+							let mut updated = subscription.clone();
+							match updated.apply(&patch, &["deviceClientId", "url", "keys"]) {
+								Ok(()) => {
+									// the server may have moved the expiry closer
+									let changed = patch
+										.contains_key("expires")
+										.then(|| json!({ "expires": updated.expires }));
+									*subscription = updated;
+									response.updated.insert(id, changed);
+								}
+								Err(e) => {
+									response.not_updated.insert(id, e);
+								}
+							}
+						}
+
+						for id in destroy.unwrap_or_default() {
+							let resolved = self.resolve_id(&id).unwrap_or_default();
+							match subscriptions.subscriptions.remove(&resolved) {
+								Some(_) => response.destroyed.push(id),
+								None => {
+									response.not_destroyed.insert(id, SetError::NotFound);
+								}
+							}
+						}
+
+						(response, created)
+					},
+				)
+				.await?;
+
+		for subscription in created {
+			async_std::task::spawn(send_verification(self.state.vapid.clone(), subscription));
+		}
+
+		Ok(MethodResult::PushSubscriptionSet(response))
+	}
+}
+
+/// Pushes the state changes of every account with verified subscriptions
+/// that we can log in to, forever.
+pub async fn run(state: State) {
+	let watching = Arc::new(Mutex::new(HashSet::new()));
+	loop {
+		for credentials in state.known_credentials() {
+			let account_id = credentials.username.clone();
+			if watching.lock().unwrap().contains(&account_id) {
+				continue;
+			}
+			match subscriptions(&state, &account_id).await {
+				Ok(subscriptions) if subscriptions.iter().any(PushSubscription::is_verified) => {}
+				Ok(_) => continue,
+				Err(e) => {
+					tracing::error!("reading push subscriptions of {} failed: {}", account_id, e);
+					continue;
+				}
+			}
+
+			watching.lock().unwrap().insert(account_id.clone());
+			let (state, watching) = (state.clone(), watching.clone());
+			async_std::task::spawn(async move {
+				if let Err(e) = deliver(&state, &credentials).await {
+					tracing::error!("pushing state changes of {} failed: {}", account_id, e);
+				}
+				watching.lock().unwrap().remove(&account_id);
+			});
+		}
+		async_std::task::sleep(DISPATCH_INTERVAL).await;
+	}
+}
+
+/// Watches an account until it has no verified subscriptions left.
+async fn deliver(state: &State, credentials: &auth::Credentials) -> tide::Result<()> {
+	// the jmap api works on the sessions of a login, pushing has its own
+	let session_id = format!("push-{}", credentials.username);
+	state
+		.authenticate(session_id.clone(), credentials.clone())
+		.await?;
+	let delivered = watch(state, credentials, &session_id).await;
+	state.logout(&session_id);
+	delivered
+}
+
+async fn watch(
+	state: &State,
+	credentials: &auth::Credentials,
+	session_id: &str,
+) -> tide::Result<()> {
+	let account_id = credentials.username.as_str();
+	let user = User {
+		email: account_id.to_owned(),
+	};
+	let jmap_api = JmapApi::new(session_id, &user, state);
+	let verified = || async {
+		Ok::<_, tide::Error>(
+			subscriptions(state, account_id)
+				.await?
+				.into_iter()
+				.filter(PushSubscription::is_verified)
+				.collect::<Vec<_>>(),
+		)
+	};
+
+	let mut watcher = Watcher::connect(credentials).await?;
+	let mut tracker = StateTracker::new(&jmap_api, wanted_types(&verified().await?)).await;
+
+	loop {
+		let reported = watcher.wait(push::POLL_INTERVAL).await?;
+
+		let subscriptions = verified().await?;
+		if subscriptions.is_empty() {
+			return Ok(());
+		}
+		tracker.set_types(wanted_types(&subscriptions));
+		let changed = tracker.changes(&jmap_api, reported).await;
+		if changed.is_empty() {
+			continue;
+		}
+
+		let gone = push_changes(state, account_id, &subscriptions, &changed).await?;
+
+		// the push service told us the device unsubscribed
+		if !gone.is_empty() {
+			state
+				.store
+				.update(
+					account_id,
+					PUSH_SUBSCRIPTION_STORE_NAME,
+					|subscriptions: &mut PushSubscriptions| {
+						for id in &gone {
+							subscriptions.subscriptions.remove(id);
+						}
+					},
+				)
+				.await?;
+		}
+	}
+}
+
+/// The types any of `subscriptions` wants, the states of the others aren't
+/// worth finding out.
+fn wanted_types(subscriptions: &[PushSubscription]) -> Vec<String> {
+	push::TYPE_NAMES
+		.iter()
+		.filter(|t| {
+			subscriptions.iter().any(|s| {
+				s.types
+					.as_ref()
+					.is_none_or(|types| types.iter().any(|w| w == *t))
+			})
+		})
+		.map(|t| t.to_string())
+		.collect()
+}
+
+/// Pushes the `changed` states to the subscriptions that want them, returns
+/// the ids of the subscriptions that are gone.
+async fn push_changes(
+	state: &State,
+	account_id: &str,
+	subscriptions: &[PushSubscription],
+	changed: &BTreeMap<String, String>,
+) -> tide::Result<Vec<Id>> {
+	let mut gone = vec![];
+	for subscription in subscriptions {
+		let changed: BTreeMap<String, String> = changed
+			.iter()
+			.filter(|(type_name, _)| {
+				subscription
+					.types
+					.as_ref()
+					.is_none_or(|types| types.contains(type_name))
+			})
+			.map(|(type_name, state)| (type_name.clone(), state.clone()))
+			.collect();
+		if changed.is_empty() {
+			continue;
+		}
+
+		let change = serde_json::to_string(&StateChange::new(account_id, changed))?;
+		let sent = webpush::send(
+			&state.vapid,
+			&subscription.url,
+			subscription.keys.as_ref(),
+			change.as_bytes(),
+		)
+		.await;
+		match sent {
+			Ok(()) => {}
+			Err(e) if e.is_gone() => gone.push(subscription.id.clone()),
+			Err(e) => {
+				tracing::warn!("pushing to subscription {} failed: {}", subscription.id, e)
+			}
+		}
+	}
+	Ok(gone)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::webpush::tests::{header, rfc8291_keys, sink};
+
+	fn subscription(id: &str, url: &str, types: Option<&[&str]>) -> PushSubscription {
+		PushSubscription {
+			id:                id.to_owned(),
+			device_client_id:  "device".to_owned(),
+			url:               url.to_owned(),
+			keys:              None,
+			verification_code: Some("code".to_owned()),
+			expires:           expires(None).unwrap(),
+			types:             types.map(|t| t.iter().map(|t| t.to_string()).collect()),
+			expected_code:     "code".to_owned(),
+		}
+	}
+
+	fn state() -> (State, std::path::PathBuf) {
+		let dir = std::env::temp_dir().join(format!(
+			"push-subscription-test-{}",
+			webpush::random_token().unwrap()
+		));
+		(State::with_data_dir(&dir), dir)
+	}
+
+	fn object(value: Value) -> Map<String, Value> {
+		value.as_object().unwrap().clone()
+	}
+
+	#[async_std::test]
+	async fn push_urls_are_public_https() {
+		assert!(is_push_url("https://93.184.216.34/device").await);
+		assert!(is_push_url("https://[2606:2800:220:1::]/device").await);
+		for url in [
+			"http://93.184.216.34/device",
+			"https://localhost/device",
+			"https://127.0.0.1/device",
+			"https://10.0.0.1/device",
+			"https://192.168.1.1:8443/device",
+			"https://169.254.169.254/latest",
+			"https://0.0.0.0/device",
+			"https://[::1]/device",
+			"https://[fd00::1]/device",
+			"https://[fe80::1]/device",
+			"https://[::ffff:127.0.0.1]/device",
+			"file:///etc/passwd",
+			"not a url",
+		] {
+			assert!(!is_push_url(url).await, "{}", url);
+		}
+	}
+
+	#[test]
+	fn apply_checks_properties() {
+		let mut created = PushSubscription {
+			verification_code: None,
+			..subscription("P0", "", None)
+		};
+		created
+			.apply(
+				&object(json!({
+					"deviceClientId": "phone",
+					"url": "https://push.example.com/device",
+					"keys": rfc8291_keys(),
+					"types": ["Email"],
+				})),
+				&["verificationCode"],
+			)
+			.unwrap();
+		assert_eq!(created.url, "https://push.example.com/device");
+		assert_eq!(created.types, Some(vec!["Email".to_owned()]));
+
+		let immutable = ["deviceClientId", "url", "keys"];
+		let invalid = [
+			json!({ "url": "https://other.example.com/" }),
+			json!({ "keys": { "p256dh": "AAAA", "auth": "BTBZMqHH6r4Tts7J_aSIgg" } }),
+			json!({ "expires": "2000-01-01T00:00:00Z" }),
+			json!({ "expectedCode": "code" }),
+			json!({ "id": "P1" }),
+		];
+		for patch in &invalid {
+			assert!(created
+				.clone()
+				.apply(&object(patch.clone()), &immutable)
+				.is_err());
+		}
+
+		assert!(!created.is_verified());
+		let mut verified = created.clone();
+		verified
+			.apply(&object(json!({ "verificationCode": "code" })), &immutable)
+			.unwrap();
+		assert!(verified.is_verified());
+	}
+
+	#[async_std::test]
+	async fn create_rejects_internal_urls() {
+		let (state, dir) = state();
+		let user = User {
+			email: "user@example.com".to_owned(),
+		};
+		let jmap_api = JmapApi::new("session", &user, &state);
+
+		let (url, _) = sink("201 Created").await;
+		let request = PushSubscriptionSetRequest {
+			create: Some(
+				std::iter::once((
+					"a".to_owned(),
+					object(json!({ "deviceClientId": "phone", "url": url })),
+				))
+				.collect(),
+			),
+			..Default::default()
+		};
+		let response = match jmap_api
+			.handle_push_subscription_set(request)
+			.await
+			.unwrap()
+		{
+			MethodResult::PushSubscriptionSet(response) => response,
+			_ => unreachable!(),
+		};
+		assert!(response.created.is_empty());
+		assert!(matches!(
+			response.not_created["a"],
+			SetError::InvalidProperties { .. }
+		));
+		assert!(subscriptions(&state, &user.email).await.unwrap().is_empty());
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[async_std::test]
+	async fn verifications_carry_the_expected_code() {
+		let (state, dir) = state();
+		let (url, pushed) = sink("201 Created").await;
+		let subscription = PushSubscription {
+			verification_code: None,
+			..subscription("P0", &url, None)
+		};
+
+		send_verification(state.vapid.clone(), subscription).await;
+
+		let request = pushed.await;
+		assert_eq!(header(&request, "content-type"), Some("application/json"));
+		let body = request.split("\r\n\r\n").nth(1).unwrap();
+		let verification: Value = serde_json::from_str(body).unwrap();
+		assert_eq!(
+			verification,
+			json!({
+				"@type": "PushVerification",
+				"pushSubscriptionId": "P0",
+				"verificationCode": "code",
+			})
+		);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[async_std::test]
+	async fn state_changes_are_pushed() {
+		let (state, dir) = state();
+		let (all, all_request) = sink("201 Created").await;
+		let (email, _) = sink("410 Gone").await;
+		// would be gone too if it were pushed to
+		let (mailbox, _) = sink("410 Gone").await;
+		let subscriptions = [
+			subscription("P0", &all, None),
+			subscription("P1", &email, Some(&["Email"])),
+			subscription("P2", &mailbox, Some(&["Mailbox"])),
+		];
+		let changed = std::iter::once(("Email".to_owned(), "s1".to_owned())).collect();
+
+		let gone = push_changes(&state, "user@example.com", &subscriptions, &changed)
+			.await
+			.unwrap();
+		assert_eq!(gone, vec!["P1".to_owned()]);
+
+		let request = all_request.await;
+		let body = request.split("\r\n\r\n").nth(1).unwrap();
+		let change: Value = serde_json::from_str(body).unwrap();
+		assert_eq!(
+			change,
+			json!({
+				"@type": "StateChange",
+				"changed": { "user@example.com": { "Email": "s1" } },
+			})
+		);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeSet;
+
+use crate::jmap::{method::MethodError, AddedItem, Id};
+
+pub struct Page<T = Id> {
+	pub position: u64,
+	pub ids:      Vec<T>,
+}
+
+/// Cuts the window described by `position`/`anchor`/`limit` out of the full
+/// query result.
+pub fn page<T: PartialEq>(
+	ids: Vec<T>,
+	position: i64,
+	anchor: Option<&T>,
+	anchor_offset: i64,
+	limit: Option<u64>,
+) -> Result<Page<T>, MethodError> {
+	let total = ids.len() as i64;
+
+	let start = match anchor {
+		Some(anchor) => {
+			let index = ids
+				.iter()
+				.position(|id| id == anchor)
+				.ok_or(MethodError::AnchorNotFound)? as i64;
+			(index + anchor_offset).clamp(0, total)
+		}
+		None if position < 0 => (total + position).max(0),
+		None => position.min(total),
+	} as usize;
+
+	let ids: Vec<T> = ids
+		.into_iter()
+		.skip(start)
+		.take(limit.map_or(usize::MAX, |l| l as usize))
+		.collect();
+
+	Ok(Page {
+		position: start as u64,
+		ids,
+	})
+}
+
+/// Computes a `queryChanges` result from the ids that changed since the old
+/// query state and the current result.
+///
+/// Every changed id is reported as removed and, if it's part of the current
+/// result, added again at its new index. That's more than strictly needed but
+/// always correct as long as the relative order of untouched ids is stable.
+pub fn query_changes(
+	current: &[Id],
+	changed: &BTreeSet<Id>,
+	max_changes: Option<u64>,
+) -> Result<(Vec<Id>, Vec<AddedItem>), MethodError> {
+	let removed: Vec<Id> = changed.iter().cloned().collect();
+	let added: Vec<AddedItem> = current
+		.iter()
+		.enumerate()
+		.filter(|(_, id)| changed.contains(*id))
+		.map(|(index, id)| AddedItem {
+			id:    id.clone(),
+			index: index as u64,
+		})
+		.collect();
+
+	if max_changes.is_some_and(|max| (removed.len() + added.len()) as u64 > max) {
+		return Err(MethodError::TooManyChanges);
+	}
+
+	Ok((removed, added))
+}
@@ -0,0 +1,283 @@
+//! `Quota` (RFC 9425), from the imap quota roots of the INBOX.
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	imap,
+	jmap::{
+		changes,
+		method::{MethodError, MethodResult},
+		query,
+		ChangesRequest,
+		Comparator,
+		GetRequest,
+		GetResponse,
+		Id,
+		JmapApi,
+		QueryRequest,
+		QueryResponse,
+		MAX_OBJECTS_IN_GET,
+	},
+};
+
+const QUOTA_CHANGES_STORE_NAME: &str = "quota-changes";
+
+pub const SORT_OPTIONS: &[&str] = &["name", "used"];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ResourceType {
+	Count,
+	Octets,
+}
+
+impl ResourceType {
+	fn as_str(self) -> &'static str {
+		match self {
+			ResourceType::Count => "count",
+			ResourceType::Octets => "octets",
+		}
+	}
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Scope {
+	Account,
+}
+
+impl Scope {
+	fn as_str(self) -> &'static str {
+		match self {
+			Scope::Account => "account",
+		}
+	}
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+	pub id:            Id,
+	pub resource_type: ResourceType,
+	pub used:          u64,
+	pub hard_limit:    u64,
+	pub scope:         Scope,
+	pub name:          String,
+	pub types:         Vec<String>,
+}
+
+impl Quota {
+	const PROPERTIES: &'static [&'static str] = &[
+		"id",
+		"resourceType",
+		"used",
+		"hardLimit",
+		"scope",
+		"name",
+		"types",
+	];
+
+	/// The quota of an imap resource, `None` for resources other than
+	/// `STORAGE` and `MESSAGE`.
+	fn from_imap(resource: &imap::QuotaResource) -> Option<Self> {
+		let (resource_type, kind, unit) = match resource.name.as_str() {
+			// imap counts storage in units of 1024 octets
+			"STORAGE" => (ResourceType::Octets, "storage", 1024),
+			"MESSAGE" => (ResourceType::Count, "messages", 1),
+			_ => return None,
+		};
+
+		// quota roots can be named anything, ids can't
+		let root: String = resource
+			.root
+			.bytes()
+			.map(|b| format!("{:02x}", b))
+			.collect();
+		let id = if root.is_empty() {
+			kind.to_owned()
+		} else {
+			format!("{}-{}", kind, root)
+		};
+		let name = if resource.root.is_empty() {
+			format!("Mail {}", kind)
+		} else {
+			format!("{} {}", resource.root, kind)
+		};
+
+		Some(Quota {
+			id,
+			resource_type,
+			used: resource.usage * unit,
+			hard_limit: resource.limit * unit,
+			scope: Scope::Account,
+			name,
+			types: vec!["Mail".to_owned()],
+		})
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaFilterCondition {
+	name:           Option<String>,
+	scopes:         Option<Vec<String>>,
+	resource_types: Option<Vec<String>>,
+	data_types:     Option<Vec<String>>,
+}
+
+impl QuotaFilterCondition {
+	fn matches(&self, quota: &Quota) -> bool {
+		let any = |list: &Option<Vec<String>>, value: &str| {
+			list.as_ref().is_none_or(|l| l.iter().any(|v| v == value))
+		};
+		self.name
+			.as_ref()
+			.is_none_or(|n| quota.name.to_lowercase().contains(&n.to_lowercase()))
+			&& any(&self.scopes, quota.scope.as_str())
+			&& any(&self.resource_types, quota.resource_type.as_str())
+			&& self
+				.data_types
+				.as_ref()
+				.is_none_or(|types| types.iter().any(|t| quota.types.contains(t)))
+	}
+}
+
+fn compare_quotas(sort: &[Comparator], a: &Quota, b: &Quota) -> Ordering {
+	for c in sort {
+		let ordering = match c.property.as_str() {
+			"used" => a.used.cmp(&b.used),
+			_ => a.name.cmp(&b.name),
+		};
+		let ordering = if c.is_ascending {
+			ordering
+		} else {
+			ordering.reverse()
+		};
+		if ordering != Ordering::Equal {
+			return ordering;
+		}
+	}
+	a.id.cmp(&b.id)
+}
+
+impl JmapApi<'_> {
+	async fn quotas(&self) -> Result<Vec<Quota>, MethodError> {
+		let resources = self
+			.with_raw_session(|mut s| async move { Ok(imap::quota(&mut s).await?) })
+			.await?;
+
+		Ok(resources.iter().filter_map(Quota::from_imap).collect())
+	}
+
+	async fn quota_state(&self, quotas: &[Quota]) -> Result<String, MethodError> {
+		let objects = changes::to_objects(quotas.iter().map(|q| (&q.id, q)))?;
+		self.record_state(QUOTA_CHANGES_STORE_NAME, &objects).await
+	}
+
+	pub async fn handle_quota_get(&self, request: GetRequest) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let quotas = self.quotas().await?;
+		let state = self.quota_state(&quotas).await?;
+
+		let ids: Vec<Id> = match request.ids {
+			Some(ids) => ids,
+			None => quotas.iter().map(|q| q.id.clone()).collect(),
+		};
+		if ids.len() > MAX_OBJECTS_IN_GET {
+			return Err(MethodError::RequestTooLarge);
+		}
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in ids {
+			match quotas.iter().find(|q| q.id == id) {
+				Some(quota) => list.push(quota),
+				None => not_found.push(id),
+			}
+		}
+
+		Ok(MethodResult::QuotaGet(GetResponse {
+			account_id: request.account_id,
+			state,
+			list: super::select_properties(list, &request.properties, Quota::PROPERTIES)?,
+			not_found,
+		}))
+	}
+
+	pub async fn handle_quota_changes(
+		&self,
+		request: ChangesRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let quotas = self.quotas().await?;
+		let objects = changes::to_objects(quotas.iter().map(|q| (&q.id, q)))?;
+		let changes = self
+			.record_changes(
+				QUOTA_CHANGES_STORE_NAME,
+				&objects,
+				&request.since_state,
+				request.max_changes,
+			)
+			.await?;
+
+		Ok(MethodResult::QuotaChanges(
+			changes.into_response(request.account_id),
+		))
+	}
+
+	pub async fn handle_quota_query(
+		&self,
+		request: QueryRequest<QuotaFilterCondition>,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		if let Some(c) = request
+			.sort
+			.iter()
+			.find(|c| !SORT_OPTIONS.contains(&c.property.as_str()))
+		{
+			return Err(MethodError::UnsupportedSort {
+				description: Some(format!("can't sort quotas by {}", c.property)),
+			});
+		}
+
+		let mut quotas = self.quotas().await?;
+		let query_state = self.quota_state(&quotas).await?;
+
+		quotas.retain(|q| {
+			request
+				.filter
+				.as_ref()
+				.is_none_or(|f| f.matches(&|c: &QuotaFilterCondition| c.matches(q)))
+		});
+		quotas.sort_by(|a, b| compare_quotas(&request.sort, a, b));
+
+		let ids: Vec<Id> = quotas.into_iter().map(|q| q.id).collect();
+		let total = ids.len() as u64;
+		let page = query::page(
+			ids,
+			request.position,
+			request.anchor.as_ref(),
+			request.anchor_offset,
+			request.limit,
+		)?;
+
+		Ok(MethodResult::QuotaQuery(QueryResponse {
+			account_id: request.account_id,
+			query_state,
+			can_calculate_changes: false,
+			position: page.position,
+			ids: page.ids,
+			total: if request.calculate_total {
+				Some(total)
+			} else {
+				None
+			},
+			limit: None,
+		}))
+	}
+}
@@ -3,6 +3,14 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 
 pub type Id = String;
+
+/// Whether `id` only uses the characters RFC 8620 allows in ids.
+pub fn is_valid_id(id: &str) -> bool {
+	(1..=255).contains(&id.len())
+		&& id
+			.bytes()
+			.all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
 pub type SessionState = String;
 
 #[derive(Deserialize, Serialize, Debug, Default)]
@@ -24,9 +32,21 @@ pub struct JmapSession {
 #[serde(rename_all = "camelCase")]
 pub struct Capabilities {
 	#[serde(rename = "urn:ietf:params:jmap:core")]
-	pub core: CoreCapabilities,
+	pub core:              CoreCapabilities,
 	#[serde(rename = "urn:ietf:params:jmap:mail")]
-	pub mail: EmptyCapabilities,
+	pub mail:              EmptyCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:submission")]
+	pub submission:        EmptyCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:vacationresponse")]
+	pub vacation_response: EmptyCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:sieve")]
+	pub sieve:             EmptyCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:quota")]
+	pub quota:             EmptyCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:blob")]
+	pub blob:              EmptyCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:webpush-vapid")]
+	pub webpush_vapid:     WebPushVapidCapabilities,
 }
 
 #[derive(Deserialize, Serialize, Debug, Default)]
@@ -46,6 +66,13 @@ pub struct CoreCapabilities {
 #[serde(rename_all = "camelCase")]
 pub struct EmptyCapabilities {}
 
+/// RFC 9749, the key push subscriptions can be restricted to.
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WebPushVapidCapabilities {
+	pub application_server_key: String,
+}
+
 #[derive(Deserialize, Serialize, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Account {
@@ -59,7 +86,17 @@ pub struct Account {
 #[serde(rename_all = "camelCase")]
 pub struct AcountCapabilities {
 	#[serde(rename = "urn:ietf:params:jmap:mail")]
-	pub mail: AccountMailCapabilities,
+	pub mail:              AccountMailCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:submission")]
+	pub submission:        AccountSubmissionCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:vacationresponse")]
+	pub vacation_response: EmptyCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:sieve")]
+	pub sieve:             AccountSieveCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:quota")]
+	pub quota:             EmptyCapabilities,
+	#[serde(rename = "urn:ietf:params:jmap:blob")]
+	pub blob:              AccountBlobCapabilities,
 }
 
 #[derive(Deserialize, Serialize, Debug, Default)]
@@ -72,3 +109,323 @@ pub struct AccountMailCapabilities {
 	pub email_query_sort_options:       Vec<String>,
 	pub may_create_top_level_mailbox:   bool,
 }
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AccountSubmissionCapabilities {
+	pub max_delayed_send:      u64,
+	pub submission_extensions: HashMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AccountSieveCapabilities {
+	pub implementation:       String,
+	pub max_size_script_name: u64,
+	pub max_size_script:      Option<u64>,
+	pub max_number_scripts:   Option<u64>,
+	pub max_number_redirects: Option<u64>,
+	pub sieve_extensions:     Vec<String>,
+	pub notification_methods: Option<Vec<String>>,
+	pub external_lists:       Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AccountBlobCapabilities {
+	pub max_size_blob_set:           Option<u64>,
+	pub max_data_sources:            u64,
+	pub supported_type_names:        Vec<String>,
+	pub supported_digest_algorithms: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GetResponse {
+	pub account_id: Id,
+	pub state:      String,
+	pub list:       Vec<serde_json::Value>,
+	pub not_found:  Vec<Id>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GetRequest {
+	pub account_id: Id,
+	pub ids:        Option<Vec<Id>>,
+	pub properties: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SetRequest {
+	pub account_id:  Id,
+	pub if_in_state: Option<String>,
+	pub create:      Option<HashMap<Id, serde_json::Map<String, serde_json::Value>>>,
+	pub update:      Option<HashMap<Id, serde_json::Map<String, serde_json::Value>>>,
+	pub destroy:     Option<Vec<Id>>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SetResponse {
+	pub account_id:    Id,
+	pub old_state:     Option<String>,
+	pub new_state:     String,
+	pub created:       HashMap<Id, serde_json::Value>,
+	pub updated:       HashMap<Id, Option<serde_json::Value>>,
+	pub destroyed:     Vec<Id>,
+	pub not_created:   HashMap<Id, SetError>,
+	pub not_updated:   HashMap<Id, SetError>,
+	pub not_destroyed: HashMap<Id, SetError>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyRequest {
+	pub from_account_id: Id,
+	pub if_from_in_state: Option<String>,
+	pub account_id: Id,
+	pub if_in_state: Option<String>,
+	pub create: HashMap<Id, serde_json::Map<String, serde_json::Value>>,
+	#[serde(default)]
+	pub on_success_destroy_original: bool,
+	pub destroy_from_if_in_state: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyResponse {
+	pub from_account_id: Id,
+	pub account_id:      Id,
+	pub old_state:       Option<String>,
+	pub new_state:       String,
+	pub created:         HashMap<Id, serde_json::Value>,
+	pub not_created:     HashMap<Id, SetError>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SetError {
+	Forbidden {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	NotFound,
+	#[serde(rename_all = "camelCase")]
+	InvalidProperties {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+		properties:  Vec<String>,
+	},
+	#[serde(rename_all = "camelCase")]
+	AlreadyExists {
+		existing_id: Id,
+	},
+	MailboxHasChild,
+	MailboxHasEmail,
+	#[serde(rename_all = "camelCase")]
+	BlobNotFound {
+		not_found: Vec<Id>,
+	},
+	/// RFC 8621, the blob to import isn't a message.
+	InvalidEmail {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	/// RFC 8621, the user may not send as the address of an identity.
+	ForbiddenFrom {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	#[serde(rename_all = "camelCase")]
+	TooLarge {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		max_size: Option<u64>,
+	},
+	/// RFC 8621, the smtp server didn't accept the envelope sender.
+	ForbiddenMailFrom {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	ForbiddenToSend {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	NoRecipients,
+	#[serde(rename_all = "camelCase")]
+	InvalidRecipients {
+		invalid_recipients: Vec<String>,
+	},
+	/// RFC 8621, the submission was already sent.
+	CannotUnsend,
+	OverQuota,
+	/// RFC 9661, the server didn't accept the script.
+	InvalidSieve {
+		#[serde(skip_serializing_if = "Option::is_none")]
+		description: Option<String>,
+	},
+	/// RFC 9661, the active script can't be destroyed.
+	SieveIsActive,
+}
+
+impl SetError {
+	pub fn invalid_properties(properties: &[&str], description: impl Into<String>) -> Self {
+		SetError::InvalidProperties {
+			description: Some(description.into()),
+			properties:  properties.iter().map(|p| p.to_string()).collect(),
+		}
+	}
+
+	pub fn forbidden(description: impl Into<String>) -> Self {
+		SetError::Forbidden {
+			description: Some(description.into()),
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangesRequest {
+	pub account_id:  Id,
+	pub since_state: String,
+	pub max_changes: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangesResponse {
+	pub account_id:       Id,
+	pub old_state:        String,
+	pub new_state:        String,
+	pub has_more_changes: bool,
+	pub created:          Vec<Id>,
+	pub updated:          Vec<Id>,
+	pub destroyed:        Vec<Id>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Operator {
+	And,
+	Or,
+	Not,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Filter<C> {
+	Operator {
+		operator:   Operator,
+		conditions: Vec<Filter<C>>,
+	},
+	Condition(C),
+}
+
+impl<C> Filter<C> {
+	/// Evaluates the filter with `matches` deciding single conditions.
+	pub fn matches(&self, matches: &impl Fn(&C) -> bool) -> bool {
+		match self {
+			Filter::Condition(c) => matches(c),
+			Filter::Operator {
+				operator: Operator::And,
+				conditions,
+			} => conditions.iter().all(|c| c.matches(matches)),
+			Filter::Operator {
+				operator: Operator::Or,
+				conditions,
+			} => conditions.iter().any(|c| c.matches(matches)),
+			Filter::Operator {
+				operator: Operator::Not,
+				conditions,
+			} => !conditions.iter().any(|c| c.matches(matches)),
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Comparator {
+	pub property:     String,
+	#[serde(default = "default_true")]
+	pub is_ascending: bool,
+	pub collation:    Option<String>,
+}
+
+fn default_true() -> bool {
+	true
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest<C> {
+	pub account_id:      Id,
+	pub filter:          Option<Filter<C>>,
+	#[serde(default)]
+	pub sort:            Vec<Comparator>,
+	#[serde(default)]
+	pub position:        i64,
+	pub anchor:          Option<Id>,
+	#[serde(default)]
+	pub anchor_offset:   i64,
+	pub limit:           Option<u64>,
+	#[serde(default)]
+	pub calculate_total: bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryResponse {
+	pub account_id:            Id,
+	pub query_state:           String,
+	pub can_calculate_changes: bool,
+	pub position:              u64,
+	pub ids:                   Vec<Id>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub total:                 Option<u64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub limit:                 Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryChangesRequest<C> {
+	pub account_id:        Id,
+	pub filter:            Option<Filter<C>>,
+	#[serde(default)]
+	pub sort:              Vec<Comparator>,
+	pub since_query_state: String,
+	pub max_changes:       Option<u64>,
+	#[allow(dead_code)]
+	pub up_to_id:          Option<Id>,
+	#[serde(default)]
+	pub calculate_total:   bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryChangesResponse {
+	pub account_id:      Id,
+	pub old_query_state: String,
+	pub new_query_state: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub total:           Option<u64>,
+	pub removed:         Vec<Id>,
+	pub added:           Vec<AddedItem>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AddedItem {
+	pub id:    Id,
+	pub index: u64,
+}
+
+/// Deserializes a property where `null` and a missing value mean different things.
+pub fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+	T: Deserialize<'de>,
+	D: serde::Deserializer<'de>,
+{
+	Option::<T>::deserialize(deserializer).map(Some)
+}
@@ -0,0 +1,703 @@
+//! `SieveScript` (RFC 9661), proxied to ManageSieve.
+//!
+//! ManageSieve only knows scripts by name, so their ids are kept in the
+//! store. Script blob ids include a hash of the content and stop resolving
+//! once the script changed, like any other blob they're immutable.
+
+use std::{
+	cmp::Ordering,
+	collections::{BTreeMap, HashMap},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+	jmap::{
+		changes,
+		method::{MethodError, MethodResult},
+		query,
+		AccountSieveCapabilities,
+		Comparator,
+		GetRequest,
+		GetResponse,
+		Id,
+		JmapApi,
+		QueryRequest,
+		QueryResponse,
+		SetError,
+		SetRequest,
+		SetResponse,
+		MAX_OBJECTS_IN_GET,
+	},
+	sieve,
+	state::{State, MAIL_SERVER},
+};
+
+const SIEVE_STORE_NAME: &str = "sieve-scripts";
+const SIEVE_CHANGES_STORE_NAME: &str = "sieve-script-changes";
+
+pub const SORT_OPTIONS: &[&str] = &["name", "isActive"];
+
+/// The name of scripts created without one, numbered if taken.
+const DEFAULT_SCRIPT_NAME: &str = "script";
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SieveScript {
+	pub id:        Id,
+	pub name:      String,
+	pub blob_id:   Id,
+	pub is_active: bool,
+}
+
+impl SieveScript {
+	const PROPERTIES: &'static [&'static str] = &["id", "name", "blobId", "isActive"];
+}
+
+/// Script ids by name.
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ScriptIds {
+	next_id: u64,
+	ids:     BTreeMap<String, Id>,
+}
+
+impl ScriptIds {
+	fn id(&mut self, name: &str) -> Id {
+		let next_id = &mut self.next_id;
+		self.ids
+			.entry(name.to_owned())
+			.or_insert_with(|| {
+				*next_id += 1;
+				format!("S{}", next_id)
+			})
+			.clone()
+	}
+
+	/// Ids of the scripts called `names`, scripts that are gone are forgotten.
+	fn assign(&mut self, names: &[String]) -> HashMap<String, Id> {
+		self.ids.retain(|name, _| names.contains(name));
+		names
+			.iter()
+			.map(|name| (name.clone(), self.id(name)))
+			.collect()
+	}
+
+	fn rename(&mut self, name: &str, new_name: &str) {
+		if let Some(id) = self.ids.remove(name) {
+			self.ids.insert(new_name.to_owned(), id);
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SieveScriptFilterCondition {
+	name:      Option<String>,
+	is_active: Option<bool>,
+}
+
+impl SieveScriptFilterCondition {
+	fn matches(&self, script: &SieveScript) -> bool {
+		self.name
+			.as_ref()
+			.is_none_or(|n| script.name.to_lowercase().contains(&n.to_lowercase()))
+			&& self.is_active.is_none_or(|a| a == script.is_active)
+	}
+}
+
+fn compare_scripts(sort: &[Comparator], a: &SieveScript, b: &SieveScript) -> Ordering {
+	for c in sort {
+		let ordering = match c.property.as_str() {
+			"isActive" => a.is_active.cmp(&b.is_active),
+			_ => a.name.cmp(&b.name),
+		};
+		let ordering = if c.is_ascending {
+			ordering
+		} else {
+			ordering.reverse()
+		};
+		if ordering != Ordering::Equal {
+			return ordering;
+		}
+	}
+	a.id.cmp(&b.id)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SieveScriptValidateRequest {
+	pub account_id: Id,
+	pub blob_id:    Id,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SieveScriptValidateResponse {
+	pub account_id: Id,
+	pub error:      Option<SetError>,
+}
+
+// stable across builds, blob ids outlive the process
+fn content_hash(content: &[u8]) -> u64 {
+	changes::fnv1a(content.iter().copied())
+}
+
+pub fn script_blob_id(script_id: &str, content: &[u8]) -> Id {
+	format!("S{}_{:x}", script_id, content_hash(content))
+}
+
+/// The script id and content hash behind a blob id of [`script_blob_id`].
+pub fn parse_script_blob_id(blob_id: &str) -> Option<(Id, u64)> {
+	let (script_id, hash) = blob_id.strip_prefix('S')?.split_once('_')?;
+	Some((script_id.to_owned(), u64::from_str_radix(hash, 16).ok()?))
+}
+
+/// The set error for a `NO` of the server, anything else fails the method.
+fn set_error(e: sieve::Error) -> Result<SetError, MethodError> {
+	match e {
+		sieve::Error::No { code, text } => Ok(match code.as_deref() {
+			Some(code) if code.starts_with("QUOTA") => SetError::OverQuota,
+			Some("ACTIVE") => SetError::SieveIsActive,
+			Some("NONEXISTENT") => SetError::NotFound,
+			Some("ALREADYEXISTS") => SetError::forbidden(text),
+			_ => SetError::InvalidSieve {
+				description: Some(text).filter(|t| !t.is_empty()),
+			},
+		}),
+		e => Err(e.into()),
+	}
+}
+
+/// The properties a client may set, `name` is `Some(None)` when it's null.
+#[derive(Default)]
+struct ScriptPatch {
+	name:    Option<Option<String>>,
+	blob_id: Option<Id>,
+}
+
+impl ScriptPatch {
+	fn parse(object: &Map<String, Value>) -> Result<Self, SetError> {
+		let mut patch = ScriptPatch::default();
+		for (key, value) in object {
+			let invalid = |description: &str| SetError::invalid_properties(&[key], description);
+			match key.as_str() {
+				"name" => {
+					patch.name = Some(match value {
+						Value::Null => None,
+						Value::String(s) if sieve::is_valid_script_name(s) => Some(s.clone()),
+						_ => return Err(invalid("must be a valid script name or null")),
+					})
+				}
+				"blobId" => {
+					patch.blob_id = Some(
+						value
+							.as_str()
+							.map(str::to_owned)
+							.ok_or_else(|| invalid("must be a string"))?,
+					)
+				}
+				"id" | "isActive" => return Err(invalid("property is set by the server")),
+				_ => return Err(invalid("unknown property")),
+			}
+		}
+		Ok(patch)
+	}
+}
+
+/// The account capabilities, as far as the ManageSieve server tells them.
+pub async fn account_capabilities(state: &State, account_id: &str) -> AccountSieveCapabilities {
+	let mut capabilities = AccountSieveCapabilities {
+		max_size_script_name: sieve::MAX_SCRIPT_NAME_LENGTH as u64,
+		..Default::default()
+	};
+
+	let session = match state.credentials(account_id) {
+		Some(credentials) => sieve::connect(MAIL_SERVER, &credentials).await,
+		None => return capabilities,
+	};
+	let session = match session {
+		Ok(session) => session,
+		Err(e) => {
+			tracing::error!("reading the managesieve capabilities failed: {}", e);
+			return capabilities;
+		}
+	};
+
+	let get = |name: &str| session.capabilities.get(name);
+	let words = |s: &String| s.split_whitespace().map(str::to_owned).collect();
+	capabilities.implementation = get("IMPLEMENTATION").cloned().unwrap_or_default();
+	capabilities.sieve_extensions = get("SIEVE").map(words).unwrap_or_default();
+	capabilities.max_number_redirects = get("MAXREDIRECTS").and_then(|m| m.parse().ok());
+	capabilities.notification_methods = get("NOTIFY").map(words);
+	capabilities.external_lists = get("EXTLISTS").map(words);
+	session.logout().await;
+
+	capabilities
+}
+
+impl JmapApi<'_> {
+	/// All scripts, new ones get an id.
+	async fn sieve_scripts(
+		&self,
+		session: &mut sieve::Session,
+	) -> Result<Vec<SieveScript>, MethodError> {
+		let listed = session.list_scripts().await?;
+		let names: Vec<String> = listed.iter().map(|(name, _)| name.clone()).collect();
+		let ids = self
+			.state
+			.store
+			.update(
+				self.account_id(),
+				SIEVE_STORE_NAME,
+				|ids: &mut ScriptIds| ids.assign(&names),
+			)
+			.await?;
+
+		let mut scripts = vec![];
+		for (name, is_active) in listed {
+			let content = session.get_script(&name).await?;
+			let id = ids[&name].clone();
+			scripts.push(SieveScript {
+				blob_id: script_blob_id(&id, &content),
+				id,
+				name,
+				is_active,
+			});
+		}
+		Ok(scripts)
+	}
+
+	async fn sieve_script_state(&self, scripts: &[SieveScript]) -> Result<String, MethodError> {
+		let objects = changes::to_objects(scripts.iter().map(|s| (&s.id, s)))?;
+		self.record_state(SIEVE_CHANGES_STORE_NAME, &objects).await
+	}
+
+	/// The content of a script blob, `None` once the script changed.
+	pub async fn fetch_script_blob(
+		&self,
+		script_id: &str,
+		hash: u64,
+	) -> Result<Option<Vec<u8>>, MethodError> {
+		let ids: ScriptIds = self
+			.state
+			.store
+			.get(self.account_id(), SIEVE_STORE_NAME)
+			.await?;
+		let name = match ids.ids.iter().find(|(_, id)| *id == script_id) {
+			Some((name, _)) => name.clone(),
+			None => return Ok(None),
+		};
+
+		let mut session = self.sieve_session().await?;
+		let content = session.get_script(&name).await;
+		session.logout().await;
+		match content {
+			Ok(content) if content_hash(&content) == hash => Ok(Some(content)),
+			Ok(_) | Err(sieve::Error::No { .. }) => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	pub async fn handle_sieve_script_get(
+		&self,
+		request: GetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let mut session = self.sieve_session().await?;
+		let scripts = self.sieve_scripts(&mut session).await;
+		session.logout().await;
+		let scripts = scripts?;
+		let state = self.sieve_script_state(&scripts).await?;
+
+		let ids: Vec<Id> = match request.ids {
+			Some(ids) => ids,
+			None => scripts.iter().map(|s| s.id.clone()).collect(),
+		};
+		if ids.len() > MAX_OBJECTS_IN_GET {
+			return Err(MethodError::RequestTooLarge);
+		}
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in ids {
+			match scripts.iter().find(|s| s.id == id) {
+				Some(script) => list.push(script),
+				None => not_found.push(id),
+			}
+		}
+
+		Ok(MethodResult::SieveScriptGet(GetResponse {
+			account_id: request.account_id,
+			state,
+			list: super::select_properties(list, &request.properties, SieveScript::PROPERTIES)?,
+			not_found,
+		}))
+	}
+
+	pub async fn handle_sieve_script_query(
+		&self,
+		request: QueryRequest<SieveScriptFilterCondition>,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		if let Some(c) = request
+			.sort
+			.iter()
+			.find(|c| !SORT_OPTIONS.contains(&c.property.as_str()))
+		{
+			return Err(MethodError::UnsupportedSort {
+				description: Some(format!("can't sort scripts by {}", c.property)),
+			});
+		}
+
+		let mut session = self.sieve_session().await?;
+		let scripts = self.sieve_scripts(&mut session).await;
+		session.logout().await;
+		let mut scripts = scripts?;
+		let query_state = self.sieve_script_state(&scripts).await?;
+
+		scripts.retain(|s| {
+			request
+				.filter
+				.as_ref()
+				.is_none_or(|f| f.matches(&|c: &SieveScriptFilterCondition| c.matches(s)))
+		});
+		scripts.sort_by(|a, b| compare_scripts(&request.sort, a, b));
+
+		let ids: Vec<Id> = scripts.into_iter().map(|s| s.id).collect();
+		let total = ids.len() as u64;
+		let page = query::page(
+			ids,
+			request.position,
+			request.anchor.as_ref(),
+			request.anchor_offset,
+			request.limit,
+		)?;
+
+		Ok(MethodResult::SieveScriptQuery(QueryResponse {
+			account_id: request.account_id,
+			query_state,
+			can_calculate_changes: false,
+			position: page.position,
+			ids: page.ids,
+			total: if request.calculate_total {
+				Some(total)
+			} else {
+				None
+			},
+			limit: None,
+		}))
+	}
+
+	pub async fn handle_sieve_script_validate(
+		&self,
+		request: SieveScriptValidateRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let error = match self.fetch_blob(&request.blob_id).await? {
+			Some(content) => {
+				let mut session = self.sieve_session().await?;
+				let checked = session.check_script(&content).await;
+				session.logout().await;
+				checked.err().map(set_error).transpose()?
+			}
+			None => Some(SetError::BlobNotFound {
+				not_found: vec![request.blob_id],
+			}),
+		};
+
+		Ok(MethodResult::SieveScriptValidate(
+			SieveScriptValidateResponse {
+				account_id: request.account_id,
+				error,
+			},
+		))
+	}
+
+	/// Creates, updates and destroys scripts, then activates the script
+	/// `on_success_activate_script` or deactivates the active one if all of
+	/// that succeeded.
+	pub async fn handle_sieve_script_set(
+		&self,
+		request: SetRequest,
+		on_success_activate_script: Option<Id>,
+		on_success_deactivate_script: bool,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let mut session = self.sieve_session().await?;
+		let result = self
+			.sieve_script_set(
+				&mut session,
+				request,
+				on_success_activate_script,
+				on_success_deactivate_script,
+			)
+			.await;
+		session.logout().await;
+		result
+	}
+
+	async fn sieve_script_set(
+		&self,
+		session: &mut sieve::Session,
+		request: SetRequest,
+		on_success_activate_script: Option<Id>,
+		on_success_deactivate_script: bool,
+	) -> Result<MethodResult, MethodError> {
+		let scripts = self.sieve_scripts(session).await?;
+		let old_state = self.sieve_script_state(&scripts).await?;
+		if request
+			.if_in_state
+			.as_ref()
+			.is_some_and(|s| *s != old_state)
+		{
+			return Err(MethodError::StateMismatch);
+		}
+
+		let mut names: HashMap<Id, String> = scripts
+			.iter()
+			.map(|s| (s.id.clone(), s.name.clone()))
+			.collect();
+		let active = scripts.iter().find(|s| s.is_active).map(|s| s.id.clone());
+
+		let mut response = SetResponse {
+			account_id: request.account_id,
+			old_state: Some(old_state),
+			..Default::default()
+		};
+
+		for (creation_id, object) in request.create.unwrap_or_default() {
+			match self.create_sieve_script(session, &names, &object).await? {
+				Ok(script) => {
+					self.record_created_id(&creation_id, &script.id);
+					names.insert(script.id.clone(), script.name.clone());
+					response.created.insert(
+						creation_id,
+						serde_json::to_value(&script).map_err(tide::Error::from)?,
+					);
+				}
+				Err(e) => {
+					response.not_created.insert(creation_id, e);
+				}
+			}
+		}
+
+		for (id, patch) in request.update.unwrap_or_default() {
+			let (script_id, name) = match self
+				.resolve_id(&id)
+				.and_then(|i| Some((i.clone(), names.get(&i)?.clone())))
+			{
+				Some(script) => script,
+				None => {
+					response.not_updated.insert(id, SetError::NotFound);
+					continue;
+				}
+			};
+			match self
+				.update_sieve_script(session, &names, &script_id, &name, &patch)
+				.await?
+			{
+				Ok((new_name, blob_id)) => {
+					names.insert(script_id, new_name);
+					response
+						.updated
+						.insert(id, blob_id.map(|b| json!({ "blobId": b })));
+				}
+				Err(e) => {
+					response.not_updated.insert(id, e);
+				}
+			}
+		}
+
+		for id in request.destroy.unwrap_or_default() {
+			let (script_id, name) = match self
+				.resolve_id(&id)
+				.and_then(|i| Some((i.clone(), names.get(&i)?.clone())))
+			{
+				Some(script) => script,
+				None => {
+					response.not_destroyed.insert(id, SetError::NotFound);
+					continue;
+				}
+			};
+			if active.as_ref() == Some(&script_id) {
+				response.not_destroyed.insert(id, SetError::SieveIsActive);
+				continue;
+			}
+			match session.delete_script(&name).await {
+				Ok(()) => {
+					names.remove(&script_id);
+					response.destroyed.push(id);
+				}
+				Err(e) => {
+					response.not_destroyed.insert(id, set_error(e)?);
+				}
+			}
+		}
+
+		let succeeded = response.not_created.is_empty()
+			&& response.not_updated.is_empty()
+			&& response.not_destroyed.is_empty();
+		let activate = on_success_activate_script
+			.and_then(|id| self.resolve_id(&id))
+			.filter(|id| names.contains_key(id));
+		if succeeded && (activate.is_some() || on_success_deactivate_script) {
+			let name = activate.as_ref().map_or("", |id| names[id].as_str());
+			session.set_active(name).await?;
+
+			// the scripts whose isActive changed count as updated
+			let mut changed = |id: &Id, is_active: bool| {
+				response
+					.updated
+					.entry(id.clone())
+					.or_insert(None)
+					.get_or_insert_with(|| json!({}))["isActive"] = json!(is_active);
+			};
+			if let Some(id) = active.as_ref().filter(|a| activate.as_ref() != Some(*a)) {
+				if names.contains_key(id) {
+					changed(id, false);
+				}
+			}
+			if let Some(id) = activate.as_ref().filter(|a| active.as_ref() != Some(*a)) {
+				changed(id, true);
+			}
+		}
+
+		response.new_state = self
+			.sieve_script_state(&self.sieve_scripts(session).await?)
+			.await?;
+
+		Ok(MethodResult::SieveScriptSet(response))
+	}
+
+	async fn create_sieve_script(
+		&self,
+		session: &mut sieve::Session,
+		names: &HashMap<Id, String>,
+		object: &Map<String, Value>,
+	) -> Result<Result<SieveScript, SetError>, MethodError> {
+		let patch = match ScriptPatch::parse(object) {
+			Ok(patch) => patch,
+			Err(e) => return Ok(Err(e)),
+		};
+		let blob_id = match patch.blob_id {
+			Some(blob_id) => blob_id,
+			None => {
+				return Ok(Err(SetError::invalid_properties(
+					&["blobId"],
+					"a script needs content",
+				)))
+			}
+		};
+		let name = match patch.name.flatten() {
+			Some(name) => {
+				if let Some((existing_id, _)) = names.iter().find(|(_, n)| **n == name) {
+					return Ok(Err(SetError::AlreadyExists {
+						existing_id: existing_id.clone(),
+					}));
+				}
+				name
+			}
+			None => (1..)
+				.map(|n| match n {
+					1 => DEFAULT_SCRIPT_NAME.to_owned(),
+					n => format!("{}-{}", DEFAULT_SCRIPT_NAME, n),
+				})
+				.find(|name| !names.values().any(|n| n == name))
+				.unwrap_or_default(),
+		};
+
+		let content = match self.fetch_blob(&blob_id).await? {
+			Some(content) => content,
+			None => {
+				return Ok(Err(SetError::BlobNotFound {
+					not_found: vec![blob_id],
+				}))
+			}
+		};
+		if let Err(e) = session.put_script(&name, &content).await {
+			return Ok(Err(set_error(e)?));
+		}
+
+		let id = self
+			.state
+			.store
+			.update(
+				self.account_id(),
+				SIEVE_STORE_NAME,
+				|ids: &mut ScriptIds| ids.id(&name),
+			)
+			.await?;
+		Ok(Ok(SieveScript {
+			blob_id: script_blob_id(&id, &content),
+			id,
+			name,
+			is_active: false,
+		}))
+	}
+
+	/// Replaces the content and renames the script, returns the new name and
+	/// the new blob id if the content changed.
+	async fn update_sieve_script(
+		&self,
+		session: &mut sieve::Session,
+		names: &HashMap<Id, String>,
+		script_id: &str,
+		name: &str,
+		object: &Map<String, Value>,
+	) -> Result<Result<(String, Option<Id>), SetError>, MethodError> {
+		let patch = match ScriptPatch::parse(object) {
+			Ok(patch) => patch,
+			Err(e) => return Ok(Err(e)),
+		};
+		// a null name keeps the current one
+		let new_name = patch.name.flatten().filter(|n| n != name);
+		if let Some(new_name) = &new_name {
+			if let Some((existing_id, _)) = names.iter().find(|(_, n)| *n == new_name) {
+				return Ok(Err(SetError::AlreadyExists {
+					existing_id: existing_id.clone(),
+				}));
+			}
+		}
+
+		let mut blob_id = None;
+		if let Some(new_blob_id) = patch.blob_id {
+			let content = match self.fetch_blob(&new_blob_id).await? {
+				Some(content) => content,
+				None => {
+					return Ok(Err(SetError::BlobNotFound {
+						not_found: vec![new_blob_id],
+					}))
+				}
+			};
+			if let Err(e) = session.put_script(name, &content).await {
+				return Ok(Err(set_error(e)?));
+			}
+			blob_id = Some(script_blob_id(script_id, &content));
+		}
+
+		let name = match new_name {
+			Some(new_name) => {
+				if let Err(e) = session.rename_script(name, &new_name).await {
+					return Ok(Err(set_error(e)?));
+				}
+				self.state
+					.store
+					.update(
+						self.account_id(),
+						SIEVE_STORE_NAME,
+						|ids: &mut ScriptIds| ids.rename(name, &new_name),
+					)
+					.await?;
+				new_name
+			}
+			None => name.to_owned(),
+		};
+		Ok(Ok((name, blob_id)))
+	}
+}
@@ -0,0 +1,1082 @@
+//! `EmailSubmission`, sending emails through the smtp submission server.
+//!
+//! Imap has no notion of submissions, so they are kept in the store once
+//! they were handed to the smtp server or put on hold.
+
+pub mod queue;
+
+use std::{
+	cmp::Ordering,
+	collections::{BTreeMap, BTreeSet, HashMap},
+};
+
+use async_native_tls::TlsStream;
+use async_std::net::TcpStream;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+	jmap::{
+		changes,
+		email::{email_blob_id, EmailGetRequest},
+		identity::{is_allowed_sender, Identity},
+		method::{MethodError, MethodResult},
+		query,
+		submission::queue::QueuedMessage,
+		ChangesRequest,
+		Comparator,
+		GetRequest,
+		GetResponse,
+		Id,
+		JmapApi,
+		QueryRequest,
+		QueryResponse,
+		SetError,
+		SetRequest,
+		SetResponse,
+		MAX_OBJECTS_IN_GET,
+	},
+	mime,
+	smtp,
+};
+
+const SUBMISSION_STORE_NAME: &str = "email-submissions";
+const SUBMISSION_CHANGES_STORE_NAME: &str = "email-submission-changes";
+
+const CREATE_PROPERTIES: &[&str] = &["identityId", "emailId", "envelope"];
+
+pub const SORT_OPTIONS: &[&str] = &["emailId", "threadId", "sentAt"];
+
+type SmtpClient = smtp::Client<TlsStream<TcpStream>>;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Address {
+	pub email:      String,
+	pub parameters: Option<BTreeMap<String, Option<String>>>,
+}
+
+impl Address {
+	fn new(email: String) -> Self {
+		Address {
+			email,
+			parameters: None,
+		}
+	}
+
+	/// Anything else could inject smtp commands.
+	fn is_valid(&self) -> bool {
+		let is_safe = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_graphic());
+		is_safe(&self.email)
+			&& !self.email.contains(&['<', '>'][..])
+			&& self.parameters.iter().flatten().all(|(key, value)| {
+				key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+					&& !key.is_empty()
+					&& value
+						.as_deref()
+						.is_none_or(|v| !v.contains(&['\r', '\n'][..]))
+			})
+	}
+
+	/// The path and parameters as used in `MAIL FROM` and `RCPT TO`.
+	fn smtp_path(&self) -> String {
+		let mut path = format!("<{}>", self.email);
+		for (key, value) in self.parameters.iter().flatten() {
+			path.push(' ');
+			path.push_str(key);
+			if let Some(value) = value {
+				path.push('=');
+				path.push_str(&xtext(value));
+			}
+		}
+		path
+	}
+}
+
+/// Encodes a parameter value as xtext (RFC 3461), jmap passes them decoded.
+fn xtext(value: &str) -> String {
+	value
+		.bytes()
+		.map(|b| match b {
+			b'!'..=b'~' if b != b'+' && b != b'=' => (b as char).to_string(),
+			b => format!("+{:02X}", b),
+		})
+		.collect()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope {
+	pub mail_from: Address,
+	pub rcpt_to:   Vec<Address>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum UndoStatus {
+	Pending,
+	Final,
+	Canceled,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Delivered {
+	Queued,
+	Yes,
+	No,
+	Unknown,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Displayed {
+	Unknown,
+	Yes,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryStatus {
+	pub smtp_reply: String,
+	pub delivered:  Delivered,
+	pub displayed:  Displayed,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailSubmission {
+	pub id:              Id,
+	pub identity_id:     Id,
+	pub email_id:        Id,
+	pub thread_id:       Id,
+	pub envelope:        Envelope,
+	pub send_at:         String,
+	pub undo_status:     UndoStatus,
+	pub delivery_status: Option<BTreeMap<String, DeliveryStatus>>,
+	pub dsn_blob_ids:    Vec<Id>,
+	pub mdn_blob_ids:    Vec<Id>,
+}
+
+impl EmailSubmission {
+	const PROPERTIES: &'static [&'static str] = &[
+		"id",
+		"identityId",
+		"emailId",
+		"threadId",
+		"envelope",
+		"sendAt",
+		"undoStatus",
+		"deliveryStatus",
+		"dsnBlobIds",
+		"mdnBlobIds",
+	];
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Submissions {
+	next_id:     u64,
+	submissions: BTreeMap<Id, EmailSubmission>,
+	/// Held submissions being sent, too late to cancel but not final before
+	/// the server took them.
+	#[serde(default)]
+	sending:     BTreeSet<Id>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailSubmissionFilterCondition {
+	identity_ids: Option<Vec<Id>>,
+	email_ids:    Option<Vec<Id>>,
+	thread_ids:   Option<Vec<Id>>,
+	undo_status:  Option<UndoStatus>,
+	before:       Option<String>,
+	after:        Option<String>,
+}
+
+fn parse_date(date: &str) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+	chrono::DateTime::parse_from_rfc3339(date).ok()
+}
+
+impl EmailSubmissionFilterCondition {
+	fn matches(&self, submission: &EmailSubmission) -> bool {
+		let send_at = parse_date(&submission.send_at);
+		self.identity_ids
+			.as_ref()
+			.is_none_or(|ids| ids.contains(&submission.identity_id))
+			&& self
+				.email_ids
+				.as_ref()
+				.is_none_or(|ids| ids.contains(&submission.email_id))
+			&& self
+				.thread_ids
+				.as_ref()
+				.is_none_or(|ids| ids.contains(&submission.thread_id))
+			&& self.undo_status.is_none_or(|s| s == submission.undo_status)
+			&& self
+				.before
+				.as_deref()
+				.is_none_or(|d| send_at.is_some_and(|s| parse_date(d).is_some_and(|d| s < d)))
+			&& self
+				.after
+				.as_deref()
+				.is_none_or(|d| send_at.is_some_and(|s| parse_date(d).is_some_and(|d| s >= d)))
+	}
+}
+
+fn compare_submissions(sort: &[Comparator], a: &EmailSubmission, b: &EmailSubmission) -> Ordering {
+	for c in sort {
+		let ordering = match c.property.as_str() {
+			"emailId" => a.email_id.cmp(&b.email_id),
+			"threadId" => a.thread_id.cmp(&b.thread_id),
+			_ => parse_date(&a.send_at).cmp(&parse_date(&b.send_at)),
+		};
+		let ordering = if c.is_ascending {
+			ordering
+		} else {
+			ordering.reverse()
+		};
+		if ordering != Ordering::Equal {
+			return ordering;
+		}
+	}
+	a.id.cmp(&b.id)
+}
+
+/// All addresses of the header fields called `names`.
+fn header_addresses(headers: &[mime::Header], names: &[&str]) -> Vec<mime::EmailAddress> {
+	headers
+		.iter()
+		.filter(|h| names.iter().any(|n| h.name.eq_ignore_ascii_case(n)))
+		.flat_map(|h| mime::parse_addresses(&h.value))
+		.collect()
+}
+
+/// The envelope RFC 8621 asks for when the client doesn't give one, from
+/// `Sender` or `From` to everyone in `To`, `Cc` and `Bcc`.
+fn envelope_from_headers(headers: &[mime::Header]) -> Result<Envelope, SetError> {
+	let sender = header_addresses(headers, &["Sender"]);
+	let from = header_addresses(headers, &["From"]);
+	let mail_from = match (sender.first(), from.as_slice()) {
+		(Some(sender), _) => sender.email.clone(),
+		(None, [from]) => from.email.clone(),
+		(None, _) => {
+			return Err(SetError::InvalidEmail {
+				description: Some("the email needs a Sender or exactly one From".to_owned()),
+			})
+		}
+	};
+
+	let mut rcpt_to: Vec<Address> = vec![];
+	for address in header_addresses(headers, &["To", "Cc", "Bcc"]) {
+		if !rcpt_to
+			.iter()
+			.any(|a| a.email.eq_ignore_ascii_case(&address.email))
+		{
+			rcpt_to.push(Address::new(address.email));
+		}
+	}
+
+	Ok(Envelope {
+		mail_from: Address::new(mail_from),
+		rcpt_to,
+	})
+}
+
+/// Removes the header field `name`, continuation lines included.
+fn strip_header(message: &[u8], name: &str) -> Vec<u8> {
+	let (_, body_start) = mime::parse_headers(message);
+	let mut out = Vec::with_capacity(message.len());
+	let mut skipping = false;
+	for line in message[..body_start].split_inclusive(|b| *b == b'\n') {
+		if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+			skipping = line.len() > name.len()
+				&& line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+				&& line[name.len()..]
+					.iter()
+					.find(|b| **b != b' ' && **b != b'\t')
+					== Some(&b':');
+		}
+		if !skipping {
+			out.extend_from_slice(line);
+		}
+	}
+	out.extend_from_slice(&message[body_start..]);
+	out
+}
+
+/// The address of a path like `<a@example.com> NOTIFY=NEVER`.
+fn path_address(path: &str) -> &str {
+	path.trim_start_matches('<').split('>').next().unwrap_or("")
+}
+
+/// The set error for a message the smtp server didn't take.
+fn rejection_error(error: smtp::Error, max_size: Option<u64>) -> Result<SetError, MethodError> {
+	Ok(match error {
+		smtp::Error::Rejected { reply, .. } if reply.code == 552 => SetError::TooLarge { max_size },
+		smtp::Error::Rejected {
+			command: smtp::Command::MailFrom,
+			reply,
+		} => SetError::ForbiddenMailFrom {
+			description: Some(reply.to_string()),
+		},
+		smtp::Error::Rejected { reply, .. } => SetError::ForbiddenToSend {
+			description: Some(reply.to_string()),
+		},
+		smtp::Error::RecipientsRejected(rejected)
+			if rejected.iter().any(|(_, r)| r.is_permanent_failure()) =>
+		{
+			SetError::InvalidRecipients {
+				invalid_recipients: rejected
+					.iter()
+					.filter(|(_, r)| r.is_permanent_failure())
+					.map(|(rcpt, _)| path_address(rcpt).to_owned())
+					.collect(),
+			}
+		}
+		smtp::Error::RecipientsRejected(rejected) => SetError::ForbiddenToSend {
+			description: rejected.first().map(|(_, r)| r.to_string()),
+		},
+		e => return Err(e.into()),
+	})
+}
+
+/// The implicit `Email/set` for the submissions that `succeeded`, keyed by
+/// the ids the `onSuccess*` arguments refer to them with.
+fn on_success_email_set(
+	account_id: Id,
+	succeeded: &HashMap<Id, Id>,
+	on_success_update_email: Option<HashMap<Id, Map<String, Value>>>,
+	on_success_destroy_email: Option<Vec<Id>>,
+) -> Option<SetRequest> {
+	let update: HashMap<Id, Map<String, Value>> = on_success_update_email
+		.unwrap_or_default()
+		.into_iter()
+		.filter_map(|(id, patch)| Some((succeeded.get(&id)?.clone(), patch)))
+		.collect();
+	let destroy: Vec<Id> = on_success_destroy_email
+		.unwrap_or_default()
+		.iter()
+		.filter_map(|id| succeeded.get(id).cloned())
+		.collect();
+	if update.is_empty() && destroy.is_empty() {
+		return None;
+	}
+	Some(SetRequest {
+		account_id,
+		if_in_state: None,
+		create: None,
+		update: Some(update),
+		destroy: Some(destroy),
+	})
+}
+
+impl JmapApi<'_> {
+	async fn submissions(&self) -> Result<Vec<EmailSubmission>, MethodError> {
+		let submissions: Submissions = self
+			.state
+			.store
+			.get(self.account_id(), SUBMISSION_STORE_NAME)
+			.await?;
+		Ok(submissions.submissions.into_values().collect())
+	}
+
+	async fn submission_state(
+		&self,
+		submissions: &[EmailSubmission],
+	) -> Result<String, MethodError> {
+		let objects = changes::to_objects(submissions.iter().map(|s| (&s.id, s)))?;
+		self.record_state(SUBMISSION_CHANGES_STORE_NAME, &objects)
+			.await
+	}
+
+	pub async fn handle_email_submission_get(
+		&self,
+		request: GetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let submissions = self.submissions().await?;
+		let state = self.submission_state(&submissions).await?;
+
+		let ids: Vec<Id> = match request.ids {
+			Some(ids) => ids,
+			None => submissions.iter().map(|s| s.id.clone()).collect(),
+		};
+		if ids.len() > MAX_OBJECTS_IN_GET {
+			return Err(MethodError::RequestTooLarge);
+		}
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in ids {
+			match submissions.iter().find(|s| s.id == id) {
+				Some(submission) => list.push(submission),
+				None => not_found.push(id),
+			}
+		}
+
+		Ok(MethodResult::EmailSubmissionGet(GetResponse {
+			account_id: request.account_id,
+			state,
+			list: super::select_properties(list, &request.properties, EmailSubmission::PROPERTIES)?,
+			not_found,
+		}))
+	}
+
+	pub async fn handle_email_submission_changes(
+		&self,
+		request: ChangesRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let submissions = self.submissions().await?;
+		let objects = changes::to_objects(submissions.iter().map(|s| (&s.id, s)))?;
+		let changes = self
+			.record_changes(
+				SUBMISSION_CHANGES_STORE_NAME,
+				&objects,
+				&request.since_state,
+				request.max_changes,
+			)
+			.await?;
+
+		Ok(MethodResult::EmailSubmissionChanges(
+			changes.into_response(request.account_id),
+		))
+	}
+
+	pub async fn handle_email_submission_query(
+		&self,
+		request: QueryRequest<EmailSubmissionFilterCondition>,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		if let Some(c) = request
+			.sort
+			.iter()
+			.find(|c| !SORT_OPTIONS.contains(&c.property.as_str()))
+		{
+			return Err(MethodError::UnsupportedSort {
+				description: Some(format!("can't sort submissions by {}", c.property)),
+			});
+		}
+
+		let mut submissions = self.submissions().await?;
+		let query_state = self.submission_state(&submissions).await?;
+
+		submissions.retain(|s| {
+			request
+				.filter
+				.as_ref()
+				.is_none_or(|f| f.matches(&|c: &EmailSubmissionFilterCondition| c.matches(s)))
+		});
+		submissions.sort_by(|a, b| compare_submissions(&request.sort, a, b));
+
+		let ids: Vec<Id> = submissions.into_iter().map(|s| s.id).collect();
+		let total = ids.len() as u64;
+		let page = query::page(
+			ids,
+			request.position,
+			request.anchor.as_ref(),
+			request.anchor_offset,
+			request.limit,
+		)?;
+
+		Ok(MethodResult::EmailSubmissionQuery(QueryResponse {
+			account_id: request.account_id,
+			query_state,
+			can_calculate_changes: false,
+			position: page.position,
+			ids: page.ids,
+			total: if request.calculate_total {
+				Some(total)
+			} else {
+				None
+			},
+			limit: None,
+		}))
+	}
+
+	/// Sends the created submissions, also returns the response of the
+	/// implicit `Email/set` for `onSuccessUpdateEmail` and
+	/// `onSuccessDestroyEmail`.
+	pub async fn handle_email_submission_set(
+		&self,
+		request: SetRequest,
+		on_success_update_email: Option<HashMap<Id, Map<String, Value>>>,
+		on_success_destroy_email: Option<Vec<Id>>,
+	) -> Result<(MethodResult, Option<MethodResult>), MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let old_state = self.submission_state(&self.submissions().await?).await?;
+		if request
+			.if_in_state
+			.as_ref()
+			.is_some_and(|s| *s != old_state)
+		{
+			return Err(MethodError::StateMismatch);
+		}
+
+		let mut response = SetResponse {
+			account_id: request.account_id.clone(),
+			old_state: Some(old_state),
+			..Default::default()
+		};
+		// email ids of the submissions that changed, by the ids the
+		// `onSuccess*` arguments refer to them with
+		let mut succeeded: HashMap<Id, Id> = HashMap::new();
+
+		let identities = self.identities().await?;
+		let mut client = None;
+		for (creation_id, object) in request.create.unwrap_or_default() {
+			let (submission, held) = match self
+				.create_submission(&mut client, &identities, &object)
+				.await?
+			{
+				Ok(created) => created,
+				Err(e) => {
+					response.not_created.insert(creation_id, e);
+					continue;
+				}
+			};
+
+			let mut created = serde_json::json!({
+				"threadId": submission.thread_id,
+				"sendAt": submission.send_at,
+				"undoStatus": submission.undo_status,
+			});
+			let email_id = submission.email_id.clone();
+			let id = self
+				.state
+				.store
+				.update(
+					self.account_id(),
+					SUBMISSION_STORE_NAME,
+					|submissions: &mut Submissions| {
+						let id = format!("S{}", submissions.next_id);
+						submissions.next_id += 1;
+						submissions.submissions.insert(
+							id.clone(),
+							EmailSubmission {
+								id: id.clone(),
+								..submission
+							},
+						);
+						id
+					},
+				)
+				.await?;
+			if let Some(held) = held {
+				queue::enqueue(self.state, self.account_id(), &id, held).await?;
+			}
+
+			self.record_created_id(&creation_id, &id);
+			created["id"] = Value::String(id);
+			response.created.insert(creation_id.clone(), created);
+			succeeded.insert(format!("#{}", creation_id), email_id);
+		}
+		if let Some(client) = client {
+			client.quit().await;
+		}
+
+		let updates = request.update.unwrap_or_default();
+		let destroy = request.destroy.unwrap_or_default();
+		if !updates.is_empty() || !destroy.is_empty() {
+			let (updated, not_updated, destroyed, not_destroyed, canceled) = self
+				.state
+				.store
+				.update(
+					self.account_id(),
+					SUBMISSION_STORE_NAME,
+					|submissions: &mut Submissions| {
+						let mut updated = vec![];
+						let mut not_updated = vec![];
+						// held messages that won't be sent anymore
+						let mut canceled = vec![];
+						for (id, patch) in updates {
+							let sending = self
+								.resolve_id(&id)
+								.is_some_and(|i| submissions.sending.contains(&i));
+							let submission = match self
+								.resolve_id(&id)
+								.and_then(|i| submissions.submissions.get_mut(&i))
+							{
+								Some(submission) => submission,
+								None => {
+									not_updated.push((id, SetError::NotFound));
+									continue;
+								}
+							};
+							let was_pending = submission.undo_status == UndoStatus::Pending;
+							match update_submission(submission, &patch, sending) {
+								Ok(()) => {
+									if was_pending && submission.undo_status == UndoStatus::Canceled
+									{
+										canceled.push(submission.id.clone());
+									}
+									updated.push((id, submission.email_id.clone()));
+								}
+								Err(e) => not_updated.push((id, e)),
+							}
+						}
+
+						let mut destroyed = vec![];
+						let mut not_destroyed = vec![];
+						for id in destroy {
+							match self
+								.resolve_id(&id)
+								.and_then(|i| submissions.submissions.remove(&i))
+							{
+								Some(submission) => {
+									if submission.undo_status == UndoStatus::Pending
+										&& !submissions.sending.contains(&submission.id)
+									{
+										canceled.push(submission.id);
+									}
+									destroyed.push((id, submission.email_id));
+								}
+								None => not_destroyed.push((id, SetError::NotFound)),
+							}
+						}
+
+						(updated, not_updated, destroyed, not_destroyed, canceled)
+					},
+				)
+				.await?;
+			queue::dequeue(self.state, self.account_id(), &canceled).await?;
+
+			for (id, email_id) in updated {
+				response.updated.insert(id.clone(), None);
+				succeeded.insert(id, email_id);
+			}
+			response.not_updated.extend(not_updated);
+			for (id, email_id) in destroyed {
+				response.destroyed.push(id.clone());
+				succeeded.insert(id, email_id);
+			}
+			response.not_destroyed.extend(not_destroyed);
+		}
+
+		response.new_state = self.submission_state(&self.submissions().await?).await?;
+
+		let email_set = match on_success_email_set(
+			request.account_id,
+			&succeeded,
+			on_success_update_email,
+			on_success_destroy_email,
+		) {
+			Some(email_set) => Some(
+				self.handle_email_set(email_set)
+					.await
+					.unwrap_or_else(MethodResult::Error),
+			),
+			None => None,
+		};
+
+		Ok((MethodResult::EmailSubmissionSet(response), email_set))
+	}
+
+	/// Checks a new submission and hands it to the smtp server, connecting
+	/// on first use. Held submissions are returned with their message
+	/// instead.
+	async fn create_submission(
+		&self,
+		client: &mut Option<SmtpClient>,
+		identities: &[Identity],
+		object: &Map<String, Value>,
+	) -> Result<Result<(EmailSubmission, Option<QueuedMessage>), SetError>, MethodError> {
+		if let Some(key) = object
+			.keys()
+			.find(|k| !CREATE_PROPERTIES.contains(&k.as_str()))
+		{
+			return Ok(Err(SetError::invalid_properties(
+				&[key],
+				"property can't be set",
+			)));
+		}
+
+		let identity = match object
+			.get("identityId")
+			.and_then(Value::as_str)
+			.and_then(|id| self.resolve_id(id))
+			.and_then(|id| identities.iter().find(|i| i.id == id))
+		{
+			Some(identity) => identity,
+			None => {
+				return Ok(Err(SetError::invalid_properties(
+					&["identityId"],
+					"no such identity",
+				)))
+			}
+		};
+		let email_id = match object
+			.get("emailId")
+			.and_then(Value::as_str)
+			.and_then(|id| self.resolve_id(id))
+		{
+			Some(id) => id,
+			None => {
+				return Ok(Err(SetError::invalid_properties(
+					&["emailId"],
+					"must be an email id",
+				)))
+			}
+		};
+		let envelope = match object.get("envelope") {
+			None | Some(Value::Null) => None,
+			Some(envelope) => match serde_json::from_value::<Envelope>(envelope.clone())
+				.ok()
+				.filter(|e| e.mail_from.is_valid() && e.rcpt_to.iter().all(Address::is_valid))
+			{
+				Some(envelope) => Some(envelope),
+				None => {
+					return Ok(Err(SetError::invalid_properties(
+						&["envelope"],
+						"invalid envelope",
+					)))
+				}
+			},
+		};
+
+		let message = match self.fetch_blob(&email_blob_id(&email_id)).await? {
+			Some(message) => message,
+			None => {
+				return Ok(Err(SetError::invalid_properties(
+					&["emailId"],
+					"no such email",
+				)))
+			}
+		};
+		let (headers, _) = mime::parse_headers(&message);
+
+		let allowed =
+			|email: &str| is_allowed_sender(&self.state.allowed_senders, self.account_id(), email);
+		if !allowed(&identity.email) {
+			return Ok(Err(SetError::ForbiddenFrom {
+				description: Some(format!("can't send as {}", identity.email)),
+			}));
+		}
+		if let Some(from) = header_addresses(&headers, &["From"])
+			.into_iter()
+			.find(|a| !allowed(&a.email))
+		{
+			return Ok(Err(SetError::ForbiddenFrom {
+				description: Some(format!("can't send as {}", from.email)),
+			}));
+		}
+
+		let mut envelope = match envelope.map_or_else(|| envelope_from_headers(&headers), Ok) {
+			Ok(envelope) if !envelope.mail_from.is_valid() => {
+				return Ok(Err(SetError::InvalidEmail {
+					description: Some("invalid sender address".to_owned()),
+				}))
+			}
+			Ok(envelope) => envelope,
+			Err(e) => return Ok(Err(e)),
+		};
+		if !allowed(&envelope.mail_from.email) {
+			return Ok(Err(SetError::ForbiddenMailFrom {
+				description: Some(format!("can't send as {}", envelope.mail_from.email)),
+			}));
+		}
+		if envelope.rcpt_to.is_empty() {
+			return Ok(Err(SetError::NoRecipients));
+		}
+		if !envelope.rcpt_to.iter().all(Address::is_valid) {
+			return Ok(Err(SetError::InvalidRecipients {
+				invalid_recipients: envelope
+					.rcpt_to
+					.iter()
+					.filter(|a| !a.is_valid())
+					.map(|a| a.email.clone())
+					.collect(),
+			}));
+		}
+
+		let hold = match queue::take_hold(&mut envelope.mail_from) {
+			Ok(hold) if hold > self.state.max_delayed_send => {
+				return Ok(Err(SetError::invalid_properties(
+					&["envelope"],
+					format!(
+						"messages can be held for {}s at most",
+						self.state.max_delayed_send
+					),
+				)))
+			}
+			Ok(hold) => hold,
+			Err(e) => return Ok(Err(e)),
+		};
+
+		let thread_id = self.email_thread_id(&email_id).await?;
+		// the recipients in bcc must not see each other
+		let message = strip_header(&message, "Bcc");
+		let rcpt_to: Vec<String> = envelope.rcpt_to.iter().map(Address::smtp_path).collect();
+
+		let mut submission = EmailSubmission {
+			id: Id::new(),
+			identity_id: identity.id.clone(),
+			email_id,
+			thread_id,
+			envelope,
+			send_at: (chrono::Utc::now() + chrono::Duration::seconds(hold as i64))
+				.format("%Y-%m-%dT%H:%M:%SZ")
+				.to_string(),
+			undo_status: UndoStatus::Pending,
+			delivery_status: None,
+			dsn_blob_ids: vec![],
+			mdn_blob_ids: vec![],
+		};
+		if hold > 0 {
+			let held = QueuedMessage {
+				mail_from: submission.envelope.mail_from.smtp_path(),
+				rcpt_to,
+				message: base64::encode(&message),
+			};
+			return Ok(Ok((submission, Some(held))));
+		}
+
+		let client = match client {
+			Some(client) => client,
+			None => {
+				let credentials = self.state.credentials(self.account_id()).ok_or_else(|| {
+					MethodError::ServerFail {
+						description: Some("no credentials for the smtp server".to_owned()),
+					}
+				})?;
+				client.insert(smtp::connect(&self.state.smtp_server, &credentials).await?)
+			}
+		};
+		let max_size = client.max_size();
+		if max_size.is_some_and(|max| message.len() as u64 > max) {
+			return Ok(Err(SetError::TooLarge { max_size }));
+		}
+
+		let replies = match client
+			.send(
+				&submission.envelope.mail_from.smtp_path(),
+				&rcpt_to,
+				&message,
+			)
+			.await
+		{
+			Ok(replies) => replies,
+			Err(e) => return rejection_error(e, max_size).map(Err),
+		};
+
+		submission.undo_status = UndoStatus::Final;
+		submission.delivery_status = Some(
+			replies
+				.into_iter()
+				.map(|(rcpt, reply)| {
+					(
+						path_address(&rcpt).to_owned(),
+						DeliveryStatus {
+							smtp_reply: reply.to_string(),
+							delivered:  Delivered::Queued,
+							displayed:  Displayed::Unknown,
+						},
+					)
+				})
+				.collect(),
+		);
+		Ok(Ok((submission, None)))
+	}
+
+	async fn email_thread_id(&self, email_id: &str) -> Result<Id, MethodError> {
+		let request = EmailGetRequest {
+			account_id: self.account_id().to_owned(),
+			ids: Some(vec![email_id.to_owned()]),
+			properties: Some(vec!["threadId".to_owned()]),
+			..Default::default()
+		};
+		let thread_id = match self.handle_email_get(request).await? {
+			MethodResult::EmailGet(response) => response
+				.list
+				.first()
+				.and_then(|email| email["threadId"].as_str())
+				.map(str::to_owned),
+			_ => None,
+		};
+		Ok(thread_id.unwrap_or_else(|| format!("T{}", email_id)))
+	}
+}
+
+/// Only `undoStatus` can change, and only from `pending` to `canceled` while
+/// the submission isn't `sending` yet.
+fn update_submission(
+	submission: &mut EmailSubmission,
+	patch: &Map<String, Value>,
+	sending: bool,
+) -> Result<(), SetError> {
+	let current = serde_json::to_value(&*submission).unwrap_or_default();
+	for (key, value) in patch {
+		if key == "undoStatus" && *value == serde_json::json!(UndoStatus::Canceled) {
+			match submission.undo_status {
+				UndoStatus::Pending if sending => return Err(SetError::CannotUnsend),
+				UndoStatus::Pending => submission.undo_status = UndoStatus::Canceled,
+				UndoStatus::Canceled => {}
+				UndoStatus::Final => return Err(SetError::CannotUnsend),
+			}
+		} else if current.get(key) != Some(value) {
+			return Err(SetError::invalid_properties(
+				&[key],
+				"property can't be changed",
+			));
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	fn reply(code: u16) -> smtp::Reply {
+		smtp::Reply {
+			code,
+			lines: vec!["reason".to_owned()],
+		}
+	}
+
+	#[test]
+	fn smtp_paths_carry_parameters() {
+		let address: Address = serde_json::from_value(json!({
+			"email": "user@example.com",
+			"parameters": { "SIZE": "100", "ENVID": "a+b=c d", "SMTPUTF8": null },
+		}))
+		.unwrap();
+		assert!(address.is_valid());
+		assert_eq!(
+			address.smtp_path(),
+			"<user@example.com> ENVID=a+2Bb+3Dc+20d SIZE=100 SMTPUTF8"
+		);
+		assert_eq!(path_address(&address.smtp_path()), "user@example.com");
+
+		let injected = [
+			json!({ "email": "a@example.com>\r\nRCPT TO:<b@example.com", "parameters": null }),
+			json!({ "email": "a b@example.com", "parameters": null }),
+			json!({ "email": "a@example.com", "parameters": { "SIZE 1": null } }),
+			json!({ "email": "a@example.com", "parameters": { "ENVID": "a\r\nDATA" } }),
+		];
+		for address in &injected {
+			let address: Address = serde_json::from_value(address.clone()).unwrap();
+			assert!(!address.is_valid(), "{:?}", address);
+		}
+	}
+
+	#[test]
+	fn rejected_recipients_are_invalid_if_permanent() {
+		let rejected = vec![
+			("<a@example.com>".to_owned(), reply(550)),
+			("<b@example.com>".to_owned(), reply(450)),
+		];
+		match rejection_error(smtp::Error::RecipientsRejected(rejected), None).unwrap() {
+			SetError::InvalidRecipients { invalid_recipients } => {
+				assert_eq!(invalid_recipients, vec!["a@example.com".to_owned()])
+			}
+			e => panic!("{:?}", e),
+		}
+
+		let busy = vec![("<b@example.com>".to_owned(), reply(450))];
+		assert!(matches!(
+			rejection_error(smtp::Error::RecipientsRejected(busy), None),
+			Ok(SetError::ForbiddenToSend { .. })
+		));
+		let mail_from = smtp::Error::Rejected {
+			command: smtp::Command::MailFrom,
+			reply:   reply(553),
+		};
+		assert!(matches!(
+			rejection_error(mail_from, None),
+			Ok(SetError::ForbiddenMailFrom { .. })
+		));
+		let too_large = smtp::Error::Rejected {
+			command: smtp::Command::Data,
+			reply:   reply(552),
+		};
+		assert!(matches!(
+			rejection_error(too_large, Some(10)),
+			Ok(SetError::TooLarge { max_size: Some(10) })
+		));
+	}
+
+	#[test]
+	fn only_pending_submissions_can_be_canceled() {
+		let mut submission: EmailSubmission = serde_json::from_value(json!({
+			"id": "S0",
+			"identityId": "I0",
+			"emailId": "E0",
+			"threadId": "T0",
+			"envelope": { "mailFrom": { "email": "a@example.com" }, "rcptTo": [] },
+			"sendAt": "2024-01-01T00:00:00Z",
+			"undoStatus": "pending",
+			"deliveryStatus": null,
+			"dsnBlobIds": [],
+			"mdnBlobIds": [],
+		}))
+		.unwrap();
+		let cancel = json!({ "undoStatus": "canceled" });
+		let cancel = cancel.as_object().unwrap();
+
+		assert!(matches!(
+			update_submission(&mut submission.clone(), cancel, true),
+			Err(SetError::CannotUnsend)
+		));
+		let same_email = json!({ "emailId": "E0" });
+		update_submission(&mut submission, same_email.as_object().unwrap(), false).unwrap();
+		let other_email = json!({ "emailId": "E1" });
+		assert!(
+			update_submission(&mut submission, other_email.as_object().unwrap(), false).is_err()
+		);
+
+		update_submission(&mut submission, cancel, false).unwrap();
+		assert_eq!(submission.undo_status, UndoStatus::Canceled);
+		submission.undo_status = UndoStatus::Final;
+		assert!(matches!(
+			update_submission(&mut submission, cancel, false),
+			Err(SetError::CannotUnsend)
+		));
+	}
+
+	#[test]
+	fn on_success_arguments_refer_to_submissions() {
+		let succeeded: HashMap<Id, Id> = vec![
+			("#created".to_owned(), "E1".to_owned()),
+			("S2".to_owned(), "E2".to_owned()),
+			("S3".to_owned(), "E3".to_owned()),
+		]
+		.into_iter()
+		.collect();
+		let sent = json!({ "keywords/$draft": null, "mailboxIds/M1": null });
+		let update = vec![
+			("#created".to_owned(), sent.as_object().unwrap().clone()),
+			("#failed".to_owned(), sent.as_object().unwrap().clone()),
+		]
+		.into_iter()
+		.collect();
+		let destroy = vec!["S3".to_owned(), "S4".to_owned()];
+
+		let email_set = on_success_email_set(
+			"user@example.com".to_owned(),
+			&succeeded,
+			Some(update),
+			Some(destroy),
+		)
+		.unwrap();
+		assert_eq!(email_set.account_id, "user@example.com");
+		assert!(email_set.create.is_none());
+		let update = email_set.update.unwrap();
+		assert_eq!(update.len(), 1);
+		assert_eq!(Value::Object(update["E1"].clone()), sent);
+		assert_eq!(email_set.destroy, Some(vec!["E3".to_owned()]));
+
+		// nothing to do for submissions that failed
+		let failed = vec!["#failed".to_owned()];
+		assert!(on_success_email_set("a".to_owned(), &succeeded, None, Some(failed)).is_none());
+		assert!(on_success_email_set("a".to_owned(), &succeeded, None, None).is_none());
+	}
+}
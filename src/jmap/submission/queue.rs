@@ -0,0 +1,607 @@
+//! Delayed sending, FUTURERELEASE (RFC 4865) done by the proxy itself.
+//!
+//! Held messages wait in the store until a background task sends them. The
+//! account's credentials are sealed into the queue with them, so sending
+//! resumes after a restart without waiting for the account to log in.
+
+use std::{
+	collections::{BTreeMap, HashSet},
+	time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	jmap::{
+		submission::{
+			path_address,
+			Address,
+			Delivered,
+			DeliveryStatus,
+			Displayed,
+			SmtpClient,
+			Submissions,
+			UndoStatus,
+			SUBMISSION_STORE_NAME,
+		},
+		Id,
+		SetError,
+	},
+	smtp,
+	state::State,
+};
+
+const QUEUE_STORE_NAME: &str = "submission-queue";
+
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A message as it will be sent, frozen when it was submitted.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedMessage {
+	pub mail_from: String,
+	pub rcpt_to:   Vec<String>,
+	/// Base64, the store only keeps json.
+	pub message:   String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Queue {
+	messages:    BTreeMap<Id, QueuedMessage>,
+	/// The password of the account, sealed by [`crate::auth::Vault`].
+	#[serde(default)]
+	credentials: Option<String>,
+}
+
+/// The longest hold in seconds, from `MAX_DELAYED_SEND`. 0 disables holds.
+pub fn max_delayed_send_from_env() -> u64 {
+	std::env::var("MAX_DELAYED_SEND")
+		.ok()
+		.and_then(|s| s.parse().ok())
+		.unwrap_or(7 * 24 * 60 * 60)
+}
+
+/// Removes the `HOLDFOR`/`HOLDUNTIL` parameters from `mail_from` and returns
+/// the seconds to hold the message, the upstream server never sees them.
+pub fn take_hold(mail_from: &mut Address) -> Result<u64, SetError> {
+	let parameters = match &mut mail_from.parameters {
+		Some(parameters) => parameters,
+		None => return Ok(0),
+	};
+	let mut take = |name: &str| {
+		let key = parameters
+			.keys()
+			.find(|k| k.eq_ignore_ascii_case(name))?
+			.clone();
+		parameters.remove(&key)
+	};
+	let hold_for = take("HOLDFOR");
+	let hold_until = take("HOLDUNTIL");
+	if parameters.is_empty() {
+		mail_from.parameters = None;
+	}
+
+	let invalid = |description: &str| SetError::invalid_properties(&["envelope"], description);
+	match (hold_for, hold_until) {
+		(None, None) => Ok(0),
+		(Some(_), Some(_)) => Err(invalid("HOLDFOR and HOLDUNTIL can't be combined")),
+		(Some(seconds), None) => seconds
+			.and_then(|s| s.parse().ok())
+			.ok_or_else(|| invalid("HOLDFOR must be a number of seconds")),
+		(None, Some(date)) => date
+			.and_then(|d| chrono::DateTime::parse_from_rfc3339(&d).ok())
+			.map(|d| {
+				(d.with_timezone(&chrono::Utc) - chrono::Utc::now())
+					.num_seconds()
+					.max(0) as u64
+			})
+			.ok_or_else(|| invalid("HOLDUNTIL must be a date")),
+	}
+}
+
+pub async fn enqueue(
+	state: &State,
+	account_id: &str,
+	id: &str,
+	message: QueuedMessage,
+) -> tide::Result<()> {
+	let sealed = match state.credentials(account_id) {
+		Some(credentials) => Some(state.vault.seal(&credentials)?),
+		None => None,
+	};
+	state
+		.store
+		.update(account_id, QUEUE_STORE_NAME, |queue: &mut Queue| {
+			queue.messages.insert(id.to_owned(), message);
+			if sealed.is_some() {
+				queue.credentials = sealed;
+			}
+		})
+		.await
+}
+
+/// Drops held messages that were canceled or destroyed.
+pub async fn dequeue(state: &State, account_id: &str, ids: &[Id]) -> tide::Result<()> {
+	if ids.is_empty() {
+		return Ok(());
+	}
+	state
+		.store
+		.update(account_id, QUEUE_STORE_NAME, |queue: &mut Queue| {
+			for id in ids {
+				queue.messages.remove(id);
+			}
+		})
+		.await
+}
+
+/// Sends due messages of every account that held one, forever.
+pub async fn run(state: State) {
+	// claims of accounts not seen yet are left over from before a restart
+	let mut resumed = HashSet::new();
+	loop {
+		let accounts = match state.store.accounts_with(QUEUE_STORE_NAME).await {
+			Ok(accounts) => accounts,
+			Err(e) => {
+				tracing::error!("listing held messages failed: {}", e);
+				vec![]
+			}
+		};
+		for account_id in accounts {
+			let resume = resumed.insert(account_id.clone());
+			if let Err(e) = dispatch(&state, &account_id, resume).await {
+				tracing::error!("sending held messages of {} failed: {}", account_id, e);
+			}
+		}
+		async_std::task::sleep(DISPATCH_INTERVAL).await;
+	}
+}
+
+async fn dispatch(state: &State, account_id: &str, resume: bool) -> tide::Result<()> {
+	let now = chrono::Utc::now();
+
+	// claimed submissions can't be canceled anymore, that would race the send
+	let due: Vec<Id> = state
+		.store
+		.update(
+			account_id,
+			SUBMISSION_STORE_NAME,
+			|submissions: &mut Submissions| {
+				if resume {
+					submissions.sending.clear();
+				}
+				let due: Vec<Id> = submissions
+					.submissions
+					.values()
+					.filter(|s| s.undo_status == UndoStatus::Pending)
+					.filter(|s| !submissions.sending.contains(&s.id))
+					.filter(|s| {
+						chrono::DateTime::parse_from_rfc3339(&s.send_at).is_ok_and(|d| d <= now)
+					})
+					.map(|s| s.id.clone())
+					.collect();
+				submissions.sending.extend(due.iter().cloned());
+				due
+			},
+		)
+		.await?;
+	if due.is_empty() {
+		return Ok(());
+	}
+
+	let queue: Queue = state.store.get(account_id, QUEUE_STORE_NAME).await?;
+	// a fresh login wins, the password may have changed since
+	let credentials = state.credentials(account_id).or_else(|| {
+		queue
+			.credentials
+			.as_ref()
+			.and_then(|sealed| state.vault.open(account_id, sealed))
+	});
+	let mut client = None;
+	let mut failure = None;
+	for id in &due {
+		let queued = match queue.messages.get(id) {
+			Some(queued) => queued,
+			None => {
+				tracing::error!("held message of submission {} is missing", id);
+				finish(state, account_id, id, None).await?;
+				continue;
+			}
+		};
+		// kept for a look by hand, dropping it would lose the message
+		let message = match base64::decode(&queued.message) {
+			Ok(message) => message,
+			Err(e) => {
+				tracing::error!("held message of submission {} is corrupt: {}", id, e);
+				continue;
+			}
+		};
+
+		let sent = match (&mut client, &credentials) {
+			(Some(client), _) => send(client, queued, &message).await,
+			(None, Some(credentials)) => match smtp::connect(&state.smtp_server, credentials).await
+			{
+				Ok(c) => send(client.insert(c), queued, &message).await,
+				Err(e) => Err(e),
+			},
+			(None, None) => {
+				failure = Some(tide::Error::from_str(
+					tide::StatusCode::InternalServerError,
+					"no credentials to send held messages with",
+				));
+				break;
+			}
+		};
+		match sent {
+			// recorded right away, a restart must not send it again
+			Ok(status) => finish(state, account_id, id, Some(status)).await?,
+			Err(e) => {
+				failure = Some(e.into());
+				break;
+			}
+		}
+	}
+	if let Some(client) = client {
+		client.quit().await;
+	}
+
+	// the rest stays pending and is tried again on the next round
+	state
+		.store
+		.update(
+			account_id,
+			SUBMISSION_STORE_NAME,
+			|submissions: &mut Submissions| {
+				for id in &due {
+					submissions.sending.remove(id);
+				}
+			},
+		)
+		.await?;
+
+	match failure {
+		Some(e) => Err(e),
+		None => Ok(()),
+	}
+}
+
+/// Records the outcome of a claimed submission and drops its held message,
+/// `None` when there was nothing to send.
+async fn finish(
+	state: &State,
+	account_id: &str,
+	id: &str,
+	status: Option<BTreeMap<String, DeliveryStatus>>,
+) -> tide::Result<()> {
+	state
+		.store
+		.update(
+			account_id,
+			SUBMISSION_STORE_NAME,
+			|submissions: &mut Submissions| {
+				if let Some(submission) = submissions.submissions.get_mut(id) {
+					match status {
+						Some(status) => {
+							submission.undo_status = UndoStatus::Final;
+							submission.delivery_status = Some(status);
+						}
+						None => submission.undo_status = UndoStatus::Canceled,
+					}
+				}
+				submissions.sending.remove(id);
+			},
+		)
+		.await?;
+	dequeue(state, account_id, &[id.to_owned()]).await
+}
+
+/// Sends one held message, messages the server rejects are done as well and
+/// get a delivery status saying so.
+async fn send(
+	client: &mut SmtpClient,
+	queued: &QueuedMessage,
+	message: &[u8],
+) -> smtp::Result<BTreeMap<String, DeliveryStatus>> {
+	let status = |rcpt: &str, reply: String, delivered| {
+		(
+			path_address(rcpt).to_owned(),
+			DeliveryStatus {
+				smtp_reply: reply,
+				delivered,
+				displayed: Displayed::Unknown,
+			},
+		)
+	};
+
+	match client
+		.send(&queued.mail_from, &queued.rcpt_to, message)
+		.await
+	{
+		Ok(replies) => Ok(replies
+			.into_iter()
+			.map(|(rcpt, reply)| status(&rcpt, reply.to_string(), Delivered::Queued))
+			.collect()),
+		Err(smtp::Error::RecipientsRejected(rejected)) => Ok(queued
+			.rcpt_to
+			.iter()
+			.map(|rcpt| {
+				let reply = rejected
+					.iter()
+					.find(|(r, _)| r == rcpt)
+					.map_or_else(|| "not sent".to_owned(), |(_, reply)| reply.to_string());
+				status(rcpt, reply, Delivered::No)
+			})
+			.collect()),
+		Err(smtp::Error::Rejected { reply, .. }) => Ok(queued
+			.rcpt_to
+			.iter()
+			.map(|rcpt| status(rcpt, reply.to_string(), Delivered::No))
+			.collect()),
+		Err(e) => Err(e),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{path::PathBuf, sync::Arc};
+
+	use super::*;
+	use crate::{
+		jmap::submission::{EmailSubmission, Envelope},
+		smtp::tests::{credentials, server},
+		webpush,
+	};
+
+	const ACCOUNT_ID: &str = "user@example.com";
+
+	fn submission(id: &str, send_at: chrono::DateTime<chrono::Utc>) -> EmailSubmission {
+		EmailSubmission {
+			id:              id.to_owned(),
+			identity_id:     "I0".to_owned(),
+			email_id:        "E0".to_owned(),
+			thread_id:       "T0".to_owned(),
+			envelope:        Envelope {
+				mail_from: Address::new(ACCOUNT_ID.to_owned()),
+				rcpt_to:   vec![Address::new("a@example.com".to_owned())],
+			},
+			send_at:         send_at.to_rfc3339(),
+			undo_status:     UndoStatus::Pending,
+			delivery_status: None,
+			dsn_blob_ids:    vec![],
+			mdn_blob_ids:    vec![],
+		}
+	}
+
+	/// An account with a due and a later submission, both held with
+	/// `message`.
+	async fn state(smtp_server: smtp::Server, message: &str) -> (State, PathBuf) {
+		let dir =
+			std::env::temp_dir().join(format!("queue-test-{}", webpush::random_token().unwrap()));
+		let mut state = State::with_data_dir(&dir);
+		state.smtp_server = Arc::new(smtp_server);
+
+		let now = chrono::Utc::now();
+		let submissions = vec![
+			submission("S0", now - chrono::Duration::seconds(1)),
+			submission("S1", now + chrono::Duration::hours(1)),
+		];
+		state
+			.store
+			.update(
+				ACCOUNT_ID,
+				SUBMISSION_STORE_NAME,
+				|stored: &mut Submissions| {
+					for submission in submissions {
+						stored.submissions.insert(submission.id.clone(), submission);
+					}
+				},
+			)
+			.await
+			.unwrap();
+		for id in &["S0", "S1"] {
+			let queued = QueuedMessage {
+				mail_from: format!("<{}>", ACCOUNT_ID),
+				rcpt_to:   vec!["<a@example.com>".to_owned()],
+				message:   message.to_owned(),
+			};
+			enqueue(&state, ACCOUNT_ID, id, queued).await.unwrap();
+		}
+		// as left by a login before a restart
+		let sealed = state.vault.seal(&credentials("secret")).unwrap();
+		state
+			.store
+			.update(ACCOUNT_ID, QUEUE_STORE_NAME, |queue: &mut Queue| {
+				queue.credentials = Some(sealed)
+			})
+			.await
+			.unwrap();
+		(state, dir)
+	}
+
+	async fn stored(state: &State) -> (Submissions, Queue) {
+		let submissions = state
+			.store
+			.get(ACCOUNT_ID, SUBMISSION_STORE_NAME)
+			.await
+			.unwrap();
+		let queue = state.store.get(ACCOUNT_ID, QUEUE_STORE_NAME).await.unwrap();
+		(submissions, queue)
+	}
+
+	/// A server nobody listens on.
+	async fn unreachable() -> smtp::Server {
+		let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+			.await
+			.unwrap();
+		smtp::Server {
+			host:         "127.0.0.1".to_owned(),
+			port:         listener.local_addr().unwrap().port(),
+			implicit_tls: true,
+		}
+	}
+
+	fn mail_from(parameters: &[(&str, Option<&str>)]) -> Address {
+		Address {
+			email:      ACCOUNT_ID.to_owned(),
+			parameters: Some(
+				parameters
+					.iter()
+					.map(|(k, v)| (k.to_string(), v.map(str::to_owned)))
+					.collect(),
+			),
+		}
+	}
+
+	#[test]
+	fn hold_parameters_are_taken() {
+		let mut address = Address::new(ACCOUNT_ID.to_owned());
+		assert_eq!(take_hold(&mut address).unwrap(), 0);
+
+		let mut address = mail_from(&[("holdfor", Some("300"))]);
+		assert_eq!(take_hold(&mut address).unwrap(), 300);
+		assert_eq!(address.parameters, None);
+
+		let mut address = mail_from(&[("HOLDFOR", Some("60")), ("BODY", Some("8BITMIME"))]);
+		assert_eq!(take_hold(&mut address).unwrap(), 60);
+		assert_eq!(address, mail_from(&[("BODY", Some("8BITMIME"))]));
+
+		let until = chrono::Utc::now() + chrono::Duration::seconds(600);
+		let mut address = mail_from(&[("HoldUntil", Some(&until.to_rfc3339()))]);
+		let seconds = take_hold(&mut address).unwrap();
+		assert!((598..=600).contains(&seconds), "{}", seconds);
+
+		// the past is now
+		let mut address = mail_from(&[("HOLDUNTIL", Some("2000-01-01T00:00:00Z"))]);
+		assert_eq!(take_hold(&mut address).unwrap(), 0);
+	}
+
+	#[test]
+	fn invalid_holds_are_rejected() {
+		for parameters in [
+			&[
+				("HOLDFOR", Some("60")),
+				("HOLDUNTIL", Some("2000-01-01T00:00:00Z")),
+			][..],
+			&[("HOLDFOR", Some("-1"))],
+			&[("HOLDFOR", Some("soon"))],
+			&[("HOLDFOR", None)],
+			&[("HOLDUNTIL", Some("tomorrow"))],
+			&[("HOLDUNTIL", None)],
+		] {
+			assert!(matches!(
+				take_hold(&mut mail_from(parameters)),
+				Err(SetError::InvalidProperties { .. })
+			));
+		}
+	}
+
+	#[async_std::test]
+	async fn due_messages_are_final_once_sent() {
+		let (smtp_server, commands) = server(true, "PLAIN").await;
+		let (state, dir) = state(smtp_server, &base64::encode("Subject: hi\r\n\r\n")).await;
+
+		dispatch(&state, ACCOUNT_ID, true).await.unwrap();
+		assert!(commands
+			.await
+			.contains(&"RCPT TO:<a@example.com>".to_owned()));
+
+		let (submissions, queue) = stored(&state).await;
+		let sent = &submissions.submissions["S0"];
+		assert_eq!(sent.undo_status, UndoStatus::Final);
+		let status = &sent.delivery_status.as_ref().unwrap()["a@example.com"];
+		assert_eq!(status.delivered, Delivered::Queued);
+		assert_eq!(status.smtp_reply, "250 ok");
+		assert_eq!(
+			submissions.submissions["S1"].undo_status,
+			UndoStatus::Pending
+		);
+		assert!(submissions.sending.is_empty());
+		let queued: Vec<&Id> = queue.messages.keys().collect();
+		assert_eq!(queued, vec!["S1"]);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[async_std::test]
+	async fn held_messages_need_credentials() {
+		let (smtp_server, _) = server(true, "PLAIN").await;
+		let (state, dir) = state(smtp_server, &base64::encode("Subject: hi\r\n\r\n")).await;
+		state
+			.store
+			.update(ACCOUNT_ID, QUEUE_STORE_NAME, |queue: &mut Queue| {
+				queue.credentials = Some("not sealed".to_owned())
+			})
+			.await
+			.unwrap();
+
+		assert!(dispatch(&state, ACCOUNT_ID, true).await.is_err());
+
+		let (submissions, queue) = stored(&state).await;
+		assert_eq!(
+			submissions.submissions["S0"].undo_status,
+			UndoStatus::Pending
+		);
+		assert!(submissions.sending.is_empty());
+		assert_eq!(queue.messages.len(), 2);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[async_std::test]
+	async fn failed_sends_stay_pending() {
+		let (state, dir) = state(unreachable().await, &base64::encode("Subject: hi\r\n\r\n")).await;
+
+		assert!(dispatch(&state, ACCOUNT_ID, true).await.is_err());
+
+		let (submissions, queue) = stored(&state).await;
+		assert_eq!(
+			submissions.submissions["S0"].undo_status,
+			UndoStatus::Pending
+		);
+		assert!(submissions.submissions["S0"].delivery_status.is_none());
+		assert!(submissions.sending.is_empty());
+		assert_eq!(queue.messages.len(), 2);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[async_std::test]
+	async fn corrupt_messages_are_kept() {
+		// nothing is sent, connecting would fail
+		let (state, dir) = state(unreachable().await, "not base64!").await;
+
+		dispatch(&state, ACCOUNT_ID, true).await.unwrap();
+
+		let (submissions, queue) = stored(&state).await;
+		assert_eq!(
+			submissions.submissions["S0"].undo_status,
+			UndoStatus::Pending
+		);
+		assert!(submissions.sending.is_empty());
+		assert_eq!(queue.messages.len(), 2);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[async_std::test]
+	async fn claims_are_released_on_resume() {
+		let (state, dir) = state(unreachable().await, "not base64!").await;
+		state
+			.store
+			.update(
+				ACCOUNT_ID,
+				SUBMISSION_STORE_NAME,
+				|submissions: &mut Submissions| {
+					submissions.sending.insert("S0".to_owned());
+				},
+			)
+			.await
+			.unwrap();
+
+		// still being sent, left alone
+		dispatch(&state, ACCOUNT_ID, false).await.unwrap();
+		assert!(stored(&state).await.0.sending.contains("S0"));
+
+		// left over from before a restart
+		dispatch(&state, ACCOUNT_ID, true).await.unwrap();
+		assert!(stored(&state).await.0.sending.is_empty());
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
@@ -0,0 +1,417 @@
+//! Threads from the server's RFC 8474 `THREADID`s, else from imap
+//! `THREAD=REFERENCES` (RFC 5256) or, as a last resort, from grouping
+//! messages by their references and subject.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::{
+	imap,
+	jmap::{
+		changes,
+		index::EmailIndex,
+		method::{MethodError, MethodResult},
+		ChangesRequest,
+		GetRequest,
+		GetResponse,
+		Id,
+		JmapApi,
+		MAX_OBJECTS_IN_GET,
+	},
+};
+
+const THREAD_CHANGES_STORE_NAME: &str = "thread-changes";
+
+/// How long in seconds after a message a reply without references is still
+/// taken to be about it.
+const SUBJECT_WINDOW: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Thread {
+	pub id:        Id,
+	/// Oldest first.
+	pub email_ids: Vec<Id>,
+}
+
+impl Thread {
+	const PROPERTIES: &'static [&'static str] = &["id", "emailIds"];
+}
+
+/// Disjoint sets of emails, by index.
+struct UnionFind {
+	parents: Vec<usize>,
+}
+
+impl UnionFind {
+	fn find(&mut self, i: usize) -> usize {
+		let mut root = i;
+		while self.parents[root] != root {
+			root = self.parents[root];
+		}
+		let mut i = i;
+		while self.parents[i] != root {
+			let next = self.parents[i];
+			self.parents[i] = root;
+			i = next;
+		}
+		root
+	}
+
+	fn union(&mut self, a: usize, b: usize) {
+		let (a, b) = (self.find(a), self.find(b));
+		if a != b {
+			self.parents[a.max(b)] = a.min(b);
+		}
+	}
+}
+
+/// Groups messages by their references and, for replies without any, by
+/// subject.
+fn join_locally(index: &EmailIndex, positions: &HashMap<&Id, usize>, sets: &mut UnionFind) {
+	// the first email seen with a message id stands for all others with it
+	let mut message_ids: HashMap<&str, usize> = HashMap::new();
+	for (_, message) in index.messages() {
+		let email = positions[&message.email_id];
+		// a message joins the threads of everything it refers to, even if
+		// the referenced message itself is gone
+		for message_id in message.message_id.iter().chain(&message.references) {
+			sets.union(*message_ids.entry(message_id).or_insert(email), email);
+		}
+	}
+
+	// a reply without references ends up with the latest message with its
+	// subject from shortly before, the subject alone is too weak for more
+	let mut originals: HashMap<&str, Vec<(i64, usize)>> = HashMap::new();
+	let mut replies = vec![];
+	for (_, message) in index.messages() {
+		let email = positions[&message.email_id];
+		if message.subject.is_empty() {
+			continue;
+		}
+		if !message.is_reply {
+			originals
+				.entry(&message.subject)
+				.or_default()
+				.push((message.received_at, email));
+		} else if message.references.is_empty() {
+			replies.push((message.subject.as_str(), message.received_at, email));
+		}
+	}
+	for (subject, received_at, email) in replies {
+		let original = originals
+			.get(subject)
+			.into_iter()
+			.flatten()
+			.filter(|(at, _)| *at <= received_at && received_at - at <= SUBJECT_WINDOW)
+			.max();
+		if let Some(&(_, original)) = original {
+			sets.union(original, email);
+		}
+	}
+}
+
+/// All threads of the account.
+#[derive(Debug, Default)]
+pub struct Threads {
+	pub threads:  BTreeMap<Id, Thread>,
+	pub by_email: HashMap<Id, Id>,
+}
+
+impl Threads {
+	pub fn new(index: &EmailIndex) -> Threads {
+		// with server side ids an email can be in several mailboxes
+		let mut emails: Vec<(i64, &Id)> = vec![];
+		let mut positions: HashMap<&Id, usize> = HashMap::new();
+		for (_, message) in index.messages() {
+			positions.entry(&message.email_id).or_insert_with(|| {
+				emails.push((message.received_at, &message.email_id));
+				emails.len() - 1
+			});
+		}
+		let mut sets = UnionFind {
+			parents: (0..emails.len()).collect(),
+		};
+
+		// only one source is used, they don't agree on what belongs together
+		// and `Email/get` reports a server's own ids as they are
+		if index.messages().any(|(_, m)| m.thread_id.is_some()) {
+			let mut thread_ids: HashMap<&Id, usize> = HashMap::new();
+			for (_, message) in index.messages() {
+				let email = positions[&message.email_id];
+				if let Some(thread_id) = &message.thread_id {
+					sets.union(*thread_ids.entry(thread_id).or_insert(email), email);
+				}
+			}
+		} else if index.mailboxes.values().any(|m| !m.threads.is_empty()) {
+			for mailbox in index.mailboxes.values() {
+				let by_uid: HashMap<u32, &Id> = mailbox
+					.messages
+					.iter()
+					.map(|m| (m.uid, &m.email_id))
+					.collect();
+				for thread in &mailbox.threads {
+					let members: Vec<usize> = thread
+						.iter()
+						.filter_map(|uid| Some(positions[by_uid.get(uid)?]))
+						.collect();
+					if let Some((first, rest)) = members.split_first() {
+						for member in rest {
+							sets.union(*first, *member);
+						}
+					}
+				}
+			}
+		} else {
+			join_locally(index, &positions, &mut sets);
+		}
+
+		let mut members: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+		for email in 0..emails.len() {
+			members.entry(sets.find(email)).or_default().push(email);
+		}
+
+		let server_ids: HashMap<&Id, &Id> = index
+			.messages()
+			.filter_map(|(_, m)| Some((&m.email_id, m.thread_id.as_ref()?)))
+			.collect();
+
+		let mut threads = Threads::default();
+		for mut group in members.into_values() {
+			group.sort_by_key(|&e| emails[e]);
+			let first = emails[group[0]].1;
+			let id = match server_ids.get(first) {
+				Some(thread_id) => (*thread_id).clone(),
+				None => format!("T{}", first),
+			};
+			let email_ids: Vec<Id> = group.iter().map(|&e| emails[e].1.clone()).collect();
+			for email_id in &email_ids {
+				threads.by_email.insert(email_id.clone(), id.clone());
+			}
+			threads.threads.insert(id.clone(), Thread { id, email_ids });
+		}
+		threads
+	}
+
+	/// Total and unread threads by mailbox id.
+	pub fn counts(&self, index: &EmailIndex) -> HashMap<Id, (u64, u64)> {
+		index
+			.mailboxes
+			.iter()
+			.map(|(mailbox_id, mailbox)| {
+				let mut total = HashSet::new();
+				let mut unread = HashSet::new();
+				for message in &mailbox.messages {
+					if let Some(thread_id) = self.by_email.get(&message.email_id) {
+						total.insert(thread_id);
+						if !message.is_seen() {
+							unread.insert(thread_id);
+						}
+					}
+				}
+				(
+					mailbox_id.clone(),
+					(total.len() as u64, unread.len() as u64),
+				)
+			})
+			.collect()
+	}
+}
+
+impl JmapApi<'_> {
+	/// The threads of all messages in `mailboxes`.
+	pub async fn threads(
+		&self,
+		mailboxes: &[(Id, imap::MailboxInfo)],
+	) -> Result<(EmailIndex, Threads), MethodError> {
+		let index = self.email_index(mailboxes).await?;
+		let threads = Threads::new(&index);
+		Ok((index, threads))
+	}
+
+	/// Records the threads in the change log and returns the state.
+	async fn thread_state(&self, threads: &Threads) -> Result<String, MethodError> {
+		let objects = changes::to_objects(&threads.threads)?;
+		self.record_state(THREAD_CHANGES_STORE_NAME, &objects).await
+	}
+
+	pub async fn handle_thread_get(
+		&self,
+		request: GetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		let (_, threads) = self.threads(&mailboxes).await?;
+		let state = self.thread_state(&threads).await?;
+
+		let ids: Vec<Id> = match request.ids {
+			Some(ids) => ids,
+			None => threads.threads.keys().cloned().collect(),
+		};
+		if ids.len() > MAX_OBJECTS_IN_GET {
+			return Err(MethodError::RequestTooLarge);
+		}
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in ids {
+			match threads.threads.get(&id) {
+				Some(thread) => list.push(thread),
+				None => not_found.push(id),
+			}
+		}
+
+		Ok(MethodResult::ThreadGet(GetResponse {
+			account_id: request.account_id,
+			state,
+			list: super::select_properties(list, &request.properties, Thread::PROPERTIES)?,
+			not_found,
+		}))
+	}
+
+	pub async fn handle_thread_changes(
+		&self,
+		request: ChangesRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let mailboxes = self.fetch_mailboxes().await?;
+		let (_, threads) = self.threads(&mailboxes).await?;
+		let objects = changes::to_objects(&threads.threads)?;
+		let changes = self
+			.record_changes(
+				THREAD_CHANGES_STORE_NAME,
+				&objects,
+				&request.since_state,
+				request.max_changes,
+			)
+			.await?;
+
+		Ok(MethodResult::ThreadChanges(
+			changes.into_response(request.account_id),
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::jmap::index::{IndexedMailbox, IndexedMessage};
+
+	const DAY: i64 = 24 * 60 * 60;
+
+	fn message(email_id: &str, received_at: i64, subject: &str) -> IndexedMessage {
+		let base = subject.trim_start_matches("Re: ");
+		IndexedMessage {
+			uid: 0,
+			email_id: email_id.to_owned(),
+			thread_id: None,
+			keywords: vec![],
+			received_at,
+			message_id: Some(format!("<{}@example.com>", email_id)),
+			references: vec![],
+			subject: base.to_lowercase(),
+			is_reply: base != subject,
+		}
+	}
+
+	fn threads(messages: Vec<IndexedMessage>) -> Vec<Vec<Id>> {
+		let mut mailbox = IndexedMailbox::default();
+		mailbox.messages = messages;
+		let mut index = EmailIndex::default();
+		index.mailboxes.insert("M1".to_owned(), mailbox);
+		let mut threads: Vec<Vec<Id>> = Threads::new(&index)
+			.threads
+			.into_values()
+			.map(|t| t.email_ids)
+			.collect();
+		threads.sort();
+		threads
+	}
+
+	fn ids(ids: &[&str]) -> Vec<Id> {
+		ids.iter().map(|id| id.to_string()).collect()
+	}
+
+	#[test]
+	fn references_join_threads() {
+		let mut reply = message("E2", 2, "Re: Lunch");
+		reply.references = vec!["<E1@example.com>".to_owned()];
+		// the referenced message may be gone, the references still count
+		let mut other = message("E3", 3, "Re: Lunch");
+		other.references = vec!["<E0@example.com>".to_owned(), "<E1@example.com>".to_owned()];
+
+		assert_eq!(
+			threads(vec![message("E1", 1, "Lunch"), reply, other]),
+			vec![ids(&["E1", "E2", "E3"])]
+		);
+	}
+
+	#[test]
+	fn server_thread_ids_join_threads() {
+		let mut first = message("E1", 1, "Lunch");
+		first.thread_id = Some("T9".to_owned());
+		let mut second = message("E2", 2, "Something else");
+		second.thread_id = Some("T9".to_owned());
+
+		let mut index = EmailIndex::default();
+		let mut mailbox = IndexedMailbox::default();
+		mailbox.messages = vec![first, second];
+		index.mailboxes.insert("M1".to_owned(), mailbox);
+		let threads = Threads::new(&index);
+		assert_eq!(threads.threads["T9"].email_ids, ids(&["E1", "E2"]));
+		assert_eq!(threads.by_email["E2"], "T9");
+	}
+
+	#[test]
+	fn server_thread_ids_are_used_alone() {
+		let mut first = message("E1", 1, "Lunch");
+		first.thread_id = Some("T1".to_owned());
+		let mut reply = message("E2", 2, "Re: Lunch");
+		reply.thread_id = Some("T2".to_owned());
+		reply.references = vec!["<E1@example.com>".to_owned()];
+
+		let mut index = EmailIndex::default();
+		let mut mailbox = IndexedMailbox::default();
+		mailbox.messages = vec![first, reply];
+		index.mailboxes.insert("M1".to_owned(), mailbox);
+		let threads = Threads::new(&index);
+		assert_eq!(threads.threads["T1"].email_ids, ids(&["E1"]));
+		assert_eq!(threads.threads["T2"].email_ids, ids(&["E2"]));
+		assert_eq!(threads.by_email["E2"], "T2");
+	}
+
+	#[test]
+	fn replies_without_references_join_a_recent_subject() {
+		assert_eq!(
+			threads(vec![
+				message("E1", 0, "Lunch"),
+				message("E2", 10 * DAY, "Lunch"),
+				message("E3", 11 * DAY, "Re: Lunch"),
+			]),
+			vec![ids(&["E1"]), ids(&["E2", "E3"])]
+		);
+	}
+
+	#[test]
+	fn subjects_alone_do_not_join_threads() {
+		// a reply long after the last message with its subject
+		let late = vec![
+			message("E1", 0, "Lunch"),
+			message("E2", 30 * DAY, "Re: Lunch"),
+		];
+		assert_eq!(threads(late), vec![ids(&["E1"]), ids(&["E2"])]);
+
+		// a reply with references belongs where they point
+		let mut reply = message("E3", DAY, "Re: Lunch");
+		reply.references = vec!["<elsewhere@example.com>".to_owned()];
+		let referenced = vec![message("E1", 0, "Lunch"), reply];
+		assert_eq!(threads(referenced), vec![ids(&["E1"]), ids(&["E3"])]);
+
+		// messages that aren't replies never join on their subject
+		let unrelated = vec![message("E1", 0, "Hello"), message("E2", DAY, "Hello")];
+		assert_eq!(threads(unrelated), vec![ids(&["E1"]), ids(&["E2"])]);
+	}
+}
@@ -0,0 +1,431 @@
+//! `VacationResponse`, installed as a generated Sieve script with the
+//! `vacation` extension over ManageSieve.
+//!
+//! The user's own active script keeps working: ours includes it while it is
+//! active and it is activated again once the vacation response is disabled.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+	jmap::{
+		changes,
+		email::html_to_text,
+		method::{MethodError, MethodResult},
+		GetRequest,
+		GetResponse,
+		Id,
+		JmapApi,
+		SetError,
+		SetRequest,
+		SetResponse,
+	},
+	sieve,
+};
+
+const VACATION_STORE_NAME: &str = "vacation-response";
+const VACATION_CHANGES_STORE_NAME: &str = "vacation-response-changes";
+
+/// The id of the only `VacationResponse` there is.
+const SINGLETON_ID: &str = "singleton";
+
+/// The name of our script on the ManageSieve server.
+pub const VACATION_SCRIPT_NAME: &str = "jmap-vacation";
+
+/// The reason sent when neither a text nor an html body is set.
+const DEFAULT_REASON: &str = "I am currently away and will read your message when I am back.";
+
+const MIME_BOUNDARY: &str = "=_jmap-vacation_=";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VacationResponse {
+	pub id:         Id,
+	pub is_enabled: bool,
+	pub from_date:  Option<String>,
+	pub to_date:    Option<String>,
+	pub subject:    Option<String>,
+	pub text_body:  Option<String>,
+	pub html_body:  Option<String>,
+}
+
+impl Default for VacationResponse {
+	fn default() -> Self {
+		VacationResponse {
+			id:         SINGLETON_ID.to_owned(),
+			is_enabled: false,
+			from_date:  None,
+			to_date:    None,
+			subject:    None,
+			text_body:  None,
+			html_body:  None,
+		}
+	}
+}
+
+impl VacationResponse {
+	const PROPERTIES: &'static [&'static str] = &[
+		"id",
+		"isEnabled",
+		"fromDate",
+		"toDate",
+		"subject",
+		"textBody",
+		"htmlBody",
+	];
+
+	fn apply(&mut self, object: &Map<String, Value>) -> Result<(), SetError> {
+		for (key, value) in object {
+			let invalid = |description: &str| SetError::invalid_properties(&[key], description);
+			let string = || match value {
+				Value::Null => Ok(None),
+				Value::String(s) => Ok(Some(s.clone())),
+				_ => Err(invalid("must be a string or null")),
+			};
+			let date = || {
+				string()?
+					.map(|d| {
+						chrono::DateTime::parse_from_rfc3339(&d)
+							.map(|d| {
+								d.with_timezone(&chrono::Utc)
+									.format("%Y-%m-%dT%H:%M:%SZ")
+									.to_string()
+							})
+							.map_err(|_| invalid("must be a UTCDate"))
+					})
+					.transpose()
+			};
+
+			match key.as_str() {
+				"id" => {
+					if value != SINGLETON_ID {
+						return Err(invalid("property can't be changed"));
+					}
+				}
+				"isEnabled" => {
+					self.is_enabled = value
+						.as_bool()
+						.ok_or_else(|| invalid("must be a boolean"))?
+				}
+				"fromDate" => self.from_date = date()?,
+				"toDate" => self.to_date = date()?,
+				"subject" => self.subject = string()?,
+				"textBody" => self.text_body = string()?,
+				"htmlBody" => self.html_body = string()?,
+				_ => return Err(invalid("unknown property")),
+			}
+		}
+		Ok(())
+	}
+
+	/// The Sieve script replying while the response is enabled, followed by
+	/// an include of the user's own script, if any. The include is optional
+	/// so deleting or renaming that script doesn't break ours.
+	pub fn script(&self, include: Option<&str>) -> String {
+		let mut extensions = vec!["vacation"];
+		let mut tests = vec![];
+		if let Some(from) = &self.from_date {
+			tests.push(format!(
+				"currentdate :zone \"+0000\" :value \"ge\" \"iso8601\" {}",
+				sieve_string(from)
+			));
+		}
+		if let Some(to) = &self.to_date {
+			tests.push(format!(
+				"currentdate :zone \"+0000\" :value \"lt\" \"iso8601\" {}",
+				sieve_string(to)
+			));
+		}
+		if !tests.is_empty() {
+			extensions.extend(["date", "relational"]);
+		}
+		if include.is_some() {
+			extensions.push("include");
+		}
+
+		let mut script = format!(
+			"require [{}];\n\n",
+			extensions
+				.iter()
+				.map(|e| sieve_string(e))
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+		if tests.is_empty() {
+			script.push_str(&self.action());
+		} else {
+			script.push_str(&format!(
+				"if allof({}) {{\n{}}}\n",
+				tests.join(", "),
+				self.action()
+			));
+		}
+		if let Some(name) = include {
+			script.push_str(&format!(
+				"\ninclude :personal :optional {};\n",
+				sieve_string(name)
+			));
+		}
+		// Sieve wants CRLF line endings
+		script.replace('\n', "\r\n")
+	}
+
+	fn action(&self) -> String {
+		let mut action = "vacation".to_owned();
+		if let Some(subject) = &self.subject {
+			action.push_str(&format!(" :subject {}", sieve_string(subject)));
+		}
+		let reason = match (&self.text_body, &self.html_body) {
+			(text, Some(html)) => {
+				action.push_str(" :mime");
+				let text = text.clone().unwrap_or_else(|| html_to_text(html));
+				mime_reason(&text, html)
+			}
+			(Some(text), None) => text.clone(),
+			(None, None) => DEFAULT_REASON.to_owned(),
+		};
+		format!("{} {};\n", action, sieve_multi_line(&reason))
+	}
+}
+
+fn sieve_string(s: &str) -> String {
+	format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A `text:` multi-line string, lines starting with a dot are doubled.
+fn sieve_multi_line(s: &str) -> String {
+	let mut out = "text:\n".to_owned();
+	for line in s.lines() {
+		if line.starts_with('.') {
+			out.push('.');
+		}
+		out.push_str(line);
+		out.push('\n');
+	}
+	// the terminating dot has to be on a line of its own
+	out.push_str(".\n");
+	out
+}
+
+/// A multipart/alternative entity for `vacation :mime`.
+fn mime_reason(text: &str, html: &str) -> String {
+	let part = |content_type: &str, body: &str| {
+		format!(
+			"--{}\nContent-Type: {}; charset=utf-8\nContent-Transfer-Encoding: 8bit\n\n{}\n",
+			MIME_BOUNDARY, content_type, body
+		)
+	};
+	format!(
+		"MIME-Version: 1.0\nContent-Type: multipart/alternative; boundary=\"{}\"\n\n{}{}--{}--",
+		MIME_BOUNDARY,
+		part("text/plain", text),
+		part("text/html", html),
+		MIME_BOUNDARY
+	)
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct Vacation {
+	response:        VacationResponse,
+	/// The script that was active before ours, ours includes it.
+	included_script: Option<String>,
+}
+
+impl JmapApi<'_> {
+	/// The stored response, enabled only if our script is the active one.
+	async fn vacation_response(&self) -> Result<VacationResponse, MethodError> {
+		let mut session = self.sieve_session().await?;
+		let scripts = session.list_scripts().await;
+		session.logout().await;
+
+		let vacation: Vacation = self
+			.state
+			.store
+			.get(self.account_id(), VACATION_STORE_NAME)
+			.await?;
+		let mut response = vacation.response;
+		response.is_enabled = scripts?.contains(&(VACATION_SCRIPT_NAME.to_owned(), true));
+		Ok(response)
+	}
+
+	async fn vacation_response_state(
+		&self,
+		response: &VacationResponse,
+	) -> Result<String, MethodError> {
+		let objects = changes::to_objects([(&response.id, response)])?;
+		self.record_state(VACATION_CHANGES_STORE_NAME, &objects)
+			.await
+	}
+
+	/// Installs or removes our script, then stores `response`.
+	async fn install_vacation_response(
+		&self,
+		response: &VacationResponse,
+	) -> Result<Result<(), SetError>, MethodError> {
+		let vacation: Vacation = self
+			.state
+			.store
+			.get(self.account_id(), VACATION_STORE_NAME)
+			.await?;
+
+		let mut session = self.sieve_session().await?;
+		let installed =
+			install_vacation_script(&mut session, response, vacation.included_script).await;
+		session.logout().await;
+		let included_script = match installed? {
+			Ok(included_script) => included_script,
+			Err(e) => return Ok(Err(e)),
+		};
+
+		self.state
+			.store
+			.update(
+				self.account_id(),
+				VACATION_STORE_NAME,
+				|vacation: &mut Vacation| {
+					vacation.response = response.clone();
+					vacation.included_script = included_script;
+				},
+			)
+			.await?;
+		Ok(Ok(()))
+	}
+
+	pub async fn handle_vacation_response_get(
+		&self,
+		request: GetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let response = self.vacation_response().await?;
+		let state = self.vacation_response_state(&response).await?;
+
+		let mut list = vec![];
+		let mut not_found = vec![];
+		for id in request.ids.unwrap_or_else(|| vec![SINGLETON_ID.to_owned()]) {
+			if id == SINGLETON_ID {
+				list.push(&response);
+			} else {
+				not_found.push(id);
+			}
+		}
+
+		Ok(MethodResult::VacationResponseGet(GetResponse {
+			account_id: request.account_id,
+			state,
+			list: super::select_properties(
+				list,
+				&request.properties,
+				VacationResponse::PROPERTIES,
+			)?,
+			not_found,
+		}))
+	}
+
+	pub async fn handle_vacation_response_set(
+		&self,
+		request: SetRequest,
+	) -> Result<MethodResult, MethodError> {
+		self.check_account(&request.account_id)?;
+
+		let mut current = self.vacation_response().await?;
+		let old_state = self.vacation_response_state(&current).await?;
+		if request
+			.if_in_state
+			.as_ref()
+			.is_some_and(|s| *s != old_state)
+		{
+			return Err(MethodError::StateMismatch);
+		}
+
+		let mut response = SetResponse {
+			account_id: request.account_id,
+			old_state: Some(old_state),
+			..Default::default()
+		};
+
+		for creation_id in request.create.unwrap_or_default().into_keys() {
+			response.not_created.insert(
+				creation_id,
+				SetError::forbidden("there is only the singleton vacation response"),
+			);
+		}
+
+		for (id, patch) in request.update.unwrap_or_default() {
+			if self.resolve_id(&id).as_deref() != Some(SINGLETON_ID) {
+				response.not_updated.insert(id, SetError::NotFound);
+				continue;
+			}
+			let mut updated = current.clone();
+			if let Err(e) = updated.apply(&patch) {
+				response.not_updated.insert(id, e);
+				continue;
+			}
+			match self.install_vacation_response(&updated).await? {
+				Ok(()) => {
+					current = updated;
+					response.updated.insert(id, None);
+				}
+				Err(e) => {
+					response.not_updated.insert(id, e);
+				}
+			}
+		}
+
+		for id in request.destroy.unwrap_or_default() {
+			response.not_destroyed.insert(
+				id,
+				SetError::forbidden("the vacation response can't be destroyed"),
+			);
+		}
+
+		response.new_state = self.vacation_response_state(&current).await?;
+
+		Ok(MethodResult::VacationResponseSet(response))
+	}
+}
+
+/// Puts our script in place and activates it, or deactivates and removes it
+/// when the response is disabled. Returns the user's own script, which ours
+/// includes.
+async fn install_vacation_script(
+	session: &mut sieve::Session,
+	response: &VacationResponse,
+	included_script: Option<String>,
+) -> Result<Result<Option<String>, SetError>, MethodError> {
+	let scripts = session.list_scripts().await?;
+	let exists = |name: &str| scripts.iter().any(|(s, _)| s == name);
+	let active = scripts
+		.iter()
+		.find(|(_, active)| *active)
+		.map(|(name, _)| name.clone());
+	let own_script = match active {
+		// still the one we included earlier, unless it was deleted since
+		Some(name) if name == VACATION_SCRIPT_NAME => included_script.filter(|s| exists(s)),
+		active => active,
+	};
+
+	if response.is_enabled {
+		if own_script.is_some() && !session.has_extension("include") {
+			return Ok(Err(SetError::forbidden(
+				"the sieve server can't include the active script",
+			)));
+		}
+		session
+			.put_script(
+				VACATION_SCRIPT_NAME,
+				response.script(own_script.as_deref()).as_bytes(),
+			)
+			.await?;
+		session.set_active(VACATION_SCRIPT_NAME).await?;
+	} else if exists(VACATION_SCRIPT_NAME) {
+		// an empty name deactivates ours when the user had no script
+		session
+			.set_active(own_script.as_deref().unwrap_or(""))
+			.await?;
+		session.delete_script(VACATION_SCRIPT_NAME).await?;
+	}
+
+	Ok(Ok(own_script))
+}
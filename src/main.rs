@@ -1,9 +1,15 @@
 mod auth;
+mod blob;
 mod error;
 mod imap;
 mod jmap;
+mod mime;
 mod routes;
+mod sieve;
+mod smtp;
 mod state;
+mod store;
+mod webpush;
 
 use tide_tracing::TraceMiddleware;
 
@@ -17,7 +23,12 @@ async fn main() -> tide::Result<()> {
 		.with_max_level(tracing::Level::INFO)
 		.init();
 
-	let mut app = tide::with_state(State::new());
+	let state = State::new();
+	async_std::task::spawn(jmap::submission::queue::run(state.clone()));
+	async_std::task::spawn(blob::run(state.blobs.clone()));
+	async_std::task::spawn(jmap::push_subscription::run(state.clone()));
+
+	let mut app = tide::with_state(state);
 
 	app.with(tide::utils::After(|mut res: tide::Response| async move {
 		if res.error().is_some() && res.is_empty().unwrap_or(false) {
@@ -42,6 +53,11 @@ async fn main() -> tide::Result<()> {
 
 	app.at("/.well-known/jmap").get(routes::session);
 	app.at("/jmap").post(routes::jmap);
+	app.at("/upload/:account_id").post(routes::upload);
+	app.at("/download/:account_id/:blob_id/:name")
+		.get(routes::download);
+	app.at("/eventsource")
+		.get(tide::sse::endpoint(routes::event_source));
 
 	app.listen("127.0.0.1:8080").await?;
 
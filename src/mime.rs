@@ -0,0 +1,1409 @@
+//! Parsing and composing of internet messages (RFC 5322) and MIME (RFC 2045
+//! ff.).
+//!
+//! Parsing is lenient, real world mail rarely follows the grammar to
+//! the letter and a message we can't fully parse should still be displayable.
+
+use std::{
+	collections::{hash_map::DefaultHasher, HashMap},
+	hash::{Hash, Hasher},
+	ops::Range,
+	sync::atomic::{AtomicU64, Ordering},
+	time::SystemTime,
+};
+
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+	pub name:  String,
+	/// Everything after the colon, still folded and encoded.
+	pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmailAddress {
+	pub name:  Option<String>,
+	pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmailAddressGroup {
+	pub name:      Option<String>,
+	pub addresses: Vec<EmailAddress>,
+}
+
+fn strip_newline(line: &[u8]) -> &[u8] {
+	let line = line.strip_suffix(b"\n").unwrap_or(line);
+	line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Splits `data` into its header fields and the offset the body starts at.
+pub fn parse_headers(data: &[u8]) -> (Vec<Header>, usize) {
+	let mut headers: Vec<Header> = vec![];
+	let mut pos = 0;
+	while pos < data.len() {
+		let end = data[pos..]
+			.iter()
+			.position(|&b| b == b'\n')
+			.map_or(data.len(), |e| pos + e + 1);
+		let line = strip_newline(&data[pos..end]);
+
+		if line.is_empty() {
+			return (headers, end);
+		}
+
+		if line[0] == b' ' || line[0] == b'\t' {
+			if let Some(last) = headers.last_mut() {
+				last.value.push_str("\r\n");
+				last.value.push_str(&String::from_utf8_lossy(line));
+			}
+		} else if let Some(colon) = line.iter().position(|&b| b == b':') {
+			headers.push(Header {
+				name:  String::from_utf8_lossy(&line[..colon]).trim().to_owned(),
+				value: String::from_utf8_lossy(&line[colon + 1..]).into_owned(),
+			});
+		}
+
+		pos = end;
+	}
+	(headers, data.len())
+}
+
+/// The first header called `name`.
+pub fn find_header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+	headers
+		.iter()
+		.find(|h| h.name.eq_ignore_ascii_case(name))
+		.map(|h| h.value.as_str())
+}
+
+pub fn unfold(value: &str) -> String {
+	value.replace("\r\n", "").replace('\n', "")
+}
+
+/// Decodes RFC 2047 encoded words, whitespace between two of them is dropped.
+pub fn decode_words(s: &str) -> String {
+	let mut out = String::new();
+	let mut rest = s;
+	let mut after_word = false;
+	while let Some(start) = rest.find("=?") {
+		let gap = &rest[..start];
+		match decode_word(&rest[start..]) {
+			Some((decoded, len)) => {
+				if !(after_word && gap.chars().all(char::is_whitespace)) {
+					out.push_str(gap);
+				}
+				out.push_str(&decoded);
+				rest = &rest[start + len..];
+				after_word = true;
+			}
+			None => {
+				out.push_str(&rest[..start + 2]);
+				rest = &rest[start + 2..];
+				after_word = false;
+			}
+		}
+	}
+	out.push_str(rest);
+	out
+}
+
+/// Decodes a single `=?charset?encoding?text?=` word at the start of `word`
+/// and returns it together with its encoded length.
+fn decode_word(word: &str) -> Option<(String, usize)> {
+	let inner = word.strip_prefix("=?")?;
+	let (charset, inner) = inner.split_once('?')?;
+	let (encoding, inner) = inner.split_once('?')?;
+	let end = inner.find("?=")?;
+	let text = &inner[..end];
+	if charset.contains(char::is_whitespace) || text.contains(char::is_whitespace) {
+		return None;
+	}
+
+	let bytes = if encoding.eq_ignore_ascii_case("B") {
+		decode_base64(text.as_bytes())
+	} else if encoding.eq_ignore_ascii_case("Q") {
+		decode_quoted_printable(text.as_bytes(), true)
+	} else {
+		return None;
+	};
+
+	// RFC 2231 allows a language after the charset
+	let (decoded, _) = decode_charset(charset.split('*').next().unwrap_or(charset), &bytes);
+	Some((
+		decoded,
+		2 + charset.len() + 1 + encoding.len() + 1 + end + 2,
+	))
+}
+
+/// Base64 that ignores line breaks, garbage and missing padding.
+pub fn decode_base64(data: &[u8]) -> Vec<u8> {
+	let mut clean: Vec<u8> = data
+		.iter()
+		.copied()
+		.filter(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+		.collect();
+	if clean.len() % 4 == 1 {
+		clean.pop();
+	}
+	base64::decode_config(&clean, base64::STANDARD_NO_PAD).unwrap_or_default()
+}
+
+/// Quoted-printable, with `_` meaning space inside of encoded words.
+pub fn decode_quoted_printable(data: &[u8], encoded_word: bool) -> Vec<u8> {
+	fn hex(b: u8) -> Option<u8> {
+		(b as char).to_digit(16).map(|d| d as u8)
+	}
+
+	let mut out = Vec::with_capacity(data.len());
+	let mut i = 0;
+	while i < data.len() {
+		match data[i] {
+			b'=' => {
+				match (data.get(i + 1), data.get(i + 2)) {
+					(Some(b'\r'), Some(b'\n')) => i += 3,
+					(Some(b'\n'), _) => i += 2,
+					(Some(&a), Some(&b)) if hex(a).is_some() && hex(b).is_some() => {
+						out.push(hex(a).unwrap_or(0) << 4 | hex(b).unwrap_or(0));
+						i += 3;
+					}
+					// a trailing soft line break cut off by a partial fetch
+					(None, _) => i += 1,
+					_ => {
+						out.push(b'=');
+						i += 1;
+					}
+				}
+				continue;
+			}
+			b'_' if encoded_word => out.push(b' '),
+			b => out.push(b),
+		}
+		i += 1;
+	}
+	out
+}
+
+/// Undoes the content transfer encoding of a body part.
+pub fn decode_transfer(encoding: Option<&str>, data: &[u8]) -> Vec<u8> {
+	match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+		Some("base64") => decode_base64(data),
+		Some("quoted-printable") => decode_quoted_printable(data, false),
+		_ => data.to_vec(),
+	}
+}
+
+/// [`decode_transfer`] for content that arrives in pieces.
+pub struct TransferDecoder {
+	encoding: Option<String>,
+	// the end of the content so far, which can only be decoded with what follows
+	pending:  Vec<u8>,
+}
+
+impl TransferDecoder {
+	pub fn new(encoding: Option<&str>) -> Self {
+		TransferDecoder {
+			encoding: encoding.map(|e| e.trim().to_ascii_lowercase()),
+			pending:  vec![],
+		}
+	}
+
+	/// Decodes as much as possible of the content so far.
+	pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+		self.pending.extend_from_slice(data);
+		let end = match self.encoding.as_deref() {
+			Some("base64") => {
+				// groups of four characters decode on their own
+				self.pending
+					.retain(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/');
+				self.pending.len() / 4 * 4
+			}
+			// soft line breaks and escapes never span lines
+			Some("quoted-printable") => self
+				.pending
+				.iter()
+				.rposition(|&b| b == b'\n')
+				.map_or(0, |i| i + 1),
+			_ => self.pending.len(),
+		};
+
+		let rest = self.pending.split_off(end);
+		let decoded = decode_transfer(self.encoding.as_deref(), &self.pending);
+		self.pending = rest;
+		decoded
+	}
+
+	/// Decodes the rest of the content.
+	pub fn finish(self) -> Vec<u8> {
+		decode_transfer(self.encoding.as_deref(), &self.pending)
+	}
+}
+
+/// Decodes `data` from `charset`, also reporting whether that went wrong.
+///
+/// Unknown charsets are treated as utf-8 and count as an encoding problem.
+pub fn decode_charset(charset: &str, data: &[u8]) -> (String, bool) {
+	match encoding_rs::Encoding::for_label(charset.trim().as_bytes()) {
+		Some(encoding) => {
+			let (decoded, _, had_errors) = encoding.decode(data);
+			(decoded.into_owned(), had_errors)
+		}
+		None => {
+			let decoded = String::from_utf8_lossy(data).into_owned();
+			(decoded, true)
+		}
+	}
+}
+
+/// Removes RFC 5322 comments, keeping quoted strings intact.
+fn strip_comments(value: &str) -> String {
+	let mut out = String::new();
+	let mut depth = 0;
+	let mut quoted = false;
+	let mut chars = value.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' => {
+				if depth == 0 {
+					out.push(c);
+					out.extend(chars.next());
+				} else {
+					chars.next();
+				}
+			}
+			'"' if depth == 0 => {
+				quoted = !quoted;
+				out.push(c);
+			}
+			'(' if !quoted => depth += 1,
+			')' if !quoted && depth > 0 => depth -= 1,
+			c if depth == 0 => out.push(c),
+			_ => {}
+		}
+	}
+	out
+}
+
+#[derive(Debug, PartialEq)]
+enum AddressToken {
+	Word(String),
+	Quoted(String),
+	Comment(String),
+	Special(char),
+}
+
+fn tokenize_addresses(value: &str) -> Vec<AddressToken> {
+	let mut tokens = vec![];
+	let mut chars = value.chars().peekable();
+	while let Some(&c) = chars.peek() {
+		match c {
+			c if c.is_whitespace() => {
+				chars.next();
+			}
+			'"' => {
+				chars.next();
+				let mut s = String::new();
+				while let Some(c) = chars.next() {
+					match c {
+						'"' => break,
+						'\\' => s.extend(chars.next()),
+						c => s.push(c),
+					}
+				}
+				tokens.push(AddressToken::Quoted(s));
+			}
+			'(' => {
+				chars.next();
+				let mut s = String::new();
+				let mut depth = 1;
+				while let Some(c) = chars.next() {
+					match c {
+						'(' => depth += 1,
+						')' => {
+							depth -= 1;
+							if depth == 0 {
+								break;
+							}
+						}
+						'\\' => {
+							s.extend(chars.next());
+							continue;
+						}
+						_ => {}
+					}
+					s.push(c);
+				}
+				tokens.push(AddressToken::Comment(s));
+			}
+			'<' | '>' | ',' | ':' | ';' => {
+				chars.next();
+				tokens.push(AddressToken::Special(c));
+			}
+			_ => {
+				let mut s = String::new();
+				while let Some(&c) = chars.peek() {
+					if c.is_whitespace() || "\"(<>,:;".contains(c) {
+						break;
+					}
+					s.push(c);
+					chars.next();
+				}
+				tokens.push(AddressToken::Word(s));
+			}
+		}
+	}
+	tokens
+}
+
+#[derive(Default)]
+struct MailboxBuilder {
+	phrase:  Vec<String>,
+	angle:   Option<String>,
+	comment: Option<String>,
+}
+
+impl MailboxBuilder {
+	fn is_empty(&self) -> bool {
+		self.phrase.is_empty() && self.angle.is_none()
+	}
+
+	fn finish(&mut self) -> Option<EmailAddress> {
+		let MailboxBuilder {
+			phrase,
+			angle,
+			comment,
+		} = std::mem::take(self);
+		let comment = comment.map(|c| decode_words(&c));
+
+		match angle {
+			Some(angle) => {
+				let name = decode_words(&phrase.join(" "));
+				// drop obsolete source routes like <@a,@b:user@host>
+				let email = angle.rsplit(':').next().unwrap_or("").trim().to_owned();
+				Some(EmailAddress {
+					name: Some(name).filter(|n| !n.is_empty()).or(comment),
+					email,
+				})
+			}
+			None if phrase.is_empty() => None,
+			None => Some(EmailAddress {
+				name:  comment,
+				email: phrase.concat(),
+			}),
+		}
+	}
+}
+
+/// Parses an address list into groups, consecutive addresses outside of a
+/// group end up together in a group without name.
+pub fn parse_grouped_addresses(value: &str) -> Vec<EmailAddressGroup> {
+	let mut groups: Vec<EmailAddressGroup> = vec![];
+	let mut group: Option<EmailAddressGroup> = None;
+	let mut ungrouped: Vec<EmailAddress> = vec![];
+	let mut mailbox = MailboxBuilder::default();
+	let mut in_angle = false;
+
+	fn flush_ungrouped(groups: &mut Vec<EmailAddressGroup>, ungrouped: &mut Vec<EmailAddress>) {
+		if !ungrouped.is_empty() {
+			groups.push(EmailAddressGroup {
+				name:      None,
+				addresses: std::mem::take(ungrouped),
+			});
+		}
+	}
+
+	for token in tokenize_addresses(&unfold(value)) {
+		if in_angle {
+			match token {
+				AddressToken::Special('>') => in_angle = false,
+				AddressToken::Word(w) | AddressToken::Quoted(w) => {
+					mailbox.angle.get_or_insert_with(String::new).push_str(&w)
+				}
+				AddressToken::Special(c) => mailbox.angle.get_or_insert_with(String::new).push(c),
+				AddressToken::Comment(_) => {}
+			}
+			continue;
+		}
+
+		match token {
+			AddressToken::Word(w) | AddressToken::Quoted(w) => mailbox.phrase.push(w),
+			AddressToken::Comment(c) => {
+				if mailbox.comment.is_none() && !c.trim().is_empty() {
+					mailbox.comment = Some(c.trim().to_owned());
+				}
+			}
+			AddressToken::Special('<') => {
+				in_angle = true;
+				mailbox.angle = Some(String::new());
+			}
+			AddressToken::Special(':') if group.is_none() && mailbox.angle.is_none() => {
+				flush_ungrouped(&mut groups, &mut ungrouped);
+				let name = decode_words(&std::mem::take(&mut mailbox).phrase.join(" "));
+				group = Some(EmailAddressGroup {
+					name:      Some(name),
+					addresses: vec![],
+				});
+			}
+			AddressToken::Special(',') | AddressToken::Special(';') => {
+				let is_group_end = token == AddressToken::Special(';');
+				if !mailbox.is_empty() {
+					if let Some(address) = mailbox.finish() {
+						match group.as_mut() {
+							Some(g) => g.addresses.push(address),
+							None => ungrouped.push(address),
+						}
+					}
+				}
+				if is_group_end {
+					groups.extend(group.take());
+				}
+			}
+			AddressToken::Special(_) => {}
+		}
+	}
+
+	if let Some(address) = mailbox.finish() {
+		match group.as_mut() {
+			Some(g) => g.addresses.push(address),
+			None => ungrouped.push(address),
+		}
+	}
+	groups.extend(group);
+	flush_ungrouped(&mut groups, &mut ungrouped);
+
+	groups
+}
+
+pub fn parse_addresses(value: &str) -> Vec<EmailAddress> {
+	parse_grouped_addresses(value)
+		.into_iter()
+		.flat_map(|g| g.addresses)
+		.collect()
+}
+
+/// Everything between angle brackets, as used for message ids and urls.
+fn angle_bracketed(value: &str) -> Vec<String> {
+	value
+		.split('<')
+		.skip(1)
+		.filter_map(|s| s.split_once('>'))
+		.map(|(inner, _)| inner.chars().filter(|c| !c.is_whitespace()).collect())
+		.collect()
+}
+
+pub fn parse_message_ids(value: &str) -> Option<Vec<String>> {
+	let ids = angle_bracketed(&strip_comments(&unfold(value)));
+	if ids.is_empty() {
+		None
+	} else {
+		Some(ids)
+	}
+}
+
+pub fn parse_urls(value: &str) -> Option<Vec<String>> {
+	let urls = angle_bracketed(&unfold(value));
+	if urls.is_empty() {
+		None
+	} else {
+		Some(urls)
+	}
+}
+
+pub fn parse_date(value: &str) -> Option<DateTime<FixedOffset>> {
+	let value = strip_comments(&unfold(value));
+	let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+	DateTime::parse_from_rfc2822(&value).ok()
+}
+
+/// The subject without reply and forward markers, roughly the base subject
+/// of RFC 5256 section 2.1. Used for sorting and threading.
+pub fn base_subject(subject: &str) -> String {
+	let mut subject = decode_words(&unfold(subject))
+		.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ")
+		.to_lowercase();
+
+	loop {
+		let before = subject.len();
+
+		while let Some(rest) = subject.strip_suffix("(fwd)") {
+			subject = rest.trim_end().to_owned();
+		}
+
+		// leading "[list] " blobs, as long as something is left after them
+		if let Some(end) = subject.strip_prefix('[').and_then(|s| s.find(']')) {
+			let rest = subject[end + 2..].trim_start();
+			if !rest.is_empty() {
+				subject = rest.to_owned();
+			}
+		}
+
+		for prefix in &["re", "fwd", "fw"] {
+			if let Some(rest) = subject.strip_prefix(prefix) {
+				// an optional blob between prefix and colon, like "re[2]:"
+				let rest = match rest.strip_prefix('[') {
+					Some(blob) => blob.find(']').map_or(rest, |end| &blob[end + 1..]),
+					None => rest,
+				};
+				if let Some(rest) = rest.trim_start().strip_prefix(':') {
+					subject = rest.trim_start().to_owned();
+					break;
+				}
+			}
+		}
+
+		if let Some(inner) = subject
+			.strip_prefix("[fwd:")
+			.and_then(|s| s.strip_suffix(']'))
+		{
+			subject = inner.trim().to_owned();
+		}
+
+		if subject.len() == before {
+			return subject;
+		}
+	}
+}
+
+/// Splits a structured header like Content-Type into its value and its
+/// parameters, with RFC 2231 continuations and charsets already applied.
+pub fn parse_parameters(value: &str) -> (String, HashMap<String, String>) {
+	let value = unfold(value);
+	let mut segments = vec![];
+	let mut current = String::new();
+	let mut quoted = false;
+	let mut chars = value.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'"' => quoted = !quoted,
+			'\\' if quoted => current.extend(chars.next()),
+			';' if !quoted => segments.push(std::mem::take(&mut current)),
+			c => current.push(c),
+		}
+	}
+	segments.push(current);
+
+	let mut segments = segments.into_iter();
+	let main = strip_comments(&segments.next().unwrap_or_default())
+		.trim()
+		.to_owned();
+	let params = segments
+		.filter_map(|s| {
+			let (key, value) = s.split_once('=')?;
+			Some((key.trim().to_owned(), value.trim().to_owned()))
+		})
+		.collect();
+
+	(main, decode_parameters(params))
+}
+
+pub fn percent_decode(s: &str) -> Vec<u8> {
+	let bytes = s.as_bytes();
+	let mut out = vec![];
+	let mut i = 0;
+	while i < bytes.len() {
+		if bytes[i] == b'%' {
+			if let Some(b) = s
+				.get(i + 1..i + 3)
+				.and_then(|h| u8::from_str_radix(h, 16).ok())
+			{
+				out.push(b);
+				i += 3;
+				continue;
+			}
+		}
+		out.push(bytes[i]);
+		i += 1;
+	}
+	out
+}
+
+/// Merges RFC 2231 parameter continuations (`name*0*=...`) and decodes
+/// extended values. Keys are lowercased.
+pub fn decode_parameters(params: Vec<(String, String)>) -> HashMap<String, String> {
+	let mut decoded = HashMap::new();
+	let mut continued: HashMap<String, Vec<(u32, bool, String)>> = HashMap::new();
+
+	for (key, value) in params {
+		let key = key.to_ascii_lowercase();
+		match key.split_once('*') {
+			None => {
+				decoded.insert(key, value);
+			}
+			Some((name, rest)) => {
+				let encoded = rest.is_empty() || rest.ends_with('*');
+				let index = rest.trim_end_matches('*').parse().unwrap_or(0);
+				continued
+					.entry(name.to_owned())
+					.or_default()
+					.push((index, encoded, value));
+			}
+		}
+	}
+
+	for (name, mut parts) in continued {
+		parts.sort_by_key(|(index, ..)| *index);
+		let mut charset = None;
+		let mut bytes = vec![];
+		for (i, (_, encoded, value)) in parts.into_iter().enumerate() {
+			if !encoded {
+				bytes.extend(value.into_bytes());
+				continue;
+			}
+			let mut value = value.as_str();
+			if i == 0 {
+				if let Some((cs, rest)) = value.split_once('\'') {
+					if let Some((_language, rest)) = rest.split_once('\'') {
+						charset = Some(cs.to_owned());
+						value = rest;
+					}
+				}
+			}
+			bytes.extend(percent_decode(value));
+		}
+		let charset = charset.filter(|c| !c.is_empty());
+		let (value, _) = decode_charset(charset.as_deref().unwrap_or("utf-8"), &bytes);
+		decoded.insert(name, value);
+	}
+
+	// encoded words aren't allowed in parameters but widely used for file names
+	for value in decoded.values_mut() {
+		if value.contains("=?") {
+			*value = decode_words(value);
+		}
+	}
+
+	decoded
+}
+
+/// A MIME entity inside of a message, bodies are ranges into the message.
+#[derive(Debug, Clone)]
+pub struct Part {
+	pub headers:      Vec<Header>,
+	/// Lowercased `type/subtype`.
+	pub content_type: String,
+	pub params:       HashMap<String, String>,
+	/// The still encoded body.
+	pub body:         Range<usize>,
+	/// Empty unless this is a multipart, embedded messages are not descended
+	/// into.
+	pub sub_parts:    Vec<Part>,
+}
+
+pub fn parse_message(data: &[u8]) -> Part {
+	parse_part(data, 0..data.len(), "text/plain")
+}
+
+fn parse_part(data: &[u8], range: Range<usize>, default_type: &str) -> Part {
+	let (headers, body_offset) = parse_headers(&data[range.clone()]);
+	let body = (range.start + body_offset).min(range.end)..range.end;
+
+	let (content_type, params) = match find_header(&headers, "Content-Type") {
+		Some(value) => {
+			let (content_type, params) = parse_parameters(value);
+			let content_type = content_type.to_ascii_lowercase();
+			if content_type.contains('/') {
+				(content_type, params)
+			} else {
+				(default_type.to_owned(), params)
+			}
+		}
+		None => (default_type.to_owned(), HashMap::new()),
+	};
+
+	let sub_parts = match params.get("boundary") {
+		Some(boundary) if content_type.starts_with("multipart/") => {
+			let child_type = if content_type == "multipart/digest" {
+				"message/rfc822"
+			} else {
+				"text/plain"
+			};
+			split_multipart(data, body.clone(), boundary)
+				.into_iter()
+				.map(|range| parse_part(data, range, child_type))
+				.collect()
+		}
+		_ => vec![],
+	};
+
+	Part {
+		headers,
+		content_type,
+		params,
+		body,
+		sub_parts,
+	}
+}
+
+fn split_multipart(data: &[u8], body: Range<usize>, boundary: &str) -> Vec<Range<usize>> {
+	let delimiter = format!("--{}", boundary);
+	let mut parts = vec![];
+	let mut start = None;
+	let mut pos = body.start;
+
+	while pos < body.end {
+		let line_end = data[pos..body.end]
+			.iter()
+			.position(|&b| b == b'\n')
+			.map_or(body.end, |e| pos + e + 1);
+		let line = strip_newline(&data[pos..line_end]);
+
+		if let Some(rest) = line.strip_prefix(delimiter.as_bytes()) {
+			let is_close = rest.starts_with(b"--");
+			let rest = if is_close { &rest[2..] } else { rest };
+			if rest.iter().all(|b| b.is_ascii_whitespace()) {
+				if let Some(start) = start {
+					// the line break before the delimiter belongs to the delimiter
+					let mut end = pos;
+					if end > start && data[end - 1] == b'\n' {
+						end -= 1;
+						if end > start && data[end - 1] == b'\r' {
+							end -= 1;
+						}
+					}
+					parts.push(start..end);
+				}
+				if is_close {
+					return parts;
+				}
+				start = Some(line_end);
+			}
+		}
+
+		pos = line_end;
+	}
+
+	if let Some(start) = start {
+		parts.push(start..body.end);
+	}
+	parts
+}
+
+/// Encodes text for an unstructured header or a phrase, using RFC 2047
+/// encoded words only when needed.
+pub fn encode_words(text: &str) -> String {
+	if text.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+		return text.to_owned();
+	}
+
+	// encoded words may be at most 75 characters, that leaves 45 bytes of utf-8
+	let mut words = vec![];
+	let mut chunk = String::new();
+	for c in text.chars() {
+		if chunk.len() + c.len_utf8() > 45 {
+			words.push(std::mem::take(&mut chunk));
+		}
+		chunk.push(c);
+	}
+	words.push(chunk);
+
+	words
+		.iter()
+		.map(|w| format!("=?UTF-8?B?{}?=", base64::encode(w)))
+		.collect::<Vec<_>>()
+		.join("\r\n ")
+}
+
+/// A display name as phrase, quoted if it contains specials.
+fn encode_phrase(name: &str) -> String {
+	if !name.is_ascii() || name.chars().any(char::is_control) {
+		encode_words(name)
+	} else if name
+		.chars()
+		.all(|c| c.is_ascii_alphanumeric() || c == ' ' || "!#$%&'*+-/=?^_`{|}~".contains(c))
+	{
+		name.to_owned()
+	} else {
+		format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+	}
+}
+
+pub fn format_address(address: &EmailAddress) -> String {
+	match address.name.as_deref() {
+		Some(name) if !name.is_empty() => {
+			format!("{} <{}>", encode_phrase(name), address.email)
+		}
+		_ => address.email.clone(),
+	}
+}
+
+pub fn format_addresses(addresses: &[EmailAddress]) -> String {
+	addresses
+		.iter()
+		.map(format_address)
+		.collect::<Vec<_>>()
+		.join(",\r\n ")
+}
+
+/// The counterpart of [`parse_grouped_addresses`], groups without a name are
+/// written as plain addresses.
+pub fn format_grouped_addresses(groups: &[EmailAddressGroup]) -> String {
+	groups
+		.iter()
+		.map(|group| match group.name.as_deref() {
+			Some(name) => format!(
+				"{}: {};",
+				encode_phrase(name),
+				format_addresses(&group.addresses)
+			),
+			None => format_addresses(&group.addresses),
+		})
+		.filter(|g| !g.is_empty())
+		.collect::<Vec<_>>()
+		.join(",\r\n ")
+}
+
+/// A `; name=value` parameter, RFC 2231 encoded unless it's plain ascii.
+pub fn encode_parameter(name: &str, value: &str) -> String {
+	if value.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+		return format!(
+			"; {}=\"{}\"",
+			name,
+			value.replace('\\', "\\\\").replace('"', "\\\"")
+		);
+	}
+
+	let encoded: String = value
+		.bytes()
+		.map(|b| match b {
+			b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+				(b as char).to_string()
+			}
+			b => format!("%{:02X}", b),
+		})
+		.collect();
+	format!("; {}*=utf-8''{}", name, encoded)
+}
+
+/// Base64 with lines of 76 characters.
+pub fn encode_base64(data: &[u8]) -> Vec<u8> {
+	let encoded = base64::encode(data);
+	let mut lines = Vec::with_capacity(encoded.len() + encoded.len() / 38);
+	for line in encoded.as_bytes().chunks(76) {
+		lines.extend_from_slice(line);
+		lines.extend_from_slice(b"\r\n");
+	}
+	lines
+}
+
+/// Something that is different every time it's called.
+fn unique_token() -> String {
+	static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+	let mut hasher = DefaultHasher::new();
+	SystemTime::now().hash(&mut hasher);
+	COUNTER.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+	std::process::id().hash(&mut hasher);
+	format!("{:016x}", hasher.finish())
+}
+
+/// A boundary that won't show up in base64 or in any sane text.
+fn generate_boundary() -> String {
+	format!("=_{}", unique_token())
+}
+
+/// A new message id, without angle brackets.
+pub fn generate_message_id(domain: &str) -> String {
+	format!("{}.{}@{}", unique_token(), std::process::id(), domain)
+}
+
+/// A MIME entity to be written, the counterpart of [`Part`].
+#[derive(Debug, Clone)]
+pub struct Entity {
+	/// The full `Content-Type` value, a multipart gets its boundary added.
+	pub content_type: String,
+	/// All other headers, values are written as they are.
+	pub headers:      Vec<Header>,
+	pub body:         EntityBody,
+}
+
+#[derive(Debug, Clone)]
+pub enum EntityBody {
+	/// The decoded content, the transfer encoding is picked when writing.
+	Data(Vec<u8>),
+	Multipart(Vec<Entity>),
+}
+
+impl Entity {
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut out = vec![];
+		self.write(&mut out);
+		out
+	}
+
+	fn write(&self, out: &mut Vec<u8>) {
+		for header in &self.headers {
+			write_header(out, &header.name, &header.value);
+		}
+
+		match &self.body {
+			EntityBody::Multipart(parts) => {
+				let boundary = generate_boundary();
+				write_header(
+					out,
+					"Content-Type",
+					&format!("{}; boundary=\"{}\"", self.content_type, boundary),
+				);
+				out.extend_from_slice(b"\r\n");
+				for part in parts {
+					out.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+					part.write(out);
+					out.extend_from_slice(b"\r\n");
+				}
+				out.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+			}
+			EntityBody::Data(data) => {
+				let is_message = self.content_type.starts_with("message/");
+				let is_7bit = data.iter().all(|&b| b < 0x80 && b != 0)
+					&& data.split(|&b| b == b'\n').all(|line| line.len() <= 998);
+				let (encoding, body) = if is_7bit {
+					("7bit", normalize_newlines(data))
+				} else if is_message {
+					// RFC 2046 doesn't allow encoding embedded messages
+					("8bit", normalize_newlines(data))
+				} else {
+					("base64", encode_base64(data))
+				};
+
+				write_header(out, "Content-Type", &self.content_type);
+				write_header(out, "Content-Transfer-Encoding", encoding);
+				out.extend_from_slice(b"\r\n");
+				out.extend_from_slice(&body);
+			}
+		}
+	}
+}
+
+fn write_header(out: &mut Vec<u8>, name: &str, value: &str) {
+	let separator = if value.starts_with([' ', '\t']) {
+		":"
+	} else {
+		": "
+	};
+	out.extend_from_slice(format!("{}{}{}\r\n", name, separator, value).as_bytes());
+}
+
+/// Turns bare line feeds into CRLF.
+fn normalize_newlines(data: &[u8]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(data.len());
+	for (i, &b) in data.iter().enumerate() {
+		if b == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+			out.push(b'\r');
+		}
+		out.push(b);
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn address(name: Option<&str>, email: &str) -> EmailAddress {
+		EmailAddress {
+			name:  name.map(str::to_owned),
+			email: email.to_owned(),
+		}
+	}
+
+	#[test]
+	fn headers_are_split_and_folded() {
+		let data = b"Subject: hi\r\n there\r\nbroken\r\nFrom:a@b\n\r\nbody";
+		let (headers, offset) = parse_headers(data);
+		assert_eq!(
+			headers,
+			vec![
+				Header {
+					name:  "Subject".to_owned(),
+					value: " hi\r\n there".to_owned(),
+				},
+				Header {
+					name:  "From".to_owned(),
+					value: "a@b".to_owned(),
+				},
+			]
+		);
+		assert_eq!(&data[offset..], b"body");
+		assert_eq!(find_header(&headers, "subject"), Some(" hi\r\n there"));
+		assert_eq!(unfold(" hi\r\n there"), " hi there");
+
+		// no body
+		let (headers, offset) = parse_headers(b"Subject: hi");
+		assert_eq!(headers.len(), 1);
+		assert_eq!(offset, 11);
+	}
+
+	#[test]
+	fn encoded_words_are_decoded() {
+		assert_eq!(
+			decode_words("=?UTF-8?Q?caf=C3=A9?= =?utf-8?b?w6k=?= x"),
+			"caféé x"
+		);
+		assert_eq!(decode_words("a =?utf-8?q?b_c?= d"), "a b c d");
+		assert_eq!(decode_words("=?ISO-8859-1?Q?Andr=E9?="), "André");
+		assert_eq!(decode_words("=?utf-8*en?q?hi?="), "hi");
+		// not encoded words
+		for s in ["=?bad", "=?utf-8?x?abc?=", "=?utf-8?q?a b?=", "a=?b"] {
+			assert_eq!(decode_words(s), s);
+		}
+	}
+
+	#[test]
+	fn base64_is_lenient() {
+		assert_eq!(decode_base64(b"aGVs\r\nbG8="), b"hello");
+		assert_eq!(decode_base64(b"aGVsbG8"), b"hello");
+		assert_eq!(decode_base64(b"aGVs bG8h*x"), b"hello!");
+		assert_eq!(decode_base64(b""), b"");
+	}
+
+	#[test]
+	fn quoted_printable_is_lenient() {
+		assert_eq!(
+			decode_quoted_printable(b"caf=C3=a9 =\r\nok=\nx =3D=zz=", false),
+			"café okx ==zz".as_bytes()
+		);
+		assert_eq!(decode_quoted_printable(b"a_b", false), b"a_b");
+		assert_eq!(decode_quoted_printable(b"a_b", true), b"a b");
+		assert_eq!(decode_transfer(Some(" BASE64 "), b"aGk="), b"hi");
+		assert_eq!(decode_transfer(Some("8bit"), b"aGk="), b"aGk=");
+	}
+
+	#[test]
+	fn transfer_decoding_in_pieces() {
+		let decode = |encoding: Option<&str>, data: &[u8]| {
+			let mut decoder = TransferDecoder::new(encoding);
+			let mut out = vec![];
+			for chunk in data.chunks(3) {
+				out.extend(decoder.push(chunk));
+			}
+			out.extend(decoder.finish());
+			out
+		};
+		assert_eq!(
+			decode(Some("Base64"), b"aGVs\r\nbG8gd29y\r\nbGQ="),
+			b"hello world"
+		);
+		assert_eq!(
+			decode(Some("quoted-printable"), b"caf=C3=A9 =\r\nau =\nlait=\r\n"),
+			"café au lait".as_bytes()
+		);
+		assert_eq!(decode(None, b"a=3Db"), b"a=3Db");
+	}
+
+	#[test]
+	fn charsets_report_problems() {
+		assert_eq!(
+			decode_charset("utf-8", "é".as_bytes()),
+			("é".to_owned(), false)
+		);
+		assert_eq!(
+			decode_charset(" ISO-8859-1 ", &[0xe9]),
+			("é".to_owned(), false)
+		);
+		assert_eq!(
+			decode_charset("utf-8", &[b'a', 0xff]),
+			("a\u{fffd}".to_owned(), true)
+		);
+		assert_eq!(decode_charset("x-unknown", b"a"), ("a".to_owned(), true));
+	}
+
+	#[test]
+	fn addresses_are_parsed() {
+		assert_eq!(
+			parse_addresses(
+				"\"Doe, John\" <john@example.com>, jane@example.com (Jane),\r\n \
+				 =?utf-8?q?Andr=C3=A9?= <andre@example.com>"
+			),
+			vec![
+				address(Some("Doe, John"), "john@example.com"),
+				address(Some("Jane"), "jane@example.com"),
+				address(Some("André"), "andre@example.com"),
+			]
+		);
+		assert_eq!(
+			parse_addresses("<@a,@b:user@host>"),
+			vec![address(None, "user@host")]
+		);
+		assert_eq!(parse_addresses(""), vec![]);
+	}
+
+	#[test]
+	fn groups_are_parsed() {
+		assert_eq!(
+			parse_grouped_addresses("Friends: a@example.com, B <b@example.com>;, c@example.com"),
+			vec![
+				EmailAddressGroup {
+					name:      Some("Friends".to_owned()),
+					addresses: vec![
+						address(None, "a@example.com"),
+						address(Some("B"), "b@example.com"),
+					],
+				},
+				EmailAddressGroup {
+					name:      None,
+					addresses: vec![address(None, "c@example.com")],
+				},
+			]
+		);
+		assert_eq!(
+			parse_grouped_addresses("undisclosed-recipients:;"),
+			vec![EmailAddressGroup {
+				name:      Some("undisclosed-recipients".to_owned()),
+				addresses: vec![],
+			}]
+		);
+	}
+
+	#[test]
+	fn ids_urls_and_dates_are_parsed() {
+		assert_eq!(
+			parse_message_ids("<a@b> (not <c@d>)\r\n <e @f>"),
+			Some(vec!["a@b".to_owned(), "e@f".to_owned()])
+		);
+		assert_eq!(parse_message_ids("no ids"), None);
+		assert_eq!(
+			parse_urls("<mailto:list@example.com>, <https://example.com/unsubscribe>"),
+			Some(vec![
+				"mailto:list@example.com".to_owned(),
+				"https://example.com/unsubscribe".to_owned(),
+			])
+		);
+		assert_eq!(
+			parse_date("Mon, 1 Mar 2021\r\n 10:00:00 +0100 (CET)").map(|d| d.to_rfc3339()),
+			Some("2021-03-01T10:00:00+01:00".to_owned())
+		);
+		assert_eq!(parse_date("yesterday"), None);
+	}
+
+	#[test]
+	fn base_subjects_drop_markers() {
+		for (subject, base) in [
+			("Re: Hello", "hello"),
+			("RE: re[2]: Fwd: hello  (fwd)", "hello"),
+			("[list] Re: topic", "topic"),
+			("Re: [list] topic", "topic"),
+			("[list]", "[list]"),
+			("[Fwd: original]", "original"),
+			("=?utf-8?q?Re:_caf=C3=A9?=", "café"),
+			("Reply\r\n needed", "reply needed"),
+			("re:", ""),
+		] {
+			assert_eq!(base_subject(subject), base, "{}", subject);
+		}
+	}
+
+	#[test]
+	fn parameters_are_decoded() {
+		let (value, params) =
+			parse_parameters("Text/Plain; Charset=\"utf-8\";\r\n format=flowed; name=\"a;\\\"b\"");
+		assert_eq!(value, "Text/Plain");
+		assert_eq!(params["charset"], "utf-8");
+		assert_eq!(params["format"], "flowed");
+		assert_eq!(params["name"], "a;\"b");
+
+		let (value, params) = parse_parameters(
+			"attachment (file); filename*1=\".txt\"; filename*0*=utf-8''%C3%A4; \
+			 title*=iso-8859-1'en'%E9t%E9; name=\"=?utf-8?q?caf=C3=A9?=\"",
+		);
+		assert_eq!(value, "attachment");
+		assert_eq!(params["filename"], "ä.txt");
+		assert_eq!(params["title"], "été");
+		assert_eq!(params["name"], "café");
+		assert_eq!(percent_decode("%41%4g%"), b"A%4g%");
+	}
+
+	#[test]
+	fn multiparts_are_split() {
+		let data = b"Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+			\r\n\
+			preamble\r\n\
+			--b\r\n\
+			Content-Type: text/html; charset=utf-8\r\n\
+			\r\n\
+			hello\r\n\
+			--b \r\n\
+			\r\n\
+			world\r\n\
+			--bb\r\n\
+			--b--\r\n\
+			epilogue\r\n";
+		let message = parse_message(data);
+		assert_eq!(message.content_type, "multipart/mixed");
+		assert_eq!(message.sub_parts.len(), 2);
+		let html = &message.sub_parts[0];
+		assert_eq!(html.content_type, "text/html");
+		assert_eq!(html.params["charset"], "utf-8");
+		assert_eq!(&data[html.body.clone()], b"hello");
+		let text = &message.sub_parts[1];
+		assert_eq!(text.content_type, "text/plain");
+		assert_eq!(&data[text.body.clone()], b"world\r\n--bb");
+	}
+
+	#[test]
+	fn digests_contain_messages() {
+		let data = b"Content-Type: Multipart/Digest; boundary=d\n\
+			\n\
+			--d\n\
+			\n\
+			Subject: inner\n\
+			--d\n\
+			Content-Type: broken\n\
+			\n\
+			unterminated";
+		let message = parse_message(data);
+		assert_eq!(message.content_type, "multipart/digest");
+		let types: Vec<_> = message
+			.sub_parts
+			.iter()
+			.map(|p| p.content_type.as_str())
+			.collect();
+		assert_eq!(types, ["message/rfc822", "message/rfc822"]);
+		assert_eq!(&data[message.sub_parts[0].body.clone()], b"Subject: inner");
+		assert_eq!(&data[message.sub_parts[1].body.clone()], b"unterminated");
+		assert!(message.sub_parts[0].sub_parts.is_empty());
+
+		assert_eq!(parse_message(b"no headers").content_type, "text/plain");
+	}
+
+	#[test]
+	fn words_are_encoded_when_needed() {
+		assert_eq!(encode_words("plain text"), "plain text");
+		assert_eq!(encode_words("grüße"), "=?UTF-8?B?Z3LDvMOfZQ==?=");
+		for text in ["é".repeat(50), "a\r\nb".to_owned()] {
+			let encoded = encode_words(&text);
+			assert!(encoded.split("\r\n ").all(|w| w.len() <= 75), "{}", encoded);
+			assert_eq!(decode_words(&unfold(&encoded)), text);
+		}
+	}
+
+	#[test]
+	fn addresses_are_formatted() {
+		for (name, formatted) in [
+			(None, "a@example.com"),
+			(Some(""), "a@example.com"),
+			(Some("John Doe"), "John Doe <a@example.com>"),
+			(Some("Doe, John"), "\"Doe, John\" <a@example.com>"),
+			(Some("a \"b\""), "\"a \\\"b\\\"\" <a@example.com>"),
+			(Some("André"), "=?UTF-8?B?QW5kcsOp?= <a@example.com>"),
+		] {
+			let address = address(name, "a@example.com");
+			assert_eq!(format_address(&address), formatted);
+			let parsed = parse_addresses(formatted);
+			assert_eq!(parsed[0].email, address.email);
+			assert_eq!(parsed[0].name, address.name.filter(|n| !n.is_empty()));
+		}
+	}
+
+	#[test]
+	fn groups_are_formatted() {
+		let groups = vec![
+			EmailAddressGroup {
+				name:      Some("Friends".to_owned()),
+				addresses: vec![
+					address(None, "a@example.com"),
+					address(Some("B"), "b@example.com"),
+				],
+			},
+			EmailAddressGroup {
+				name:      None,
+				addresses: vec![address(None, "c@example.com")],
+			},
+		];
+		let mut with_empty = groups.clone();
+		with_empty.push(EmailAddressGroup {
+			name:      None,
+			addresses: vec![],
+		});
+		let formatted = format_grouped_addresses(&with_empty);
+		assert_eq!(
+			formatted,
+			"Friends: a@example.com,\r\n B <b@example.com>;,\r\n c@example.com"
+		);
+		assert_eq!(parse_grouped_addresses(&formatted), groups);
+	}
+
+	#[test]
+	fn parameters_are_encoded() {
+		for (value, encoded) in [
+			("a \"b\".txt", "; filename=\"a \\\"b\\\".txt\""),
+			("ä b.txt", "; filename*=utf-8''%C3%A4%20b.txt"),
+		] {
+			assert_eq!(encode_parameter("filename", value), encoded);
+			let (_, params) = parse_parameters(&format!("attachment{}", encoded));
+			assert_eq!(params["filename"], value);
+		}
+	}
+
+	#[test]
+	fn base64_lines_are_short() {
+		let data: Vec<u8> = (0..=255).collect();
+		let encoded = encode_base64(&data);
+		let lines: Vec<_> = encoded.split(|&b| b == b'\n').map(|l| l.len()).collect();
+		assert_eq!(lines, [77, 77, 77, 77, 41, 0]);
+		assert_eq!(decode_base64(&encoded), data);
+		assert_eq!(encode_base64(b""), b"");
+	}
+
+	#[test]
+	fn entities_are_written() {
+		let entity = Entity {
+			content_type: "multipart/mixed".to_owned(),
+			headers:      vec![
+				Header {
+					name:  "Subject".to_owned(),
+					value: " hi".to_owned(),
+				},
+				Header {
+					name:  "X-Tag".to_owned(),
+					value: "a".to_owned(),
+				},
+			],
+			body:         EntityBody::Multipart(vec![
+				Entity {
+					content_type: "text/plain; charset=utf-8".to_owned(),
+					headers:      vec![],
+					body:         EntityBody::Data(b"line\nline\r\n".to_vec()),
+				},
+				Entity {
+					content_type: "text/plain; charset=utf-8".to_owned(),
+					headers:      vec![],
+					body:         EntityBody::Data("grüße".as_bytes().to_vec()),
+				},
+				Entity {
+					content_type: "message/rfc822".to_owned(),
+					headers:      vec![],
+					body:         EntityBody::Data("Subject: é\n\nbody".as_bytes().to_vec()),
+				},
+			]),
+		};
+		let data = entity.to_bytes();
+		assert!(data.starts_with(b"Subject: hi\r\nX-Tag: a\r\n"));
+
+		let message = parse_message(&data);
+		assert_eq!(message.content_type, "multipart/mixed");
+		let parts: Vec<_> = message
+			.sub_parts
+			.iter()
+			.map(|p| {
+				(
+					find_header(&p.headers, "Content-Transfer-Encoding").unwrap(),
+					decode_transfer(
+						find_header(&p.headers, "Content-Transfer-Encoding"),
+						&data[p.body.clone()],
+					),
+				)
+			})
+			.collect();
+		assert_eq!(
+			parts,
+			[
+				(" 7bit", b"line\r\nline\r\n".to_vec()),
+				(" base64", "grüße".as_bytes().to_vec()),
+				(" 8bit", "Subject: é\r\n\r\nbody".as_bytes().to_vec()),
+			]
+		);
+	}
+}
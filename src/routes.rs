@@ -1,17 +1,41 @@
 use std::{
 	collections::{hash_map::DefaultHasher, HashMap},
 	hash::{Hash, Hasher},
+	ops::Range,
+	str::FromStr,
+	time::{Duration, Instant},
 };
 
-use crate::{auth::User, jmap, state};
+use async_std::{channel::Sender, io::ReadExt};
+use futures::TryStreamExt;
+use serde::Deserialize;
+use tide::{http::Mime, StatusCode};
+
+use crate::{
+	auth::User,
+	blob,
+	error::ProblemDetails,
+	imap,
+	jmap::{self, email::BlobLocation},
+	mime,
+	state,
+};
+
+/// Octets fetched from the imap server at a time while downloading.
+const DOWNLOAD_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Limits of the event source ping interval in seconds, RFC 8620 allows a
+/// minimum of at most 30 and a maximum of at least 300.
+const MIN_PING_INTERVAL: u64 = 30;
+const MAX_PING_INTERVAL: u64 = 300;
 
 pub async fn jmap(mut req: tide::Request<state::State>) -> tide::Result<tide::Response> {
-	// let user = req.ext::<User>().unwrap();
 	let request: jmap::method::Request = req.body_json().await?;
 
+	let user = req.ext::<User>().unwrap();
 	let session_id = req.session().id();
 
-	let jmap_api = jmap::JmapApi::new(session_id, req.state());
+	let jmap_api = jmap::JmapApi::new(session_id, user, req.state());
 	let response = jmap_api.handle_request(request).await?;
 
 	let body = serde_json::to_value(&response)?;
@@ -19,10 +43,11 @@ pub async fn jmap(mut req: tide::Request<state::State>) -> tide::Result<tide::Re
 	Ok(body.into())
 }
 
-pub async fn session<State>(req: tide::Request<State>) -> tide::Result<tide::Response> {
+pub async fn session(req: tide::Request<state::State>) -> tide::Result<tide::Response> {
 	let user = req.ext::<User>().unwrap();
 
 	let account_id: jmap::Id = user.email.clone();
+	let sieve = jmap::sieve_script::account_capabilities(req.state(), &account_id).await;
 
 	let mut accounts = HashMap::new();
 	accounts.insert(
@@ -37,36 +62,70 @@ pub async fn session<State>(req: tide::Request<State>) -> tide::Result<tide::Res
 					max_mailbox_depth:              None,
 					max_size_mailbox_name:          490,
 					max_size_attachments_per_email: 50000000,
-					email_query_sort_options:       vec![
-						"receivedAt".to_owned(),
-						"from".to_owned(),
-						"to".to_owned(),
-						"subject".to_owned(),
-						"size".to_owned(),
-						"header.x-spam-score".to_owned(),
-					],
+					email_query_sort_options:       jmap::email::search::SORT_OPTIONS
+						.iter()
+						.map(|o| o.to_string())
+						.collect(),
 					may_create_top_level_mailbox:   true,
 				},
+				submission: jmap::AccountSubmissionCapabilities {
+					max_delayed_send:      req.state().max_delayed_send,
+					submission_extensions: HashMap::new(),
+				},
+				vacation_response: jmap::EmptyCapabilities {},
+				sieve,
+				quota: jmap::EmptyCapabilities {},
+				blob: jmap::AccountBlobCapabilities {
+					max_size_blob_set:           Some(blob::MAX_SIZE_UPLOAD),
+					max_data_sources:            jmap::blob::MAX_DATA_SOURCES as u64,
+					supported_type_names:        jmap::blob::TYPE_NAMES
+						.iter()
+						.map(|t| t.to_string())
+						.collect(),
+					supported_digest_algorithms: jmap::blob::DIGEST_ALGORITHMS
+						.iter()
+						.map(|a| a.to_string())
+						.collect(),
+				},
 			},
 		},
 	);
 
 	let mut primary_accounts = HashMap::new();
 	primary_accounts.insert("urn:ietf:params:jmap:mail".to_owned(), account_id.clone());
+	primary_accounts.insert(
+		"urn:ietf:params:jmap:submission".to_owned(),
+		account_id.clone(),
+	);
+	primary_accounts.insert(
+		"urn:ietf:params:jmap:vacationresponse".to_owned(),
+		account_id.clone(),
+	);
+	primary_accounts.insert("urn:ietf:params:jmap:sieve".to_owned(), account_id.clone());
+	primary_accounts.insert("urn:ietf:params:jmap:quota".to_owned(), account_id.clone());
+	primary_accounts.insert("urn:ietf:params:jmap:blob".to_owned(), account_id.clone());
 
 	let session = jmap::JmapSession {
 		capabilities: jmap::Capabilities {
-			core: jmap::CoreCapabilities {
-				max_size_upload:         50_000_000,
-				max_concurrent_upload:   4,
+			core:              jmap::CoreCapabilities {
+				max_size_upload:         blob::MAX_SIZE_UPLOAD,
+				max_concurrent_upload:   blob::MAX_CONCURRENT_UPLOAD as u64,
 				max_size_request:        10_000_000,
 				max_concurrent_requests: 4,
 				max_calls_in_request:    16,
-				max_objects_in_get:      500,
+				max_objects_in_get:      jmap::MAX_OBJECTS_IN_GET as u64,
 				max_objects_in_set:      500,
 				collation_algorithms:    vec![],
 			},
-			mail: jmap::EmptyCapabilities {},
+			mail:              jmap::EmptyCapabilities {},
+			submission:        jmap::EmptyCapabilities {},
+			vacation_response: jmap::EmptyCapabilities {},
+			sieve:             jmap::EmptyCapabilities {},
+			quota:             jmap::EmptyCapabilities {},
+			blob:              jmap::EmptyCapabilities {},
+			webpush_vapid:     jmap::WebPushVapidCapabilities {
+				application_server_key: req.state().vapid.public_key(),
+			},
 		},
 		accounts,
 		primary_accounts,
@@ -91,8 +150,427 @@ pub async fn session<State>(req: tide::Request<State>) -> tide::Result<tide::Res
 	Ok(body.into())
 }
 
+fn problem(
+	status: StatusCode,
+	r#type: &str,
+	limit: Option<&str>,
+	detail: &str,
+) -> tide::Result<tide::Response> {
+	let body = serde_json::to_value(ProblemDetails {
+		r#type: r#type.to_owned(),
+		limit:  limit.map(str::to_owned),
+		status: status.into(),
+		detail: detail.to_owned(),
+	})?;
+
+	Ok(tide::Response::builder(status)
+		.body(body)
+		.content_type("application/problem+json")
+		.build())
+}
+
+pub async fn upload(mut req: tide::Request<state::State>) -> tide::Result<tide::Response> {
+	let user = req.ext::<User>().unwrap().clone();
+	let account_id = req.param("account_id")?.to_owned();
+	if account_id != user.email {
+		return problem(
+			StatusCode::NotFound,
+			"about:blank",
+			None,
+			"account not found",
+		);
+	}
+
+	let too_large = || {
+		problem(
+			StatusCode::PayloadTooLarge,
+			"urn:ietf:params:jmap:error:limit",
+			Some("maxSizeUpload"),
+			&format!("uploads are limited to {} octets", blob::MAX_SIZE_UPLOAD),
+		)
+	};
+	if req
+		.len()
+		.is_some_and(|len| len as u64 > blob::MAX_SIZE_UPLOAD)
+	{
+		return too_large();
+	}
+
+	let blobs = req.state().blobs.clone();
+	let _upload = match blobs.start_upload(&account_id) {
+		Some(upload) => upload,
+		None => {
+			return problem(
+				StatusCode::TooManyRequests,
+				"urn:ietf:params:jmap:error:limit",
+				Some("maxConcurrentUpload"),
+				&format!(
+					"only {} uploads at a time are allowed",
+					blob::MAX_CONCURRENT_UPLOAD
+				),
+			)
+		}
+	};
+
+	let r#type = req
+		.content_type()
+		.map_or_else(|| "application/octet-stream".to_owned(), |t| t.to_string());
+
+	// read one octet more than allowed to tell a full upload from a too large one
+	let mut data = vec![];
+	req.take_body()
+		.take(blob::MAX_SIZE_UPLOAD + 1)
+		.read_to_end(&mut data)
+		.await?;
+	if data.len() as u64 > blob::MAX_SIZE_UPLOAD {
+		return too_large();
+	}
+
+	let info = blobs.put(&account_id, &r#type, &data).await?;
+	let body = serde_json::json!({
+		"accountId": account_id,
+		"blobId": info.blob_id,
+		"type": info.r#type,
+		"size": info.size,
+	});
+
+	Ok(tide::Response::builder(StatusCode::Created)
+		.body(body)
+		.build())
+}
+
+#[derive(Deserialize)]
+struct DownloadQuery {
+	accept: Option<String>,
+}
+
+/// The range asked for by a `Range` header, `Ok(None)` for everything and
+/// `Err(())` if it's out of bounds. Only single byte ranges are supported,
+/// anything else is answered with everything.
+fn byte_range(range: Option<&str>, size: u64) -> Result<Option<Range<u64>>, ()> {
+	let spec = match range.and_then(|r| r.trim().strip_prefix("bytes=")) {
+		Some(spec) if !spec.contains(',') => spec,
+		_ => return Ok(None),
+	};
+	let (start, end) = match spec.split_once('-') {
+		Some(bounds) => bounds,
+		None => return Ok(None),
+	};
+
+	let range = match (start.trim(), end.trim()) {
+		// the last octets
+		("", suffix) => match suffix.parse::<u64>() {
+			Ok(0) => return Err(()),
+			Ok(length) => size.saturating_sub(length)..size,
+			Err(_) => return Ok(None),
+		},
+		(start, end) => {
+			let start: u64 = match start.parse() {
+				Ok(start) => start,
+				Err(_) => return Ok(None),
+			};
+			let end = match end.parse::<u64>() {
+				Ok(end) if end >= start => end.saturating_add(1).min(size),
+				_ if end.is_empty() => size,
+				_ => return Ok(None),
+			};
+			start..end
+		}
+	};
+	if range.start >= size {
+		return Err(());
+	}
+	Ok(Some(range))
+}
+
+pub async fn download(req: tide::Request<state::State>) -> tide::Result<tide::Response> {
+	let user = req.ext::<User>().unwrap();
+	if req.param("account_id")? != user.email {
+		return problem(
+			StatusCode::NotFound,
+			"about:blank",
+			None,
+			"account not found",
+		);
+	}
+	let blob_id = req.param("blob_id")?;
+	let not_found = || problem(StatusCode::NotFound, "about:blank", None, "blob not found");
+	let not_satisfiable = |size: u64| {
+		let mut response = problem(
+			StatusCode::RequestedRangeNotSatisfiable,
+			"about:blank",
+			None,
+			&format!("the blob has {} octets", size),
+		)?;
+		response.insert_header("Content-Range", format!("bytes */{}", size));
+		Ok(response)
+	};
+
+	// route parameters arrive as they were in the url
+	let name = String::from_utf8_lossy(&mime::percent_decode(req.param("name")?)).into_owned();
+	let query: DownloadQuery = req.query()?;
+	let content_type = query
+		.accept
+		.and_then(|accept| Mime::from_str(&accept).ok())
+		.unwrap_or(tide::http::mime::BYTE_STREAM);
+	let range = req.header("Range").map(|r| r.as_str().to_owned());
+
+	let session_id = req.session().id();
+	let jmap_api = jmap::JmapApi::new(session_id, user, req.state());
+
+	let mut response = tide::Response::new(StatusCode::Ok);
+	if jmap::email::parse_blob_id(blob_id).is_none() {
+		// uploads and sieve scripts are small enough to keep in memory
+		let data = match jmap_api.fetch_blob(blob_id).await? {
+			Some(data) => data,
+			None => return not_found(),
+		};
+		let size = data.len() as u64;
+		response.insert_header("Accept-Ranges", "bytes");
+		match byte_range(range.as_deref(), size) {
+			Ok(Some(range)) => {
+				response.set_status(StatusCode::PartialContent);
+				response.insert_header(
+					"Content-Range",
+					format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+				);
+				response.set_body(&data[range.start as usize..range.end as usize]);
+			}
+			Ok(None) => response.set_body(data),
+			Err(()) => return not_satisfiable(size),
+		}
+	} else {
+		let location = match jmap_api.locate_blob(blob_id).await? {
+			Some(location) => location,
+			None => return not_found(),
+		};
+
+		let (range, length) = match location.size {
+			Some(size) => {
+				response.insert_header("Accept-Ranges", "bytes");
+				let range = match byte_range(range.as_deref(), size) {
+					Ok(Some(range)) => {
+						response.set_status(StatusCode::PartialContent);
+						response.insert_header(
+							"Content-Range",
+							format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+						);
+						range
+					}
+					Ok(None) => 0..size,
+					Err(()) => return not_satisfiable(size),
+				};
+				let length = (range.end - range.start) as usize;
+				(range, Some(length))
+			}
+			// the encoded content has to be decoded from the start to know where anything is
+			None => {
+				response.insert_header("Accept-Ranges", "none");
+				(0..u64::MAX, None)
+			}
+		};
+
+		let (sender, receiver) = async_std::channel::bounded(2);
+		async_std::task::spawn(send_blob(
+			req.state().clone(),
+			session_id.to_owned(),
+			location,
+			range,
+			sender,
+		));
+		response.set_body(tide::Body::from_reader(receiver.into_async_read(), length));
+	}
+
+	response.set_content_type(content_type);
+	response.insert_header(
+		"Content-Disposition",
+		format!("attachment{}", mime::encode_parameter("filename", &name)),
+	);
+	// blobs never change
+	response.insert_header("Cache-Control", "private, immutable, max-age=31536000");
+	Ok(response)
+}
+
+/// Fetches `range` of a blob piece by piece for a download, which stops when
+/// the client goes away.
+async fn send_blob(
+	state: state::State,
+	session_id: String,
+	location: BlobLocation,
+	range: Range<u64>,
+	sender: Sender<std::io::Result<Vec<u8>>>,
+) {
+	let mut decoder = location
+		.is_encoded()
+		.then(|| mime::TransferDecoder::new(location.encoding.as_deref()));
+
+	let mut offset = range.start;
+	while offset < range.end {
+		let length = (range.end - offset).min(DOWNLOAD_CHUNK_SIZE);
+		let data = match fetch_blob_chunk(&state, &session_id, &location, offset, length).await {
+			Ok(data) => data,
+			Err(e) => {
+				tracing::error!("downloading {:?} failed: {}", location, e);
+				let e = std::io::Error::other(e.to_string());
+				sender.send(Err(e)).await.ok();
+				return;
+			}
+		};
+		offset += data.len() as u64;
+		// partial fetches past the end are cut short
+		let is_last = (data.len() as u64) < length;
+
+		let data = match &mut decoder {
+			Some(decoder) => decoder.push(&data),
+			None => data,
+		};
+		if sender.send(Ok(data)).await.is_err() {
+			return;
+		}
+		if is_last {
+			break;
+		}
+	}
+
+	if let Some(decoder) = decoder {
+		sender.send(Ok(decoder.finish())).await.ok();
+	}
+}
+
+async fn fetch_blob_chunk(
+	state: &state::State,
+	session_id: &str,
+	location: &BlobLocation,
+	offset: u64,
+	length: u64,
+) -> tide::Result<Vec<u8>> {
+	state
+		.with_raw_session(session_id, |mut s| async move {
+			// the session is shared, another request may have selected something else
+			imap::select(&mut s, &location.mailbox, false).await?;
+			let fetched = imap::uid_fetch(
+				&mut s,
+				&[location.uid],
+				&location.fetch_item(offset, length),
+			)
+			.await?;
+
+			Ok(fetched
+				.iter()
+				.find(|(uid, _)| *uid == location.uid)
+				.and_then(|(_, attributes)| location.content(attributes))
+				.map(<[u8]>::to_vec)
+				.unwrap_or_default())
+		})
+		.await
+}
+
+#[derive(Deserialize)]
+struct EventSourceQuery {
+	types:      Option<String>,
+	closeafter: Option<String>,
+	ping:       Option<u64>,
+}
+
+pub async fn event_source(
+	req: tide::Request<state::State>,
+	sender: tide::sse::Sender,
+) -> tide::Result<()> {
+	let user = req.ext::<User>().unwrap().clone();
+	let query: EventSourceQuery = req.query()?;
+	let types = jmap::push::parse_types(query.types.as_deref().unwrap_or("*"));
+	let close_after_state = query.closeafter.as_deref() == Some("state");
+	let ping = query
+		.ping
+		.filter(|p| *p > 0)
+		.map(|p| Duration::from_secs(p.clamp(MIN_PING_INTERVAL, MAX_PING_INTERVAL)));
+
+	let credentials = req.state().credentials(&user.email).ok_or_else(|| {
+		tide::Error::from_str(
+			StatusCode::InternalServerError,
+			"no credentials for the imap server",
+		)
+	})?;
+	let mut watcher = jmap::push::Watcher::connect(&credentials).await?;
+
+	let session_id = req.session().id();
+	let jmap_api = jmap::JmapApi::new(session_id, &user, req.state());
+	let mut tracker = jmap::push::StateTracker::new(&jmap_api, types).await;
+	let mut next_ping = ping.map(|p| Instant::now() + p);
+
+	loop {
+		let timeout = match next_ping {
+			Some(at) => at
+				.saturating_duration_since(Instant::now())
+				.min(jmap::push::POLL_INTERVAL),
+			None => jmap::push::POLL_INTERVAL,
+		};
+		let reported = watcher.wait(timeout).await?;
+
+		let changed = tracker.changes(&jmap_api, reported).await;
+		if !changed.is_empty() {
+			let change = jmap::push::StateChange::new(&user.email, changed);
+			let data = serde_json::to_string(&change)?;
+			// a failed send means the client went away
+			let sent = sender.send("state", data, None).await.is_ok();
+			if !sent || close_after_state {
+				return Ok(());
+			}
+		}
+
+		if let (Some(ping), Some(at)) = (ping, next_ping) {
+			if Instant::now() >= at {
+				let data = serde_json::json!({ "interval": ping.as_secs() });
+				if sender.send("ping", data.to_string(), None).await.is_err() {
+					return Ok(());
+				}
+				next_ping = Some(Instant::now() + ping);
+			}
+		}
+	}
+}
+
 fn calculate_hash<T: Hash>(t: &T) -> u64 {
 	let mut s = DefaultHasher::new();
 	t.hash(&mut s);
 	s.finish()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn byte_range_bounded() {
+		assert_eq!(byte_range(Some("bytes=0-9"), 100), Ok(Some(0..10)));
+		assert_eq!(byte_range(Some("bytes=90-200"), 100), Ok(Some(90..100)));
+		assert_eq!(
+			byte_range(Some("bytes=0-18446744073709551615"), 100),
+			Ok(Some(0..100))
+		);
+	}
+
+	#[test]
+	fn byte_range_open_ended_and_suffix() {
+		assert_eq!(byte_range(Some("bytes=10-"), 100), Ok(Some(10..100)));
+		assert_eq!(byte_range(Some("bytes=-10"), 100), Ok(Some(90..100)));
+		assert_eq!(byte_range(Some("bytes=-500"), 100), Ok(Some(0..100)));
+		assert_eq!(byte_range(Some("bytes=-0"), 100), Err(()));
+	}
+
+	#[test]
+	fn byte_range_out_of_bounds() {
+		assert_eq!(byte_range(Some("bytes=100-"), 100), Err(()));
+		assert_eq!(byte_range(Some("bytes=200-300"), 100), Err(()));
+		assert_eq!(byte_range(Some("bytes=-10"), 0), Err(()));
+	}
+
+	#[test]
+	fn byte_range_everything() {
+		assert_eq!(byte_range(None, 100), Ok(None));
+		assert_eq!(byte_range(Some("bytes=0-9,20-29"), 100), Ok(None));
+		assert_eq!(byte_range(Some("items=0-9"), 100), Ok(None));
+		assert_eq!(byte_range(Some("bytes=9-0"), 100), Ok(None));
+		assert_eq!(byte_range(Some("bytes=a-b"), 100), Ok(None));
+	}
+}
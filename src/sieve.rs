@@ -0,0 +1,276 @@
+//! A minimal ManageSieve client (RFC 5804).
+
+use std::{collections::HashMap, fmt};
+
+use async_native_tls::TlsStream;
+use async_std::{
+	io::{prelude::*, BufReader},
+	net::TcpStream,
+};
+
+use crate::{
+	auth,
+	imap::raw::{self, Token},
+};
+
+const PORT: u16 = 4190;
+
+/// Servers have to support names of at least this many characters.
+pub const MAX_SCRIPT_NAME_LENGTH: usize = 128;
+
+#[derive(Debug)]
+pub enum Error {
+	Io(std::io::Error),
+	Tls(async_native_tls::Error),
+	Protocol(String),
+	/// A `NO` response, with its response code if there was one.
+	No {
+		code: Option<String>,
+		text: String,
+	},
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "managesieve connection failed: {}", e),
+			Error::Tls(e) => write!(f, "managesieve tls failed: {}", e),
+			Error::Protocol(e) => write!(f, "managesieve protocol error: {}", e),
+			Error::No { text, .. } => write!(f, "managesieve server said no: {}", text),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+	fn from(e: std::io::Error) -> Self {
+		Error::Io(e)
+	}
+}
+
+impl From<async_native_tls::Error> for Error {
+	fn from(e: async_native_tls::Error) -> Self {
+		Error::Tls(e)
+	}
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub type Session = Connection<TlsStream<TcpStream>>;
+
+pub struct Connection<S> {
+	stream:           BufReader<S>,
+	/// Capability names, uppercased, with their value.
+	pub capabilities: HashMap<String, String>,
+}
+
+/// Connects to `host`, upgrades the connection with `STARTTLS` and logs in
+/// with the same credentials as for imap.
+pub async fn connect(host: &str, credentials: &auth::Credentials) -> Result<Session> {
+	let tcp = TcpStream::connect((host, PORT)).await?;
+
+	let mut plain = Connection::new(tcp);
+	plain.read_capabilities().await?;
+	if !plain.capabilities.contains_key("STARTTLS") {
+		return Err(Error::Protocol(
+			"the server doesn't offer STARTTLS".to_owned(),
+		));
+	}
+	plain.command(b"STARTTLS").await?;
+
+	let tls = async_native_tls::connect(host, plain.stream.into_inner()).await?;
+	let mut session = Connection::new(tls);
+	// the capabilities are sent again after the tls negotiation
+	session.read_capabilities().await?;
+	session.authenticate(credentials).await?;
+	Ok(session)
+}
+
+fn quoted(s: &str) -> String {
+	format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn literal(data: &[u8]) -> Vec<u8> {
+	let mut out = format!("{{{}+}}\r\n", data.len()).into_bytes();
+	out.extend_from_slice(data);
+	out
+}
+
+/// Whether `name` can be used as a script name (RFC 5804, section 1.6).
+pub fn is_valid_script_name(name: &str) -> bool {
+	!name.is_empty()
+		&& name.chars().count() <= MAX_SCRIPT_NAME_LENGTH
+		&& !name
+			.chars()
+			.any(|c| c.is_control() || c == '\u{2028}' || c == '\u{2029}')
+}
+
+impl<S: Read + Write + Unpin> Connection<S> {
+	pub fn new(stream: S) -> Self {
+		Connection {
+			stream:       BufReader::new(stream),
+			capabilities: HashMap::new(),
+		}
+	}
+
+	/// Reads a line including the literals it contains.
+	async fn read_line(&mut self) -> Result<Vec<u8>> {
+		let mut line = vec![];
+		loop {
+			let start = line.len();
+			if self.stream.read_until(b'\n', &mut line).await? == 0 {
+				return Err(Error::Protocol("connection closed".to_owned()));
+			}
+			match raw::trailing_literal(&line[start..]) {
+				Some(len) => {
+					let mut literal = vec![0; len];
+					self.stream.read_exact(&mut literal).await?;
+					line.extend(literal);
+				}
+				None => return Ok(line),
+			}
+		}
+	}
+
+	/// Reads lines up to the final `OK`, `NO` or `BYE` and returns the ones
+	/// before it.
+	async fn response(&mut self) -> Result<Vec<Vec<Token>>> {
+		let mut lines = vec![];
+		loop {
+			let line = self.read_line().await?;
+			let tokens = raw::parse_tokens(&line).ok_or_else(|| {
+				Error::Protocol(format!(
+					"invalid response `{}`",
+					String::from_utf8_lossy(&line).trim_end()
+				))
+			})?;
+
+			let status = match tokens.first() {
+				Some(Token::Atom(status)) => status.to_ascii_uppercase(),
+				_ => {
+					lines.push(tokens);
+					continue;
+				}
+			};
+			let code = tokens
+				.get(1)
+				.and_then(Token::as_list)
+				.and_then(|code| code.first()?.as_str())
+				.map(str::to_ascii_uppercase);
+			let text = tokens
+				.last()
+				.filter(|t| matches!(t, Token::String(_)))
+				.and_then(Token::as_str)
+				.unwrap_or("")
+				.to_owned();
+			return match status.as_str() {
+				"OK" => Ok(lines),
+				"NO" => Err(Error::No { code, text }),
+				_ => Err(Error::Protocol(format!("{} {}", status, text))),
+			};
+		}
+	}
+
+	async fn command(&mut self, command: &[u8]) -> Result<Vec<Vec<Token>>> {
+		let stream = self.stream.get_mut();
+		stream.write_all(&[command, b"\r\n"].concat()).await?;
+		stream.flush().await?;
+		self.response().await
+	}
+
+	async fn read_capabilities(&mut self) -> Result<()> {
+		self.capabilities = self
+			.response()
+			.await?
+			.iter()
+			.filter_map(|line| {
+				Some((
+					line.first()?.as_str()?.to_ascii_uppercase(),
+					line.get(1).and_then(Token::as_str).unwrap_or("").to_owned(),
+				))
+			})
+			.collect();
+		Ok(())
+	}
+
+	async fn authenticate(&mut self, credentials: &auth::Credentials) -> Result<()> {
+		let token = base64::encode(format!(
+			"\0{}\0{}",
+			credentials.username, credentials.password
+		));
+		self.command(format!("AUTHENTICATE \"PLAIN\" {}", quoted(&token)).as_bytes())
+			.await?;
+		Ok(())
+	}
+
+	/// Whether the server supports the sieve extension `name`.
+	pub fn has_extension(&self, name: &str) -> bool {
+		self.capabilities
+			.get("SIEVE")
+			.is_some_and(|s| s.split_whitespace().any(|e| e.eq_ignore_ascii_case(name)))
+	}
+
+	/// All scripts by name and whether they are the active one.
+	pub async fn list_scripts(&mut self) -> Result<Vec<(String, bool)>> {
+		Ok(self
+			.command(b"LISTSCRIPTS")
+			.await?
+			.iter()
+			.filter_map(|line| {
+				Some((
+					line.first()?.as_str()?.to_owned(),
+					line.get(1).is_some_and(|t| t.is_atom("ACTIVE")),
+				))
+			})
+			.collect())
+	}
+
+	pub async fn get_script(&mut self, name: &str) -> Result<Vec<u8>> {
+		self.command(format!("GETSCRIPT {}", quoted(name)).as_bytes())
+			.await?
+			.first()
+			.and_then(|line| line.first()?.as_bytes())
+			.map(<[u8]>::to_vec)
+			.ok_or_else(|| Error::Protocol("GETSCRIPT returned no script".to_owned()))
+	}
+
+	pub async fn put_script(&mut self, name: &str, content: &[u8]) -> Result<()> {
+		let mut command = format!("PUTSCRIPT {} ", quoted(name)).into_bytes();
+		command.extend(literal(content));
+		self.command(&command).await?;
+		Ok(())
+	}
+
+	/// Activates the script `name`, an empty name deactivates all scripts.
+	pub async fn set_active(&mut self, name: &str) -> Result<()> {
+		self.command(format!("SETACTIVE {}", quoted(name)).as_bytes())
+			.await?;
+		Ok(())
+	}
+
+	/// Checks `content` without storing it, the error text says what's wrong.
+	pub async fn check_script(&mut self, content: &[u8]) -> Result<()> {
+		let mut command = b"CHECKSCRIPT ".to_vec();
+		command.extend(literal(content));
+		self.command(&command).await?;
+		Ok(())
+	}
+
+	pub async fn rename_script(&mut self, name: &str, new_name: &str) -> Result<()> {
+		self.command(format!("RENAMESCRIPT {} {}", quoted(name), quoted(new_name)).as_bytes())
+			.await?;
+		Ok(())
+	}
+
+	pub async fn delete_script(&mut self, name: &str) -> Result<()> {
+		self.command(format!("DELETESCRIPT {}", quoted(name)).as_bytes())
+			.await?;
+		Ok(())
+	}
+
+	pub async fn logout(mut self) {
+		// everything is done already, a failing logout doesn't matter
+		let _ = self.command(b"LOGOUT").await;
+	}
+}
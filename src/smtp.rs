@@ -0,0 +1,668 @@
+//! A minimal smtp submission client (RFC 6409) for `EmailSubmission/set`.
+
+use std::{collections::HashMap, fmt, net::SocketAddr};
+
+use async_native_tls::{TlsConnector, TlsStream};
+use async_std::{
+	io::{prelude::*, BufReader},
+	net::TcpStream,
+};
+
+use crate::{auth, state::MAIL_SERVER};
+
+/// The submission server, from `SMTP_SERVER` and `SMTP_PORT`.
+///
+/// Port 465 uses implicit tls, every other port is upgraded with `STARTTLS`.
+#[derive(Debug, Clone)]
+pub struct Server {
+	pub host:         String,
+	pub port:         u16,
+	pub implicit_tls: bool,
+}
+
+impl Server {
+	pub fn from_env() -> Self {
+		let port = std::env::var("SMTP_PORT")
+			.ok()
+			.and_then(|p| p.parse().ok())
+			.unwrap_or(587);
+		Server {
+			host: std::env::var("SMTP_SERVER").unwrap_or_else(|_| MAIL_SERVER.to_owned()),
+			port,
+			implicit_tls: port == 465,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+	pub code:  u16,
+	pub lines: Vec<String>,
+}
+
+impl Reply {
+	pub fn is_positive(&self) -> bool {
+		(200..400).contains(&self.code)
+	}
+
+	pub fn is_permanent_failure(&self) -> bool {
+		self.code >= 500
+	}
+}
+
+impl fmt::Display for Reply {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {}", self.code, self.lines.join(" "))
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+	Greeting,
+	Hello,
+	StartTls,
+	Auth,
+	MailFrom,
+	Data,
+}
+
+#[derive(Debug)]
+pub enum Error {
+	Io(std::io::Error),
+	Tls(async_native_tls::Error),
+	Protocol(String),
+	/// The server didn't accept a command.
+	Rejected {
+		command: Command,
+		reply:   Reply,
+	},
+	/// Recipients the server didn't accept, nothing was sent.
+	RecipientsRejected(Vec<(String, Reply)>),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "smtp connection failed: {}", e),
+			Error::Tls(e) => write!(f, "smtp tls failed: {}", e),
+			Error::Protocol(e) => write!(f, "smtp protocol error: {}", e),
+			Error::Rejected { command, reply } => {
+				write!(f, "smtp server rejected {:?}: {}", command, reply)
+			}
+			Error::RecipientsRejected(rejected) => {
+				write!(f, "smtp server rejected {} recipients", rejected.len())
+			}
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+	fn from(e: std::io::Error) -> Self {
+		Error::Io(e)
+	}
+}
+
+impl From<async_native_tls::Error> for Error {
+	fn from(e: async_native_tls::Error) -> Self {
+		Error::Tls(e)
+	}
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct Client<S> {
+	stream:         BufReader<S>,
+	/// Our name in `EHLO`.
+	hello_name:     String,
+	/// Keywords of the `EHLO` reply, uppercased, with their parameters.
+	pub extensions: HashMap<String, Vec<String>>,
+}
+
+/// Connects and logs in with the same credentials as for imap.
+pub async fn connect(
+	server: &Server,
+	credentials: &auth::Credentials,
+) -> Result<Client<TlsStream<TcpStream>>> {
+	let tcp = TcpStream::connect((server.host.as_str(), server.port)).await?;
+	let hello_name = address_literal(tcp.local_addr()?);
+
+	let mut client = if server.implicit_tls {
+		let tls = tls_connector().connect(&server.host, tcp).await?;
+		let mut client = Client::new(tls, hello_name);
+		client.expect(None, Command::Greeting).await?;
+		client.hello().await?;
+		client
+	} else {
+		let mut plain = Client::new(tcp, hello_name.clone());
+		plain.expect(None, Command::Greeting).await?;
+		plain.hello().await?;
+		if !plain.extensions.contains_key("STARTTLS") {
+			return Err(Error::Protocol(
+				"the server doesn't offer STARTTLS".to_owned(),
+			));
+		}
+		plain.expect(Some("STARTTLS"), Command::StartTls).await?;
+
+		let tls = tls_connector()
+			.connect(&server.host, plain.stream.into_inner())
+			.await?;
+		let mut client = Client::new(tls, hello_name);
+		client.hello().await?;
+		client
+	};
+
+	client.login(credentials).await?;
+	Ok(client)
+}
+
+fn tls_connector() -> TlsConnector {
+	let connector = TlsConnector::new();
+	// the test server has a certificate of its own
+	#[cfg(test)]
+	let connector = connector.add_root_certificate(tests::certificate());
+	connector
+}
+
+fn address_literal(address: SocketAddr) -> String {
+	match address {
+		SocketAddr::V4(a) => format!("[{}]", a.ip()),
+		SocketAddr::V6(a) => format!("[IPv6:{}]", a.ip()),
+	}
+}
+
+/// Converts line endings to CRLF and escapes lines starting with a dot.
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(message.len() + 64);
+	for line in message.split_inclusive(|b| *b == b'\n') {
+		let line = line
+			.strip_suffix(b"\n")
+			.map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+			.unwrap_or(line);
+		if line.starts_with(b".") {
+			out.push(b'.');
+		}
+		out.extend_from_slice(line);
+		out.extend_from_slice(b"\r\n");
+	}
+	out.extend_from_slice(b".\r\n");
+	out
+}
+
+impl<S: Read + Write + Unpin> Client<S> {
+	pub fn new(stream: S, hello_name: String) -> Self {
+		Client {
+			stream: BufReader::new(stream),
+			hello_name,
+			extensions: HashMap::new(),
+		}
+	}
+
+	async fn read_reply(&mut self) -> Result<Reply> {
+		let mut lines = vec![];
+		loop {
+			let mut line = String::new();
+			if self.stream.read_line(&mut line).await? == 0 {
+				return Err(Error::Protocol("connection closed".to_owned()));
+			}
+			let line = line.trim_end_matches(&['\r', '\n'][..]);
+			let code = line
+				.get(..3)
+				.and_then(|c| c.parse().ok())
+				.ok_or_else(|| Error::Protocol(format!("invalid reply `{}`", line)))?;
+			lines.push(line.get(4..).unwrap_or("").to_owned());
+			// `250-` continues, `250 ` ends the reply
+			if line.as_bytes().get(3) != Some(&b'-') {
+				return Ok(Reply { code, lines });
+			}
+		}
+	}
+
+	/// Sends `command` (if any) and returns the reply, whatever it is.
+	async fn command(&mut self, command: Option<&str>) -> Result<Reply> {
+		if let Some(command) = command {
+			let stream = self.stream.get_mut();
+			stream.write_all(command.as_bytes()).await?;
+			stream.write_all(b"\r\n").await?;
+			stream.flush().await?;
+		}
+		self.read_reply().await
+	}
+
+	/// Like [`Client::command`], failing with [`Error::Rejected`] unless the
+	/// reply is positive.
+	async fn expect(&mut self, command: Option<&str>, kind: Command) -> Result<Reply> {
+		let reply = self.command(command).await?;
+		if !reply.is_positive() {
+			return Err(Error::Rejected {
+				command: kind,
+				reply,
+			});
+		}
+		Ok(reply)
+	}
+
+	pub async fn hello(&mut self) -> Result<()> {
+		let reply = self
+			.expect(Some(&format!("EHLO {}", self.hello_name)), Command::Hello)
+			.await?;
+		// the first line is the greeting
+		self.extensions = reply
+			.lines
+			.iter()
+			.skip(1)
+			.filter_map(|line| {
+				let mut words = line.split_whitespace();
+				Some((
+					words.next()?.to_ascii_uppercase(),
+					words.map(str::to_owned).collect(),
+				))
+			})
+			.collect();
+		Ok(())
+	}
+
+	pub async fn login(&mut self, credentials: &auth::Credentials) -> Result<()> {
+		let mechanisms = self.extensions.get("AUTH").cloned().unwrap_or_default();
+		let supports = |m: &str| mechanisms.iter().any(|s| s.eq_ignore_ascii_case(m));
+
+		if supports("PLAIN") || !supports("LOGIN") {
+			let token = base64::encode(format!(
+				"\0{}\0{}",
+				credentials.username, credentials.password
+			));
+			self.expect(Some(&format!("AUTH PLAIN {}", token)), Command::Auth)
+				.await?;
+		} else {
+			self.expect(Some("AUTH LOGIN"), Command::Auth).await?;
+			self.expect(Some(&base64::encode(&credentials.username)), Command::Auth)
+				.await?;
+			self.expect(Some(&base64::encode(&credentials.password)), Command::Auth)
+				.await?;
+		}
+		Ok(())
+	}
+
+	/// The largest message the server accepts, if it told us.
+	pub fn max_size(&self) -> Option<u64> {
+		self.extensions
+			.get("SIZE")?
+			.first()?
+			.parse()
+			.ok()
+			.filter(|s| *s > 0)
+	}
+
+	/// Sends one message, `mail_from` and `rcpt_to` are paths including
+	/// their parameters, like `<a@example.com> SIZE=100`.
+	///
+	/// Returns the reply to each recipient. Nothing is sent if any recipient
+	/// is rejected, the transaction is reset after every failure so the
+	/// connection can be used for the next one.
+	pub async fn send(
+		&mut self,
+		mail_from: &str,
+		rcpt_to: &[String],
+		message: &[u8],
+	) -> Result<Vec<(String, Reply)>> {
+		let result = self.transaction(mail_from, rcpt_to, message).await;
+		if matches!(
+			result,
+			Err(Error::Rejected { .. }) | Err(Error::RecipientsRejected(_))
+		) {
+			self.command(Some("RSET")).await?;
+		}
+		result
+	}
+
+	async fn transaction(
+		&mut self,
+		mail_from: &str,
+		rcpt_to: &[String],
+		message: &[u8],
+	) -> Result<Vec<(String, Reply)>> {
+		self.expect(Some(&format!("MAIL FROM:{}", mail_from)), Command::MailFrom)
+			.await?;
+
+		let mut recipients = vec![];
+		let mut rejected = vec![];
+		for rcpt in rcpt_to {
+			let reply = self.command(Some(&format!("RCPT TO:{}", rcpt))).await?;
+			if reply.is_positive() {
+				recipients.push((rcpt.clone(), reply));
+			} else {
+				rejected.push((rcpt.clone(), reply));
+			}
+		}
+		if !rejected.is_empty() {
+			return Err(Error::RecipientsRejected(rejected));
+		}
+
+		self.expect(Some("DATA"), Command::Data).await?;
+		let stream = self.stream.get_mut();
+		stream.write_all(&dot_stuff(message)).await?;
+		stream.flush().await?;
+		self.expect(None, Command::Data).await?;
+
+		Ok(recipients)
+	}
+
+	pub async fn quit(mut self) {
+		// the message is already accepted, a failing quit doesn't matter
+		let _ = self.command(Some("QUIT")).await;
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use std::sync::OnceLock;
+
+	use async_native_tls::{Certificate, TlsAcceptor};
+	use async_std::{net::TcpListener, task};
+	use openssl::{
+		asn1::Asn1Time,
+		ec::{EcGroup, EcKey},
+		hash::MessageDigest,
+		nid::Nid,
+		pkcs12::Pkcs12,
+		pkey::PKey,
+		x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+	};
+
+	use super::*;
+
+	/// A self-signed certificate for 127.0.0.1, as pem and as pkcs12 with
+	/// its key.
+	fn identity() -> &'static (Vec<u8>, Vec<u8>) {
+		static IDENTITY: OnceLock<(Vec<u8>, Vec<u8>)> = OnceLock::new();
+		IDENTITY.get_or_init(|| {
+			let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+			let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+			let mut name = X509NameBuilder::new().unwrap();
+			name.append_entry_by_text("CN", "127.0.0.1").unwrap();
+			let name = name.build();
+
+			let mut builder = X509::builder().unwrap();
+			builder.set_version(2).unwrap();
+			builder.set_subject_name(&name).unwrap();
+			builder.set_issuer_name(&name).unwrap();
+			builder.set_pubkey(&key).unwrap();
+			builder
+				.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+				.unwrap();
+			builder
+				.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+				.unwrap();
+			let addresses = SubjectAlternativeName::new()
+				.ip("127.0.0.1")
+				.build(&builder.x509v3_context(None, None))
+				.unwrap();
+			builder.append_extension(addresses).unwrap();
+			builder.sign(&key, MessageDigest::sha256()).unwrap();
+			let certificate = builder.build();
+
+			let pkcs12 = Pkcs12::builder()
+				.name("smtp")
+				.pkey(&key)
+				.cert(&certificate)
+				.build2("test")
+				.unwrap();
+			(certificate.to_pem().unwrap(), pkcs12.to_der().unwrap())
+		})
+	}
+
+	pub fn certificate() -> Certificate {
+		Certificate::from_pem(&identity().0).unwrap()
+	}
+
+	pub fn credentials(password: &str) -> auth::Credentials {
+		auth::Credentials {
+			username: "user@example.com".to_owned(),
+			password: password.to_owned(),
+		}
+	}
+
+	/// A submission server on this machine offering the `auth` mechanisms,
+	/// that serves one client. Returns the commands it got, with `TLS` where
+	/// the connection became secure and the message after `DATA`.
+	pub async fn server(
+		implicit_tls: bool,
+		auth: &'static str,
+	) -> (Server, task::JoinHandle<Vec<String>>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let server = Server {
+			host: "127.0.0.1".to_owned(),
+			port: listener.local_addr().unwrap().port(),
+			implicit_tls,
+		};
+		let handle = task::spawn(async move {
+			let (tcp, _) = listener.accept().await.unwrap();
+			let acceptor = TlsAcceptor::new(&identity().1[..], "test").await.unwrap();
+			let mut commands = vec![];
+			if implicit_tls {
+				let tls = acceptor.accept(tcp).await.unwrap();
+				commands.push("TLS".to_owned());
+				converse(&mut BufReader::new(tls), true, true, auth, &mut commands).await;
+			} else {
+				let mut plain = BufReader::new(tcp);
+				if converse(&mut plain, true, false, auth, &mut commands).await {
+					let tls = acceptor.accept(plain.into_inner()).await.unwrap();
+					commands.push("TLS".to_owned());
+					converse(&mut BufReader::new(tls), false, true, auth, &mut commands).await;
+				}
+			}
+			commands
+		});
+		(server, handle)
+	}
+
+	async fn write_line<S: Write + Unpin>(stream: &mut BufReader<S>, line: &str) {
+		let stream = stream.get_mut();
+		stream.write_all(line.as_bytes()).await.unwrap();
+		stream.write_all(b"\r\n").await.unwrap();
+		stream.flush().await.unwrap();
+	}
+
+	async fn read_line<S: Read + Unpin>(stream: &mut BufReader<S>) -> Option<String> {
+		let mut line = String::new();
+		match stream.read_line(&mut line).await.unwrap() {
+			0 => None,
+			_ => Some(line),
+		}
+	}
+
+	/// Answers commands until the client quits, returns whether it asked
+	/// for `STARTTLS`.
+	async fn converse<S: Read + Write + Unpin>(
+		stream: &mut BufReader<S>,
+		greet: bool,
+		secure: bool,
+		auth: &str,
+		commands: &mut Vec<String>,
+	) -> bool {
+		let plain_token = base64::encode("\0user@example.com\0secret");
+		if greet {
+			write_line(stream, "220 localhost ESMTP").await;
+		}
+		while let Some(line) = read_line(stream).await {
+			let command = line.trim_end().to_owned();
+			commands.push(command.clone());
+			let upper = command.to_ascii_uppercase();
+
+			let reply = if upper.starts_with("EHLO ") {
+				let mut lines = vec![
+					"250-localhost".to_owned(),
+					"250-SIZE 1000".to_owned(),
+					format!("250-AUTH {}", auth),
+				];
+				if !secure {
+					lines.push("250-STARTTLS".to_owned());
+				}
+				lines.push("250 8BITMIME".to_owned());
+				lines.join("\r\n")
+			} else if upper == "STARTTLS" {
+				write_line(stream, "220 ready").await;
+				return true;
+			} else if upper.starts_with("AUTH") && !secure {
+				"530 must issue STARTTLS first".to_owned()
+			} else if upper.starts_with("AUTH PLAIN ") {
+				if command[11..] == plain_token {
+					"235 ok".to_owned()
+				} else {
+					"535 invalid credentials".to_owned()
+				}
+			} else if upper == "AUTH LOGIN" {
+				write_line(stream, "334 VXNlcm5hbWU6").await;
+				let username = read_line(stream).await.unwrap();
+				commands.push(username.trim_end().to_owned());
+				write_line(stream, "334 UGFzc3dvcmQ6").await;
+				let password = read_line(stream).await.unwrap();
+				commands.push(password.trim_end().to_owned());
+				if base64::decode(password.trim_end()).unwrap() == b"secret" {
+					"235 ok".to_owned()
+				} else {
+					"535 invalid credentials".to_owned()
+				}
+			} else if upper.starts_with("MAIL FROM:") || upper == "RSET" {
+				"250 ok".to_owned()
+			} else if upper.starts_with("RCPT TO:") {
+				if upper.contains("REJECTED") {
+					"550 no such user".to_owned()
+				} else if upper.contains("BUSY") {
+					"450 try again later".to_owned()
+				} else {
+					"250 ok".to_owned()
+				}
+			} else if upper == "DATA" {
+				write_line(stream, "354 go ahead").await;
+				let mut message = String::new();
+				while let Some(line) = read_line(stream).await {
+					if line == ".\r\n" {
+						break;
+					}
+					message.push_str(&line);
+				}
+				commands.push(message);
+				"250 queued".to_owned()
+			} else if upper == "QUIT" {
+				write_line(stream, "221 bye").await;
+				return false;
+			} else {
+				"500 unknown command".to_owned()
+			};
+			write_line(stream, &reply).await;
+		}
+		false
+	}
+
+	#[async_std::test]
+	async fn starttls_and_auth_plain() {
+		let (server, commands) = server(false, "PLAIN LOGIN").await;
+		let mut client = connect(&server, &credentials("secret")).await.unwrap();
+		assert_eq!(client.max_size(), Some(1000));
+
+		let replies = client
+			.send(
+				"<user@example.com> SIZE=35 BODY=8BITMIME",
+				&["<a@example.com> NOTIFY=SUCCESS,FAILURE".to_owned()],
+				b"Subject: hi\n\n.starts with a dot\nend\n",
+			)
+			.await
+			.unwrap();
+		assert_eq!(replies.len(), 1);
+		assert_eq!(replies[0].1.code, 250);
+		client.quit().await;
+
+		assert_eq!(
+			commands.await,
+			vec![
+				"EHLO [127.0.0.1]".to_owned(),
+				"STARTTLS".to_owned(),
+				"TLS".to_owned(),
+				"EHLO [127.0.0.1]".to_owned(),
+				format!(
+					"AUTH PLAIN {}",
+					base64::encode("\0user@example.com\0secret")
+				),
+				"MAIL FROM:<user@example.com> SIZE=35 BODY=8BITMIME".to_owned(),
+				"RCPT TO:<a@example.com> NOTIFY=SUCCESS,FAILURE".to_owned(),
+				"DATA".to_owned(),
+				"Subject: hi\r\n\r\n..starts with a dot\r\nend\r\n".to_owned(),
+				"QUIT".to_owned(),
+			]
+		);
+	}
+
+	#[async_std::test]
+	async fn implicit_tls_and_auth_login() {
+		let (server, commands) = server(true, "LOGIN").await;
+		let client = connect(&server, &credentials("secret")).await.unwrap();
+		client.quit().await;
+
+		assert_eq!(
+			commands.await,
+			vec![
+				"TLS".to_owned(),
+				"EHLO [127.0.0.1]".to_owned(),
+				"AUTH LOGIN".to_owned(),
+				base64::encode("user@example.com"),
+				base64::encode("secret"),
+				"QUIT".to_owned(),
+			]
+		);
+	}
+
+	#[async_std::test]
+	async fn wrong_credentials_are_rejected() {
+		let (server, _) = server(false, "PLAIN").await;
+		match connect(&server, &credentials("wrong")).await {
+			Err(Error::Rejected {
+				command: Command::Auth,
+				reply,
+			}) => assert_eq!(reply.code, 535),
+			_ => panic!("the login must fail"),
+		}
+	}
+
+	#[async_std::test]
+	async fn rejected_recipients_send_nothing() {
+		let (server, commands) = server(true, "PLAIN").await;
+		let mut client = connect(&server, &credentials("secret")).await.unwrap();
+
+		let rcpt_to = [
+			"<a@example.com>".to_owned(),
+			"<rejected@example.com>".to_owned(),
+			"<busy@example.com>".to_owned(),
+		];
+		match client
+			.send("<user@example.com>", &rcpt_to, b"Subject: hi\n\n")
+			.await
+		{
+			Err(Error::RecipientsRejected(rejected)) => {
+				let codes: Vec<(&str, u16)> = rejected
+					.iter()
+					.map(|(rcpt, reply)| (rcpt.as_str(), reply.code))
+					.collect();
+				assert_eq!(
+					codes,
+					vec![("<rejected@example.com>", 550), ("<busy@example.com>", 450)]
+				);
+			}
+			_ => panic!("the recipients must be rejected"),
+		}
+
+		// the connection is still good for the next message
+		client
+			.send("<user@example.com>", &rcpt_to[..1], b"Subject: hi\n\n")
+			.await
+			.unwrap();
+		client.quit().await;
+
+		let commands = commands.await;
+		let data = commands.iter().filter(|c| *c == "DATA").count();
+		assert_eq!(data, 1);
+		let reset = commands.iter().position(|c| c == "RSET").unwrap();
+		assert_eq!(commands[reset - 1], "RCPT TO:<busy@example.com>");
+	}
+}
@@ -1,34 +1,84 @@
-use std::{ops::DerefMut, sync::Arc};
+use std::{
+	collections::HashMap as StdHashMap,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
 
-use async_imap::Session;
-use async_native_tls::TlsStream;
-use async_std::{future::Future, net::TcpStream, sync::Mutex};
+use async_std::{
+	future::Future,
+	net::TcpStream,
+	sync::{Mutex, MutexGuardArc},
+};
 use flurry::HashMap;
 
-use crate::{auth, imap};
+use crate::{
+	auth,
+	blob::{self, BlobStore},
+	imap,
+	imap::raw::RawSession,
+	jmap,
+	smtp,
+	store::Store,
+	webpush::Vapid,
+};
+
+/// The host of the imap, smtp and ManageSieve servers.
+pub const MAIL_SERVER: &str = "hrmny.sh";
 
 pub type ImapSession = async_imap::Session<async_native_tls::TlsStream<TcpStream>>;
 
 #[derive(Clone)]
 pub struct State {
-	imap_sessions: Arc<HashMap<String, Arc<Mutex<ImapSession>>>>,
+	imap_sessions:        Arc<HashMap<String, Arc<Mutex<ImapSession>>>>,
+	raw_sessions:         Arc<HashMap<String, Arc<Mutex<RawSession>>>>,
+	pub store:            Store,
+	pub role_names:       Arc<StdHashMap<String, String>>,
+	pub allowed_senders:  Arc<Vec<String>>,
+	// by account, kept to log in to the smtp server when sending
+	credentials:          Arc<HashMap<String, auth::Credentials>>,
+	pub smtp_server:      Arc<smtp::Server>,
+	pub max_delayed_send: u64,
+	pub blobs:            BlobStore,
+	pub vapid:            Vapid,
+	pub vault:            auth::Vault,
 }
 
 impl State {
 	pub fn new() -> Self {
+		let data_dir =
+			PathBuf::from(std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_owned()));
+		Self::with_data_dir(&data_dir)
+	}
+
+	/// Keeps the store, uploads and keys in `data_dir`.
+	pub fn with_data_dir(data_dir: &Path) -> Self {
 		State {
-			imap_sessions: Arc::new(HashMap::new()),
+			imap_sessions:    Arc::new(HashMap::new()),
+			raw_sessions:     Arc::new(HashMap::new()),
+			store:            Store::new(data_dir),
+			role_names:       Arc::new(jmap::mailbox::role_names_from_env()),
+			allowed_senders:  Arc::new(jmap::identity::allowed_senders_from_env()),
+			credentials:      Arc::new(HashMap::new()),
+			smtp_server:      Arc::new(smtp::Server::from_env()),
+			max_delayed_send: jmap::submission::queue::max_delayed_send_from_env(),
+			blobs:            BlobStore::new(data_dir.join("uploads"), blob::ttl_from_env()),
+			vapid:            Vapid::load(&data_dir.join("vapid.pem"))
+				.expect("loading the vapid key failed"),
+			vault:            auth::Vault::load(&data_dir.join("vault.key"))
+				.expect("loading the vault key failed"),
 		}
 	}
 
-	pub async fn with_imap_session<'a, T, F, Fut>(&self, session_id: &str, f: F) -> tide::Result<T>
+	// the guard is passed by value so the future returned by `f` can own it
+	pub async fn with_imap_session<T, F, Fut>(&self, session_id: &str, f: F) -> tide::Result<T>
 	where
-		F: Send + Sync + 'a + FnOnce(&'a mut ImapSession) -> Fut,
-		Fut: Future<Output = tide::Result<T>> + Send + 'a,
-		T: 'static,
+		F: Send + FnOnce(MutexGuardArc<ImapSession>) -> Fut,
+		Fut: Future<Output = tide::Result<T>> + Send,
 	{
-		let guard = self.imap_sessions.guard();
-		let s = match self.imap_sessions.get(session_id, &guard) {
+		let s = match self
+			.imap_sessions
+			.get(session_id, &self.imap_sessions.guard())
+		{
 			Some(s) => s.clone(),
 			None => {
 				return Err(tide::Error::from_str(
@@ -37,11 +87,52 @@ impl State {
 				))
 			}
 		};
-		let mut s = s.lock().await;
-		let fut = f(s.deref_mut());
-		let res = fut.await?;
-		drop(s);
-		Ok(res)
+		let mut s = s.lock_arc().await;
+		imap::drain_unsolicited(&mut s);
+		f(s).await
+	}
+
+	/// Like [`State::with_imap_session`], for the connection used by extension commands.
+	pub async fn with_raw_session<T, F, Fut>(&self, session_id: &str, f: F) -> tide::Result<T>
+	where
+		F: Send + FnOnce(MutexGuardArc<RawSession>) -> Fut,
+		Fut: Future<Output = tide::Result<T>> + Send,
+	{
+		let s = match self
+			.raw_sessions
+			.get(session_id, &self.raw_sessions.guard())
+		{
+			Some(s) => s.clone(),
+			None => {
+				return Err(tide::Error::from_str(
+					tide::StatusCode::InternalServerError,
+					"no raw imap session found for session id",
+				))
+			}
+		};
+		f(s.lock_arc().await).await
+	}
+
+	pub fn credentials(&self, account_id: &str) -> Option<auth::Credentials> {
+		self.credentials
+			.get(account_id, &self.credentials.guard())
+			.cloned()
+	}
+
+	/// Closes the imap sessions of `session_id`.
+	pub fn logout(&self, session_id: &str) {
+		self.imap_sessions
+			.remove(session_id, &self.imap_sessions.guard());
+		self.raw_sessions
+			.remove(session_id, &self.raw_sessions.guard());
+	}
+
+	/// Credentials of every account that logged in since the start.
+	pub fn known_credentials(&self) -> Vec<auth::Credentials> {
+		self.credentials
+			.values(&self.credentials.guard())
+			.cloned()
+			.collect()
 	}
 }
 
@@ -61,13 +152,30 @@ impl State {
 			return Ok(());
 		}
 
-		let session = match imap::create_imap_session("hrmny.sh", credentials).await {
+		let raw_session = match imap::create_raw_session(MAIL_SERVER, &credentials).await {
+			Ok(s) => s,
+			Err(e) => {
+				tracing::error!("failed to create raw imap session: {:#?}", e);
+				return Err(e.into());
+			}
+		};
+		let session = match imap::create_imap_session(MAIL_SERVER, credentials.clone()).await {
 			Ok(s) => s,
 			Err(e) => {
 				tracing::error!("failed to create imap session: {:#?}", e);
 				return Err(e.into());
 			}
 		};
+		self.raw_sessions.insert(
+			session_id.clone(),
+			Arc::new(Mutex::new(raw_session)),
+			&self.raw_sessions.guard(),
+		);
+		self.credentials.insert(
+			credentials.username.clone(),
+			credentials,
+			&self.credentials.guard(),
+		);
 		self.imap_sessions.insert(
 			session_id,
 			Arc::new(Mutex::new(session)),
@@ -0,0 +1,222 @@
+use std::{
+	collections::HashMap,
+	future::Future,
+	path::PathBuf,
+	sync::{Arc, Mutex as StdMutex},
+};
+
+use async_std::{
+	fs,
+	prelude::*,
+	sync::{Mutex, MutexGuardArc},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Json documents persisted per account in the data directory.
+#[derive(Clone)]
+pub struct Store {
+	dir:   PathBuf,
+	// one per document, accounts and documents don't wait for each other
+	locks: Arc<StdMutex<HashMap<PathBuf, Arc<Mutex<()>>>>>,
+}
+
+impl Store {
+	pub fn new(dir: impl Into<PathBuf>) -> Self {
+		Store {
+			dir:   dir.into(),
+			locks: Arc::new(StdMutex::new(HashMap::new())),
+		}
+	}
+
+	fn path(&self, account_id: &str, name: &str) -> PathBuf {
+		// account ids are email addresses, keep them out of the path syntax
+		self.dir
+			.join(base64::encode_config(account_id, base64::URL_SAFE_NO_PAD))
+			.join(format!("{}.json", name))
+	}
+
+	async fn lock(&self, account_id: &str, name: &str) -> MutexGuardArc<()> {
+		let lock = self
+			.locks
+			.lock()
+			.unwrap()
+			.entry(self.path(account_id, name))
+			.or_default()
+			.clone();
+		lock.lock_arc().await
+	}
+
+	async fn read<T: DeserializeOwned + Default>(
+		&self,
+		account_id: &str,
+		name: &str,
+	) -> tide::Result<T> {
+		match fs::read(self.path(account_id, name)).await {
+			Ok(data) => Ok(serde_json::from_slice(&data)?),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	async fn write<T: Serialize>(
+		&self,
+		account_id: &str,
+		name: &str,
+		value: &T,
+	) -> tide::Result<()> {
+		let path = self.path(account_id, name);
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent).await?;
+		}
+
+		// write to a temporary file first so a crash never leaves a truncated document
+		let tmp = path.with_extension("json.tmp");
+		fs::write(&tmp, serde_json::to_vec(value)?).await?;
+		fs::rename(&tmp, &path).await?;
+
+		Ok(())
+	}
+
+	/// Accounts that have a `name` document.
+	pub async fn accounts_with(&self, name: &str) -> tide::Result<Vec<String>> {
+		let mut entries = match fs::read_dir(&self.dir).await {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+			Err(e) => return Err(e.into()),
+		};
+		let mut accounts = vec![];
+		while let Some(entry) = entries.next().await {
+			let account_id = base64::decode_config(
+				entry?.file_name().to_string_lossy().as_bytes(),
+				base64::URL_SAFE_NO_PAD,
+			)
+			.ok()
+			.and_then(|id| String::from_utf8(id).ok());
+			if let Some(account_id) = account_id {
+				if fs::metadata(self.path(&account_id, name)).await.is_ok() {
+					accounts.push(account_id);
+				}
+			}
+		}
+		Ok(accounts)
+	}
+
+	/// Loads the document without changing it.
+	pub async fn get<T>(&self, account_id: &str, name: &str) -> tide::Result<T>
+	where
+		T: DeserializeOwned + Default,
+	{
+		let _lock = self.lock(account_id, name).await;
+		self.read(account_id, name).await
+	}
+
+	/// Loads the document, applies `f` to it and writes it back.
+	pub async fn update<T, R, F>(&self, account_id: &str, name: &str, f: F) -> tide::Result<R>
+	where
+		T: DeserializeOwned + Serialize + Default,
+		F: FnOnce(&mut T) -> R,
+	{
+		let _lock = self.lock(account_id, name).await;
+		let mut value = self.read(account_id, name).await?;
+		let result = f(&mut value);
+		self.write(account_id, name, &value).await?;
+		Ok(result)
+	}
+
+	/// Like [`Store::update`] for changes that have to wait for something,
+	/// the document stays locked until `f` is done. `f` returns the new
+	/// document, or `None` to leave it as it is.
+	pub async fn update_async<T, R, E, F, Fut>(
+		&self,
+		account_id: &str,
+		name: &str,
+		f: F,
+	) -> Result<R, E>
+	where
+		T: DeserializeOwned + Serialize + Default,
+		E: From<tide::Error>,
+		F: FnOnce(T) -> Fut,
+		Fut: Future<Output = Result<(Option<T>, R), E>>,
+	{
+		let _lock = self.lock(account_id, name).await;
+		let value = self.read(account_id, name).await?;
+		let (value, result) = f(value).await?;
+		if let Some(value) = value {
+			self.write(account_id, name, &value).await?;
+		}
+		Ok(result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, Serialize, Default)]
+	struct Counter {
+		count: u64,
+	}
+
+	#[async_std::test]
+	async fn concurrent_updates_are_not_lost() {
+		let dir = std::env::temp_dir().join(format!("store-test-{}", std::process::id()));
+		let store = Store::new(&dir);
+
+		let updates = (0..20).map(|i| {
+			let store = store.clone();
+			async_std::task::spawn(async move {
+				let name = if i % 2 == 0 { "even" } else { "odd" };
+				store
+					.update("a@example.com", name, |c: &mut Counter| c.count += 1)
+					.await
+			})
+		});
+		for update in updates.collect::<Vec<_>>() {
+			update.await.unwrap();
+		}
+
+		let even: Counter = store.get("a@example.com", "even").await.unwrap();
+		let odd: Counter = store.get("a@example.com", "odd").await.unwrap();
+		let other: Counter = store.get("b@example.com", "even").await.unwrap();
+		assert_eq!((even.count, odd.count, other.count), (10, 10, 0));
+		assert_eq!(
+			store.accounts_with("even").await.unwrap(),
+			vec!["a@example.com"]
+		);
+		assert!(store.accounts_with("other").await.unwrap().is_empty());
+
+		fs::remove_dir_all(dir).await.ok();
+	}
+
+	#[async_std::test]
+	async fn async_updates_hold_the_lock() {
+		let dir = std::env::temp_dir().join(format!("store-async-test-{}", std::process::id()));
+		let store = Store::new(&dir);
+
+		let updates = (0..10).map(|_| {
+			let store = store.clone();
+			async_std::task::spawn(async move {
+				store
+					.update_async("a@example.com", "slow", |c: Counter| async move {
+						// everyone reads before anyone writes without the lock
+						async_std::task::sleep(std::time::Duration::from_millis(5)).await;
+						let count = c.count + 1;
+						Ok::<_, tide::Error>((Some(Counter { count }), count))
+					})
+					.await
+			})
+		});
+		for update in updates.collect::<Vec<_>>() {
+			update.await.unwrap();
+		}
+		let unchanged = store
+			.update_async("a@example.com", "slow", |c: Counter| async move {
+				Ok::<_, tide::Error>((None, c.count))
+			})
+			.await
+			.unwrap();
+		assert_eq!(unchanged, 10);
+
+		fs::remove_dir_all(dir).await.ok();
+	}
+}
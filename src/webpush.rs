@@ -0,0 +1,525 @@
+//! A minimal Web Push client (RFC 8030) for push subscriptions.
+//!
+//! Messages are encrypted for the client as in RFC 8291 and the push service
+//! is told who sends them with VAPID (RFC 8292).
+
+use std::{fmt, path::Path, sync::Arc, time::Duration};
+
+use async_std::net::TcpStream;
+use openssl::{
+	bn::BigNumContext,
+	derive::Deriver,
+	ec::{EcGroup, EcKey, EcPoint, PointConversionForm},
+	ecdsa::EcdsaSig,
+	error::ErrorStack,
+	hash::MessageDigest,
+	nid::Nid,
+	pkey::{PKey, Private, Public},
+	sign::Signer,
+	symm::Cipher,
+};
+use serde::{Deserialize, Serialize};
+use tide::http::{mime, Method, Request, Response, StatusCode, Url};
+
+use crate::state::MAIL_SERVER;
+
+/// Messages are a single record of at most this size.
+const RECORD_SIZE: u32 = 4096;
+
+/// How long in seconds the push service keeps a message for a device that
+/// isn't reachable, state changes are stale after that anyway.
+const TTL: u64 = 24 * 60 * 60;
+
+/// How long in seconds a VAPID token is valid, RFC 8292 allows a day.
+const TOKEN_LIFETIME: i64 = 12 * 60 * 60;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The keys a client encrypts its messages with, base64url encoded.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Keys {
+	/// An uncompressed P-256 public key.
+	pub p256dh: String,
+	/// The authentication secret.
+	pub auth:   String,
+}
+
+impl Keys {
+	pub fn is_valid(&self) -> bool {
+		let p256dh = decode(&self.p256dh).and_then(|k| public_key(&k).ok());
+		let auth = decode(&self.auth).filter(|a| a.len() == 16);
+		p256dh.is_some() && auth.is_some()
+	}
+}
+
+#[derive(Debug)]
+pub enum Error {
+	Io(std::io::Error),
+	Tls(async_native_tls::Error),
+	Crypto(ErrorStack),
+	Http(String),
+	InvalidSubscription(String),
+	PayloadTooLarge,
+	/// The push service didn't accept the message.
+	Rejected(StatusCode),
+}
+
+impl Error {
+	/// Whether the push service says the subscription is gone for good.
+	pub fn is_gone(&self) -> bool {
+		matches!(
+			self,
+			Error::Rejected(StatusCode::NotFound | StatusCode::Gone)
+		)
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Io(e) => write!(f, "push connection failed: {}", e),
+			Error::Tls(e) => write!(f, "push tls failed: {}", e),
+			Error::Crypto(e) => write!(f, "push encryption failed: {}", e),
+			Error::Http(e) => write!(f, "push request failed: {}", e),
+			Error::InvalidSubscription(e) => write!(f, "invalid push subscription: {}", e),
+			Error::PayloadTooLarge => write!(f, "push message too large"),
+			Error::Rejected(status) => write!(f, "push service rejected the message: {}", status),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+	fn from(e: std::io::Error) -> Self {
+		Error::Io(e)
+	}
+}
+
+impl From<async_native_tls::Error> for Error {
+	fn from(e: async_native_tls::Error) -> Self {
+		Error::Tls(e)
+	}
+}
+
+impl From<ErrorStack> for Error {
+	fn from(e: ErrorStack) -> Self {
+		Error::Crypto(e)
+	}
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn encode(data: impl AsRef<[u8]>) -> String {
+	base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> Option<Vec<u8>> {
+	// padding is optional in base64url
+	base64::decode_config(data.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn p256() -> Result<EcGroup> {
+	Ok(EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)
+}
+
+fn public_key(data: &[u8]) -> Result<EcKey<Public>> {
+	let group = p256()?;
+	let mut context = BigNumContext::new()?;
+	let point = EcPoint::from_bytes(&group, data, &mut context)?;
+	Ok(EcKey::from_public_key(&group, &point)?)
+}
+
+fn public_key_bytes(key: &EcKey<Private>) -> Result<Vec<u8>> {
+	let mut context = BigNumContext::new()?;
+	Ok(key
+		.public_key()
+		.to_bytes(key.group(), PointConversionForm::UNCOMPRESSED, &mut context)?)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+	let key = PKey::hmac(key)?;
+	let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+	signer.update(data)?;
+	Ok(signer.sign_to_vec()?)
+}
+
+/// A random base64url token of 16 octets.
+pub fn random_token() -> Result<String> {
+	let mut token = [0; 16];
+	openssl::rand::rand_bytes(&mut token)?;
+	Ok(encode(token))
+}
+
+/// The key pair of this server, that push services may restrict
+/// subscriptions to.
+#[derive(Clone)]
+pub struct Vapid {
+	key:     Arc<EcKey<Private>>,
+	/// How the push service can reach us, a `mailto:` or `https:` url.
+	subject: String,
+}
+
+impl Vapid {
+	/// Loads the key from `path`, the first time it is generated and saved
+	/// there. The subject is `VAPID_SUBJECT`, the postmaster by default.
+	pub fn load(path: &Path) -> std::io::Result<Self> {
+		let key = match std::fs::read(path) {
+			Ok(pem) => EcKey::private_key_from_pem(&pem).map_err(std::io::Error::other)?,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				let key = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+					.and_then(|group| EcKey::generate(&group))
+					.map_err(std::io::Error::other)?;
+				let pem = key.private_key_to_pem().map_err(std::io::Error::other)?;
+				if let Some(dir) = path.parent() {
+					std::fs::create_dir_all(dir)?;
+				}
+				std::fs::write(path, pem)?;
+				key
+			}
+			Err(e) => return Err(e),
+		};
+
+		Ok(Vapid {
+			key:     Arc::new(key),
+			subject: std::env::var("VAPID_SUBJECT")
+				.unwrap_or_else(|_| format!("mailto:postmaster@{}", MAIL_SERVER)),
+		})
+	}
+
+	/// The public key in base64url, the `applicationServerKey` of clients.
+	pub fn public_key(&self) -> String {
+		public_key_bytes(&self.key).map(encode).unwrap_or_default()
+	}
+
+	/// The `Authorization` header for a message to `url`.
+	fn authorization(&self, url: &Url) -> Result<String> {
+		let header = encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+		let claims = serde_json::json!({
+			"aud": url.origin().ascii_serialization(),
+			"exp": chrono::Utc::now().timestamp() + TOKEN_LIFETIME,
+			"sub": self.subject,
+		});
+		let unsigned = format!("{}.{}", header, encode(claims.to_string()));
+
+		// JWS wants r and s as 32 octets each rather than DER
+		let signature = EcdsaSig::sign(&openssl::sha::sha256(unsigned.as_bytes()), &self.key)?;
+		let mut raw = vec![];
+		for n in [signature.r(), signature.s()].iter() {
+			let n = n.to_vec();
+			raw.resize(raw.len() + 32 - n.len(), 0);
+			raw.extend_from_slice(&n);
+		}
+
+		Ok(format!(
+			"vapid t={}.{}, k={}",
+			unsigned,
+			encode(raw),
+			self.public_key()
+		))
+	}
+}
+
+/// Encrypts `payload` for the client with `keys`, the body of a message with
+/// `Content-Encoding: aes128gcm`.
+pub fn encrypt(keys: &Keys, payload: &[u8]) -> Result<Vec<u8>> {
+	// every message gets a key pair and salt of its own
+	let group = p256()?;
+	let key = EcKey::generate(&group)?;
+	let mut salt = [0; 16];
+	openssl::rand::rand_bytes(&mut salt)?;
+	encrypt_with(&key, &salt, keys, payload)
+}
+
+fn encrypt_with(key: &EcKey<Private>, salt: &[u8], keys: &Keys, payload: &[u8]) -> Result<Vec<u8>> {
+	let invalid = |key: &str| Error::InvalidSubscription(format!("bad {} key", key));
+	let client_public = decode(&keys.p256dh).ok_or_else(|| invalid("p256dh"))?;
+	let auth = decode(&keys.auth).ok_or_else(|| invalid("auth"))?;
+	// the tag and the delimiter take 17 octets
+	if payload.len() + 17 > RECORD_SIZE as usize {
+		return Err(Error::PayloadTooLarge);
+	}
+
+	let server_public = public_key_bytes(key)?;
+	let private = PKey::from_ec_key(key.clone())?;
+	let peer = PKey::from_ec_key(public_key(&client_public)?)?;
+	let mut deriver = Deriver::new(&private)?;
+	deriver.set_peer(&peer)?;
+	let shared_secret = deriver.derive_to_vec()?;
+
+	// HKDF-SHA-256, every expand only needs a single block
+	let prk = hmac_sha256(&auth, &shared_secret)?;
+	let info = [
+		&b"WebPush: info\0"[..],
+		&client_public,
+		&server_public,
+		&[1],
+	]
+	.concat();
+	let ikm = hmac_sha256(&prk, &info)?;
+	let prk = hmac_sha256(salt, &ikm)?;
+	let cek = hmac_sha256(&prk, b"Content-Encoding: aes128gcm\0\x01")?;
+	let nonce = hmac_sha256(&prk, b"Content-Encoding: nonce\0\x01")?;
+
+	// the delimiter marks the only record as the last one
+	let plaintext = [payload, &[2]].concat();
+	let mut tag = [0; 16];
+	let ciphertext = openssl::symm::encrypt_aead(
+		Cipher::aes_128_gcm(),
+		&cek[..16],
+		Some(&nonce[..12]),
+		&[],
+		&plaintext,
+		&mut tag,
+	)?;
+
+	let mut body = salt.to_vec();
+	body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+	body.push(server_public.len() as u8);
+	body.extend_from_slice(&server_public);
+	body.extend_from_slice(&ciphertext);
+	body.extend_from_slice(&tag);
+	Ok(body)
+}
+
+/// Posts `payload` to a push subscription, encrypted if it has `keys`.
+pub async fn send(vapid: &Vapid, url: &str, keys: Option<&Keys>, payload: &[u8]) -> Result<()> {
+	let url = Url::parse(url).map_err(|e| Error::InvalidSubscription(e.to_string()))?;
+
+	let mut request = Request::new(Method::Post, url.clone());
+	match keys {
+		Some(keys) => {
+			request.set_body(encrypt(keys, payload)?);
+			request.set_content_type(mime::BYTE_STREAM);
+			request.insert_header("Content-Encoding", "aes128gcm");
+		}
+		None => {
+			request.set_body(payload);
+			request.set_content_type(mime::JSON);
+		}
+	}
+	request.insert_header("TTL", TTL.to_string());
+	request.insert_header("Authorization", vapid.authorization(&url)?);
+
+	let response = async_std::future::timeout(TIMEOUT, post(&url, request))
+		.await
+		.map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+	if !response.status().is_success() {
+		return Err(Error::Rejected(response.status()));
+	}
+	Ok(())
+}
+
+async fn post(url: &Url, request: Request) -> Result<Response> {
+	let host = url
+		.host_str()
+		.ok_or_else(|| Error::InvalidSubscription("the url has no host".to_owned()))?;
+	let port = url.port_or_known_default().unwrap_or(443);
+	let tcp = TcpStream::connect((host, port)).await?;
+
+	let response = if url.scheme() == "https" {
+		let tls = async_native_tls::connect(host, tcp).await?;
+		async_h1::connect(tls, request).await
+	} else {
+		async_h1::connect(tcp, request).await
+	};
+	response.map_err(|e| Error::Http(e.to_string()))
+}
+
+#[cfg(test)]
+pub mod tests {
+	use async_std::{
+		io::prelude::*,
+		net::TcpListener,
+		task::{self, JoinHandle},
+	};
+	use openssl::bn::BigNum;
+
+	use super::*;
+
+	/// The keys of the user agent in RFC 8291 appendix A.
+	pub fn rfc8291_keys() -> Keys {
+		Keys {
+			p256dh: "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"
+				.to_owned(),
+			auth:   "BTBZMqHH6r4Tts7J_aSIgg".to_owned(),
+		}
+	}
+
+	/// A push service on this machine that answers one request with `status`,
+	/// returns its url and the request it got.
+	pub async fn sink(status: &'static str) -> (String, JoinHandle<String>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!(
+			"http://127.0.0.1:{}/push/device",
+			listener.local_addr().unwrap().port()
+		);
+		let handle = task::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut request = vec![];
+			let mut buffer = [0; 4096];
+			loop {
+				let n = stream.read(&mut buffer).await.unwrap();
+				request.extend_from_slice(&buffer[..n]);
+				let text = String::from_utf8_lossy(&request).to_lowercase();
+				if let Some(end) = text.find("\r\n\r\n") {
+					let length = text
+						.lines()
+						.find_map(|l| l.strip_prefix("content-length: "))
+						.map_or(0, |l| l.trim().parse().unwrap());
+					if n == 0 || request.len() >= end + 4 + length {
+						break;
+					}
+				}
+			}
+			let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+			stream.write_all(response.as_bytes()).await.unwrap();
+			// the body may be encrypted
+			String::from_utf8_lossy(&request).into_owned()
+		});
+		(url, handle)
+	}
+
+	pub fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+		request.lines().find_map(|line| {
+			let (key, value) = line.split_once(": ")?;
+			key.eq_ignore_ascii_case(name).then_some(value)
+		})
+	}
+
+	fn vapid() -> (Vapid, std::path::PathBuf) {
+		let dir = std::env::temp_dir().join(format!("webpush-test-{}", random_token().unwrap()));
+		(Vapid::load(&dir.join("vapid.pem")).unwrap(), dir)
+	}
+
+	#[test]
+	fn encrypt_rfc8291_example() {
+		let group = p256().unwrap();
+		let private = decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw").unwrap();
+		let public = decode(
+			"BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8",
+		)
+		.unwrap();
+		let mut context = BigNumContext::new().unwrap();
+		let point = EcPoint::from_bytes(&group, &public, &mut context).unwrap();
+		let key =
+			EcKey::from_private_components(&group, &BigNum::from_slice(&private).unwrap(), &point)
+				.unwrap();
+		let salt = decode("DGv6ra1nlYgDCS1FRnbzlw").unwrap();
+
+		let body = encrypt_with(
+			&key,
+			&salt,
+			&rfc8291_keys(),
+			b"When I grow up, I want to be a watermelon",
+		)
+		.unwrap();
+		assert_eq!(
+			encode(body),
+			"DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN"
+		);
+	}
+
+	#[test]
+	fn keys_are_validated() {
+		assert!(rfc8291_keys().is_valid());
+		let short_auth = Keys {
+			auth: "BTBZMqHH6r4Tts7J".to_owned(),
+			..rfc8291_keys()
+		};
+		assert!(!short_auth.is_valid());
+		let not_a_point = Keys {
+			p256dh: "AAAA".to_owned(),
+			..rfc8291_keys()
+		};
+		assert!(!not_a_point.is_valid());
+		assert!(matches!(
+			encrypt(&rfc8291_keys(), &[0; RECORD_SIZE as usize]),
+			Err(Error::PayloadTooLarge)
+		));
+	}
+
+	#[test]
+	fn vapid_key_is_kept() {
+		let (vapid, dir) = vapid();
+		let again = Vapid::load(&dir.join("vapid.pem")).unwrap();
+		assert_eq!(vapid.public_key(), again.public_key());
+		assert_eq!(decode(&vapid.public_key()).unwrap().len(), 65);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn vapid_signature_is_raw() {
+		let (vapid, dir) = vapid();
+		let url = Url::parse("https://push.example.com/device?x=1").unwrap();
+		let authorization = vapid.authorization(&url).unwrap();
+
+		let (token, key) = authorization
+			.strip_prefix("vapid t=")
+			.and_then(|a| a.split_once(", k="))
+			.unwrap();
+		assert_eq!(key, vapid.public_key());
+		let parts: Vec<&str> = token.split('.').collect();
+		assert_eq!(parts.len(), 3);
+		let claims: serde_json::Value = serde_json::from_slice(&decode(parts[1]).unwrap()).unwrap();
+		assert_eq!(claims["aud"], "https://push.example.com");
+
+		// r and s, not a DER sequence
+		let signature = decode(parts[2]).unwrap();
+		assert_eq!(signature.len(), 64);
+		let signature = EcdsaSig::from_private_components(
+			BigNum::from_slice(&signature[..32]).unwrap(),
+			BigNum::from_slice(&signature[32..]).unwrap(),
+		)
+		.unwrap();
+		let digest = openssl::sha::sha256(format!("{}.{}", parts[0], parts[1]).as_bytes());
+		let public = public_key(&decode(key).unwrap()).unwrap();
+		assert!(signature.verify(&digest, &public).unwrap());
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[async_std::test]
+	async fn send_posts_to_the_push_service() {
+		let (vapid, dir) = vapid();
+
+		let (url, request) = sink("201 Created").await;
+		send(&vapid, &url, None, br#"{"@type":"StateChange"}"#)
+			.await
+			.unwrap();
+		let request = request.await;
+		assert!(request.starts_with("POST /push/device HTTP/1.1\r\n"));
+		assert!(request.ends_with(r#"{"@type":"StateChange"}"#));
+		assert_eq!(header(&request, "content-type"), Some("application/json"));
+		assert_eq!(header(&request, "ttl"), Some("86400"));
+		assert!(header(&request, "authorization")
+			.unwrap()
+			.starts_with("vapid t="));
+
+		let (url, request) = sink("201 Created").await;
+		send(&vapid, &url, Some(&rfc8291_keys()), b"hello")
+			.await
+			.unwrap();
+		let request = request.await;
+		assert_eq!(header(&request, "content-encoding"), Some("aes128gcm"));
+		// salt, record size, key and the padded payload with its tag
+		let length = 16 + 4 + 1 + 65 + 6 + 16;
+		assert_eq!(
+			header(&request, "content-length"),
+			Some(length.to_string().as_str())
+		);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[async_std::test]
+	async fn send_reports_gone_subscriptions() {
+		let (vapid, dir) = vapid();
+		let (url, _) = sink("410 Gone").await;
+		let e = send(&vapid, &url, None, b"{}").await.unwrap_err();
+		assert!(e.is_gone(), "{}", e);
+
+		let (url, _) = sink("500 Internal Server Error").await;
+		let e = send(&vapid, &url, None, b"{}").await.unwrap_err();
+		assert!(!e.is_gone(), "{}", e);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}